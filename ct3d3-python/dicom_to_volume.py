@@ -15,14 +15,6 @@ args = parser.parse_args()
 
 DICOM_DIR = os.path.dirname(args.dropped_file)
 
-AXIAL = "axial"
-SAGITAL = "sagital"
-CORONAL = "coronal"
-
-AXIAL_COSINES = (1,0,0,0,1,0)
-SAGITAL_COSINES =  (0,1,0,0,0,-1)
-CORONAL_COSINES = (1,0,0,0,0,-1)
-
 def rescale_array(array,minimum_range=0.000010):
     min_val=np.amin(array)
     max_val=np.amax(array)
@@ -32,46 +24,41 @@ def rescale_array(array,minimum_range=0.000010):
 
 class DicomFile:
     
-    @classmethod
-    def direction_cosines_to_plane(cls,direction_cosines):
-
-        direction_cosines = list(map(int,direction_cosines))
-        direction_cosines = list(map(abs,direction_cosines))
-        direction_cosines = tuple(direction_cosines)
-
-        if direction_cosines == (1,0,0,0,0,1):
-            return CORONAL
-        elif direction_cosines == (0,1,0,0,0,1):
-            return SAGITAL
-        elif direction_cosines == (1,0,0,0,1,0):
-            return AXIAL
-
-        return AXIAL
-    
     def __init__(self,path):
         dicom=dcmread(path)
-        self.plane = DicomFile.direction_cosines_to_plane(
-            dicom[0x0020,0x0037].value
-        )
         self.dicom=dicom
         self.path=path
         self.pixels_raw = dicom.pixel_array
-        self.pixels=rescale_array(np.float64(self.pixels_raw))
-        self.mm_per_pixel=dicom[0x0028,0x0030].value #tuple x,y\
+        self.mm_per_pixel=dicom[0x0028,0x0030].value #tuple row spacing, column spacing
         self.mm_per_slice=dicom[0x0018,0x0050].value
-        self.w,self.h=self.pixels.shape
-        self.shape=self.pixels.shape
+        self.orientation=np.array(dicom[0x0020,0x0037].value,dtype=np.float64) #row direction, column direction
+        self.position=np.array(dicom[0x0020,0x0032].value,dtype=np.float64) #centre of the first pixel
+        self.h,self.w=self.pixels_raw.shape
         self.hounsfield=float(dicom[0x0028,0x1053].value)*np.float64(dicom.pixel_array)+float(dicom[0x0028,0x1052].value)
 
+# The voxel data is written as stored, x along the image rows, y down the columns and z across
+# slices. The origin and direction metadata let the viewer reorient it (processing/resample.rs).
 files=natsorted(list(os.listdir(DICOM_DIR)))
-dicom_file=DicomFile(f"{DICOM_DIR}\{files[0]}")
-w=dicom_file.w
-h=dicom_file.h
-
-d=len(files)
-
-cellx,celly=dicom_file.mm_per_pixel[0],dicom_file.mm_per_pixel[1]
-cellz=dicom_file.mm_per_slice
+dicom_files=[DicomFile(os.path.join(DICOM_DIR,file)) for file in files]
+
+row_direction=dicom_files[0].orientation[0:3]
+column_direction=dicom_files[0].orientation[3:6]
+slice_normal=np.cross(row_direction,column_direction)
+dicom_files.sort(key=lambda dicom_file: float(np.dot(dicom_file.position,slice_normal)))
+
+first=dicom_files[0]
+w=first.w
+h=first.h
+d=len(dicom_files)
+
+cellx,celly=first.mm_per_pixel[1],first.mm_per_pixel[0]
+if d > 1:
+    slice_step=(dicom_files[-1].position-first.position)/(d-1)
+    cellz=float(np.linalg.norm(slice_step))
+    slice_direction=slice_step/cellz
+else:
+    cellz=float(first.mm_per_slice)
+    slice_direction=slice_normal
 
 axx=cellx*w/2
 axy=celly*h/2
@@ -83,59 +70,31 @@ axz=axz/minax
 
 axs = np.array([axx, axy, axz], dtype=np.float32)
 res = np.array([w, h, d],int)
+spacing = np.array([cellx, celly, cellz], dtype=np.float32)
+origin = first.position
+direction = np.concatenate([row_direction, column_direction, slice_direction])
 
 volume=np.zeros((w,h,d),dtype=np.float32)
-for z,file in enumerate(files):
-    dicom=DicomFile(os.path.join(DICOM_DIR,file))
-    volume[:,:,z]=dicom.hounsfield.astype(np.float32).transpose()
-
-volume=rescale_array(volume)
+for z,dicom_file in enumerate(dicom_files):
+    volume[:,:,z]=dicom_file.hounsfield.astype(np.float32).transpose()
 
-dcos = tuple(map(int,dicom_file.dicom[0x0020,0x0037].value))
+hu_range = (float(np.amin(volume)), float(np.amax(volume)))
 
-cosines = {
-    "axial":AXIAL_COSINES,
-    "sagital":SAGITAL_COSINES,
-    "coronal":CORONAL_COSINES
-}[dicom_file.plane]
-
-for i in [0,1,2]:
-    if dcos[i] != 0 and cosines[i] != 0 and dcos[i] != cosines[i]:
-        volume = np.flip(volume, axis=0)
-        break
-
-for i in [3,4,5]:
-    if dcos[i] != 0 and cosines[i] != 0 and dcos[i] != cosines[i]:
-        volume = np.flip(volume, axis=1)
-        break
-
-match dicom_file.plane:
+volume=rescale_array(volume)
 
-    case "axial":
-        volume = volume.transpose((0,2,1))
-        axs = axs[[0,2,1]]
-        res = res[[0,2,1]]
-        pass
+with open("temp/initial_volume.txt","wb") as fl:
 
-    case "sagital":
-        volume = volume.transpose((2,1,0))
-        axs = axs[[2,1,0]]
-        res = res[[2,1,0]]
-        pass
+    fl.write((" ".join([str(ax) for ax in axs])).encode('ascii')+b"\n")
 
-    case "coronal":
-        volume = volume.transpose((0,1,2))
-        axs = axs[[0,1,2]]
-        res = res[[0,1,2]]
-        pass
+    fl.write((" ".join([str(r) for r in res]).encode('ascii')+b"\n"))
 
-volume = np.flip(volume, axis=1)
+    fl.write(("spacing "+" ".join([str(s) for s in spacing])).encode('ascii')+b"\n")
 
-with open("temp/initial_volume.txt","wb") as fl:
+    fl.write(("hu_range "+" ".join([str(h) for h in hu_range])).encode('ascii')+b"\n")
 
-    fl.write((" ".join([str(ax) for ax in axs])).encode('ascii')+b"\n")
+    fl.write(("origin "+" ".join([str(o) for o in origin])).encode('ascii')+b"\n")
 
-    fl.write((" ".join([str(r) for r in res]).encode('ascii')+b"\n"))
+    fl.write(("direction "+" ".join([str(c) for c in direction])).encode('ascii')+b"\n")
     print("Writing data to file...")
     volume = volume.astype(dtype=np.float32)
     for z in range(res[2]):
@@ -1,16 +1,24 @@
-use std::any::Any;
 use std::io::Write;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use std::fs::File;
 
-use ocl::{flags, Platform, Device, Context, Queue, CommandQueueProperties, Buffer, Program, Kernel, SpatialDims};
+use ocl::{Platform, Device, Context, Queue, CommandQueueProperties, Buffer, Kernel, SpatialDims, Event};
 use glam::{Vec3, Quat};
-use subprocess::{Exec, Redirection};
+use subprocess::Exec;
 
-use crate::types::ct3d_error::CT3DError;
-use crate::types::application_state::ApplicationState;
+use crate::types::ct3d_error::{CT3DError, ErrorContext};
+use crate::types::application_state::{ApplicationState, OpenCLState, VolumeLevel, HIT_BUFFER_STRIDE};
 use crate::types::volume::Volume;
-use crate::tools::resources::read_resource_file_as_text;
+use crate::types::rgb_image::{RGBImage, FrameReadback};
+use crate::types::camera_state::{CameraState, CameraPresets, Keyframe};
+use crate::types::labelmap::{LabelMap, MAX_LABELS};
+use crate::types::transfer_function::TRANSFER_FUNCTION_ENTRIES;
+use crate::tools::kernel_loader::KernelSource;
+use crate::tools::resources::resource_directory;
+use crate::processing::morphology::MorphologyOperation;
+use crate::processing::resample::{Orientation, reorient_labelmap};
+use crate::tools::segmentation_tool::{IslandCleanup, MORPHOLOGY_RADIUS_MM};
+use crate::input::action::Action;
 
 const INPUT_DATA_BUFFER_SIZE_BYTES: u32 = 1024*1024*1024; // 1 GB of Storage
 const DRAG_RADIANS_PER_SCREEN_X: f32=1.0*2.0*(std::f64::consts::PI as f32); // One rotation per half screen
@@ -19,143 +27,472 @@ const MIN_CAMERA_Z: f32 = -10.0;
 const MAX_CAMERA_Z: f32 = -0.75;
 const ZOOM_SPEED: f32 = 0.25;
 const LOCAL_SIZE: usize = 512;
-const LOW_CUTOFF_CHANGE_SPEED: f32 = 0.010;
+const PROGRESSIVE_PIXEL_STEPS: [u32; 3] = [4, 2, 1]; // Refinement passes after the view changes, before supersampling
+const MAX_SUPERSAMPLES: u32 = 8;
+const INTERACTIVE_MAX_RES: i32 = 192; // Longest axis of the level of detail rendered while the camera moves
+// Positions of buffers in the render kernel's arguments that get replaced
+const OUTPUT_ARG_INDEX: u32 = 1;
+const HIT_ARG_INDEX: u32 = 5;
+const LABELS_ARG_INDEX: u32 = 6;
+const ACCUMULATION_ARG_INDEX: u32 = 9;
+const KERNEL_WATCH_SECONDS: f32 = 0.5; // How often kernel files are checked for changes
+pub const CAMERA_PRESETS_PATH: &str = "temp/camera.json";
+const KEYFRAME_SPACING_SECONDS: f32 = 2.0;
 
 pub fn init(application_state: &mut ApplicationState ) -> Result<(), CT3DError>{
-    let platform = Platform::default();
+    let platform = Platform::first()?;
     // Query for devices of type GPU
-    let devices = Device::list_all(platform).unwrap().into_iter().filter(|device| device.info(ocl::enums::DeviceInfo::Type).unwrap().to_string().to_ascii_lowercase().contains("gpu")).collect::<Vec<Device>>();
-            // Filter the list of devices by vendor
-    let nvidia_devices = devices
-    .into_iter()
-    .filter(|device| device.info(ocl::enums::DeviceInfo::Vendor).unwrap().to_string().starts_with("NVIDIA"))
-    .collect::<Vec<Device>>();
+    let mut devices = Vec::new();
+    for device in Device::list_all(platform)? {
+        if device.info(ocl::enums::DeviceInfo::Type)?.to_string().to_ascii_lowercase().contains("gpu") {
+            devices.push(device);
+        }
+    }
+    // Filter the list of devices by vendor
+    let mut nvidia_devices = Vec::new();
+    for device in devices {
+        if device.info(ocl::enums::DeviceInfo::Vendor)?.to_string().starts_with("NVIDIA") {
+            nvidia_devices.push(device);
+        }
+    }
 
     if nvidia_devices.len() == 0 {
-        panic!("No NVIDIA GPU devices found.");
+        return Err(CT3DError::OpenCL("No NVIDIA GPU devices found.".into()));
     }
 
     let nvidia_device = nvidia_devices[0];
 
     application_state.opencl_state.device = Some(nvidia_device);
 
-    application_state.opencl_state.context = Some(Context::builder().platform(platform).devices(application_state.opencl_state.device.unwrap()).build().unwrap());
+    application_state.opencl_state.context = Some(Context::builder().platform(platform).devices(application_state.opencl_state.device.unwrap()).build()?);
 
     application_state.opencl_state.queue = Some(Queue::new(
-        application_state.opencl_state.context.as_ref().unwrap(), application_state.opencl_state.device.unwrap(), None).unwrap());
+        application_state.opencl_state.context.as_ref().unwrap(), application_state.opencl_state.device.unwrap(), Some(CommandQueueProperties::new().profiling()))?);
 
-    application_state.opencl_state.output_buffer = Some(Buffer::builder()
-        .queue(application_state.opencl_state.queue.as_ref().unwrap().clone())
-        .flags(ocl::core::MEM_WRITE_ONLY)
-        .len(application_state.width*application_state.height*3)
-        .build()
-        .unwrap()
-    );
+    allocate_frame_buffers(application_state.width, application_state.height, application_state)?;
 
     application_state.opencl_state.input_data_buffer = Some(Buffer::builder()
         .queue(application_state.opencl_state.queue.as_ref().unwrap().clone())
         .flags(ocl::core::MEM_READ_ONLY)
         .len(INPUT_DATA_BUFFER_SIZE_BYTES/4)
-        .build()
-        .unwrap()
+        .build()?
     );
 
     // Zero the input data buffer
     let zeros = vec![0.0; (INPUT_DATA_BUFFER_SIZE_BYTES/4) as usize];
-    application_state.opencl_state.input_data_buffer.as_ref().unwrap().write(&zeros).enq().unwrap();
+    application_state.opencl_state.input_data_buffer.as_ref().unwrap().write(&zeros).enq()?;
     
     application_state.opencl_state.screen_dimensions_buffer = Some(Buffer::builder()
         .queue(application_state.opencl_state.queue.as_ref().unwrap().clone())
         .flags(ocl::core::MEM_READ_ONLY)
         .len(2)
-        .build()
-        .unwrap()
+        .build()?
     );
 
     application_state.opencl_state.general_parameters_buffer = Some(Buffer::builder()
     .queue(application_state.opencl_state.queue.as_ref().unwrap().clone())
     .flags(ocl::core::MEM_READ_ONLY)
-    .len(2) // Remember to update if new parameters are added
-    .build()
-    .unwrap()
+    .len(11) // Remember to update if new parameters are added
+    .build()?
     );
 
     application_state.opencl_state.axes_buffer = Some(Buffer::builder()
     .queue(application_state.opencl_state.queue.as_ref().unwrap().clone())
     .flags(ocl::core::MEM_READ_ONLY)
     .len(9)
-    .build()
-    .unwrap()
+    .build()?
     );
 
-    // let source_code =
-    // crate::kernel_helpers::color::COLOR.to_owned() +
-    // &crate::kernel_helpers::math::MATH.to_owned() +
-    // &crate::kernel_helpers::raycasting::RAYCASTING.to_owned() +
-    // &crate::kernels::render::RENDER.to_owned();
-
-    let source_code = read_resource_file_as_text("kernel_helpers/math.cl".to_owned())? +
-    &read_resource_file_as_text("kernel_helpers/raycasting.cl".to_owned())? +
-    &read_resource_file_as_text("kernels/render.cl".to_owned())?;
 
-    File::create("debug/kernel_source.cl").unwrap().write(source_code.as_bytes()).unwrap();
+    // Replaced with a buffer of the right size when a labelmap is loaded
+    application_state.opencl_state.labels_buffer = Some(Buffer::builder()
+        .queue(application_state.opencl_state.queue.as_ref().unwrap().clone())
+        .flags(ocl::core::MEM_READ_ONLY)
+        .len(1)
+        .build()?
+    );
 
+    application_state.opencl_state.label_colors_buffer = Some(Buffer::builder()
+        .queue(application_state.opencl_state.queue.as_ref().unwrap().clone())
+        .flags(ocl::core::MEM_READ_ONLY)
+        .len(MAX_LABELS*4)
+        .build()?
+    );
 
-    application_state.opencl_state.program = Some(Program::builder()
-        .src(source_code.as_str())
-        .build(&application_state.opencl_state.context.as_ref().unwrap().clone())
-        .unwrap()
+    application_state.opencl_state.render_parameters_buffer = Some(Buffer::builder()
+        .queue(application_state.opencl_state.queue.as_ref().unwrap().clone())
+        .flags(ocl::core::MEM_READ_ONLY)
+        .len(3)
+        .build()?
     );
 
-    application_state.opencl_state.kernel = Some(Kernel::builder()
-        .program(&application_state.opencl_state.program.as_ref().unwrap())
+    application_state.opencl_state.transfer_function_buffer = Some(Buffer::builder()
         .queue(application_state.opencl_state.queue.as_ref().unwrap().clone())
-        .arg(application_state.opencl_state.screen_dimensions_buffer.as_ref().unwrap())
-        .arg(application_state.opencl_state.output_buffer.as_ref().unwrap())
-        .arg(application_state.opencl_state.input_data_buffer.as_ref().unwrap())
-        .arg(application_state.opencl_state.axes_buffer.as_ref().unwrap())
-        .arg(application_state.opencl_state.general_parameters_buffer.as_ref().unwrap())
-        .name("render")
-        .build()
-        .unwrap()
+        .flags(ocl::core::MEM_READ_ONLY)
+        .len(TRANSFER_FUNCTION_ENTRIES*4)
+        .build()?
     );
+    update_transfer_function(application_state)?;
 
-    change_volume(application_state, Box::new(crate::content::generate_initial_volume::generate_initial_volume()));
+    build_render_kernel(application_state)?;
+    build_filters_program(application_state)?;
 
+    change_volume(application_state, Box::new(crate::content::generate_initial_volume::generate_initial_volume()?))?;
+
+    if std::path::Path::new(CAMERA_PRESETS_PATH).exists() {
+        application_state.camera_presets = CameraPresets::deserialize_from_file(CAMERA_PRESETS_PATH.to_owned())?;
+    }
+
+    Ok(())
+
+}
+
+/// Assemble render.cl with its helpers and the configured defines, and build the render kernel
+/// over the current buffers. When developing with a resource directory the expanded source is kept in
+/// debug/kernel_source.cl.
+fn build_render_kernel(application_state: &mut ApplicationState) -> Result<(), CT3DError> {
+    let opencl_state = &mut application_state.opencl_state;
+    let source = KernelSource::load("kernels/render.cl", opencl_state.kernel_config.defines())?;
+    if resource_directory().is_some() {
+        std::fs::create_dir_all("debug")?;
+        File::create("debug/kernel_source.cl")?.write_all(source.text.as_bytes())?;
+    }
+    let program = source.build(opencl_state.context.as_ref().unwrap())?;
+
+    let kernel = Kernel::builder()
+        .program(&program)
+        .queue(opencl_state.queue.as_ref().unwrap().clone())
+        .arg(opencl_state.screen_dimensions_buffer.as_ref().unwrap())
+        .arg(opencl_state.output_buffer.as_ref().unwrap())
+        .arg(opencl_state.input_data_buffer.as_ref().unwrap())
+        .arg(opencl_state.axes_buffer.as_ref().unwrap())
+        .arg(opencl_state.general_parameters_buffer.as_ref().unwrap())
+        .arg(opencl_state.hit_buffer.as_ref().unwrap())
+        .arg(opencl_state.labels_buffer.as_ref().unwrap())
+        .arg(opencl_state.label_colors_buffer.as_ref().unwrap())
+        .arg(opencl_state.render_parameters_buffer.as_ref().unwrap())
+        .arg(opencl_state.accumulation_buffer.as_ref().unwrap())
+        .arg(opencl_state.transfer_function_buffer.as_ref().unwrap())
+        .name("render")
+        .build()?;
+
+    opencl_state.program = Some(program);
+    opencl_state.kernel = Some(kernel);
+    opencl_state.render_source = Some(source);
+    // Forces a fresh render with the new kernel
+    opencl_state.contents_version += 1;
     Ok(())
+}
 
+fn build_filters_program(application_state: &mut ApplicationState) -> Result<(), CT3DError> {
+    let opencl_state = &mut application_state.opencl_state;
+    let source = KernelSource::load("kernels/filters.cl", Vec::new())?;
+    opencl_state.filters_program = Some(source.build(opencl_state.context.as_ref().unwrap())?);
+    opencl_state.filters_source = Some(source);
+    Ok(())
+}
+
+/// Rebuild kernels whose files changed on disk, or whose defines changed. A kernel that fails to
+/// build is reported and the previous one kept running.
+fn reload_changed_kernels(application_state: &mut ApplicationState) {
+    let opencl_state = &mut application_state.opencl_state;
+    if opencl_state.last_kernel_check.map(|time| time.elapsed().as_secs_f32() < KERNEL_WATCH_SECONDS).unwrap_or(false) {
+        return;
+    }
+    opencl_state.last_kernel_check = Some(Instant::now());
+
+    let render_changed = opencl_state.render_source.as_ref()
+        .map(|source| source.changed() || source.defines != opencl_state.kernel_config.defines())
+        .unwrap_or(false);
+    if render_changed {
+        match build_render_kernel(application_state) {
+            Ok(()) => println!("Reloaded kernels/render.cl"),
+            Err(e) => {
+                eprintln!("{}", e);
+                application_state.opencl_state.render_source.as_mut().unwrap().mark_seen();
+            }
+        }
+    }
+
+    if application_state.opencl_state.filters_source.as_ref().map(|source| source.changed()).unwrap_or(false) {
+        match build_filters_program(application_state) {
+            Ok(()) => println!("Reloaded kernels/filters.cl"),
+            Err(e) => {
+                eprintln!("{}", e);
+                application_state.opencl_state.filters_source.as_mut().unwrap().mark_seen();
+            }
+        }
+    }
 }
 
-pub fn change_volume(application_state: &mut ApplicationState, volume: Box<Volume>){
+pub fn change_volume(application_state: &mut ApplicationState, volume: Box<Volume>) -> Result<(), CT3DError> {
+    // Measurements are stored in the coordinates of the old volume
+    application_state.active_measurement = None;
+    application_state.measurements.clear();
+    application_state.rois.clear();
+    application_state.freehand_contour = None;
+    application_state.filter_preview = None;
+    if let Some(labelmap) = application_state.labelmap.as_ref() {
+        if !labelmap.matches(&volume) {
+            application_state.labelmap = None;
+            application_state.labelmap_source = None;
+        }
+    }
+    application_state.overlay.volume_changed();
+    upload_volume(&mut application_state.opencl_state, &volume)?;
     application_state.volume = Some(volume);
-    application_state.volume.as_ref().unwrap().as_ref().to_ocl_buffer(application_state.opencl_state.input_data_buffer.as_mut().unwrap())
+    Ok(())
+}
+
+/// Write the volume and its mip chain to input_data_buffer, one after another.
+/// Levels that no longer fit are left out.
+pub fn upload_volume(opencl_state: &mut OpenCLState, volume: &Volume) -> Result<(), CT3DError> {
+    let buffer = opencl_state.input_data_buffer.as_ref().unwrap();
+    volume.to_ocl_buffer(buffer, 0)?;
+    opencl_state.volume_levels = vec![VolumeLevel { offset: 0, res: volume.res }];
+    let mut offset = volume.ocl_buffer_len();
+    for level in volume.mip_levels() {
+        if offset + level.ocl_buffer_len() > (INPUT_DATA_BUFFER_SIZE_BYTES / 4) as usize {
+            break;
+        }
+        level.to_ocl_buffer(buffer, offset)?;
+        opencl_state.volume_levels.push(VolumeLevel { offset, res: level.res });
+        offset += level.ocl_buffer_len();
+    }
+    opencl_state.contents_version += 1;
+    Ok(())
+}
+
+/// Index into `volume_levels` to render: full resolution when idle, a coarse level while the camera moves
+pub fn render_level(application_state: &ApplicationState) -> usize {
+    let levels = &application_state.opencl_state.volume_levels;
+    if !application_state.drag_state.interacting() || levels.is_empty() {
+        return 0;
+    }
+    levels.iter().position(|level| level.res.max_element() <= INTERACTIVE_MAX_RES).unwrap_or(levels.len() - 1)
+}
+
+/// Device buffers sized by the output resolution, swapped into the render kernel once it exists
+fn allocate_frame_buffers(width: u32, height: u32, application_state: &mut ApplicationState) -> Result<(), CT3DError> {
+    let output_buffer = Buffer::builder()
+        .queue(application_state.opencl_state.queue.as_ref().unwrap().clone())
+        .flags(ocl::core::MEM_WRITE_ONLY)
+        .len(width*height)
+        .build()?;
+    let hit_buffer = Buffer::builder()
+        .queue(application_state.opencl_state.queue.as_ref().unwrap().clone())
+        .flags(ocl::core::MEM_WRITE_ONLY)
+        .len(width*height*(HIT_BUFFER_STRIDE as u32))
+        .build()?;
+    let accumulation_buffer = Buffer::builder()
+        .queue(application_state.opencl_state.queue.as_ref().unwrap().clone())
+        .flags(ocl::core::MEM_READ_WRITE)
+        .len(width*height*4)
+        .build()?;
+    if let Some(kernel) = application_state.opencl_state.kernel.as_ref() {
+        kernel.set_arg(OUTPUT_ARG_INDEX, &output_buffer)?;
+        kernel.set_arg(HIT_ARG_INDEX, &hit_buffer)?;
+        kernel.set_arg(ACCUMULATION_ARG_INDEX, &accumulation_buffer)?;
+    }
+    application_state.opencl_state.output_buffer = Some(output_buffer);
+    application_state.opencl_state.hit_buffer = Some(hit_buffer);
+    application_state.opencl_state.accumulation_buffer = Some(accumulation_buffer);
+    Ok(())
+}
+
+/// Reallocate everything sized by the output resolution. Works for any size, not only the one given to `init`.
+pub fn resize(width: u32, height: u32, application_state: &mut ApplicationState) -> Result<(), CT3DError> {
+    allocate_frame_buffers(width, height, application_state)?;
+
+    application_state.width = width;
+    application_state.height = height;
+    application_state.screen_buffer = RGBImage::new(width as usize, height as usize);
+    application_state.frame_readback = FrameReadback::new((width*height) as usize);
+    application_state.hit_data = vec![0.0; (width*height) as usize * HIT_BUFFER_STRIDE];
+
+    Ok(())
+}
+
+/// Upload a labelmap to the device. It must be on the same voxel grid as the current volume.
+pub fn change_labelmap(application_state: &mut ApplicationState, labelmap: Box<LabelMap>) -> Result<(), CT3DError> {
+    let mut labelmap = labelmap;
+    if let Some(volume) = application_state.volume.as_ref() {
+        // Volumes are reoriented for display when they load, the labels have to follow
+        labelmap = Box::new(reorient_labelmap(&labelmap, Orientation::of_volume(volume)));
+        if !labelmap.matches(volume) {
+            return Err(CT3DError::Unsupported(format!(
+                "Labelmap ({} voxels of {} mm at {}) does not cover the volume ({} voxels of {} mm at {})",
+                labelmap.res, labelmap.spacing, labelmap.origin, volume.res, volume.spacing, volume.origin
+            )));
+        }
+    }
+
+    application_state.labelmap = Some(labelmap);
+    upload_labelmap(application_state)
+}
+
+/// Upload the labels and colours of the current labelmap, call after editing it in place
+pub fn upload_labelmap(application_state: &mut ApplicationState) -> Result<(), CT3DError> {
+    let labelmap = match application_state.labelmap.as_ref() {
+        Some(labelmap) => labelmap,
+        None => return Ok(())
+    };
+
+    if application_state.opencl_state.labels_buffer.as_ref().unwrap().len() != labelmap.data.len() {
+        let labels_buffer = Buffer::builder()
+            .queue(application_state.opencl_state.queue.as_ref().unwrap().clone())
+            .flags(ocl::core::MEM_READ_ONLY)
+            .len(labelmap.data.len().max(1))
+            .build()?;
+        application_state.opencl_state.kernel.as_ref().unwrap().set_arg(LABELS_ARG_INDEX, &labels_buffer)?;
+        application_state.opencl_state.labels_buffer = Some(labels_buffer);
+    }
+    labelmap.to_ocl_buffer(application_state.opencl_state.labels_buffer.as_mut().unwrap())?;
+
+    update_label_colors(application_state)
+}
+
+/// Upload label colours and visibility, call after editing the label table
+pub fn update_label_colors(application_state: &mut ApplicationState) -> Result<(), CT3DError> {
+    if let Some(labelmap) = application_state.labelmap.as_ref() {
+        let color_data = labelmap.table.to_color_data();
+        application_state.opencl_state.label_colors_buffer.as_ref().unwrap().write(&color_data).enq()?;
+    }
+    application_state.opencl_state.contents_version += 1;
+    Ok(())
+}
+
+/// Upload the colour table of `application_state.transfer_function` after it was edited. Before `init`
+/// there is no device to upload to, and the table is uploaded by `init`.
+pub fn update_transfer_function(application_state: &mut ApplicationState) -> Result<(), CT3DError> {
+    if let Some(buffer) = application_state.opencl_state.transfer_function_buffer.as_ref() {
+        buffer.write(&application_state.transfer_function.to_table()).enq()?;
+    }
+    application_state.opencl_state.contents_version += 1;
+    Ok(())
+}
+
+/// Work out cutoffs between the main intensity classes of the volume and start at the lowest one
+pub fn suggest_cutoffs(application_state: &mut ApplicationState) {
+    let volume = match application_state.volume.as_ref() {
+        Some(volume) => volume,
+        None => return
+    };
+    application_state.suggested_cutoffs = crate::processing::quantize::suggest_cutoffs(volume);
+    let described: Vec<String> = application_state.suggested_cutoffs.iter().map(|cutoff| match volume.to_hu(*cutoff) {
+        Some(hu) => format!("{:.3} ({:.0} HU)", cutoff, hu),
+        None => format!("{:.3}", cutoff)
+    }).collect();
+    println!("Suggested cutoffs (N to cycle): {}", described.join(", "));
+    if let Some(cutoff) = application_state.suggested_cutoffs.first() {
+        application_state.low_cutoff = *cutoff;
+    }
+}
+
+/// Jump to the next suggested cutoff above the current one, wrapping around
+pub fn next_suggested_cutoff(application_state: &mut ApplicationState) {
+    let cutoffs = &application_state.suggested_cutoffs;
+    if let Some(cutoff) = cutoffs.iter().find(|cutoff| **cutoff > application_state.low_cutoff + 1e-4).or(cutoffs.first()) {
+        application_state.low_cutoff = *cutoff;
+        println!("Cutoff {:.3}", cutoff);
+    }
+}
+
+pub fn print_label_table(application_state: &ApplicationState) {
+    if let Some(labelmap) = application_state.labelmap.as_ref() {
+        println!("Labels (overlay {}):", if application_state.show_labels { "on" } else { "off" });
+        for (index, label) in labelmap.table.labels.iter().enumerate() {
+            println!("  F{}: {} ({}) {}", index + 1, label.name, label.value, if label.visible { "visible" } else { "hidden" });
+        }
+    }
 }
 
 pub fn main(application_state: &mut ApplicationState, delta_time: Duration) -> Result<(), CT3DError>{
 
-    if *application_state.keymap.get(&sdl2::keyboard::Scancode::A) {
-        application_state.low_cutoff = (application_state.low_cutoff - LOW_CUTOFF_CHANGE_SPEED).max(0.0f32);
+    reload_changed_kernels(application_state);
+    application_state.frame_stats.record_frame(delta_time);
+    application_state.frame_stats.collect_kernel_times();
+
+    if application_state.playback.playing {
+        application_state.playback.time += delta_time.as_secs_f32();
+        let animation = &application_state.camera_presets.animation;
+        if application_state.playback.time > animation.duration() {
+            application_state.playback.playing = false;
+        }
+        if let Some(camera_state) = animation.sample(application_state.playback.time) {
+            camera_state.apply(application_state);
+            update_transfer_function(application_state)?;
+        }
     }
 
-    if *application_state.keymap.get(&sdl2::keyboard::Scancode::D) {
-        application_state.low_cutoff = (application_state.low_cutoff + LOW_CUTOFF_CHANGE_SPEED).min(1.0f32);
+    let labels_enabled = if application_state.show_labels && application_state.labelmap.is_some() { 1.0 } else { 0.0 };
+    let level_offset = application_state.opencl_state.volume_levels.get(render_level(application_state)).map(|level| level.offset).unwrap_or(0);
+
+    // Everything the image depends on. While it stays the same the image is refined, then left alone.
+    let mut signature = vec![
+        application_state.width as f32, application_state.height as f32,
+        application_state.camera_z, application_state.low_cutoff, labels_enabled,
+        level_offset as f32, application_state.opencl_state.contents_version as f32,
+        application_state.supersamples as f32
+    ];
+    signature.extend([application_state.RIGHT, application_state.UP, application_state.FORWARD].iter().flat_map(|axis| axis.to_array()));
+    signature.extend(application_state.clip_box.to_parameters());
+    if signature != application_state.render_progress.signature {
+        application_state.render_progress.signature = signature;
+        application_state.render_progress.pass = 0;
     }
 
-    if *application_state.keymap.get(&sdl2::keyboard::Scancode::Q) {
-        application_state.low_cutoff = (application_state.low_cutoff - LOW_CUTOFF_CHANGE_SPEED/4.0).max(0.0f32);
+    let passes = PROGRESSIVE_PIXEL_STEPS.len() + application_state.supersamples as usize;
+    let mut rendered = false;
+    if !application_state.progressive_rendering {
+        // Full quality in one go, for headless rendering
+        render_pass(application_state, 1, 0, level_offset, labels_enabled)?;
+        for sample in 1..=application_state.supersamples {
+            render_pass(application_state, 1, sample, level_offset, labels_enabled)?;
+        }
+        application_state.render_progress.pass = passes;
+        rendered = true;
+    } else if application_state.render_progress.pass < passes {
+        let pass = application_state.render_progress.pass;
+        if pass < PROGRESSIVE_PIXEL_STEPS.len() {
+            render_pass(application_state, PROGRESSIVE_PIXEL_STEPS[pass], 0, level_offset, labels_enabled)?;
+        } else {
+            render_pass(application_state, 1, (pass + 1 - PROGRESSIVE_PIXEL_STEPS.len()) as u32, level_offset, labels_enabled)?;
+        }
+        application_state.render_progress.pass += 1;
+        rendered = true;
     }
 
-    if *application_state.keymap.get(&sdl2::keyboard::Scancode::E) {
-        application_state.low_cutoff = (application_state.low_cutoff + LOW_CUTOFF_CHANGE_SPEED/4.0).min(1.0f32);
+    // The viewer shows the previous frame while this one transfers, headless rendering waits for it
+    if rendered {
+        application_state.frame_readback.request(application_state.opencl_state.output_buffer.as_ref().unwrap())?;
     }
+    application_state.frame_readback.collect(&mut application_state.screen_buffer, !rendered || !application_state.progressive_rendering)?;
+    application_state.screen_buffer.resolve();
+
+    crate::tools::measurements::draw_overlays(application_state);
+    crate::tools::roi_tool::draw_overlays(application_state);
+    crate::tools::frame_timing::draw_overlays(application_state);
+    crate::ui::overlay::draw_overlays(application_state);
+
+    Ok(())
+}
+
+/// Run the render kernel with one ray per `pixel_step` square, averaging it into the device's
+/// accumulation buffer as `sample`. The hit buffer is only written by sample 0, so probes use unjittered rays.
+fn render_pass(application_state: &mut ApplicationState, pixel_step: u32, sample: u32, level_offset: usize, labels_enabled: f32) -> Result<(), CT3DError> {
 
     let screen_dimensions_vec = vec![application_state.width as i32, application_state.height as i32];
 
-    application_state.opencl_state.screen_dimensions_buffer.as_mut().unwrap().write(&screen_dimensions_vec).enq().unwrap();
+    application_state.opencl_state.screen_dimensions_buffer.as_mut().unwrap().write(&screen_dimensions_vec).enq()?;
+
+    let write_hit_buffer = application_state.write_hit_buffer && sample == 0;
 
-    let general_parameters_vec =vec![application_state.camera_z, application_state.low_cutoff];
+    let transfer_function_enabled = if application_state.transfer_function.enabled { 1.0 } else { 0.0 };
+    let mut general_parameters_vec =vec![application_state.camera_z, application_state.low_cutoff, if write_hit_buffer { 1.0 } else { 0.0 }, labels_enabled, transfer_function_enabled];
+    general_parameters_vec.extend(application_state.clip_box.to_parameters());
 
-    application_state.opencl_state.general_parameters_buffer.as_mut().unwrap().write(&general_parameters_vec).enq().unwrap();
+    application_state.opencl_state.general_parameters_buffer.as_mut().unwrap().write(&general_parameters_vec).enq()?;
 
     let mut axes_vec = vec![0.0; 9];
     axes_vec[0*3+0] = application_state.RIGHT.x;
@@ -168,48 +505,184 @@ pub fn main(application_state: &mut ApplicationState, delta_time: Duration) -> R
     axes_vec[2*3+1] = application_state.FORWARD.y;
     axes_vec[2*3+2] = application_state.FORWARD.z;
 
-    application_state.opencl_state.axes_buffer.as_mut().unwrap().write(&axes_vec).enq().unwrap();
+    application_state.opencl_state.axes_buffer.as_mut().unwrap().write(&axes_vec).enq()?;
 
-    let work_size = application_state.width*application_state.height;
+    let render_parameters_vec = vec![level_offset as i32, pixel_step as i32, sample as i32];
+    application_state.opencl_state.render_parameters_buffer.as_mut().unwrap().write(&render_parameters_vec).enq()?;
 
+    // Rounded up to whole work groups, the kernel skips ids past the last block
+    let blocks = application_state.width.div_ceil(pixel_step) * application_state.height.div_ceil(pixel_step);
+    let work_size = (blocks as usize).div_ceil(LOCAL_SIZE) * LOCAL_SIZE;
+
+    // Timed from profiling events for the statistics overlay
+    let mut event = Event::empty();
     unsafe {
         let kernel = application_state.opencl_state.kernel.as_mut().unwrap();
-        kernel.set_default_global_work_size(SpatialDims::One(work_size as usize)).set_default_local_work_size(SpatialDims::One(LOCAL_SIZE)).enq().unwrap();
+        kernel.set_default_global_work_size(SpatialDims::One(work_size)).set_default_local_work_size(SpatialDims::One(LOCAL_SIZE));
+        kernel.cmd().enew(&mut event).enq()?;
     }
+    application_state.frame_stats.track_kernel(event);
 
-    application_state.screen_buffer.extract_from_buffer(&application_state.opencl_state.output_buffer.as_ref().unwrap())?;
+    if write_hit_buffer {
+        application_state.opencl_state.hit_buffer.as_ref().unwrap().read(&mut application_state.hit_data).enq()?;
+    }
 
     Ok(())
 }
 
-pub fn quit(application_state: &mut ApplicationState ) -> Result<(), CT3DError>{
+/// Text describing the voxel under the mouse cursor
+pub fn probe_readout(application_state: &ApplicationState) -> String {
+    match application_state.probe(application_state.mouse_x, application_state.mouse_y) {
+        Some(probe) => {
+            let value = match probe.hu {
+                Some(hu) => format!("{:.0} HU", hu),
+                None => format!("{:.3}", probe.value)
+            };
+            format!("voxel ({}, {}, {})  {:.1}, {:.1}, {:.1} mm  LPS {:.1}, {:.1}, {:.1}  {}",
+                probe.voxel.x, probe.voxel.y, probe.voxel.z,
+                probe.patient_mm.x, probe.patient_mm.y, probe.patient_mm.z,
+                probe.patient_lps.x, probe.patient_lps.y, probe.patient_lps.z,
+                value)
+        },
+        None => String::new()
+    }
+}
+
+pub fn quit() -> Result<(), CT3DError>{
 
     Ok(())
 }
 
 
 // Event handlers
-pub fn rmb_down(x: i32, y: i32, application_state: &mut ApplicationState) -> Result<(), CT3DError> {
 
-    application_state.drag_state.init_x = x;
-    application_state.drag_state.init_y = y;
-    application_state.drag_state.init_RIGHT = application_state.RIGHT;
-    application_state.drag_state.init_UP = application_state.UP;
-    application_state.drag_state.init_FORWARD = application_state.FORWARD;
-    application_state.drag_state.dragging = true;
+/// Do what `action` asks for. The viewer gets actions from its keybindings, tests can pass them in directly.
+pub fn perform(action: &Action, application_state: &mut ApplicationState) -> Result<(), CT3DError> {
+    let (x, y) = (application_state.mouse_x, application_state.mouse_y);
+    match action {
+        Action::PointerMove { x, y } => pointer_move(*x, *y, application_state),
+        Action::RotateDrag => {
+            application_state.drag_state.init_x = x;
+            application_state.drag_state.init_y = y;
+            application_state.drag_state.init_RIGHT = application_state.RIGHT;
+            application_state.drag_state.init_UP = application_state.UP;
+            application_state.drag_state.init_FORWARD = application_state.FORWARD;
+            application_state.drag_state.dragging = true;
+        },
+        Action::RotateDragEnd => application_state.drag_state.dragging = false,
+        Action::Zoom(steps) => zoom(*steps, application_state),
+        Action::AdjustCutoff(delta) => application_state.low_cutoff = (application_state.low_cutoff + delta).max(0.0).min(1.0),
+        Action::NextSuggestedCutoff => {
+            if application_state.suggested_cutoffs.is_empty() {
+                suggest_cutoffs(application_state);
+            } else {
+                next_suggested_cutoff(application_state);
+            }
+        },
+        Action::ToggleSupersampling => {
+            application_state.supersamples = if application_state.supersamples == 0 { MAX_SUPERSAMPLES } else { 0 };
+            println!("Supersampling {}", if application_state.supersamples == 0 { "off".to_owned() } else { format!("{}x", application_state.supersamples) });
+        },
+        Action::ToggleFrameStats => application_state.show_frame_stats = !application_state.show_frame_stats,
+        Action::ToggleHud => application_state.overlay.show_hud = !application_state.overlay.show_hud,
+        Action::ToggleControls => application_state.overlay.show_controls = !application_state.overlay.show_controls,
+        Action::ToggleMeasurementList => application_state.overlay.show_measurements = !application_state.overlay.show_measurements,
+        Action::ToggleTransferFunction => {
+            application_state.transfer_function.enabled = !application_state.transfer_function.enabled;
+            update_transfer_function(application_state)?;
+        },
+        Action::ToggleLabels => {
+            application_state.show_labels = !application_state.show_labels;
+            print_label_table(application_state);
+        },
+        Action::ToggleLabel(number) => {
+            if let Some(label) = application_state.labelmap.as_mut().and_then(|labelmap| labelmap.table.labels.get_mut(number.wrapping_sub(1))) {
+                label.visible = !label.visible;
+            }
+            update_label_colors(application_state)?;
+            print_label_table(application_state);
+        },
+        Action::SaveBookmark(number) => {
+            let name = format!("{}", number);
+            let camera_state = CameraState::capture(application_state);
+            application_state.camera_presets.set_bookmark(name.clone(), camera_state);
+            application_state.camera_presets.serialize_to_file(CAMERA_PRESETS_PATH.to_owned())?;
+            println!("Saved camera bookmark {}.", name);
+        },
+        Action::RecallBookmark(number) => {
+            if let Some(bookmark) = application_state.camera_presets.get_bookmark(&format!("{}", number)) {
+                let camera_state = bookmark.state.clone();
+                application_state.playback.playing = false;
+                camera_state.apply(application_state);
+                update_transfer_function(application_state)?;
+            }
+        },
+        Action::AddKeyframe => {
+            let animation = &mut application_state.camera_presets.animation;
+            let time = if animation.keyframes.is_empty() { 0.0 } else { animation.duration() + KEYFRAME_SPACING_SECONDS };
+            let state = CameraState::capture(application_state);
+            application_state.camera_presets.animation.insert(Keyframe { time, state });
+            application_state.camera_presets.serialize_to_file(CAMERA_PRESETS_PATH.to_owned())?;
+            println!("Added keyframe at {:.1}s.", time);
+        },
+        Action::ClearKeyframes => {
+            application_state.camera_presets.animation.keyframes.clear();
+            application_state.playback.playing = false;
+            application_state.playback.time = 0.0;
+            application_state.camera_presets.serialize_to_file(CAMERA_PRESETS_PATH.to_owned())?;
+            println!("Cleared keyframes.");
+        },
+        Action::TogglePlayback => {
+            let playback = &mut application_state.playback;
+            let animation = &application_state.camera_presets.animation;
+            playback.playing = !playback.playing && !animation.keyframes.is_empty();
+            // Resume where playback was paused, start over once it has reached the end
+            if playback.playing && playback.time >= animation.duration() {
+                playback.time = 0.0;
+            }
+        },
+        Action::CycleMeasurementTool => crate::tools::measurements::cycle_tool(application_state),
+        Action::PlacePoint => crate::tools::measurements::place_point(application_state, x, y),
+        Action::FinishMeasurement => crate::tools::measurements::finish_active(application_state),
+        Action::UndoMeasurement => crate::tools::measurements::undo(application_state),
+        Action::PlaceSphereRoi => crate::tools::roi_tool::place_roi(application_state, x, y, true),
+        Action::PlaceBoxRoi => crate::tools::roi_tool::place_roi(application_state, x, y, false),
+        Action::DrawFreehandRoi => crate::tools::roi_tool::start_freehand(application_state, x, y),
+        Action::DrawFreehandRoiEnd => crate::tools::roi_tool::finish_freehand(application_state),
+        Action::ThresholdAtCutoff => crate::tools::segmentation_tool::threshold_at_cutoff(application_state)?,
+        Action::GrowRegion => crate::tools::segmentation_tool::grow_from_pixel(application_state, x, y, false)?,
+        Action::GrowRegionConfident => crate::tools::segmentation_tool::grow_from_pixel(application_state, x, y, true)?,
+        Action::EraseLabel => crate::tools::segmentation_tool::erase_label_at_pixel(application_state, x, y)?,
+        Action::KeepPickedComponent => crate::tools::segmentation_tool::clean_islands_at_pixel(application_state, x, y, IslandCleanup::Picked)?,
+        Action::RemoveSmallIslands => crate::tools::segmentation_tool::clean_islands_at_pixel(application_state, x, y, IslandCleanup::RemoveSmall)?,
+        Action::KeepLargestIsland => crate::tools::segmentation_tool::clean_islands_at_pixel(application_state, x, y, IslandCleanup::Largest)?,
+        Action::ShrinkLabel => crate::tools::segmentation_tool::morph_label_at_pixel(application_state, x, y, MorphologyOperation::Margin, -MORPHOLOGY_RADIUS_MM)?,
+        Action::GrowLabel => crate::tools::segmentation_tool::morph_label_at_pixel(application_state, x, y, MorphologyOperation::Margin, MORPHOLOGY_RADIUS_MM)?,
+        Action::OpenLabel => crate::tools::segmentation_tool::morph_label_at_pixel(application_state, x, y, MorphologyOperation::Open, MORPHOLOGY_RADIUS_MM)?,
+        Action::CloseLabel => crate::tools::segmentation_tool::morph_label_at_pixel(application_state, x, y, MorphologyOperation::Close, MORPHOLOGY_RADIUS_MM)?,
+        Action::FillHoles => crate::tools::segmentation_tool::morph_label_at_pixel(application_state, x, y, MorphologyOperation::FillHoles, 0.0)?,
+        Action::FillHolesPerSlice => crate::tools::segmentation_tool::morph_label_at_pixel(application_state, x, y, MorphologyOperation::FillHolesPerSlice, 0.0)?,
+        Action::CycleFilterPreview => crate::tools::filter_tool::cycle_preview(application_state)?,
+        Action::ApplyFilterPreview => crate::tools::filter_tool::apply_preview(application_state),
+        Action::ExportLabels => crate::tools::segmentation_tool::export(application_state)?,
+        Action::SaveSession => crate::tools::session_tool::save(application_state)?,
+        Action::LoadSession => crate::tools::session_tool::load(application_state, application_state.session_path.clone())?,
+        Action::LoadFile(path) => drop_file(path.clone(), application_state)?
+    }
 
     Ok(())
 }
-pub fn rmb_up(x: i32, y: i32, application_state: &mut ApplicationState) -> Result<(), CT3DError> {
 
-    application_state.drag_state.dragging = false;
+fn pointer_move(x: i32, y: i32, application_state: &mut ApplicationState) {
 
-    Ok(())
-}
-pub fn mouse_move(x: i32, y: i32, application_state: &mut ApplicationState) -> Result<(), CT3DError> {
+    application_state.mouse_x = x;
+    application_state.mouse_y = y;
+
+    if application_state.freehand_contour.is_some() {
+        crate::tools::roi_tool::extend_freehand(application_state, x, y);
+    }
 
     if(application_state.drag_state.dragging){
-        
         let dx = x - application_state.drag_state.init_x;
         let dy = y - application_state.drag_state.init_y;
         // application_state.drag_state.init_x = x;
@@ -235,60 +708,190 @@ pub fn mouse_move(x: i32, y: i32, application_state: &mut ApplicationState) -> R
         application_state.UP = rotation_matrix * application_state.drag_state.init_UP;
         application_state.FORWARD = rotation_matrix * application_state.drag_state.init_FORWARD;
     }
-
-    Ok(())
 }
 
-pub fn wheel(delta: i32, application_state: &mut ApplicationState) -> Result<(), CT3DError> {
+fn zoom(delta: i32, application_state: &mut ApplicationState) {
 
     let old_camera_z = application_state.camera_z;
     let mut new_camera_z = old_camera_z + (delta.signum() as f32) * ZOOM_SPEED;
     new_camera_z = new_camera_z.max(MIN_CAMERA_Z).min(MAX_CAMERA_Z);
     application_state.camera_z = new_camera_z;
-    
-    Ok(())
+    application_state.drag_state.last_zoom = Some(std::time::Instant::now());
+}
+
+/// What a dropped NIfTI or NRRD file holds
+enum DroppedImage {
+    Labels(Box<LabelMap>),
+    Volume(Box<Volume>)
+}
+
+/// Segmentations become the labelmap, anything else (a CT scan, say) the volume
+fn read_dropped_image(filename: &str) -> Result<DroppedImage, CT3DError> {
+    let mut image = Volume::read_image(filename.to_owned()).with_context(|| format!("Could not load {}", filename))?;
+    if LabelMap::holds_labels(&image) {
+        let labelmap = LabelMap::from_image(&image, filename).with_context(|| format!("Could not load labelmap {}", filename))?;
+        Ok(DroppedImage::Labels(Box::new(labelmap)))
+    } else {
+        image.normalize_to_hu_range();
+        Ok(DroppedImage::Volume(Box::new(crate::content::generate_initial_volume::in_display_orientation(&image))))
+    }
 }
 
 pub fn drop_file(filename: String, application_state: &mut ApplicationState) -> Result<(), CT3DError> {
 
     println!("{}", filename);
 
-    let capture_result = Exec::cmd("python").arg("ct3d3-python/dicom_to_volume.py").arg("--dropped-file").arg(filename).capture();
-
-    match capture_result {
-        Ok(captured_data) =>{
-            if(subprocess::ExitStatus::success(captured_data.exit_status)){
-                println!("Python subprocess run successfully.");
-                change_volume(application_state, Box::new(crate::content::generate_initial_volume::generate_initial_volume()));
-            }else{
-                println!("Python subprocess exited with non-zero code.");
+    if crate::loaders::nifti::is_nifti_path(&filename) || crate::loaders::nrrd::is_nrrd_path(&filename) {
+        match read_dropped_image(&filename)? {
+            DroppedImage::Labels(labelmap) => {
+                change_labelmap(application_state, labelmap)?;
+                application_state.labelmap_source = Some(filename.clone());
+                print_label_table(application_state);
+                crate::ui::overlay::toast(application_state, format!("Loaded labels {}", filename));
+            },
+            DroppedImage::Volume(volume) => {
+                change_volume(application_state, volume)?;
+                application_state.volume_source = Some(filename.clone());
+                suggest_cutoffs(application_state);
+                crate::ui::overlay::toast(application_state, format!("Loaded {}", filename));
             }
         }
-        Err(e)=>{
-            println!("Python could not be started or run successfully.");
-            println!("{}", e);
-        }
+        return Ok(());
     }
 
+    let captured_data = Exec::cmd("python").arg("ct3d3-python/dicom_to_volume.py").arg("--dropped-file").arg(filename.clone()).capture()
+        .context("Python could not be started or run successfully")?;
+    if !captured_data.exit_status.success() {
+        return Err(CT3DError::Dicom(format!("dicom_to_volume.py could not convert {} ({:?})", filename, captured_data.exit_status)));
+    }
+    println!("Python subprocess run successfully.");
+    change_volume(application_state, Box::new(crate::content::generate_initial_volume::generate_initial_volume()?))?;
+    application_state.volume_source = Some(filename.clone());
+    suggest_cutoffs(application_state);
+    crate::ui::overlay::toast(application_state, format!("Loaded {}", filename));
+
     Ok(())
 
 }
 
-pub fn key_down(scancode: Option<sdl2::keyboard::Scancode>, application_state: &mut ApplicationState) -> Result<(), CT3DError> {
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::input::keybindings::{InputEvent, InputMapper, Keybindings, MOUSE_RIGHT};
+
+    /// Feed window input through the default keybindings, performing the actions and then the held ones
+    /// for `frames` frames of `frame_seconds` each, the way the viewer does
+    fn replay(events: Vec<InputEvent>, frames: usize, frame_seconds: f32, application_state: &mut ApplicationState) {
+        let mut mapper = InputMapper::new(Keybindings::default_bindings());
+        for event in events {
+            for action in mapper.handle(event) {
+                perform(&action, application_state).unwrap();
+            }
+        }
+        for _ in 0..frames {
+            for action in mapper.held_actions(frame_seconds) {
+                perform(&action, application_state).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn right_drag_rotates_until_released() {
+        let mut application_state = ApplicationState::new(100, 100);
+        let initial_forward = application_state.FORWARD;
+        replay(vec![
+            InputEvent::PointerMoved { x: 50, y: 50 },
+            InputEvent::Pressed(MOUSE_RIGHT.to_owned()),
+            InputEvent::PointerMoved { x: 75, y: 50 },
+            InputEvent::Released(MOUSE_RIGHT.to_owned())
+        ], 0, 0.0, &mut application_state);
+        let dragged_forward = application_state.FORWARD;
+        assert!(dragged_forward.distance(initial_forward) > 0.1);
+        assert!((dragged_forward.length() - 1.0).abs() < 1e-4);
+
+        perform(&Action::PointerMove { x: 10, y: 90 }, &mut application_state).unwrap();
+        assert_eq!(application_state.FORWARD, dragged_forward);
+        assert_eq!((application_state.mouse_x, application_state.mouse_y), (10, 90));
+    }
 
-    if let Some(scancode) = scancode {
-        application_state.keymap.insert(scancode, true);
-    };
+    #[test]
+    fn held_cutoff_keys_change_it_at_the_same_speed_at_any_frame_rate() {
+        let mut fast = ApplicationState::new(100, 100);
+        let mut slow = ApplicationState::new(100, 100);
+        fast.low_cutoff = 0.2;
+        slow.low_cutoff = 0.2;
+        replay(vec![InputEvent::Pressed("D".to_owned())], 144, 1.0 / 144.0, &mut fast);
+        replay(vec![InputEvent::Pressed("D".to_owned())], 20, 1.0 / 20.0, &mut slow);
+        assert!((fast.low_cutoff - 0.8).abs() < 1e-4);
+        assert!((slow.low_cutoff - 0.8).abs() < 1e-4);
+
+        replay(vec![InputEvent::Pressed("A".to_owned())], 1000, 0.1, &mut fast);
+        assert_eq!(fast.low_cutoff, 0.0);
+    }
 
-    Ok(())
+    #[test]
+    fn zoom_stays_within_limits() {
+        let mut application_state = ApplicationState::new(100, 100);
+        replay(vec![InputEvent::Wheel(1); 1000], 0, 0.0, &mut application_state);
+        assert_eq!(application_state.camera_z, MAX_CAMERA_Z);
+        replay(vec![InputEvent::Wheel(-3); 1000], 0, 0.0, &mut application_state);
+        assert_eq!(application_state.camera_z, MIN_CAMERA_Z);
+    }
 
-}
-pub fn key_up(scancode: Option<sdl2::keyboard::Scancode>, application_state: &mut ApplicationState) -> Result<(), CT3DError> {
+    #[test]
+    fn playback_resumes_where_it_was_paused() {
+        let mut application_state = ApplicationState::new(100, 100);
+        for time in [0.0, 2.0] {
+            let state = CameraState::capture(&application_state);
+            application_state.camera_presets.animation.insert(Keyframe { time, state });
+        }
 
-    if let Some(scancode) = scancode {
-        application_state.keymap.insert(scancode, false);
-    };
+        perform(&Action::TogglePlayback, &mut application_state).unwrap();
+        assert!(application_state.playback.playing);
+        application_state.playback.time = 1.5;
+        perform(&Action::TogglePlayback, &mut application_state).unwrap();
+        assert!(!application_state.playback.playing);
+        perform(&Action::TogglePlayback, &mut application_state).unwrap();
+        assert_eq!((application_state.playback.playing, application_state.playback.time), (true, 1.5));
+
+        // Past the end it starts over
+        application_state.playback.playing = false;
+        application_state.playback.time = 2.1;
+        perform(&Action::TogglePlayback, &mut application_state).unwrap();
+        assert_eq!((application_state.playback.playing, application_state.playback.time), (true, 0.0));
+    }
 
-    Ok(())
+    #[test]
+    fn dropped_images_go_by_their_contents() {
+        let res = glam::IVec3::new(16, 12, 4);
+        let spacing = Vec3::new(0.8, 0.8, 2.0);
+        let count = (res.x * res.y * res.z) as usize;
+        let labels: Vec<u16> = (0..count).map(|index| (index % 3) as u16).collect();
+        let intensities: Vec<u16> = (0..count).map(|index| (index * 7 % 3000) as u16).collect();
+        let labels_path = std::env::temp_dir().join("ct3d3_dropped_labels.nii").to_string_lossy().into_owned();
+        let scan_path = std::env::temp_dir().join("ct3d3_dropped_scan.nrrd").to_string_lossy().into_owned();
+        crate::loaders::nifti::write_nifti_u16(labels_path.clone(), res, spacing, Vec3::ZERO, glam::Mat3::IDENTITY, &labels).unwrap();
+        crate::loaders::nrrd::write_nrrd_u16(scan_path.clone(), res, spacing, Vec3::ZERO, glam::Mat3::IDENTITY, &intensities).unwrap();
+
+        match read_dropped_image(&labels_path).unwrap() {
+            DroppedImage::Labels(labelmap) => assert_eq!(labelmap.table.labels.len(), 2),
+            DroppedImage::Volume(_) => panic!("{} holds labels", labels_path)
+        }
+        match read_dropped_image(&scan_path).unwrap() {
+            DroppedImage::Volume(volume) => {
+                let hu_range = volume.hu_range.unwrap();
+                assert_eq!((hu_range.x, hu_range.y), (0.0, *intensities.iter().max().unwrap() as f32));
+                assert!(volume.data.iter().all(|value| (0.0..=1.0).contains(value)));
+            },
+            DroppedImage::Labels(_) => panic!("{} holds intensities", scan_path)
+        }
+
+        // Negative values are never labels, however few there are
+        let mut scan = Volume::new(Vec3::ONE, glam::IVec3::splat(2));
+        scan.data = vec![-1000.0, 40.0, 40.0, -1000.0, 40.0, 40.0, 40.0, -1000.0];
+        assert!(!LabelMap::holds_labels(&scan));
 
-}
\ No newline at end of file
+        std::fs::remove_file(labels_path).unwrap();
+        std::fs::remove_file(scan_path).unwrap();
+    }
+}
@@ -0,0 +1,623 @@
+use std::collections::HashMap;
+
+use crate::types::ct3d_error::CT3DError;
+use crate::types::application_state::ApplicationState;
+use crate::types::camera_state::CameraPresets;
+use crate::types::volume::Volume;
+use crate::types::roi::{Roi, NamedRoi, SliceContour};
+use crate::processing::roi_statistics::{RoiStatistics, DEFAULT_HISTOGRAM_BINS};
+use crate::processing::segmentation::{GrowCriterion, LabelOperation};
+use crate::types::labelmap::LabelMap;
+use crate::processing::connected_components::{Components, Connectivity};
+use crate::processing::morphology::MorphologyOperation;
+use crate::processing::filters::Filter;
+use crate::processing::resample::{Interpolation, Orientation, reorient_labelmap};
+use crate::input::keybindings::Keybindings;
+
+const DEFAULT_FPS: f32 = 30.0;
+const DEFAULT_SIZE: u32 = 640;
+
+const USAGE: &str = "Usage:
+    CT3D3                                   Start the interactive viewer
+        --vsync <on|off>      Present frames in step with the display (default on)
+        --max-fps <n>         Limit the frame rate, at least 1 (default 0, no limit)
+        --session <path>      Restore a saved session, and save to it with Ctrl+S (default temp/session.json)
+    CT3D3 animate <camera.json> <output>    Render the keyframe animation to a directory of PPM frames,
+                                            or to a video file (.mp4, .mkv, ...) through ffmpeg
+        --fps <n>             Frame rate (default 30)
+        --size <w>x<h>        Output resolution (default 640x640)
+        --volume <path>       Volume file to render (default temp/initial_volume.txt)
+        --session <path>      Render with the files, transfer function and labels of a saved session
+        --bookmark <name>     Render a single bookmark instead of the animation
+        --supersample <n>     Average n extra jittered samples per pixel (default 0)
+        --hud <on|off>        Draw the viewer's text overlay (file, cutoff, measurements) on the frames (default off)
+    CT3D3 roi <volume>                      Report ROI statistics (coordinates in mm from the first voxel corner)
+        --sphere <x,y,z,r>    Spherical ROI
+        --box <x0,y0,z0,x1,y1,z1>
+                              Axis aligned box ROI
+        --freehand <slice:x,y;x,y;...>
+                              Polygon on an axial slice, separate several slices with |
+        --rois <rois.json>    List of named ROIs, for batch reports
+        --bins <n>            Histogram bins (default 32)
+        --format <csv|json>   Report format (default csv)
+        --output <path>       Write the report to a file instead of stdout
+    CT3D3 segment <volume> <output>         Threshold or region grow into a labelmap (.nii, .nii.gz, .nrrd)
+        --range <low,high>    Intensity range, in HU when the volume has a HU range
+        --seed <x,y,z>        Grow from this voxel instead of thresholding the whole volume
+        --confidence <k>      With --seed, grow within k standard deviations of the region mean
+        --label <n>           Label value to write (default 1)
+        --into <labelmap>     Add to an existing labelmap instead of starting an empty one
+        --connectivity <n>    6, 18 or 26 connected components for the options below (default 26)
+        --keep-largest <n>    Keep only the n largest connected components
+        --min-size <mL>       Remove connected components smaller than this
+    CT3D3 components <labelmap>             Report the connected components of a label
+        --label <n>           Label to analyse (default: every non-zero voxel)
+        --connectivity <n>    6, 18 or 26 (default 26)
+        --format <csv|json>   Report format (default csv)
+    CT3D3 morphology <labelmap> <output>    Clean up a label
+        --op <name>           erode, dilate, open, close, margin, fill-holes or fill-holes-2d (per axial slice)
+        --radius <mm>         Ball radius in mm, respecting the voxel spacing; negative margins shrink (default 1)
+        --label <n>           Label to change (default 1)
+    CT3D3 mesh <volume> <output>            Extract a surface mesh in mm (.stl, .obj, .ply)
+        --threshold <value>   Iso value, in HU when the volume has a HU range
+        --labelmap <path>     Mesh a label instead of a threshold
+        --label <n>           Label to mesh (default 1)
+        --stl <binary|ascii>  STL encoding (default binary)
+        --min-shell <mm2>     Remove disconnected pieces with less surface area than this
+        --close-holes <n>     Close holes with at most n boundary edges (0 for any size)
+        --smooth <n>          Taubin smoothing iterations
+        --decimate <n>        Reduce to about n triangles
+    CT3D3 filter <volume> <output>          Denoise a volume
+        --gaussian <sigma>    Gaussian blur, sigma in mm
+        --median <r>          3D median over a (2r+1)³ voxel window
+        --bilateral <sigma,range>
+                              Bilateral filter, sigma in mm and range in HU (or normalized density)
+        --perona-malik <iterations,kappa>
+                              Perona-Malik diffusion, kappa in HU (or normalized density)
+        --curvature <iterations>
+                              Mean curvature flow
+        --device <cpu|gpu>    Run Gaussian and bilateral filters with OpenCL (default cpu)
+    CT3D3 quantize <volume>                 Cluster voxel intensities and print the class centres and thresholds
+        --method <name>       kmeans, otsu or multi-otsu (default kmeans)
+        --classes <n>         Number of classes, 2 to 256 (default 3, otsu always uses 2)
+        --labels <path>       Write the classes as a labelmap (.nii, .nii.gz, .nrrd)
+        --quantized <path>    Write a volume with every voxel replaced by its class centre
+    CT3D3 resample <volume> <output>        Crop, reorient and resample, keeping patient (LPS) coordinates;
+                                            the steps run in the order listed here
+        --crop <x0,y0,z0,x1,y1,z1>
+                              Keep voxels from the first corner up to (excluding) the second
+        --flip <x|y|z>        Reverse an axis
+        --permute <i,j,k>     Reorder the axes, e.g. 2,0,1 makes the old z axis the new x axis
+        --reorient <code>     Axis directions such as RAS or LPS (x towards right, y anterior, z superior)
+        --spacing <x,y,z>     New voxel spacing in mm
+        --isotropic <mm|min>  Cubic voxels of this size, or as small as the smallest spacing
+        --interpolation <name>
+                              nearest, trilinear or lanczos (default trilinear)
+    CT3D3 keybindings                       Print the viewer's default keybindings as JSON, a starting point
+                                            for temp/keybindings.json";
+
+/// Positional arguments plus `--key value` options
+pub struct CommandLine {
+    pub positional: Vec<String>,
+    pub options: HashMap<String, String>
+}
+
+impl CommandLine {
+    pub fn parse(args: Vec<String>) -> Result<CommandLine, CT3DError> {
+        let mut positional = Vec::new();
+        let mut options = HashMap::new();
+        let mut iter = args.into_iter();
+        while let Some(arg) = iter.next() {
+            if let Some(key) = arg.strip_prefix("--") {
+                let value = iter.next().ok_or_else(|| usage_error(format!("Missing value for --{}", key)))?;
+                options.insert(key.to_owned(), value);
+            } else {
+                positional.push(arg);
+            }
+        }
+        Ok(CommandLine { positional, options })
+    }
+
+    pub fn positional(&self, index: usize, name: &str) -> Result<String, CT3DError> {
+        self.positional.get(index).cloned().ok_or_else(|| usage_error(format!("Missing argument <{}>", name)))
+    }
+
+    pub fn option(&self, key: &str) -> Option<&String> {
+        self.options.get(key)
+    }
+
+    pub fn parsed_option<T: std::str::FromStr>(&self, key: &str, default: T) -> Result<T, CT3DError> {
+        match self.options.get(key) {
+            Some(value) => value.parse::<T>().map_err(|_| usage_error(format!("Invalid value for --{}: {}", key, value))),
+            None => Ok(default)
+        }
+    }
+
+    /// An `on` or `off` option
+    pub fn switch_option(&self, key: &str, default: bool) -> Result<bool, CT3DError> {
+        match self.options.get(key).map(|value| value.as_str()) {
+            Some("on") => Ok(true),
+            Some("off") => Ok(false),
+            Some(value) => Err(usage_error(format!("Invalid value for --{}: {}", key, value))),
+            None => Ok(default)
+        }
+    }
+
+    pub fn size_option(&self, key: &str, default: (u32, u32)) -> Result<(u32, u32), CT3DError> {
+        match self.options.get(key) {
+            Some(value) => {
+                let parts: Vec<&str> = value.split('x').collect();
+                let invalid = || usage_error(format!("Invalid value for --{}: {}", key, value));
+                if parts.len() != 2 {
+                    return Err(invalid());
+                }
+                let width = parts[0].parse::<u32>().map_err(|_| invalid())?;
+                let height = parts[1].parse::<u32>().map_err(|_| invalid())?;
+                Ok((width, height))
+            },
+            None => Ok(default)
+        }
+    }
+}
+
+pub fn usage_error(message: String) -> CT3DError {
+    CT3DError::Usage(format!("{}\n\n{}", message, USAGE))
+}
+
+/// Entry point for everything that runs without a window
+pub fn run(args: Vec<String>) -> Result<(), CT3DError> {
+    let command_line = CommandLine::parse(args)?;
+    let command = command_line.positional(0, "command")?;
+    match command.as_str() {
+        "animate" => animate(&command_line),
+        "roi" => roi(&command_line),
+        "segment" => segment(&command_line),
+        "components" => components(&command_line),
+        "morphology" => morphology(&command_line),
+        "mesh" => mesh(&command_line),
+        "filter" => filter(&command_line),
+        "quantize" => quantize(&command_line),
+        "resample" => resample(&command_line),
+        "keybindings" => {
+            println!("{}", Keybindings::default_bindings().to_json()?);
+            Ok(())
+        },
+        "help" | "--help" => {
+            println!("{}", USAGE);
+            Ok(())
+        },
+        _ => Err(usage_error(format!("Unknown command: {}", command)))
+    }
+}
+
+/// Set up the OpenCL renderer without opening a window
+fn headless_application_state(command_line: &CommandLine) -> Result<ApplicationState, CT3DError> {
+    let (width, height) = command_line.size_option("size", (DEFAULT_SIZE, DEFAULT_SIZE))?;
+    let mut application_state = ApplicationState::new(width, height);
+    crate::application::init(&mut application_state)?;
+    if let Some(volume_path) = command_line.option("volume") {
+        let volume = Volume::deserialize_from_file(volume_path.clone())?;
+        crate::application::change_volume(&mut application_state, Box::new(volume))?;
+        application_state.volume_source = Some(volume_path.clone());
+    }
+    if let Some(session_path) = command_line.option("session") {
+        crate::tools::session_tool::load(&mut application_state, session_path.clone())?;
+    }
+    Ok(application_state)
+}
+
+fn animate(command_line: &CommandLine) -> Result<(), CT3DError> {
+    let presets = CameraPresets::deserialize_from_file(command_line.positional(1, "camera.json")?)?;
+    let output = command_line.positional(2, "output")?;
+    let fps = command_line.parsed_option("fps", DEFAULT_FPS)?;
+
+    let mut application_state = headless_application_state(command_line)?;
+    application_state.supersamples = command_line.parsed_option("supersample", 0u32)?;
+    application_state.overlay.show_hud = command_line.switch_option("hud", false)?;
+
+    if let Some(name) = command_line.option("bookmark") {
+        let bookmark = presets.get_bookmark(name).ok_or_else(|| usage_error(format!("No bookmark named {}", name)))?;
+        crate::tools::animation_export::render_camera_state(&mut application_state, &bookmark.state)?;
+        application_state.screen_buffer.serialize_to_ppm(output.clone())?;
+        println!("Wrote {}", output);
+        return Ok(());
+    }
+
+    if presets.animation.keyframes.is_empty() {
+        return Err(usage_error("The camera file has no keyframes".to_owned()));
+    }
+
+    let frame_count = crate::tools::animation_export::render_animation(&mut application_state, &presets.animation, fps, output.clone())?;
+    println!("Wrote {} frames to {}", frame_count, output);
+
+    Ok(())
+}
+
+fn parse_floats(text: &str, count: usize, key: &str) -> Result<Vec<f32>, CT3DError> {
+    let values: Vec<f32> = text.split(',').map(|value| value.trim().parse::<f32>()).collect::<Result<Vec<f32>, _>>()
+        .map_err(|_| usage_error(format!("Invalid value for --{}: {}", key, text)))?;
+    if values.len() != count {
+        return Err(usage_error(format!("Expected {} comma separated numbers for --{}", count, key)));
+    }
+    Ok(values)
+}
+
+/// Values that have to be positive: spacings, sigmas and kappa
+fn parse_positive(text: &str, count: usize, key: &str) -> Result<Vec<f32>, CT3DError> {
+    let values = parse_floats(text, count, key)?;
+    if !values.iter().all(|value| value.is_finite() && *value > 0.0) {
+        return Err(usage_error(format!("--{} must be positive, found {}", key, text)));
+    }
+    Ok(values)
+}
+
+fn parse_contour(text: &str) -> Result<SliceContour, CT3DError> {
+    let invalid = || usage_error(format!("Invalid value for --freehand: {}", text));
+    let (slice, points) = text.split_once(':').ok_or_else(invalid)?;
+    let slice = slice.trim().parse::<i32>().map_err(|_| invalid())?;
+    let points = points.split(';')
+        .map(|point| parse_floats(point, 2, "freehand").map(|p| glam::Vec2::new(p[0], p[1])))
+        .collect::<Result<Vec<glam::Vec2>, CT3DError>>()?;
+    if points.len() < 3 {
+        return Err(usage_error("A freehand contour needs at least 3 points".to_owned()));
+    }
+    Ok(SliceContour { slice, points })
+}
+
+fn roi(command_line: &CommandLine) -> Result<(), CT3DError> {
+    let volume = Volume::deserialize_from_file(command_line.positional(1, "volume")?)?;
+    let bins = command_line.parsed_option("bins", DEFAULT_HISTOGRAM_BINS)?;
+
+    let mut rois: Vec<NamedRoi> = Vec::new();
+    if let Some(path) = command_line.option("rois") {
+        let file = std::fs::File::open(path)?;
+        rois.extend(serde_json::from_reader::<_, Vec<NamedRoi>>(std::io::BufReader::new(file))?);
+    }
+    if let Some(text) = command_line.option("sphere") {
+        let v = parse_floats(text, 4, "sphere")?;
+        rois.push(NamedRoi { name: "sphere".to_owned(), roi: Roi::Sphere { center: glam::Vec3::new(v[0], v[1], v[2]), radius: v[3] } });
+    }
+    if let Some(text) = command_line.option("box") {
+        let v = parse_floats(text, 6, "box")?;
+        let a = glam::Vec3::new(v[0], v[1], v[2]);
+        let b = glam::Vec3::new(v[3], v[4], v[5]);
+        rois.push(NamedRoi { name: "box".to_owned(), roi: Roi::Box { min: a.min(b), max: a.max(b) } });
+    }
+    if let Some(text) = command_line.option("freehand") {
+        let contours = text.split('|').map(parse_contour).collect::<Result<Vec<SliceContour>, CT3DError>>()?;
+        rois.push(NamedRoi { name: "freehand".to_owned(), roi: Roi::Freehand { contours } });
+    }
+    if rois.is_empty() {
+        return Err(usage_error("No ROI given, use --sphere, --box, --freehand or --rois".to_owned()));
+    }
+
+    let statistics = RoiStatistics::compute_all(&volume, &rois, bins);
+
+    let report = match command_line.option("format").map(|format| format.as_str()).unwrap_or("csv") {
+        "csv" => crate::processing::roi_statistics::to_csv(&statistics),
+        "json" => serde_json::to_string_pretty(&statistics)? + "\n",
+        format => return Err(usage_error(format!("Unknown format: {}", format)))
+    };
+
+    match command_line.option("output") {
+        Some(path) => std::fs::write(path, report)?,
+        None => print!("{}", report)
+    }
+
+    Ok(())
+}
+
+/// Load a labelmap drawn on `volume`, in the volume's axis order
+fn labelmap_for(volume: &Volume, path: &str) -> Result<LabelMap, CT3DError> {
+    let labelmap = reorient_labelmap(&LabelMap::load(path.to_owned())?, Orientation::of_volume(volume));
+    if !labelmap.matches(volume) {
+        return Err(usage_error(format!("The labelmap {} does not cover the same voxels as the volume", path)));
+    }
+    Ok(labelmap)
+}
+
+fn segment(command_line: &CommandLine) -> Result<(), CT3DError> {
+    let volume = Volume::deserialize_from_file(command_line.positional(1, "volume")?)?;
+    let output = command_line.positional(2, "output")?;
+    let label = command_line.parsed_option("label", 1u16)?;
+
+    let range = match command_line.option("range") {
+        Some(text) => {
+            let v = parse_floats(text, 2, "range")?;
+            // Ranges are given in HU whenever the volume knows its HU range
+            let low = volume.from_hu(v[0]).unwrap_or(v[0]);
+            let high = volume.from_hu(v[1]).unwrap_or(v[1]);
+            Some((low.min(high), low.max(high)))
+        },
+        None => None
+    };
+
+    let mask = match command_line.option("seed") {
+        Some(text) => {
+            let v = parse_floats(text, 3, "seed")?;
+            let seed = glam::IVec3::new(v[0] as i32, v[1] as i32, v[2] as i32);
+            let criterion = match (command_line.option("confidence"), range) {
+                (Some(_), _) => GrowCriterion::Confidence { multiplier: command_line.parsed_option("confidence", 2.5f32)? },
+                (None, Some((low, high))) => GrowCriterion::Range { low, high },
+                (None, None) => return Err(usage_error("--seed needs --range or --confidence".to_owned()))
+            };
+            crate::processing::segmentation::region_grow(&volume, seed, criterion)
+        },
+        None => {
+            let (low, high) = range.ok_or_else(|| usage_error("Missing --range".to_owned()))?;
+            crate::processing::segmentation::threshold(&volume, low, high)
+        }
+    };
+
+    let mask = clean_components(command_line, mask, volume.res, volume.spacing)?;
+
+    let mut labelmap = match command_line.option("into") {
+        Some(path) => labelmap_for(&volume, path)?,
+        None => LabelMap::for_volume(&volume)
+    };
+    crate::processing::segmentation::apply_mask(&mut labelmap, &mask, label, LabelOperation::Replace);
+    labelmap.save(output.clone())?;
+
+    let statistics = RoiStatistics::from_indices(&volume, format!("Label {}", label), labelmap.label_indices(label), DEFAULT_HISTOGRAM_BINS);
+    println!("{}", statistics.describe());
+    println!("Wrote {}", output);
+
+    Ok(())
+}
+
+fn connectivity_option(command_line: &CommandLine) -> Result<Connectivity, CT3DError> {
+    let count = command_line.parsed_option("connectivity", 26u32)?;
+    Connectivity::from_count(count).ok_or_else(|| usage_error(format!("Invalid value for --connectivity: {}", count)))
+}
+
+/// Apply --keep-largest and --min-size to a mask
+fn clean_components(command_line: &CommandLine, mask: Vec<bool>, res: glam::IVec3, spacing: glam::Vec3) -> Result<Vec<bool>, CT3DError> {
+    if command_line.option("keep-largest").is_none() && command_line.option("min-size").is_none() {
+        return Ok(mask);
+    }
+    let components = Components::label(&mask, res, spacing, connectivity_option(command_line)?);
+    let keep_largest = command_line.parsed_option("keep-largest", usize::MAX)?;
+    let min_size = command_line.parsed_option("min-size", 0.0f32)?;
+    Ok(components.mask_where(|component| (component.id as usize) <= keep_largest && component.volume_ml >= min_size))
+}
+
+fn components(command_line: &CommandLine) -> Result<(), CT3DError> {
+    let labelmap = LabelMap::load(command_line.positional(1, "labelmap")?)?;
+    let mask = match command_line.option("label") {
+        Some(_) => labelmap.label_mask(command_line.parsed_option("label", 1u16)?),
+        None => labelmap.data.iter().map(|value| *value != 0).collect()
+    };
+    let components = Components::label(&mask, labelmap.res, labelmap.spacing, connectivity_option(command_line)?);
+
+    match command_line.option("format").map(|format| format.as_str()).unwrap_or("csv") {
+        "csv" => {
+            println!("id,voxel_count,volume_ml,centroid_x_mm,centroid_y_mm,centroid_z_mm,min_x,min_y,min_z,max_x,max_y,max_z");
+            for c in components.components.iter() {
+                println!(
+                    "{},{},{},{},{},{},{},{},{},{},{},{}",
+                    c.id, c.voxel_count, c.volume_ml, c.centroid_mm.x, c.centroid_mm.y, c.centroid_mm.z,
+                    c.bounds_min.x, c.bounds_min.y, c.bounds_min.z, c.bounds_max.x, c.bounds_max.y, c.bounds_max.z
+                );
+            }
+        },
+        "json" => println!("{}", serde_json::to_string_pretty(&components.components)?),
+        format => return Err(usage_error(format!("Unknown format: {}", format)))
+    }
+
+    Ok(())
+}
+
+fn morphology(command_line: &CommandLine) -> Result<(), CT3DError> {
+    let mut labelmap = LabelMap::load(command_line.positional(1, "labelmap")?)?;
+    let output = command_line.positional(2, "output")?;
+    let name = command_line.option("op").ok_or_else(|| usage_error("Missing --op".to_owned()))?;
+    let operation = MorphologyOperation::from_name(name).ok_or_else(|| usage_error(format!("Unknown operation: {}", name)))?;
+    let radius_mm = command_line.parsed_option("radius", 1.0f32)?;
+    let label = command_line.parsed_option("label", 1u16)?;
+
+    let before = labelmap.voxel_count(label);
+    let mask = operation.apply(&labelmap.label_mask(label), labelmap.res, labelmap.spacing, radius_mm);
+    crate::processing::morphology::update_label(&mut labelmap, label, &mask);
+    labelmap.save(output.clone())?;
+
+    println!("Label {}: {} voxels before, {} after", label, before, labelmap.voxel_count(label));
+    println!("Wrote {}", output);
+
+    Ok(())
+}
+
+fn mesh(command_line: &CommandLine) -> Result<(), CT3DError> {
+    let volume = Volume::deserialize_from_file(command_line.positional(1, "volume")?)?;
+    let output = command_line.positional(2, "output")?;
+    let ascii = match command_line.option("stl").map(|encoding| encoding.as_str()).unwrap_or("binary") {
+        "binary" => false,
+        "ascii" => true,
+        encoding => return Err(usage_error(format!("Unknown STL encoding: {}", encoding)))
+    };
+
+    let mut mesh = match (command_line.option("labelmap"), command_line.option("threshold")) {
+        (Some(path), _) => {
+            let labelmap = labelmap_for(&volume, path)?;
+            crate::processing::marching_cubes::label_surface(&labelmap, command_line.parsed_option("label", 1u16)?)
+        },
+        (None, Some(_)) => {
+            let threshold = command_line.parsed_option("threshold", 0.0f32)?;
+            let threshold = volume.from_hu(threshold).unwrap_or(threshold);
+            crate::processing::marching_cubes::volume_surface(&volume, threshold)
+        },
+        (None, None) => return Err(usage_error("Missing --threshold or --labelmap".to_owned()))
+    };
+
+    println!("Extracted {}", mesh.describe());
+    if command_line.option("min-shell").is_some() {
+        let removed = crate::processing::mesh_processing::remove_small_shells(&mut mesh, command_line.parsed_option("min-shell", 0.0f32)?);
+        println!("Removed {} small shells", removed);
+    }
+    if command_line.option("close-holes").is_some() {
+        let closed = crate::processing::mesh_processing::close_holes(&mut mesh, command_line.parsed_option("close-holes", 0usize)?);
+        println!("Closed {} holes", closed);
+    }
+    if command_line.option("smooth").is_some() {
+        crate::processing::mesh_processing::taubin_smooth(&mut mesh, command_line.parsed_option("smooth", 0usize)?);
+    }
+    if command_line.option("decimate").is_some() {
+        crate::processing::mesh_processing::decimate(&mut mesh, command_line.parsed_option("decimate", 0usize)?);
+    }
+
+    mesh.serialize_to_file(output.clone(), ascii)?;
+    println!("{}", mesh.describe());
+    println!("Wrote {}", output);
+
+    Ok(())
+}
+
+fn filter(command_line: &CommandLine) -> Result<(), CT3DError> {
+    let volume = Volume::deserialize_from_file(command_line.positional(1, "volume")?)?;
+    let output = command_line.positional(2, "output")?;
+    // Intensity differences are given in HU whenever the volume knows its HU range
+    let intensity = |hu: f32| match (volume.from_hu(hu), volume.from_hu(0.0)) {
+        (Some(value), Some(zero)) => value - zero,
+        _ => hu
+    };
+
+    let filter = if let Some(text) = command_line.option("gaussian") {
+        Filter::Gaussian { sigma_mm: parse_positive(text, 1, "gaussian")?[0] }
+    } else if command_line.option("median").is_some() {
+        let radius = command_line.parsed_option("median", 1)?;
+        if radius < 1 {
+            return Err(usage_error(format!("--median must be at least 1, found {}", radius)));
+        }
+        Filter::Median { radius }
+    } else if let Some(text) = command_line.option("bilateral") {
+        let v = parse_positive(text, 2, "bilateral")?;
+        Filter::Bilateral { sigma_mm: v[0], sigma_range: intensity(v[1]) }
+    } else if let Some(text) = command_line.option("perona-malik") {
+        let v = parse_positive(text, 2, "perona-malik")?;
+        Filter::PeronaMalik { iterations: v[0] as usize, kappa: intensity(v[1]) }
+    } else if command_line.option("curvature").is_some() {
+        Filter::Curvature { iterations: command_line.parsed_option("curvature", 5)? }
+    } else {
+        return Err(usage_error("No filter given".to_owned()));
+    };
+
+    let start = std::time::Instant::now();
+    let filtered = match command_line.option("device").map(|device| device.as_str()).unwrap_or("cpu") {
+        "cpu" => filter.apply(&volume)?,
+        "gpu" => {
+            let application_state = headless_application_state(command_line)?;
+            filter.apply_with_opencl(&application_state.opencl_state, &volume)?
+        },
+        device => return Err(usage_error(format!("Unknown device: {}", device)))
+    };
+    filtered.serialize_to_file(output.clone())?;
+
+    println!("{} took {:.2} s", filter.describe(), start.elapsed().as_secs_f32());
+    println!("Wrote {}", output);
+
+    Ok(())
+}
+
+fn quantize(command_line: &CommandLine) -> Result<(), CT3DError> {
+    let volume = Volume::deserialize_from_file(command_line.positional(1, "volume")?)?;
+    let classes = command_line.parsed_option("classes", 3usize)?;
+    if !(2..=crate::processing::quantize::MAX_CLASSES).contains(&classes) {
+        return Err(usage_error(format!("--classes must be 2 to {}, found {}", crate::processing::quantize::MAX_CLASSES, classes)));
+    }
+    let quantization = match command_line.option("method").map(|method| method.as_str()).unwrap_or("kmeans") {
+        "kmeans" => crate::processing::quantize::k_means(&volume.data, classes),
+        "otsu" => crate::processing::quantize::otsu(&volume.data),
+        "multi-otsu" => crate::processing::quantize::multi_otsu(&volume.data, classes),
+        method => return Err(usage_error(format!("Unknown method: {}", method)))
+    };
+
+    let describe = |value: f32| match volume.to_hu(value) {
+        Some(hu) => format!("{:.4} ({:.0} HU)", value, hu),
+        None => format!("{:.4}", value)
+    };
+    println!("Centres: {}", quantization.centers.iter().map(|center| describe(*center)).collect::<Vec<String>>().join(", "));
+    println!("Thresholds: {}", quantization.thresholds.iter().map(|threshold| describe(*threshold)).collect::<Vec<String>>().join(", "));
+
+    if let Some(path) = command_line.option("labels") {
+        quantization.to_labelmap(&volume).save(path.clone())?;
+        println!("Wrote {}", path);
+    }
+    if let Some(path) = command_line.option("quantized") {
+        volume.with_data(quantization.quantized()).serialize_to_file(path.clone())?;
+        println!("Wrote {}", path);
+    }
+
+    Ok(())
+}
+
+fn describe_geometry(volume: &Volume) -> String {
+    format!("{}x{}x{} voxels of {:.3}x{:.3}x{:.3} mm, {} at ({:.2}, {:.2}, {:.2}) mm LPS",
+        volume.res.x, volume.res.y, volume.res.z,
+        volume.spacing.x, volume.spacing.y, volume.spacing.z,
+        Orientation::of_volume(volume).code(),
+        volume.origin.x, volume.origin.y, volume.origin.z)
+}
+
+fn resample(command_line: &CommandLine) -> Result<(), CT3DError> {
+    let mut volume = Volume::deserialize_from_file(command_line.positional(1, "volume")?)?;
+    let output = command_line.positional(2, "output")?;
+    println!("Input: {}", describe_geometry(&volume));
+
+    if let Some(text) = command_line.option("crop") {
+        let v = parse_floats(text, 6, "crop")?;
+        volume = crate::processing::resample::crop(&volume, glam::IVec3::new(v[0] as i32, v[1] as i32, v[2] as i32), glam::IVec3::new(v[3] as i32, v[4] as i32, v[5] as i32));
+    }
+    if let Some(axis) = command_line.option("flip") {
+        let axis = match axis.as_str() {
+            "x" => 0,
+            "y" => 1,
+            "z" => 2,
+            _ => return Err(usage_error(format!("Unknown axis: {}", axis)))
+        };
+        volume = crate::processing::resample::flip(&volume, axis);
+    }
+    if let Some(text) = command_line.option("permute") {
+        let v = parse_floats(text, 3, "permute")?;
+        volume = crate::processing::resample::permute(&volume, [v[0] as usize, v[1] as usize, v[2] as usize])?;
+    }
+    if let Some(code) = command_line.option("reorient") {
+        let orientation = Orientation::from_code(code).ok_or_else(|| usage_error(format!("Invalid orientation: {}", code)))?;
+        volume = crate::processing::resample::reorient(&volume, orientation);
+    }
+
+    let interpolation_name = command_line.option("interpolation").map(|name| name.as_str()).unwrap_or("trilinear");
+    let interpolation = Interpolation::from_name(interpolation_name).ok_or_else(|| usage_error(format!("Unknown interpolation: {}", interpolation_name)))?;
+    if let Some(text) = command_line.option("spacing") {
+        let v = parse_positive(text, 3, "spacing")?;
+        volume = crate::processing::resample::resample(&volume, glam::Vec3::new(v[0], v[1], v[2]), interpolation)?;
+    } else if let Some(text) = command_line.option("isotropic") {
+        let spacing_mm = if text == "min" { volume.spacing.min_element() } else { parse_positive(text, 1, "isotropic")?[0] };
+        volume = crate::processing::resample::resample_isotropic(&volume, spacing_mm, interpolation)?;
+    }
+
+    volume.serialize_to_file(output.clone())?;
+    println!("Output: {}", describe_geometry(&volume));
+    println!("Wrote {}", output);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn invalid_filter_parameters_are_usage_errors() {
+        let res = glam::IVec3::new(4, 4, 4);
+        let volume = Volume::new(Volume::radii_for(res, glam::Vec3::ONE), res);
+        let path = std::env::temp_dir().join("ct3d3_cli_filter_volume.txt").to_string_lossy().into_owned();
+        let output = std::env::temp_dir().join("ct3d3_cli_filter_output.txt").to_string_lossy().into_owned();
+        volume.serialize_to_file(path.clone()).unwrap();
+
+        for (key, value) in [("median", "-1"), ("median", "0"), ("gaussian", "inf"), ("bilateral", "0,50"), ("bilateral", "1.5,0"), ("perona-malik", "10,0"), ("perona-malik", "10,nan")] {
+            let args = ["filter", &path, &output, &format!("--{}", key), value].iter().map(|arg| arg.to_string()).collect();
+            assert!(matches!(run(args), Err(CT3DError::Usage(_))), "--{} {}", key, value);
+        }
+        assert!(!std::path::Path::new(&output).exists());
+        std::fs::remove_file(path).unwrap();
+    }
+}
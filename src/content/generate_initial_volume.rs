@@ -2,6 +2,8 @@ use noise::{Perlin};
 use noise::NoiseFn;
 use glam::{Vec3, IVec3};
 use crate::types::volume::Volume;
+use crate::types::ct3d_error::CT3DError;
+use crate::processing::resample::{Orientation, reorient};
 use std::fs;
 
 ///! Generate a volume to test the basic rendering with
@@ -46,21 +48,43 @@ pub fn construct_initial_volume() -> Volume {
     volume
 }
 
-pub fn generate_initial_volume() -> Volume {
-    let meta = fs::metadata("temp/initial_volume.txt");
+/// Axis directions the viewer shows volumes in: x towards the patient's left, y inferior and
+/// z posterior, the arrangement dicom_to_volume.py used to produce for axial series
+const DISPLAY_ORIENTATION: &str = "LIP";
+const INITIAL_VOLUME_PATH: &str = "temp/initial_volume.txt";
+
+pub fn generate_initial_volume() -> Result<Volume, CT3DError> {
+    let (volume, legacy) = load_or_construct_initial_volume()?;
+    // Files without a direction were written in display order already, reading them as LPS would flip them
+    if legacy {
+        return Ok(volume);
+    }
+    Ok(in_display_orientation(&volume))
+}
+
+/// The volume resampled to the axis directions the viewer shows volumes in
+pub fn in_display_orientation(volume: &Volume) -> Volume {
+    reorient(volume, Orientation::from_code(DISPLAY_ORIENTATION).unwrap())
+}
+
+/// The volume and whether it was loaded from a file written before volumes had a direction
+fn load_or_construct_initial_volume() -> Result<(Volume, bool), CT3DError> {
+    let meta = fs::metadata(INITIAL_VOLUME_PATH);
     match meta {
         Ok(_) => {
             println!("Loading pre-generatedd initial volume...");
-            let volume = Volume::deserialize_from_file("temp/initial_volume.txt".to_owned()).unwrap();
+            let volume = Volume::deserialize_from_file(INITIAL_VOLUME_PATH.to_owned())?;
+            let legacy = !Volume::file_has_direction(INITIAL_VOLUME_PATH.to_owned())?;
             print!("Done!");
-            volume
+            Ok((volume, legacy))
         },
         Err(_) => {
             println!("Regenerating initial volume...");
             let result = construct_initial_volume();
-            result.serialize_to_file("temp/initial_volume.txt".to_owned()).unwrap();
+            std::fs::create_dir_all("temp")?;
+            result.serialize_to_file(INITIAL_VOLUME_PATH.to_owned())?;
             println!("Done!");
-            result
+            Ok((result, false))
         }
     }
 
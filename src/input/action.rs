@@ -0,0 +1,101 @@
+use serde::{Serialize, Deserialize};
+
+/// Something the user asked the viewer to do, independent of the key, button or device that asked for it.
+/// Actions that work on "the pixel under the mouse" use the position of the last `PointerMove`.
+/// Performed by `application::perform`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Action {
+    /// The mouse moved to pixel (x, y). Generated from pointer motion, not bound to keys.
+    PointerMove { x: i32, y: i32 },
+    /// Rotate the camera by dragging: starts where the pointer is when the binding goes down and
+    /// follows it until the binding is released
+    RotateDrag,
+    /// Generated when the binding of `RotateDrag` is released
+    RotateDragEnd,
+    /// Move the camera towards (positive) or away from the volume by a number of wheel steps
+    Zoom(i32),
+    /// Change the density cutoff at this rate per second while the binding is held
+    AdjustCutoff(f32),
+    /// Step through the cutoffs suggested by intensity clustering
+    NextSuggestedCutoff,
+    ToggleSupersampling,
+    /// Show or hide frame rate, frame time and render kernel time
+    ToggleFrameStats,
+    /// Show or hide the text listing the loaded files, cutoff, tool and measurements
+    ToggleHud,
+    /// Show or hide the panel with the cutoff, window and level sliders and the transfer function editor
+    ToggleControls,
+    /// Show or hide the list of all measurements, each with a button deleting it
+    ToggleMeasurementList,
+    /// Colour surfaces by density with the transfer function, or by position
+    ToggleTransferFunction,
+    /// Show or hide the labelmap overlay
+    ToggleLabels,
+    /// Show or hide one label, counting from 1 in the order of the label table
+    ToggleLabel(usize),
+    SaveBookmark(u32),
+    RecallBookmark(u32),
+    AddKeyframe,
+    ClearKeyframes,
+    TogglePlayback,
+    CycleMeasurementTool,
+    /// Place a point of the active measurement tool on the surface under the mouse
+    PlacePoint,
+    FinishMeasurement,
+    UndoMeasurement,
+    PlaceSphereRoi,
+    PlaceBoxRoi,
+    /// Draw a freehand ROI: starts on the slice under the pointer when the binding goes down, adds
+    /// the points the pointer passes over and closes the contour when the binding is released
+    DrawFreehandRoi,
+    /// Generated when the binding of `DrawFreehandRoi` is released
+    DrawFreehandRoiEnd,
+    ThresholdAtCutoff,
+    GrowRegion,
+    GrowRegionConfident,
+    EraseLabel,
+    KeepPickedComponent,
+    RemoveSmallIslands,
+    KeepLargestIsland,
+    ShrinkLabel,
+    GrowLabel,
+    OpenLabel,
+    CloseLabel,
+    FillHoles,
+    FillHolesPerSlice,
+    CycleFilterPreview,
+    ApplyFilterPreview,
+    ExportLabels,
+    /// Save the loaded files, view, transfer function, labels and annotations to the session file
+    SaveSession,
+    /// Restore what the session file was saved with
+    LoadSession,
+    /// Load a DICOM file's series as the volume, or a NIfTI/NRRD file as the labelmap when it holds
+    /// labels and as the volume when it holds intensities
+    LoadFile(String)
+}
+
+impl Action {
+    /// Actions repeated every frame while their binding is held rather than once per press. Their
+    /// amounts are rates per second, see `scaled`.
+    pub fn is_continuous(&self) -> bool {
+        matches!(self, Action::AdjustCutoff(_))
+    }
+
+    /// The action generated when the binding of a drag action is released
+    pub fn drag_end(&self) -> Option<Action> {
+        match self {
+            Action::RotateDrag => Some(Action::RotateDragEnd),
+            Action::DrawFreehandRoi => Some(Action::DrawFreehandRoiEnd),
+            _ => None
+        }
+    }
+
+    /// The share of a continuous action that falls in a frame lasting `seconds`
+    pub fn scaled(&self, seconds: f32) -> Action {
+        match self {
+            Action::AdjustCutoff(rate) => Action::AdjustCutoff(rate * seconds),
+            action => action.clone()
+        }
+    }
+}
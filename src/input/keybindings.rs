@@ -0,0 +1,313 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+
+use crate::types::ct3d_error::{CT3DError, ErrorContext};
+use crate::input::action::Action;
+
+/// Bindings the viewer loads on top of the defaults, if the file exists
+pub const KEYBINDINGS_PATH: &str = "temp/keybindings.json";
+
+// Keys go by SDL's scancode names ("A", "1", "[", "Return", "F1", "Left Shift"), mouse buttons by
+// these. Chords put modifiers first: "Ctrl+1", "Shift+G".
+pub const MOUSE_LEFT: &str = "MouseLeft";
+pub const MOUSE_MIDDLE: &str = "MouseMiddle";
+pub const MOUSE_RIGHT: &str = "MouseRight";
+const LOW_CUTOFF_CHANGE_PER_SECOND: f32 = 0.6;
+// Longest frame continuous actions are scaled to, so a hitch (a kernel rebuild, say) does not make them jump
+const MAX_CONTINUOUS_STEP_SECONDS: f32 = 0.1;
+
+const MODIFIERS: [(&str, [&str; 2]); 3] = [
+    ("Ctrl", ["LEFT CTRL", "RIGHT CTRL"]),
+    ("Alt", ["LEFT ALT", "RIGHT ALT"]),
+    ("Shift", ["LEFT SHIFT", "RIGHT SHIFT"])
+];
+
+/// "shift+ctrl+g" and "Ctrl+Shift+G" are the same chord, written the second way
+fn normalize_chord(text: &str) -> Result<String, CT3DError> {
+    let parts: Vec<&str> = text.split('+').map(|part| part.trim()).collect();
+    let (key, modifier_names) = parts.split_last().unwrap();
+    if key.is_empty() {
+        return Err(CT3DError::Format(format!("No key in binding \"{}\"", text)));
+    }
+    let mut chord = String::new();
+    for (modifier, _) in MODIFIERS.iter() {
+        if modifier_names.iter().any(|name| name.eq_ignore_ascii_case(modifier)) {
+            chord += &format!("{}+", modifier);
+        }
+    }
+    if let Some(unknown) = modifier_names.iter().find(|name| !MODIFIERS.iter().any(|(modifier, _)| name.eq_ignore_ascii_case(modifier))) {
+        return Err(CT3DError::Format(format!("Unknown modifier \"{}\" in binding \"{}\"", unknown, text)));
+    }
+    Ok(chord + &key.to_ascii_uppercase())
+}
+
+/// Which action each key, button or chord performs
+#[derive(Debug, Clone, PartialEq)]
+pub struct Keybindings {
+    bindings: BTreeMap<String, Action>
+}
+
+impl Default for Keybindings {
+    fn default() -> Self {
+        Keybindings::new()
+    }
+}
+
+impl Keybindings {
+    pub fn new() -> Keybindings {
+        Keybindings { bindings: BTreeMap::new() }
+    }
+
+    pub fn bind(&mut self, chord: &str, action: Action) -> Result<(), CT3DError> {
+        self.bindings.insert(normalize_chord(chord)?, action);
+        Ok(())
+    }
+
+    pub fn get(&self, chord: &str) -> Option<&Action> {
+        normalize_chord(chord).ok().and_then(|chord| self.bindings.get(&chord))
+    }
+
+    /// The bindings the viewer has always had
+    pub fn default_bindings() -> Keybindings {
+        let mut keybindings = Keybindings::new();
+        let mut bind = |chord: &str, action: Action| keybindings.bind(chord, action).unwrap();
+
+        bind(MOUSE_LEFT, Action::PlacePoint);
+        bind(MOUSE_RIGHT, Action::RotateDrag);
+
+        bind("A", Action::AdjustCutoff(-LOW_CUTOFF_CHANGE_PER_SECOND));
+        bind("D", Action::AdjustCutoff(LOW_CUTOFF_CHANGE_PER_SECOND));
+        bind("Q", Action::AdjustCutoff(-LOW_CUTOFF_CHANGE_PER_SECOND / 4.0));
+        bind("E", Action::AdjustCutoff(LOW_CUTOFF_CHANGE_PER_SECOND / 4.0));
+        bind("N", Action::NextSuggestedCutoff);
+        bind("J", Action::ToggleSupersampling);
+        bind("F", Action::ToggleFrameStats);
+        bind("Tab", Action::ToggleControls);
+        bind("Shift+Tab", Action::ToggleHud);
+        bind("W", Action::ToggleTransferFunction);
+
+        for index in 1..=9 {
+            bind(&format!("{}", index), Action::RecallBookmark(index));
+            bind(&format!("Ctrl+{}", index), Action::SaveBookmark(index));
+        }
+        bind("K", Action::AddKeyframe);
+        bind("Delete", Action::ClearKeyframes);
+        bind("P", Action::TogglePlayback);
+
+        bind("M", Action::CycleMeasurementTool);
+        bind("Shift+M", Action::ToggleMeasurementList);
+        bind("Return", Action::FinishMeasurement);
+        bind("Backspace", Action::UndoMeasurement);
+        bind("R", Action::PlaceSphereRoi);
+        bind("B", Action::PlaceBoxRoi);
+        bind(&format!("Shift+{}", MOUSE_LEFT), Action::DrawFreehandRoi);
+
+        bind("L", Action::ToggleLabels);
+        for index in 1..=12 {
+            bind(&format!("F{}", index), Action::ToggleLabel(index));
+        }
+        bind("T", Action::ThresholdAtCutoff);
+        bind("G", Action::GrowRegion);
+        bind("Shift+G", Action::GrowRegionConfident);
+        bind("U", Action::EraseLabel);
+        bind("C", Action::KeepPickedComponent);
+        bind("I", Action::RemoveSmallIslands);
+        bind("Shift+I", Action::KeepLargestIsland);
+        bind("[", Action::ShrinkLabel);
+        bind("]", Action::GrowLabel);
+        bind("O", Action::OpenLabel);
+        bind("Shift+O", Action::CloseLabel);
+        bind("H", Action::FillHoles);
+        bind("Shift+H", Action::FillHolesPerSlice);
+        bind("X", Action::ExportLabels);
+
+        bind("Ctrl+S", Action::SaveSession);
+        bind("Ctrl+O", Action::LoadSession);
+
+        bind("V", Action::CycleFilterPreview);
+        bind("Shift+V", Action::ApplyFilterPreview);
+
+        keybindings
+    }
+
+    /// Bindings from a JSON object of chords and actions, e.g. `{"W": {"AdjustCutoff": 0.01}, "Shift+R": "PlaceBoxRoi"}`,
+    /// on top of the default bindings
+    pub fn from_json(text: &str) -> Result<Keybindings, CT3DError> {
+        let overrides: BTreeMap<String, Action> = serde_json::from_str(text)?;
+        let mut keybindings = Keybindings::default_bindings();
+        for (chord, action) in overrides {
+            keybindings.bind(&chord, action)?;
+        }
+        Ok(keybindings)
+    }
+
+    pub fn to_json(&self) -> Result<String, CT3DError> {
+        Ok(serde_json::to_string_pretty(&self.bindings)?)
+    }
+
+    /// `from_json` of the file at `path` if there is one, the default bindings otherwise
+    pub fn load_or_default(path: &str) -> Result<Keybindings, CT3DError> {
+        if !Path::new(path).exists() {
+            return Ok(Keybindings::default_bindings());
+        }
+        Keybindings::from_json(&fs::read_to_string(path)?).with_context(|| format!("Invalid keybindings in {}", path))
+    }
+}
+
+/// Input as it comes from a window, before it is given a meaning by the keybindings
+#[derive(Debug, Clone, PartialEq)]
+pub enum InputEvent {
+    Pressed(String),
+    Released(String),
+    PointerMoved { x: i32, y: i32 },
+    Wheel(i32),
+    FileDropped(String)
+}
+
+/// Turns input events into actions: tracks held modifiers, ignores key repeat, ends drags and
+/// remembers which continuous actions are held down
+pub struct InputMapper {
+    pub keybindings: Keybindings,
+    down: HashSet<String>,
+    held: HashMap<String, Action>
+}
+
+impl InputMapper {
+    pub fn new(keybindings: Keybindings) -> InputMapper {
+        InputMapper { keybindings, down: HashSet::new(), held: HashMap::new() }
+    }
+
+    fn is_modifier(key: &str) -> bool {
+        MODIFIERS.iter().any(|(_, names)| names.contains(&key))
+    }
+
+    fn modifier_down(&self, modifier: &str) -> bool {
+        MODIFIERS.iter().find(|(name, _)| *name == modifier).map(|(_, names)| names.iter().any(|key| self.down.contains(*key))).unwrap_or(false)
+    }
+
+    /// The action of `key` with the modifiers that are down, or of the bare key if that chord is not bound
+    fn lookup(&self, key: &str) -> Option<Action> {
+        let modifiers: String = MODIFIERS.iter()
+            .filter(|(modifier, _)| self.modifier_down(modifier))
+            .map(|(modifier, _)| format!("{}+", modifier))
+            .collect();
+        self.keybindings.get(&format!("{}{}", modifiers, key))
+            .or_else(|| self.keybindings.get(key))
+            .cloned()
+    }
+
+    pub fn handle(&mut self, event: InputEvent) -> Vec<Action> {
+        match event {
+            InputEvent::Pressed(key) => {
+                let key = key.to_ascii_uppercase();
+                if !self.down.insert(key.clone()) || InputMapper::is_modifier(&key) {
+                    return Vec::new();
+                }
+                match self.lookup(&key) {
+                    Some(action) if action.is_continuous() => {
+                        self.held.insert(key, action);
+                        Vec::new()
+                    },
+                    Some(action) => {
+                        if let Some(end) = action.drag_end() {
+                            self.held.insert(key, end);
+                        }
+                        vec![action]
+                    },
+                    None => Vec::new()
+                }
+            },
+            InputEvent::Released(key) => {
+                let key = key.to_ascii_uppercase();
+                self.down.remove(&key);
+                match self.held.remove(&key) {
+                    Some(action) if !action.is_continuous() => vec![action],
+                    _ => Vec::new()
+                }
+            },
+            InputEvent::PointerMoved { x, y } => vec![Action::PointerMove { x, y }],
+            InputEvent::Wheel(steps) => vec![Action::Zoom(steps)],
+            InputEvent::FileDropped(path) => vec![Action::LoadFile(path)]
+        }
+    }
+
+    /// Continuous actions whose bindings are down, scaled to a frame that took `seconds`
+    pub fn held_actions(&self, seconds: f32) -> Vec<Action> {
+        let seconds = seconds.min(MAX_CONTINUOUS_STEP_SECONDS);
+        self.held.values().filter(|action| action.is_continuous()).map(|action| action.scaled(seconds)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn press(mapper: &mut InputMapper, key: &str) -> Vec<Action> {
+        mapper.handle(InputEvent::Pressed(key.to_owned()))
+    }
+
+    fn release(mapper: &mut InputMapper, key: &str) -> Vec<Action> {
+        mapper.handle(InputEvent::Released(key.to_owned()))
+    }
+
+    #[test]
+    fn chords_use_modifiers_and_fall_back_to_the_bare_key() {
+        let mut mapper = InputMapper::new(Keybindings::default_bindings());
+        assert_eq!(press(&mut mapper, "G"), vec![Action::GrowRegion]);
+        release(&mut mapper, "G");
+
+        press(&mut mapper, "Left Shift");
+        assert_eq!(press(&mut mapper, "G"), vec![Action::GrowRegionConfident]);
+        // Shift+C is not bound, so it does what C does
+        assert_eq!(press(&mut mapper, "C"), vec![Action::KeepPickedComponent]);
+        release(&mut mapper, "Left Shift");
+
+        press(&mut mapper, "Right Ctrl");
+        assert_eq!(press(&mut mapper, "3"), vec![Action::SaveBookmark(3)]);
+    }
+
+    #[test]
+    fn repeated_presses_are_ignored_until_released() {
+        let mut mapper = InputMapper::new(Keybindings::default_bindings());
+        assert_eq!(press(&mut mapper, "K"), vec![Action::AddKeyframe]);
+        assert_eq!(press(&mut mapper, "K"), vec![]);
+        release(&mut mapper, "K");
+        assert_eq!(press(&mut mapper, "K"), vec![Action::AddKeyframe]);
+    }
+
+    #[test]
+    fn continuous_actions_are_held_and_drags_end_on_release() {
+        let mut mapper = InputMapper::new(Keybindings::default_bindings());
+        assert_eq!(press(&mut mapper, "D"), vec![]);
+        assert_eq!(mapper.held_actions(0.05), vec![Action::AdjustCutoff(LOW_CUTOFF_CHANGE_PER_SECOND * 0.05)]);
+        // A long stall counts as a short frame
+        assert_eq!(mapper.held_actions(2.0), vec![Action::AdjustCutoff(LOW_CUTOFF_CHANGE_PER_SECOND * MAX_CONTINUOUS_STEP_SECONDS)]);
+        release(&mut mapper, "D");
+        assert_eq!(mapper.held_actions(0.05), vec![]);
+
+        assert_eq!(press(&mut mapper, MOUSE_RIGHT), vec![Action::RotateDrag]);
+        assert_eq!(mapper.handle(InputEvent::PointerMoved { x: 5, y: 6 }), vec![Action::PointerMove { x: 5, y: 6 }]);
+        assert_eq!(mapper.held_actions(0.05), vec![]);
+        assert_eq!(release(&mut mapper, MOUSE_RIGHT), vec![Action::RotateDragEnd]);
+
+        press(&mut mapper, "Left Shift");
+        assert_eq!(press(&mut mapper, MOUSE_LEFT), vec![Action::DrawFreehandRoi]);
+        release(&mut mapper, "Left Shift");
+        assert_eq!(release(&mut mapper, MOUSE_LEFT), vec![Action::DrawFreehandRoiEnd]);
+    }
+
+    #[test]
+    fn bindings_from_json_override_the_defaults() {
+        let keybindings = Keybindings::from_json(r#"{"shift+w": {"AdjustCutoff": 0.5}, "mouseleft": "RotateDrag"}"#).unwrap();
+        assert_eq!(keybindings.get("Shift+W"), Some(&Action::AdjustCutoff(0.5)));
+        assert_eq!(keybindings.get(MOUSE_LEFT), Some(&Action::RotateDrag));
+        assert_eq!(keybindings.get("K"), Some(&Action::AddKeyframe));
+
+        let written = Keybindings::default_bindings().to_json().unwrap();
+        assert_eq!(Keybindings::from_json(&written).unwrap(), Keybindings::default_bindings());
+
+        assert!(Keybindings::from_json(r#"{"Hyper+K": "AddKeyframe"}"#).is_err());
+        assert!(Keybindings::from_json(r#"{"K": "Teleport"}"#).is_err());
+    }
+}
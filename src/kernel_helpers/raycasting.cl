@@ -1,3 +1,5 @@
+#include "math.cl"
+
 #define RAYCASTING_DENOM_EPSILON 1.0e-6
 
 #define FLOAT3_EAST ((float3)(1.0,0.0,0.0))
@@ -0,0 +1,77 @@
+// Volume filters run by processing/filters.rs. Voxels are stored x fastest, then y, then z.
+
+int3 filter_voxel_coord(int index, int3 res){
+    return (int3)(index % res.x, (index / res.x) % res.y, index / (res.x * res.y));
+}
+
+int filter_clamped_index(int3 coord, int3 res){
+    int3 clamped = clamp(coord, (int3)(0, 0, 0), res - (int3)(1, 1, 1));
+    return clamped.z * res.x * res.y + clamped.y * res.x + clamped.x;
+}
+
+// parameters: res x, res y, res z, axis, radius
+// weights: 2 * radius + 1 normalized Gaussian weights
+__kernel void gaussian_pass(
+    __global float * input,
+    __global float * output,
+    __global int * parameters,
+    __global float * weights
+){
+    int index = get_global_id(0);
+    int3 res = (int3)(parameters[0], parameters[1], parameters[2]);
+    int axis = parameters[3];
+    int radius = parameters[4];
+    if(index >= res.x * res.y * res.z){
+        return;
+    }
+
+    int3 coord = filter_voxel_coord(index, res);
+    int3 step = (int3)(axis == 0, axis == 1, axis == 2);
+
+    float sum = 0.0;
+    for(int k = -radius; k <= radius; k++){
+        sum += weights[k + radius] * input[filter_clamped_index(coord + step * k, res)];
+    }
+    output[index] = sum;
+}
+
+// parameters: res x, res y, res z, extent x, extent y, extent z (window half size in voxels)
+// float_parameters: spacing x, spacing y, spacing z, spatial sigma (mm), range sigma
+__kernel void bilateral(
+    __global float * input,
+    __global float * output,
+    __global int * parameters,
+    __global float * float_parameters
+){
+    int index = get_global_id(0);
+    int3 res = (int3)(parameters[0], parameters[1], parameters[2]);
+    int3 extent = (int3)(parameters[3], parameters[4], parameters[5]);
+    float3 spacing = (float3)(float_parameters[0], float_parameters[1], float_parameters[2]);
+    float sigma_mm = float_parameters[3];
+    float sigma_range = float_parameters[4];
+    if(index >= res.x * res.y * res.z){
+        return;
+    }
+
+    int3 coord = filter_voxel_coord(index, res);
+    float center = input[index];
+    float sum = 0.0;
+    float total_weight = 0.0;
+    for(int z = -extent.z; z <= extent.z; z++){
+        for(int y = -extent.y; y <= extent.y; y++){
+            for(int x = -extent.x; x <= extent.x; x++){
+                float3 offset_mm = (float3)(x, y, z) * spacing;
+                float distance_squared = dot(offset_mm, offset_mm);
+                if(distance_squared > 4.0 * sigma_mm * sigma_mm){
+                    continue;
+                }
+                float neighbour = input[filter_clamped_index(coord + (int3)(x, y, z), res)];
+                float difference = neighbour - center;
+                float weight = exp(-distance_squared / (2.0 * sigma_mm * sigma_mm)) * exp(-difference * difference / (2.0 * sigma_range * sigma_range));
+                sum += weight * neighbour;
+                total_weight += weight;
+            }
+        }
+    }
+    output[index] = sum / total_weight;
+}
@@ -1,12 +1,21 @@
 
 
+// DOWNSAMPLING, NORMAL_SEARCH_RADIUS, DROPOFF_RATE, HIT_BUFFER_STRIDE and TRANSFER_FUNCTION_ENTRIES are
+// defined by the application, see KernelConfig in types/application_state.rs
+#include "math.cl"
+#include "raycasting.cl"
+
 #define F 1.0
 #define FIXED_STEP_MARCH_ENTER_MAX_STEPS 32
-#define DOWNSAMPLING 1
-#define NORMAL_SEARCH_RADIUS 1
-#define DROPOFF_RATE 0.70
 #define INITIAL_SCALE 1.05
 
+// Subpixel offsets of supersampling passes follow the R2 low discrepancy sequence
+#define R2_ALPHA_X 0.7548776662
+#define R2_ALPHA_Y 0.5698402910
+
+// Labels composited in front of the surface stop the ray once they block (almost) all light
+#define LABEL_MIN_TRANSMITTANCE 0.01
+
 
 typedef struct VolumeData {
     int enabled;
@@ -62,6 +71,13 @@ float vd_get_by_int3(VolumeData* vd, int3 coord){
     return (float)vd->buffer[header_start+id];
 }
 
+int vd_label_at(VolumeData* vd, __global ushort * labels, float3 coord){
+    int3 icoord = vd_map_float3(vd, coord);
+    int W = vd->res.x;
+    int H = vd->res.y;
+    return (int)labels[W*H*icoord.z + W*icoord.y + icoord.x];
+}
+
 float vd_float3_is_in_bounds(VolumeData* vd, float3 coord){
     float3 radii = vd->radii;
     return coord.x >= -radii.x && coord.x < radii.x && coord.y >= -radii.y && coord.y < radii.y && coord.z >= -radii.z && coord.z < radii.z;
@@ -178,10 +194,16 @@ float sdf(float3 world_coords, ApplicationState application_state){
 
 __kernel void render(
     __global int * screen_dimensions,
-    __global float * screen_buffer,
+    __global uint * screen_buffer,
     __global float * input_data_buffer,
     __global float * axes_buffer,
-    __global float * general_parameters_buffer
+    __global float * general_parameters_buffer,
+    __global float * hit_buffer,
+    __global ushort * labels_buffer,
+    __global float * label_colors_buffer,
+    __global int * render_parameters_buffer,
+    __global float * accumulation_buffer,
+    __global float * transfer_function_buffer
 ){
 
     ApplicationState application_state;
@@ -204,30 +226,72 @@ __kernel void render(
         axes_buffer[2*3+2]
     );
 
-    VolumeData vd = vd_build(input_data_buffer);
+    // render_parameters_buffer: where the level of detail being rendered starts in input_data_buffer,
+    // pixel step, supersampling sample (0 for the pixel corner, as without supersampling).
+    // Labels are on the full resolution grid, which always comes first.
+    VolumeData vd = vd_build(input_data_buffer + render_parameters_buffer[0]);
+    VolumeData label_vd = vd_build(input_data_buffer);
 
     application_state.vd = &vd;
     
     int id = get_global_id(0);
 
-    int y = id / screen_dimensions[0];
-    int x = id % screen_dimensions[0];
-
     int w = screen_dimensions[0];
     int h = screen_dimensions[1];
 
-    float u = (float) x / (float) w;
-    float v = (float) y / (float) h;
+    // One ray per pixel_step x pixel_step block, filling the whole block
+    int pixel_step = render_parameters_buffer[1];
+    int sample = render_parameters_buffer[2];
+    int columns = (w + pixel_step - 1) / pixel_step;
+    int rows = (h + pixel_step - 1) / pixel_step;
+    if(id >= columns * rows){
+        return;
+    }
+
+    int y = (id / columns) * pixel_step;
+    int x = (id % columns) * pixel_step;
+
+    float2 jitter = (float2)(0.0, 0.0);
+    if(sample > 0){
+        float2 r2 = (float2)(0.5 + sample * R2_ALPHA_X, 0.5 + sample * R2_ALPHA_Y);
+        jitter = r2 - floor(r2) - (float2)(0.5, 0.5);
+    }
+
+    float u = ((float) x + jitter.x) / (float) w;
+    float v = ((float) y + jitter.y) / (float) h;
 
     u = u - 0.5;
     v = 0.5 - v;
 
-    u = u * (float)w / (float)h; 
+    // The shorter side of the screen spans -0.5..0.5, so nothing gets stretched or cropped away
+    float short_side = (float)min(w, h);
+    u = u * (float)w / short_side;
+    v = v * (float)h / short_side;
 
     float camera_z = general_parameters_buffer[0];
 
     float LOW_CUTOFF = general_parameters_buffer[1];
 
+    int WRITE_HIT_BUFFER = general_parameters_buffer[2] != 0.0;
+
+    int LABELS_ENABLED = general_parameters_buffer[3] != 0.0;
+
+    // Surfaces coloured by density from the RGBA table in transfer_function_buffer instead of by position
+    int TRANSFER_FUNCTION_ENABLED = general_parameters_buffer[4] != 0.0;
+
+    // Clip box as fractions of each axis, lower corner then upper corner. Samples outside are skipped.
+    float3 clip_low = -vd.radii + (float3)(general_parameters_buffer[5], general_parameters_buffer[6], general_parameters_buffer[7]) * 2.0f * vd.radii;
+    float3 clip_high = -vd.radii + (float3)(general_parameters_buffer[8], general_parameters_buffer[9], general_parameters_buffer[10]) * 2.0f * vd.radii;
+
+    OptFloat3 hit = OptFloat3_miss();
+    float hit_depth = -1.0;
+    float hit_value = -1.0;
+
+    // Label overlay, composited front to back each time the ray enters a visible label
+    float3 label_color = (float3)(0.0,0.0,0.0);
+    float transmittance = 1.0;
+    int previous_label = 0;
+
     float3 color = ((float)x/(float)w >= LOW_CUTOFF) ? ((float3)(1.0,1.0,1.0)) : ((float3)(0.0,0.0,0.0));
 
     float3 ro = (float3)(0.0,0.0,camera_z);
@@ -270,6 +334,32 @@ __kernel void render(
 
                 while(vd_float3_is_in_bounds(&vd, local_pt)&&(length(local_pt)<max_distance))
                 {
+                    if(any(local_pt < clip_low) || any(local_pt > clip_high)){
+                        local_pt += float3_scaled_by(local_dir,fixed_march_step);
+                        continue;
+                    }
+                    if(LABELS_ENABLED){
+                        int label = vd_label_at(&label_vd, labels_buffer, local_pt);
+                        if(label != previous_label){
+                            float alpha = label_colors_buffer[label*4+3];
+                            if(alpha > 0.0){
+                                float3 label_rgb = (float3)(
+                                    label_colors_buffer[label*4+0],
+                                    label_colors_buffer[label*4+1],
+                                    label_colors_buffer[label*4+2]
+                                );
+                                float3 label_world_pt = local_to_world_coords(local_pt,application_state);
+                                float label_grey = INITIAL_SCALE-DROPOFF_RATE*length(label_world_pt - ro)/fabs(camera_z);
+                                label_color += float3_scaled_by(label_rgb, transmittance*alpha*label_grey);
+                                transmittance *= (1.0 - alpha);
+                            }
+                            previous_label = label;
+                        }
+                        if(transmittance < LABEL_MIN_TRANSMITTANCE){
+                            ipoint = OptFloat3_hit(local_pt);
+                            break;
+                        }
+                    }
                     float value = vd_query(&vd, local_pt);
                     if(value >= LOW_CUTOFF){
                         
@@ -281,6 +371,9 @@ __kernel void render(
 
                 if(ipoint.present){
                     float3 world_pt = local_to_world_coords(ipoint.value,application_state);
+                    hit = ipoint;
+                    hit_depth = length(world_pt - ro);
+                    hit_value = vd_query(&vd, ipoint.value);
                     float grey = INITIAL_SCALE-DROPOFF_RATE*length(world_pt - (float3)(0.0,0.0,camera_z))/fabs(camera_z);
                     float _u = fabs(ipoint.value.x/vd.radii.x);
                     float _v = fabs(ipoint.value.y/vd.radii.y);
@@ -293,6 +386,10 @@ __kernel void render(
                         b+_v,
                         c+_w
                     );
+                    if(TRANSFER_FUNCTION_ENABLED){
+                        int entry = clamp((int)(hit_value * (float)(TRANSFER_FUNCTION_ENTRIES - 1) + 0.5), 0, TRANSFER_FUNCTION_ENTRIES - 1);
+                        base_color = vload4(entry, transfer_function_buffer).xyz;
+                    }
                     color = float3_scaled_by(base_color,grey);
                 }
 
@@ -305,10 +402,34 @@ __kernel void render(
 
     }
 
-    int tid = y * w + x;
-    int offs = tid * 3;
-    screen_buffer[offs+0] = color.x;
-    screen_buffer[offs+1] = color.y;
-    screen_buffer[offs+2] = color.z;
+    color = label_color + float3_scaled_by(color, transmittance);
+
+    for(int block_y = y; block_y < min(y + pixel_step, h); block_y++){
+        for(int block_x = x; block_x < min(x + pixel_step, w); block_x++){
+            int tid = block_y * w + block_x;
+
+            // Running mean over the supersamples, kept in floats so it does not band
+            float4 accumulated = (float4)(color, 1.0);
+            if(sample > 0){
+                float4 previous = vload4(tid, accumulation_buffer);
+                accumulated = previous + (accumulated - previous) / (float)(sample + 1);
+            }
+            vstore4(accumulated, tid, accumulation_buffer);
+
+            // Packed 0xAARRGGBB, ready to be copied into the texture as is
+            uint3 rgb = convert_uint3(clamp(accumulated.xyz, 0.0f, 1.0f) * 255.0f);
+            screen_buffer[tid] = 0xff000000 | (rgb.x << 16) | (rgb.y << 8) | rgb.z;
+
+            if(WRITE_HIT_BUFFER){
+                int hit_offs = tid * HIT_BUFFER_STRIDE;
+                hit_buffer[hit_offs+0] = (float)hit.present;
+                hit_buffer[hit_offs+1] = hit.value.x;
+                hit_buffer[hit_offs+2] = hit.value.y;
+                hit_buffer[hit_offs+3] = hit.value.z;
+                hit_buffer[hit_offs+4] = hit_depth;
+                hit_buffer[hit_offs+5] = hit_value;
+            }
+        }
+    }
 
 }
@@ -0,0 +1,69 @@
+//! CT3D as a library: volume I/O (`types::volume`, `loaders`), processing (`processing`), cameras
+//! (`types::camera_state`) and the OpenCL renderer (`application`), which renders headless into
+//! `ApplicationState::screen_buffer`. The SDL viewer is the `CT3D3` binary, built with the `sdl` feature.
+
+pub mod types {
+    pub mod rgb_image;
+    pub mod ct3d_error;
+    pub mod application_state;
+    pub mod volume;
+    pub mod camera_state;
+    pub mod measurement;
+    pub mod roi;
+    pub mod labelmap;
+    pub mod mesh;
+    pub mod transfer_function;
+    pub mod clip_box;
+    pub mod session;
+}
+
+pub mod tools {
+    pub mod resources;
+    pub mod kernel_loader;
+    pub mod bitmap_font;
+    pub mod frame_timing;
+    pub mod animation_export;
+    pub mod picking;
+    pub mod measurements;
+    pub mod roi_tool;
+    pub mod segmentation_tool;
+    pub mod filter_tool;
+    pub mod session_tool;
+}
+
+pub mod loaders {
+    pub mod samples;
+    pub mod nifti;
+    pub mod nrrd;
+}
+
+pub mod processing {
+    pub mod parallel;
+    pub mod roi_statistics;
+    pub mod connected_components;
+    pub mod filters;
+    pub mod morphology;
+    pub mod quantize;
+    pub mod resample;
+    pub mod marching_cubes;
+    pub mod mesh_processing;
+    pub mod segmentation;
+}
+
+pub mod input {
+    pub mod action;
+    pub mod keybindings;
+}
+
+pub mod ui {
+    pub mod widgets;
+    pub mod overlay;
+}
+
+pub mod application;
+
+pub mod cli;
+
+pub mod content {
+    pub mod generate_initial_volume;
+}
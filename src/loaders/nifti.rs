@@ -0,0 +1,216 @@
+use std::fs::File;
+use std::io::{Read, Write, BufWriter};
+
+use byteorder::{ByteOrder, LittleEndian, BigEndian, WriteBytesExt};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use glam::{Vec3, IVec3, Mat3};
+
+use crate::types::ct3d_error::CT3DError;
+use crate::types::volume::Volume;
+use crate::loaders::samples::{SampleType, decode_samples, voxel_count};
+
+const NIFTI1_HEADER_SIZE: usize = 348;
+
+pub fn is_nifti_path(path: &str) -> bool {
+    let lower = path.to_ascii_lowercase();
+    lower.ends_with(".nii") || lower.ends_with(".nii.gz")
+}
+
+fn sample_type(datatype: i16) -> Result<SampleType, CT3DError> {
+    match datatype {
+        2 => Ok(SampleType::U8),
+        4 => Ok(SampleType::I16),
+        8 => Ok(SampleType::I32),
+        16 => Ok(SampleType::F32),
+        64 => Ok(SampleType::F64),
+        256 => Ok(SampleType::I8),
+        512 => Ok(SampleType::U16),
+        768 => Ok(SampleType::U32),
+        _ => Err(CT3DError::Unsupported(format!("Unsupported NIfTI datatype {}", datatype)))
+    }
+}
+
+struct Header<'a> {
+    bytes: &'a [u8],
+    big_endian: bool
+}
+
+impl<'a> Header<'a> {
+    fn i16(&self, offset: usize) -> i16 {
+        if self.big_endian { BigEndian::read_i16(&self.bytes[offset..]) } else { LittleEndian::read_i16(&self.bytes[offset..]) }
+    }
+    fn f32(&self, offset: usize) -> f32 {
+        if self.big_endian { BigEndian::read_f32(&self.bytes[offset..]) } else { LittleEndian::read_f32(&self.bytes[offset..]) }
+    }
+}
+
+/// Origin and direction from the sform, else the qform, converted from NIfTI's RAS to LPS
+fn patient_geometry(header: &Header) -> Option<(Vec3, Mat3)> {
+    let (origin, axes) = if header.i16(254) > 0 {
+        let row = |offset: usize| [header.f32(offset), header.f32(offset + 4), header.f32(offset + 8), header.f32(offset + 12)];
+        let (x, y, z) = (row(280), row(296), row(312));
+        let axes = Mat3::from_cols(Vec3::new(x[0], y[0], z[0]), Vec3::new(x[1], y[1], z[1]), Vec3::new(x[2], y[2], z[2]));
+        (Vec3::new(x[3], y[3], z[3]), axes)
+    } else if header.i16(252) > 0 {
+        let (b, c, d) = (header.f32(256), header.f32(260), header.f32(264));
+        let a = (1.0 - b*b - c*c - d*d).max(0.0).sqrt();
+        let qfac = if header.f32(76) < 0.0 { -1.0 } else { 1.0 };
+        let rotation = Mat3::from_cols(
+            Vec3::new(a*a + b*b - c*c - d*d, 2.0*(b*c + a*d), 2.0*(b*d - a*c)),
+            Vec3::new(2.0*(b*c - a*d), a*a + c*c - b*b - d*d, 2.0*(c*d + a*b)),
+            Vec3::new(2.0*(b*d + a*c), 2.0*(c*d - a*b), a*a + d*d - b*b - c*c) * qfac
+        );
+        (Vec3::new(header.f32(268), header.f32(272), header.f32(276)), rotation)
+    } else {
+        return None;
+    };
+    let ras_to_lps = Mat3::from_diagonal(Vec3::new(-1.0, -1.0, 1.0));
+    let axes = ras_to_lps * axes;
+    let direction = Mat3::from_cols(axes.x_axis.normalize_or_zero(), axes.y_axis.normalize_or_zero(), axes.z_axis.normalize_or_zero());
+    if direction.determinant().abs() < 1e-6 {
+        return None;
+    }
+    Some((ras_to_lps * origin, direction))
+}
+
+/// Read a single file NIfTI-1 image (.nii or .nii.gz). Values are returned unnormalized,
+/// with the header's intensity scaling applied.
+pub fn read_nifti(path: String) -> Result<Volume, CT3DError> {
+    let mut bytes = Vec::new();
+    let file = File::open(&path)?;
+    if path.to_ascii_lowercase().ends_with(".gz") {
+        GzDecoder::new(file).read_to_end(&mut bytes)?;
+    } else {
+        let mut file = file;
+        file.read_to_end(&mut bytes)?;
+    }
+
+    if bytes.len() < NIFTI1_HEADER_SIZE {
+        return Err(CT3DError::Format(format!("{} is too short to be a NIfTI file", path)));
+    }
+
+    let big_endian = match (LittleEndian::read_i32(&bytes), BigEndian::read_i32(&bytes)) {
+        (348, _) => false,
+        (_, 348) => true,
+        _ => return Err(CT3DError::Format(format!("{} is not a NIfTI-1 file", path)))
+    };
+    if &bytes[344..348] != b"n+1\0" {
+        return Err(CT3DError::Unsupported(format!("{} is not a single file NIfTI-1 image", path)));
+    }
+    let header = Header { bytes: &bytes, big_endian };
+
+    let ndim = header.i16(40);
+    if ndim < 3 {
+        return Err(CT3DError::Unsupported(format!("Expected a 3D NIfTI image, found {} dimensions", ndim)));
+    }
+    for extra_dim in 4..=(ndim.min(7) as usize) {
+        if header.i16(40 + 2*extra_dim) > 1 {
+            return Err(CT3DError::Unsupported("Only single volume NIfTI images are supported".to_owned()));
+        }
+    }
+    let res = IVec3::new(header.i16(42) as i32, header.i16(44) as i32, header.i16(46) as i32);
+    let count = voxel_count(res)?;
+    let spacing = Vec3::new(header.f32(80).abs(), header.f32(84).abs(), header.f32(88).abs());
+    let sample_type = sample_type(header.i16(70))?;
+    let vox_offset = header.f32(108) as usize;
+    let scl_slope = header.f32(112);
+    let scl_inter = header.f32(116);
+
+    let mut data = decode_samples(&bytes[vox_offset.min(bytes.len())..], sample_type, big_endian, count)?;
+
+    if scl_slope != 0.0 && !(scl_slope == 1.0 && scl_inter == 0.0) {
+        data.iter_mut().for_each(|value| *value = *value * scl_slope + scl_inter);
+    }
+
+    let mut volume = Volume::new(Volume::radii_for(res, spacing), res);
+    volume.spacing = spacing;
+    if let Some((origin, direction)) = patient_geometry(&header) {
+        volume.origin = origin;
+        volume.direction = direction;
+    }
+    volume.data = data;
+
+    Ok(volume)
+}
+
+/// Write a 16 bit unsigned single file NIfTI-1 image, gzip compressed if `path` ends in .gz.
+/// The patient geometry (LPS) goes into the sform.
+pub fn write_nifti_u16(path: String, res: IVec3, spacing: Vec3, origin: Vec3, direction: Mat3, data: &[u16]) -> Result<(), CT3DError> {
+    let mut header = vec![0u8; NIFTI1_HEADER_SIZE + 4]; // header plus an empty extension block
+    LittleEndian::write_i32(&mut header[0..], NIFTI1_HEADER_SIZE as i32);
+    for (i, dim) in [3, res.x, res.y, res.z, 1, 1, 1, 1].iter().enumerate() {
+        LittleEndian::write_i16(&mut header[40 + 2*i..], *dim as i16);
+    }
+    LittleEndian::write_i16(&mut header[70..], 512); // datatype: uint16
+    LittleEndian::write_i16(&mut header[72..], 16); // bitpix
+    for (i, pixdim) in [1.0, spacing.x, spacing.y, spacing.z, 1.0, 1.0, 1.0, 1.0].iter().enumerate() {
+        LittleEndian::write_f32(&mut header[76 + 4*i..], *pixdim);
+    }
+    LittleEndian::write_f32(&mut header[108..], (NIFTI1_HEADER_SIZE + 4) as f32); // vox_offset
+    header[123] = 2; // xyzt_units: millimetres
+    LittleEndian::write_i16(&mut header[254..], 1); // sform_code: scanner coordinates
+    let lps_to_ras = Mat3::from_diagonal(Vec3::new(-1.0, -1.0, 1.0));
+    let axes = lps_to_ras * direction * Mat3::from_diagonal(spacing);
+    let origin = lps_to_ras * origin;
+    for row in 0..3 {
+        let values = [axes.x_axis[row], axes.y_axis[row], axes.z_axis[row], origin[row]];
+        for (i, value) in values.iter().enumerate() {
+            LittleEndian::write_f32(&mut header[280 + 16*row + 4*i..], *value);
+        }
+    }
+    header[344..348].copy_from_slice(b"n+1\0");
+
+    let file = File::create(&path)?;
+    let mut writer: Box<dyn Write> = if path.to_ascii_lowercase().ends_with(".gz") {
+        Box::new(GzEncoder::new(BufWriter::new(file), Compression::default()))
+    } else {
+        Box::new(BufWriter::new(file))
+    };
+    writer.write_all(&header)?;
+    for value in data.iter() {
+        writer.write_u16::<LittleEndian>(*value)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> String {
+        std::env::temp_dir().join(name).to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn written_images_read_back() {
+        let res = IVec3::new(3, 4, 5);
+        let spacing = Vec3::new(0.5, 0.75, 2.0);
+        let origin = Vec3::new(10.0, -20.0, 30.0);
+        let direction = Mat3::from_cols(Vec3::new(0.0, -1.0, 0.0), Vec3::new(0.0, 0.0, -1.0), Vec3::new(1.0, 0.0, 0.0));
+        let data: Vec<u16> = (0..60).map(|i| i * 1000).collect();
+        for name in ["ct3d3_nifti_test.nii", "ct3d3_nifti_test.nii.gz"] {
+            let path = temp_path(name);
+            write_nifti_u16(path.clone(), res, spacing, origin, direction, &data).unwrap();
+            let volume = read_nifti(path.clone()).unwrap();
+            std::fs::remove_file(path).unwrap();
+            assert_eq!(volume.res, res);
+            assert_eq!(volume.spacing, spacing);
+            assert!(volume.origin.abs_diff_eq(origin, 1e-5) && volume.direction.abs_diff_eq(direction, 1e-6));
+            assert_eq!(volume.data, data.iter().map(|value| *value as f32).collect::<Vec<f32>>());
+        }
+    }
+
+    #[test]
+    fn empty_and_negative_sizes_are_rejected() {
+        for res in [IVec3::new(3, 0, 5), IVec3::new(3, -4, 5)] {
+            let path = temp_path("ct3d3_nifti_invalid_test.nii");
+            write_nifti_u16(path.clone(), res, Vec3::ONE, Vec3::ZERO, Mat3::IDENTITY, &[0; 60]).unwrap();
+            let result = read_nifti(path.clone());
+            std::fs::remove_file(path).unwrap();
+            assert!(matches!(result, Err(CT3DError::Format(_))));
+        }
+    }
+}
@@ -0,0 +1,227 @@
+use std::fs::File;
+use std::io::{Read, Write, BufWriter};
+use std::path::Path;
+use std::collections::HashMap;
+
+use byteorder::{LittleEndian, WriteBytesExt};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use glam::{Vec3, IVec3, Mat3};
+
+use crate::types::ct3d_error::CT3DError;
+use crate::types::volume::Volume;
+use crate::loaders::samples::{SampleType, decode_samples, voxel_count};
+
+pub fn is_nrrd_path(path: &str) -> bool {
+    path.to_ascii_lowercase().ends_with(".nrrd") || path.to_ascii_lowercase().ends_with(".nhdr")
+}
+
+fn sample_type(name: &str) -> Result<SampleType, CT3DError> {
+    match name {
+        "uchar" | "unsigned char" | "uint8" | "uint8_t" => Ok(SampleType::U8),
+        "signed char" | "int8" | "int8_t" => Ok(SampleType::I8),
+        "short" | "short int" | "signed short" | "signed short int" | "int16" | "int16_t" => Ok(SampleType::I16),
+        "ushort" | "unsigned short" | "unsigned short int" | "uint16" | "uint16_t" => Ok(SampleType::U16),
+        "int" | "signed int" | "int32" | "int32_t" => Ok(SampleType::I32),
+        "uint" | "unsigned int" | "uint32" | "uint32_t" => Ok(SampleType::U32),
+        "float" => Ok(SampleType::F32),
+        "double" => Ok(SampleType::F64),
+        _ => Err(CT3DError::Unsupported(format!("Unsupported NRRD type {}", name)))
+    }
+}
+
+fn parse_numbers(text: &str) -> Result<Vec<f32>, CT3DError> {
+    text.split(|c: char| c.is_whitespace() || c == ',' || c == '(' || c == ')')
+        .filter(|part| !part.is_empty())
+        .map(|part| part.parse::<f32>().map_err(CT3DError::from))
+        .collect()
+}
+
+/// Spacing from "space directions", the length of each axis vector
+fn spacing_from_directions(text: &str) -> Result<Vec3, CT3DError> {
+    let values = parse_numbers(text)?;
+    if values.len() != 9 {
+        return Err(CT3DError::Format(format!("Expected three space directions, found: {}", text)));
+    }
+    Ok(Vec3::new(
+        Vec3::new(values[0], values[1], values[2]).length(),
+        Vec3::new(values[3], values[4], values[5]).length(),
+        Vec3::new(values[6], values[7], values[8]).length()
+    ))
+}
+
+/// Unit axis directions from "space directions", converted to LPS according to the "space" field
+fn direction_from_directions(text: &str, space: Option<&String>) -> Result<Mat3, CT3DError> {
+    let values = parse_numbers(text)?;
+    if values.len() != 9 {
+        return Err(CT3DError::Format(format!("Expected three space directions, found: {}", text)));
+    }
+    let axes = Mat3::from_cols_slice(&values);
+    Ok(to_lps(space) * Mat3::from_cols(axes.x_axis.normalize_or_zero(), axes.y_axis.normalize_or_zero(), axes.z_axis.normalize_or_zero()))
+}
+
+fn to_lps(space: Option<&String>) -> Mat3 {
+    match space.map(|space| space.as_str()) {
+        Some("right-anterior-superior") | Some("RAS") => Mat3::from_diagonal(Vec3::new(-1.0, -1.0, 1.0)),
+        Some("left-anterior-superior") | Some("LAS") => Mat3::from_diagonal(Vec3::new(1.0, -1.0, 1.0)),
+        _ => Mat3::IDENTITY
+    }
+}
+
+/// Read a 3D NRRD image, attached (.nrrd) or detached (.nhdr), with raw or gzip encoding
+pub fn read_nrrd(path: String) -> Result<Volume, CT3DError> {
+    let mut bytes = Vec::new();
+    File::open(&path)?.read_to_end(&mut bytes)?;
+
+    if !bytes.starts_with(b"NRRD") {
+        return Err(CT3DError::Format(format!("{} is not a NRRD file", path)));
+    }
+
+    // The header ends at the first empty line
+    let mut fields: HashMap<String, String> = HashMap::new();
+    let mut position = 0;
+    let mut first_line = true;
+    loop {
+        let end = match bytes[position..].iter().position(|b| *b == b'\n') {
+            Some(offset) => position + offset,
+            None => bytes.len()
+        };
+        let line = String::from_utf8_lossy(&bytes[position..end]).trim_end_matches('\r').to_owned();
+        position = (end + 1).min(bytes.len());
+        if first_line {
+            first_line = false;
+            continue;
+        }
+        if line.is_empty() {
+            break;
+        }
+        if !line.starts_with('#') && !line.contains(":=") {
+            if let Some((key, value)) = line.split_once(':') {
+                fields.insert(key.trim().to_ascii_lowercase(), value.trim().to_owned());
+            }
+        }
+        if end == bytes.len() {
+            break;
+        }
+    }
+
+    let field = |key: &str| fields.get(key).cloned().ok_or_else(|| CT3DError::Format(format!("NRRD header has no \"{}\" field", key)));
+
+    let dimension = field("dimension")?.parse::<i32>()?;
+    if dimension != 3 {
+        return Err(CT3DError::Unsupported(format!("Expected a 3D NRRD image, found {} dimensions", dimension)));
+    }
+    let sizes = parse_numbers(&field("sizes")?)?;
+    if sizes.len() != 3 {
+        return Err(CT3DError::Format(format!("Expected three sizes, found {}", sizes.len())));
+    }
+    if sizes.iter().any(|size| size.fract() != 0.0) {
+        return Err(CT3DError::Format(format!("Expected whole numbers as sizes, found: {}", field("sizes")?)));
+    }
+    let res = IVec3::new(sizes[0] as i32, sizes[1] as i32, sizes[2] as i32);
+    let count = voxel_count(res)?;
+    let sample_type = sample_type(&field("type")?)?;
+    let big_endian = fields.get("endian").map(|endian| endian == "big").unwrap_or(false);
+
+    let spacing = if let Some(directions) = fields.get("space directions") {
+        spacing_from_directions(directions)?
+    } else if let Some(spacings) = fields.get("spacings") {
+        let values = parse_numbers(spacings)?;
+        Vec3::new(values[0], values[1], values[2])
+    } else {
+        Vec3::new(1.0, 1.0, 1.0)
+    };
+
+    let encoded = match fields.get("data file").or(fields.get("datafile")) {
+        Some(data_file) => {
+            let data_path = Path::new(&path).parent().unwrap_or(Path::new("")).join(data_file);
+            let mut data = Vec::new();
+            File::open(data_path)?.read_to_end(&mut data)?;
+            data
+        },
+        None => bytes[position..].to_vec()
+    };
+
+    let raw = match field("encoding")?.as_str() {
+        "raw" => encoded,
+        "gzip" | "gz" => {
+            let mut decoded = Vec::new();
+            GzDecoder::new(encoded.as_slice()).read_to_end(&mut decoded)?;
+            decoded
+        },
+        encoding => return Err(CT3DError::Unsupported(format!("Unsupported NRRD encoding {}", encoding)))
+    };
+
+    let data = decode_samples(&raw, sample_type, big_endian, count)?;
+
+    let mut volume = Volume::new(Volume::radii_for(res, spacing), res);
+    volume.spacing = spacing;
+    if let Some(directions) = fields.get("space directions") {
+        volume.direction = direction_from_directions(directions, fields.get("space"))?;
+    }
+    if let Some(origin) = fields.get("space origin") {
+        let values = parse_numbers(origin)?;
+        if values.len() == 3 {
+            volume.origin = to_lps(fields.get("space")) * Vec3::new(values[0], values[1], values[2]);
+        }
+    }
+    volume.data = data;
+
+    Ok(volume)
+}
+
+/// Write a gzip encoded 16 bit unsigned NRRD image with attached data, in LPS patient space
+pub fn write_nrrd_u16(path: String, res: IVec3, spacing: Vec3, origin: Vec3, direction: Mat3, data: &[u16]) -> Result<(), CT3DError> {
+    let mut writer = BufWriter::new(File::create(&path)?);
+    write!(writer, "NRRD0004\ntype: uint16\ndimension: 3\nsizes: {} {} {}\nspace: left-posterior-superior\n", res.x, res.y, res.z)?;
+    let axes: Vec<String> = (0..3).map(|axis| {
+        let column = direction.col(axis) * spacing[axis];
+        format!("({},{},{})", column.x, column.y, column.z)
+    }).collect();
+    write!(writer, "space directions: {}\nspace origin: ({},{},{})\n", axes.join(" "), origin.x, origin.y, origin.z)?;
+    write!(writer, "endian: little\nencoding: gzip\n\n")?;
+    let mut encoder = GzEncoder::new(writer, Compression::default());
+    for value in data.iter() {
+        encoder.write_u16::<LittleEndian>(*value)?;
+    }
+    encoder.finish()?.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> String {
+        std::env::temp_dir().join(name).to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn written_images_read_back() {
+        let path = temp_path("ct3d3_nrrd_test.nrrd");
+        let res = IVec3::new(3, 4, 5);
+        let spacing = Vec3::new(0.5, 0.75, 2.0);
+        let origin = Vec3::new(10.0, -20.0, 30.0);
+        let direction = Mat3::from_cols(Vec3::new(0.0, -1.0, 0.0), Vec3::new(0.0, 0.0, -1.0), Vec3::new(1.0, 0.0, 0.0));
+        let data: Vec<u16> = (0..60).map(|i| i * 1000).collect();
+        write_nrrd_u16(path.clone(), res, spacing, origin, direction, &data).unwrap();
+        let volume = read_nrrd(path.clone()).unwrap();
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(volume.res, res);
+        assert_eq!(volume.spacing, spacing);
+        assert!(volume.origin.abs_diff_eq(origin, 1e-5) && volume.direction.abs_diff_eq(direction, 1e-6));
+        assert_eq!(volume.data, data.iter().map(|value| *value as f32).collect::<Vec<f32>>());
+    }
+
+    #[test]
+    fn invalid_sizes_are_rejected() {
+        for sizes in ["3 0 5", "3 -4 5", "3 4.5 5", "65536 65536 65536"] {
+            let path = temp_path("ct3d3_nrrd_invalid_test.nrrd");
+            std::fs::write(&path, format!("NRRD0004\ntype: uint8\ndimension: 3\nsizes: {}\nencoding: raw\n\n", sizes)).unwrap();
+            let result = read_nrrd(path.clone());
+            std::fs::remove_file(path).unwrap();
+            assert!(matches!(result, Err(CT3DError::Format(_))), "sizes {}", sizes);
+        }
+    }
+}
@@ -0,0 +1,73 @@
+use std::io::Cursor;
+
+use byteorder::{ReadBytesExt, LittleEndian, BigEndian, ByteOrder};
+use glam::IVec3;
+
+use crate::types::ct3d_error::CT3DError;
+
+/// Voxel storage types shared by the NIfTI and NRRD readers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SampleType {
+    U8,
+    I8,
+    U16,
+    I16,
+    U32,
+    I32,
+    F32,
+    F64
+}
+
+impl SampleType {
+    pub fn size(&self) -> usize {
+        match self {
+            SampleType::U8 | SampleType::I8 => 1,
+            SampleType::U16 | SampleType::I16 => 2,
+            SampleType::U32 | SampleType::I32 | SampleType::F32 => 4,
+            SampleType::F64 => 8
+        }
+    }
+}
+
+fn decode_with<B: ByteOrder>(bytes: &[u8], sample_type: SampleType, count: usize) -> std::io::Result<Vec<f32>> {
+    let mut cursor = Cursor::new(bytes);
+    let mut values = Vec::with_capacity(count);
+    for _ in 0..count {
+        let value = match sample_type {
+            SampleType::U8 => cursor.read_u8()? as f32,
+            SampleType::I8 => cursor.read_i8()? as f32,
+            SampleType::U16 => cursor.read_u16::<B>()? as f32,
+            SampleType::I16 => cursor.read_i16::<B>()? as f32,
+            SampleType::U32 => cursor.read_u32::<B>()? as f32,
+            SampleType::I32 => cursor.read_i32::<B>()? as f32,
+            SampleType::F32 => cursor.read_f32::<B>()?,
+            SampleType::F64 => cursor.read_f64::<B>()? as f32
+        };
+        values.push(value);
+    }
+    Ok(values)
+}
+
+/// Number of voxels in an image of `res`, rejecting empty or negative sizes and sizes that do not fit in memory
+pub fn voxel_count(res: IVec3) -> Result<usize, CT3DError> {
+    if res.min_element() <= 0 {
+        return Err(CT3DError::Format(format!("Invalid image size {}x{}x{}", res.x, res.y, res.z)));
+    }
+    (res.x as usize).checked_mul(res.y as usize)
+        .and_then(|count| count.checked_mul(res.z as usize))
+        .ok_or_else(|| CT3DError::Format(format!("Image size {}x{}x{} is too large", res.x, res.y, res.z)))
+}
+
+/// Decode `count` samples from the start of `bytes`
+pub fn decode_samples(bytes: &[u8], sample_type: SampleType, big_endian: bool, count: usize) -> Result<Vec<f32>, CT3DError> {
+    let size = count.checked_mul(sample_type.size()).ok_or_else(|| CT3DError::Format(format!("{} voxels is too many to read", count)))?;
+    if bytes.len() < size {
+        return Err(CT3DError::Format(format!("Expected {} bytes of voxel data, found {}", size, bytes.len())));
+    }
+    let values = if big_endian {
+        decode_with::<BigEndian>(bytes, sample_type, count)?
+    } else {
+        decode_with::<LittleEndian>(bytes, sample_type, count)?
+    };
+    Ok(values)
+}
@@ -0,0 +1,282 @@
+use std::collections::VecDeque;
+
+use glam::{Vec3, DVec3, IVec3};
+use serde::Serialize;
+
+use crate::types::volume::Volume;
+use crate::processing::segmentation::{in_bounds, voxel_index, voxel_coord};
+
+/// Which neighbours of a voxel count as touching it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Connectivity {
+    /// 6 neighbours sharing a face
+    Face,
+    /// 18 neighbours sharing a face or an edge
+    Edge,
+    /// 26 neighbours sharing a face, an edge or a corner
+    Vertex
+}
+
+impl Connectivity {
+    pub fn from_count(count: u32) -> Option<Connectivity> {
+        match count {
+            6 => Some(Connectivity::Face),
+            18 => Some(Connectivity::Edge),
+            26 => Some(Connectivity::Vertex),
+            _ => None
+        }
+    }
+
+    pub fn offsets(&self) -> Vec<IVec3> {
+        // Number of non-zero components allowed in an offset
+        let max_axes = match self {
+            Connectivity::Face => 1,
+            Connectivity::Edge => 2,
+            Connectivity::Vertex => 3
+        };
+        let mut offsets = Vec::new();
+        for z in -1..=1 {
+            for y in -1..=1 {
+                for x in -1..=1 {
+                    let axes = (x != 0) as i32 + (y != 0) as i32 + (z != 0) as i32;
+                    if axes > 0 && axes <= max_axes {
+                        offsets.push(IVec3::new(x, y, z));
+                    }
+                }
+            }
+        }
+        offsets
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Component {
+    pub id: u32,
+    pub voxel_count: usize,
+    pub volume_ml: f32,
+    pub centroid: Vec3, // voxel coordinates
+    pub centroid_mm: Vec3,
+    pub bounds_min: IVec3,
+    pub bounds_max: IVec3 // inclusive
+}
+
+/// Connected components of a binary mask. `labels` holds the component id of every voxel, 0 is background.
+/// Components are sorted by size, the largest has id 1.
+pub struct Components {
+    pub res: IVec3,
+    pub labels: Vec<u32>,
+    pub components: Vec<Component>
+}
+
+impl Component {
+    pub fn describe(&self) -> String {
+        format!(
+            "Component {}: {} voxels, {:.2} mL, centroid ({:.1}, {:.1}, {:.1}) mm, voxels ({}, {}, {})..({}, {}, {})",
+            self.id, self.voxel_count, self.volume_ml,
+            self.centroid_mm.x, self.centroid_mm.y, self.centroid_mm.z,
+            self.bounds_min.x, self.bounds_min.y, self.bounds_min.z,
+            self.bounds_max.x, self.bounds_max.y, self.bounds_max.z
+        )
+    }
+}
+
+impl Components {
+    /// Label the connected components of `mask`, a voxel grid of size `res` with `spacing` mm per voxel
+    pub fn label(mask: &[bool], res: IVec3, spacing: Vec3, connectivity: Connectivity) -> Components {
+        let offsets = connectivity.offsets();
+        let voxel_ml = spacing.x * spacing.y * spacing.z / 1000.0;
+
+        let mut labels = vec![0u32; mask.len()];
+        let mut components = Vec::new();
+        let mut queue = VecDeque::new();
+
+        for start in 0..mask.len() {
+            if !mask[start] || labels[start] != 0 {
+                continue;
+            }
+            let id = components.len() as u32 + 1;
+            let start_coord = voxel_coord(res, start);
+            let mut voxel_count = 0usize;
+            let mut sum = DVec3::ZERO;
+            let mut bounds_min = start_coord;
+            let mut bounds_max = start_coord;
+
+            labels[start] = id;
+            queue.push_back(start_coord);
+            while let Some(coord) = queue.pop_front() {
+                voxel_count += 1;
+                sum += coord.as_dvec3();
+                bounds_min = bounds_min.min(coord);
+                bounds_max = bounds_max.max(coord);
+                for offset in offsets.iter() {
+                    let neighbour = coord + *offset;
+                    if !in_bounds(res, neighbour) {
+                        continue;
+                    }
+                    let index = voxel_index(res, neighbour);
+                    if mask[index] && labels[index] == 0 {
+                        labels[index] = id;
+                        queue.push_back(neighbour);
+                    }
+                }
+            }
+
+            let centroid = (sum / voxel_count as f64).as_vec3();
+            components.push(Component {
+                id,
+                voxel_count,
+                volume_ml: voxel_count as f32 * voxel_ml,
+                centroid,
+                // Voxel centres sit half a voxel from the first voxel corner
+                centroid_mm: (centroid + Vec3::splat(0.5)) * spacing,
+                bounds_min,
+                bounds_max
+            });
+        }
+
+        // Renumber so that ids follow size, largest first
+        components.sort_by(|a, b| b.voxel_count.cmp(&a.voxel_count).then(a.id.cmp(&b.id)));
+        let mut renumber = vec![0u32; components.len() + 1];
+        for (index, component) in components.iter_mut().enumerate() {
+            renumber[component.id as usize] = index as u32 + 1;
+            component.id = index as u32 + 1;
+        }
+        for label in labels.iter_mut() {
+            *label = renumber[*label as usize];
+        }
+
+        Components { res, labels, components }
+    }
+
+    /// Components of a binary volume, every non-zero voxel is foreground
+    pub fn of_volume(volume: &Volume, connectivity: Connectivity) -> Components {
+        let mask: Vec<bool> = volume.data.iter().map(|value| *value != 0.0).collect();
+        Components::label(&mask, volume.res, volume.spacing, connectivity)
+    }
+
+    pub fn get(&self, id: u32) -> Option<&Component> {
+        self.components.get((id as usize).wrapping_sub(1))
+    }
+
+    pub fn largest(&self) -> Option<&Component> {
+        self.components.first()
+    }
+
+    /// Id of the component containing `coord`, if any
+    pub fn component_at(&self, coord: IVec3) -> Option<u32> {
+        if !in_bounds(self.res, coord) {
+            return None;
+        }
+        match self.labels[voxel_index(self.res, coord)] {
+            0 => None,
+            id => Some(id)
+        }
+    }
+
+    /// Mask of the components accepted by `keep`
+    pub fn mask_where(&self, keep: impl Fn(&Component) -> bool) -> Vec<bool> {
+        let mut kept = vec![false; self.components.len() + 1];
+        for component in self.components.iter() {
+            kept[component.id as usize] = keep(component);
+        }
+        self.labels.iter().map(|label| kept[*label as usize]).collect()
+    }
+
+    /// Mask of the `count` largest components
+    pub fn keep_largest(&self, count: usize) -> Vec<bool> {
+        self.mask_where(|component| (component.id as usize) <= count)
+    }
+
+    /// Mask without the components smaller than `min_ml` millilitres
+    pub fn remove_smaller_than(&self, min_ml: f32) -> Vec<bool> {
+        self.mask_where(|component| component.volume_ml >= min_ml)
+    }
+
+    /// Mask of the single component containing `coord`, empty if `coord` is background
+    pub fn pick(&self, coord: IVec3) -> Vec<bool> {
+        match self.component_at(coord) {
+            Some(id) => self.mask_where(|component| component.id == id),
+            None => vec![false; self.labels.len()]
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn count(mask: &[bool]) -> usize {
+        mask.iter().filter(|value| **value).count()
+    }
+
+    fn mask_of(res: IVec3, voxels: &[IVec3]) -> Vec<bool> {
+        let mut mask = vec![false; (res.x * res.y * res.z) as usize];
+        for voxel in voxels {
+            mask[voxel_index(res, *voxel)] = true;
+        }
+        mask
+    }
+
+    #[test]
+    fn connectivity_decides_which_voxels_touch() {
+        // A chain whose links share a face, then an edge, then a corner twice
+        let res = IVec3::new(7, 6, 5);
+        let chain = [IVec3::new(1, 1, 1), IVec3::new(2, 1, 1), IVec3::new(3, 2, 1), IVec3::new(4, 3, 2), IVec3::new(5, 4, 3)];
+        let mask = mask_of(res, &chain);
+        for (neighbours, expected) in [(6, 4), (18, 3), (26, 1)] {
+            let components = Components::label(&mask, res, Vec3::ONE, Connectivity::from_count(neighbours).unwrap());
+            assert_eq!(components.components.len(), expected, "{}-connectivity", neighbours);
+            assert_eq!(components.components.iter().map(|component| component.voxel_count).sum::<usize>(), chain.len());
+        }
+        assert!(Connectivity::from_count(8).is_none());
+    }
+
+    /// A 2x2x2 cube, a line of three and a single voxel, with 2 x 5 x 10 mm (0.1 mL) voxels
+    fn three_blobs() -> Components {
+        let res = IVec3::new(8, 8, 8);
+        let mut voxels = Vec::new();
+        for z in 0..2 {
+            for y in 0..2 {
+                for x in 0..2 {
+                    voxels.push(IVec3::new(x, y, z));
+                }
+            }
+        }
+        voxels.extend([IVec3::new(5, 5, 5), IVec3::new(6, 5, 5), IVec3::new(7, 5, 5), IVec3::new(0, 7, 7)]);
+        Components::label(&mask_of(res, &voxels), res, Vec3::new(2.0, 5.0, 10.0), Connectivity::Vertex)
+    }
+
+    #[test]
+    fn components_are_numbered_by_size() {
+        let components = three_blobs();
+        let sizes: Vec<usize> = components.components.iter().map(|component| component.voxel_count).collect();
+        assert_eq!(sizes, vec![8, 3, 1]);
+        let cube = components.largest().unwrap();
+        assert_eq!(cube.id, 1);
+        assert!((cube.volume_ml - 0.8).abs() < 1e-6);
+        assert_eq!(cube.centroid, Vec3::splat(0.5));
+        assert_eq!(cube.centroid_mm, Vec3::new(2.0, 5.0, 10.0));
+        assert_eq!((cube.bounds_min, cube.bounds_max), (IVec3::ZERO, IVec3::ONE));
+        assert_eq!(components.get(2).unwrap().bounds_max, IVec3::new(7, 5, 5));
+        assert!(components.get(0).is_none() && components.get(4).is_none());
+    }
+
+    #[test]
+    fn masks_keep_the_chosen_components() {
+        let components = three_blobs();
+        assert_eq!(count(&components.keep_largest(1)), 8);
+        assert_eq!(count(&components.keep_largest(2)), 11);
+        assert_eq!(count(&components.keep_largest(5)), 12);
+
+        // Volumes follow the spacing: 0.8, 0.3 and 0.1 mL
+        assert_eq!(count(&components.remove_smaller_than(0.25)), 11);
+        assert_eq!(count(&components.remove_smaller_than(0.35)), 8);
+        assert_eq!(count(&components.remove_smaller_than(1.0)), 0);
+
+        let line = components.pick(IVec3::new(6, 5, 5));
+        assert_eq!(count(&line), 3);
+        assert!(line[voxel_index(components.res, IVec3::new(5, 5, 5))]);
+        assert_eq!(count(&components.pick(IVec3::new(4, 4, 4))), 0);
+        assert_eq!(count(&components.pick(IVec3::new(8, 0, 0))), 0);
+    }
+}
@@ -0,0 +1,444 @@
+use glam::{Vec3, IVec3};
+use ocl::{Buffer, Kernel};
+
+use crate::types::ct3d_error::CT3DError;
+use crate::types::volume::Volume;
+use crate::types::application_state::OpenCLState;
+use crate::processing::parallel::parallel_fill;
+use crate::processing::segmentation::{voxel_index, voxel_coord};
+
+/// Intensity parameters (`sigma_range`, `kappa`) are in normalized density, like `Volume::data`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Filter {
+    Gaussian { sigma_mm: f32 },
+    Median { radius: i32 },
+    Bilateral { sigma_mm: f32, sigma_range: f32 },
+    PeronaMalik { iterations: usize, kappa: f32 },
+    Curvature { iterations: usize }
+}
+
+/// Filters cycled through by the viewer's preview
+pub const PREVIEW_FILTERS: [Filter; 5] = [
+    Filter::Gaussian { sigma_mm: 1.0 },
+    Filter::Median { radius: 1 },
+    Filter::Bilateral { sigma_mm: 1.5, sigma_range: 0.05 },
+    Filter::PeronaMalik { iterations: 10, kappa: 0.05 },
+    Filter::Curvature { iterations: 5 }
+];
+
+impl Filter {
+    pub fn describe(&self) -> String {
+        match self {
+            Filter::Gaussian { sigma_mm } => format!("Gaussian, sigma {} mm", sigma_mm),
+            Filter::Median { radius } => format!("Median, radius {} voxels", radius),
+            Filter::Bilateral { sigma_mm, sigma_range } => format!("Bilateral, sigma {} mm, range {}", sigma_mm, sigma_range),
+            Filter::PeronaMalik { iterations, kappa } => format!("Perona-Malik diffusion, {} iterations, kappa {}", iterations, kappa),
+            Filter::Curvature { iterations } => format!("Curvature flow, {} iterations", iterations)
+        }
+    }
+
+    pub fn apply(&self, volume: &Volume) -> Result<Volume, CT3DError> {
+        match *self {
+            Filter::Gaussian { sigma_mm } => gaussian(volume, sigma_mm),
+            Filter::Median { radius } => median(volume, radius),
+            Filter::Bilateral { sigma_mm, sigma_range } => bilateral(volume, sigma_mm, sigma_range),
+            Filter::PeronaMalik { iterations, kappa } => perona_malik(volume, iterations, kappa),
+            Filter::Curvature { iterations } => Ok(curvature_flow(volume, iterations))
+        }
+    }
+
+    /// Run on the device where there is a kernel for the filter, on the CPU otherwise
+    pub fn apply_with_opencl(&self, opencl_state: &OpenCLState, volume: &Volume) -> Result<Volume, CT3DError> {
+        match *self {
+            Filter::Gaussian { sigma_mm } => gaussian_opencl(opencl_state, volume, sigma_mm),
+            Filter::Bilateral { sigma_mm, sigma_range } => bilateral_opencl(opencl_state, volume, sigma_mm, sigma_range),
+            _ => self.apply(volume)
+        }
+    }
+}
+
+/// Sigmas and kappa divide the weights, zero would make every voxel NaN
+fn check_positive(name: &str, value: f32) -> Result<(), CT3DError> {
+    if !value.is_finite() || value <= 0.0 {
+        return Err(CT3DError::Usage(format!("{} must be positive, found {}", name, value)));
+    }
+    Ok(())
+}
+
+fn clamped_index(res: IVec3, coord: IVec3) -> usize {
+    voxel_index(res, coord.clamp(IVec3::ZERO, res - IVec3::ONE))
+}
+
+/// Normalized Gaussian weights out to three sigma
+pub fn gaussian_weights(sigma_voxels: f32) -> Vec<f32> {
+    let radius = (3.0 * sigma_voxels).ceil().max(1.0) as i32;
+    let weights: Vec<f32> = (-radius..=radius).map(|k| (-(k * k) as f32 / (2.0 * sigma_voxels * sigma_voxels)).exp()).collect();
+    let sum: f32 = weights.iter().sum();
+    weights.iter().map(|w| w / sum).collect()
+}
+
+fn convolve_axis(data: &[f32], res: IVec3, axis: usize, weights: &[f32]) -> Vec<f32> {
+    let radius = (weights.len() / 2) as i32;
+    parallel_fill(data.len(), 1, |start, part| {
+        for (i, value) in part.iter_mut().enumerate() {
+            let coord = voxel_coord(res, start + i);
+            let mut sum = 0.0;
+            for (k, weight) in weights.iter().enumerate() {
+                let mut neighbour = coord;
+                neighbour[axis] += k as i32 - radius;
+                sum += weight * data[clamped_index(res, neighbour)];
+            }
+            *value = sum;
+        }
+    })
+}
+
+/// Separable Gaussian blur. `sigma_mm` is converted to voxels per axis, so anisotropic volumes blur evenly.
+pub fn gaussian(volume: &Volume, sigma_mm: f32) -> Result<Volume, CT3DError> {
+    check_positive("Sigma", sigma_mm)?;
+    let mut data = volume.data.clone();
+    for axis in 0..3 {
+        let sigma_voxels = sigma_mm / volume.spacing[axis];
+        if sigma_voxels > 0.1 {
+            data = convolve_axis(&data, volume.res, axis, &gaussian_weights(sigma_voxels));
+        }
+    }
+    Ok(volume.with_data(data))
+}
+
+/// Median over a cube of (2 * radius + 1)³ voxels
+pub fn median(volume: &Volume, radius: i32) -> Result<Volume, CT3DError> {
+    if radius < 1 {
+        return Err(CT3DError::Usage(format!("Radius must be at least 1, found {}", radius)));
+    }
+    let res = volume.res;
+    // A cube wider than the volume takes in the same voxels
+    let radius = radius.min(res.max_element());
+    let data = parallel_fill(volume.data.len(), 1, |start, part| {
+        let mut window = Vec::with_capacity(((2 * radius + 1) as usize).pow(3));
+        for (i, value) in part.iter_mut().enumerate() {
+            let coord = voxel_coord(res, start + i);
+            let lower = (coord - IVec3::splat(radius)).max(IVec3::ZERO);
+            let upper = (coord + IVec3::splat(radius)).min(res - IVec3::ONE);
+            window.clear();
+            for z in lower.z..=upper.z {
+                for y in lower.y..=upper.y {
+                    for x in lower.x..=upper.x {
+                        window.push(volume.data[voxel_index(res, IVec3::new(x, y, z))]);
+                    }
+                }
+            }
+            let middle = window.len() / 2;
+            *value = *window.select_nth_unstable_by(middle, |a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal)).1;
+        }
+    });
+    Ok(volume.with_data(data))
+}
+
+/// Neighbourhood offsets within two spatial sigmas, with their spatial weights
+fn bilateral_offsets(spacing: Vec3, sigma_mm: f32) -> Vec<(IVec3, f32)> {
+    let extent = (Vec3::splat(2.0 * sigma_mm) / spacing).ceil().as_ivec3();
+    let mut offsets = Vec::new();
+    for z in -extent.z..=extent.z {
+        for y in -extent.y..=extent.y {
+            for x in -extent.x..=extent.x {
+                let offset = IVec3::new(x, y, z);
+                let distance_squared = (offset.as_vec3() * spacing).length_squared();
+                if distance_squared <= 4.0 * sigma_mm * sigma_mm {
+                    offsets.push((offset, (-distance_squared / (2.0 * sigma_mm * sigma_mm)).exp()));
+                }
+            }
+        }
+    }
+    offsets
+}
+
+/// Edge preserving blur, neighbours are weighted by distance and by similarity in value
+pub fn bilateral(volume: &Volume, sigma_mm: f32, sigma_range: f32) -> Result<Volume, CT3DError> {
+    check_positive("Sigma", sigma_mm)?;
+    check_positive("Range sigma", sigma_range)?;
+    let res = volume.res;
+    let offsets = bilateral_offsets(volume.spacing, sigma_mm);
+    let data = parallel_fill(volume.data.len(), 1, |start, part| {
+        for (i, value) in part.iter_mut().enumerate() {
+            let index = start + i;
+            let coord = voxel_coord(res, index);
+            let center = volume.data[index];
+            let (mut sum, mut total_weight) = (0.0, 0.0);
+            for (offset, spatial_weight) in offsets.iter() {
+                let neighbour = volume.data[clamped_index(res, coord + *offset)];
+                let difference = neighbour - center;
+                let weight = spatial_weight * (-difference * difference / (2.0 * sigma_range * sigma_range)).exp();
+                sum += weight * neighbour;
+                total_weight += weight;
+            }
+            *value = sum / total_weight;
+        }
+    });
+    Ok(volume.with_data(data))
+}
+
+/// Explicit time step at half the stability limit of the 3D heat equation on this grid
+fn diffusion_time_step(spacing: Vec3) -> f32 {
+    let inverse_squares = (Vec3::ONE / (spacing * spacing)).dot(Vec3::ONE);
+    0.5 / (2.0 * inverse_squares)
+}
+
+/// Perona-Malik anisotropic diffusion: smooths where gradients are small compared to `kappa`, keeps edges
+pub fn perona_malik(volume: &Volume, iterations: usize, kappa: f32) -> Result<Volume, CT3DError> {
+    check_positive("Kappa", kappa)?;
+    let res = volume.res;
+    let spacing = volume.spacing;
+    let time_step = diffusion_time_step(spacing);
+    let mut data = volume.data.clone();
+    for _ in 0..iterations {
+        let current = &data;
+        data = parallel_fill(current.len(), 1, |start, part| {
+            for (i, value) in part.iter_mut().enumerate() {
+                let index = start + i;
+                let coord = voxel_coord(res, index);
+                let center = current[index];
+                let mut flux = 0.0;
+                for axis in 0..3 {
+                    for direction in [-1, 1] {
+                        let mut neighbour = coord;
+                        neighbour[axis] += direction;
+                        let gradient = (current[clamped_index(res, neighbour)] - center) / spacing[axis];
+                        let conductance = (-(gradient / kappa) * (gradient / kappa)).exp();
+                        flux += conductance * gradient / spacing[axis];
+                    }
+                }
+                *value = center + time_step * flux;
+            }
+        });
+    }
+    Ok(volume.with_data(data))
+}
+
+/// Mean curvature flow: iso-surfaces move by their curvature, which smooths noise inside regions
+/// while keeping the position of strong edges
+pub fn curvature_flow(volume: &Volume, iterations: usize) -> Volume {
+    let res = volume.res;
+    let spacing = volume.spacing;
+    let time_step = diffusion_time_step(spacing);
+    let mut data = volume.data.clone();
+    for _ in 0..iterations {
+        let current = &data;
+        data = parallel_fill(current.len(), 1, |start, part| {
+            for (i, value) in part.iter_mut().enumerate() {
+                let index = start + i;
+                let coord = voxel_coord(res, index);
+                let at = |offset: IVec3| current[clamped_index(res, coord + offset)];
+                let unit = |axis: usize| {
+                    let mut offset = IVec3::ZERO;
+                    offset[axis] = 1;
+                    offset
+                };
+                let center = current[index];
+
+                let mut first = [0.0f32; 3];
+                let mut second = [[0.0f32; 3]; 3];
+                for a in 0..3 {
+                    first[a] = (at(unit(a)) - at(-unit(a))) / (2.0 * spacing[a]);
+                    second[a][a] = (at(unit(a)) - 2.0 * center + at(-unit(a))) / (spacing[a] * spacing[a]);
+                    for b in (a + 1)..3 {
+                        let mixed = (at(unit(a) + unit(b)) - at(unit(a) - unit(b)) - at(unit(b) - unit(a)) + at(-unit(a) - unit(b)))
+                            / (4.0 * spacing[a] * spacing[b]);
+                        second[a][b] = mixed;
+                        second[b][a] = mixed;
+                    }
+                }
+
+                let gradient_squared = first.iter().map(|g| g * g).sum::<f32>();
+                if gradient_squared < 1e-12 {
+                    // No level set through this voxel (a flat region or an isolated spike), diffuse instead
+                    *value = center + time_step * (second[0][0] + second[1][1] + second[2][2]);
+                    continue;
+                }
+                let mut numerator = 0.0;
+                for a in 0..3 {
+                    for b in 0..3 {
+                        let identity = if a == b { gradient_squared } else { 0.0 };
+                        numerator += (identity - first[a] * first[b]) * second[a][b];
+                    }
+                }
+                *value = center + time_step * numerator / gradient_squared;
+            }
+        });
+    }
+    volume.with_data(data)
+}
+
+fn opencl_buffers(opencl_state: &OpenCLState, volume: &Volume) -> Result<(Buffer<f32>, Buffer<f32>), CT3DError> {
+    let queue = opencl_state.queue.as_ref().unwrap().clone();
+    let input = Buffer::builder().queue(queue.clone()).flags(ocl::core::MEM_READ_WRITE).len(volume.data.len()).copy_host_slice(&volume.data).build()?;
+    let output = Buffer::builder().queue(queue).flags(ocl::core::MEM_READ_WRITE).len(volume.data.len()).build()?;
+    Ok((input, output))
+}
+
+fn opencl_parameters<T: ocl::OclPrm>(opencl_state: &OpenCLState, values: &[T]) -> Result<Buffer<T>, CT3DError> {
+    Ok(Buffer::builder()
+        .queue(opencl_state.queue.as_ref().unwrap().clone())
+        .flags(ocl::core::MEM_READ_ONLY)
+        .len(values.len())
+        .copy_host_slice(values)
+        .build()?)
+}
+
+/// `gaussian` on the device, one kernel launch per axis
+pub fn gaussian_opencl(opencl_state: &OpenCLState, volume: &Volume, sigma_mm: f32) -> Result<Volume, CT3DError> {
+    check_positive("Sigma", sigma_mm)?;
+    let (mut input, mut output) = opencl_buffers(opencl_state, volume)?;
+    for axis in 0..3 {
+        let sigma_voxels = sigma_mm / volume.spacing[axis];
+        if sigma_voxels <= 0.1 {
+            continue;
+        }
+        let weights = gaussian_weights(sigma_voxels);
+        // Must match the layout read by gaussian_pass in kernels/filters.cl
+        let parameters = opencl_parameters(opencl_state, &[volume.res.x, volume.res.y, volume.res.z, axis as i32, (weights.len() / 2) as i32])?;
+        let weights = opencl_parameters(opencl_state, &weights)?;
+        let kernel = Kernel::builder()
+            .program(opencl_state.filters_program.as_ref().unwrap())
+            .queue(opencl_state.queue.as_ref().unwrap().clone())
+            .name("gaussian_pass")
+            .arg(&input)
+            .arg(&output)
+            .arg(&parameters)
+            .arg(&weights)
+            .build()?;
+        unsafe {
+            kernel.cmd().global_work_size(volume.data.len()).enq()?;
+        }
+        std::mem::swap(&mut input, &mut output);
+    }
+    let mut data = vec![0.0f32; volume.data.len()];
+    input.read(&mut data).enq()?;
+    Ok(volume.with_data(data))
+}
+
+/// `bilateral` on the device
+pub fn bilateral_opencl(opencl_state: &OpenCLState, volume: &Volume, sigma_mm: f32, sigma_range: f32) -> Result<Volume, CT3DError> {
+    check_positive("Sigma", sigma_mm)?;
+    check_positive("Range sigma", sigma_range)?;
+    let (input, output) = opencl_buffers(opencl_state, volume)?;
+    let extent = (Vec3::splat(2.0 * sigma_mm) / volume.spacing).ceil().as_ivec3();
+    // Must match the layout read by bilateral in kernels/filters.cl
+    let parameters = opencl_parameters(opencl_state, &[volume.res.x, volume.res.y, volume.res.z, extent.x, extent.y, extent.z])?;
+    let float_parameters = opencl_parameters(opencl_state, &[volume.spacing.x, volume.spacing.y, volume.spacing.z, sigma_mm, sigma_range])?;
+    let kernel = Kernel::builder()
+        .program(opencl_state.filters_program.as_ref().unwrap())
+        .queue(opencl_state.queue.as_ref().unwrap().clone())
+        .name("bilateral")
+        .arg(&input)
+        .arg(&output)
+        .arg(&parameters)
+        .arg(&float_parameters)
+        .build()?;
+    unsafe {
+        kernel.cmd().global_work_size(volume.data.len()).enq()?;
+    }
+    let mut data = vec![0.0f32; volume.data.len()];
+    output.read(&mut data).enq()?;
+    Ok(volume.with_data(data))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::application_state::ApplicationState;
+
+    /// 16 x 16 x 12 voxels of 0.8 x 0.8 x 1.5 mm holding `value(coord)`
+    fn volume_from(value: impl Fn(IVec3) -> f32) -> Volume {
+        let res = IVec3::new(16, 16, 12);
+        let spacing = Vec3::new(0.8, 0.8, 1.5);
+        let mut volume = Volume::new(Volume::radii_for(res, spacing), res);
+        volume.spacing = spacing;
+        volume.data = (0..volume.data.len()).map(|index| value(voxel_coord(res, index))).collect();
+        volume
+    }
+
+    fn noise(coord: IVec3) -> f32 {
+        let hash = (coord.x as u32).wrapping_mul(73856093) ^ (coord.y as u32).wrapping_mul(19349663) ^ (coord.z as u32).wrapping_mul(83492791);
+        (hash % 1000) as f32 / 1000.0
+    }
+
+    fn mean(volume: &Volume) -> f32 {
+        volume.data.iter().sum::<f32>() / volume.data.len() as f32
+    }
+
+    fn max_difference(a: &Volume, b: &Volume) -> f32 {
+        a.data.iter().zip(b.data.iter()).map(|(a, b)| (a - b).abs()).fold(0.0, f32::max)
+    }
+
+    #[test]
+    fn gaussian_preserves_the_mean() {
+        // Away from the border, where clamping would count the edge voxels more than once
+        let volume = volume_from(|coord| if coord.cmpge(IVec3::new(6, 6, 4)).all() && coord.cmplt(IVec3::new(10, 10, 8)).all() { noise(coord) } else { 0.0 });
+        let blurred = gaussian(&volume, 1.0).unwrap();
+        assert!((mean(&blurred) / mean(&volume) - 1.0).abs() < 1e-4);
+        assert!(blurred.data.iter().fold(0.0, |a: f32, b| a.max(*b)) < volume.data.iter().fold(0.0, |a: f32, b| a.max(*b)));
+        assert_eq!(gaussian(&volume, 0.01).unwrap().data, volume.data);
+    }
+
+    #[test]
+    fn median_removes_a_spike() {
+        let spike = IVec3::new(7, 8, 5);
+        let volume = volume_from(|coord| if coord == spike { 1.0 } else { 0.2 });
+        assert!(median(&volume, 1).unwrap().data.iter().all(|value| *value == 0.2));
+
+        // A step survives unchanged
+        let step = volume_from(|coord| if coord.x < 8 { 0.2 } else { 0.8 });
+        assert_eq!(median(&step, 1).unwrap().data, step.data);
+    }
+
+    #[test]
+    fn bilateral_keeps_a_step_edge() {
+        let step = volume_from(|coord| if coord.x < 8 { 0.2 } else { 0.8 });
+        assert!(max_difference(&bilateral(&step, 1.5, 0.05).unwrap(), &step) < 1e-3);
+        assert!(max_difference(&gaussian(&step, 1.5).unwrap(), &step) > 0.1);
+
+        // Small differences are still smoothed
+        let noisy = volume_from(|coord| 0.5 + 0.02 * noise(coord));
+        let smoothed = bilateral(&noisy, 1.5, 0.05).unwrap();
+        let spread = |volume: &Volume| volume.data.iter().fold(0.0, |a: f32, b| a.max(*b)) - volume.data.iter().fold(1.0, |a: f32, b| a.min(*b));
+        assert!(spread(&smoothed) < spread(&noisy) * 0.5);
+    }
+
+    #[test]
+    fn invalid_parameters_are_rejected() {
+        let volume = volume_from(noise);
+        for filter in [
+            Filter::Gaussian { sigma_mm: f32::INFINITY },
+            Filter::Median { radius: 0 },
+            Filter::Median { radius: -1 },
+            Filter::Bilateral { sigma_mm: 0.0, sigma_range: 0.05 },
+            Filter::Bilateral { sigma_mm: 1.5, sigma_range: 0.0 },
+            Filter::Bilateral { sigma_mm: 1.5, sigma_range: f32::NAN },
+            Filter::PeronaMalik { iterations: 10, kappa: 0.0 },
+            Filter::PeronaMalik { iterations: 10, kappa: -0.05 }
+        ] {
+            assert!(matches!(filter.apply(&volume), Err(CT3DError::Usage(_))), "{}", filter.describe());
+        }
+
+        // A radius beyond the volume is the whole volume
+        let step = volume_from(|coord| if coord.x < 4 { 0.2 } else { 0.8 });
+        assert!(median(&step, i32::MAX).unwrap().data.iter().all(|value| *value == 0.8));
+    }
+
+    /// Needs an OpenCL device: `cargo test opencl_filters -- --ignored`
+    #[test]
+    #[ignore]
+    fn opencl_filters_match_the_cpu() {
+        let mut application_state = ApplicationState::new(16, 16);
+        if let Err(e) = crate::application::init(&mut application_state) {
+            println!("Skipped, no OpenCL device: {}", e);
+            return;
+        }
+        let volume = volume_from(|coord| noise(coord) * if coord.x < 8 { 0.3 } else { 1.0 });
+        for filter in [Filter::Gaussian { sigma_mm: 1.0 }, Filter::Bilateral { sigma_mm: 1.5, sigma_range: 0.05 }] {
+            let device = filter.apply_with_opencl(&application_state.opencl_state, &volume).unwrap();
+            assert!(max_difference(&device, &filter.apply(&volume).unwrap()) < 1e-4, "{}", filter.describe());
+        }
+    }
+}
@@ -0,0 +1,302 @@
+use std::collections::HashMap;
+
+use glam::{Vec3, IVec3, Mat3};
+
+use crate::types::mesh::Mesh;
+use crate::types::volume::Volume;
+use crate::types::labelmap::LabelMap;
+
+/// Triangles for every one of the 256 corner configurations, as triples of cube edge indices
+pub struct CaseTable {
+    pub cases: Vec<Vec<[u8; 3]>>
+}
+
+/// Cube corner `i` sits at (bit 0, bit 1, bit 2) of `i`
+fn corner_offset(corner: usize) -> IVec3 {
+    IVec3::new((corner & 1) as i32, ((corner >> 1) & 1) as i32, ((corner >> 2) & 1) as i32)
+}
+
+/// The 12 cube edges as (lower corner, upper corner, axis)
+fn cube_edges() -> Vec<(usize, usize, usize)> {
+    let mut edges = Vec::new();
+    for axis in 0..3 {
+        for corner in 0..8 {
+            if corner & (1 << axis) == 0 {
+                edges.push((corner, corner | (1 << axis), axis));
+            }
+        }
+    }
+    edges
+}
+
+/// Whether two cube edges lie in a common face
+fn share_face(edges: &[(usize, usize, usize)], a: usize, b: usize) -> bool {
+    let corners = |edge: usize| [edges[edge].0, edges[edge].1];
+    (0..3).any(|axis| (0..2).any(|side| {
+        let on_face = |corner: &usize| (corner >> axis) & 1 == side;
+        corners(a).iter().all(on_face) && corners(b).iter().all(on_face)
+    }))
+}
+
+impl Default for CaseTable {
+    fn default() -> Self {
+        CaseTable::new()
+    }
+}
+
+impl CaseTable {
+    /// Build the table from the face configurations rather than hard-coding it. On faces with two
+    /// diagonally opposite inside corners the inside corners are kept apart. That decision only depends
+    /// on the face, so neighbouring cubes always agree and the surface has no cracks.
+    pub fn new() -> CaseTable {
+        let edges = cube_edges();
+        let edge_between = |a: usize, b: usize| edges.iter().position(|(lower, upper, _)| (*lower == a.min(b)) && (*upper == a.max(b))).unwrap();
+        let edge_midpoint = |edge: usize| (corner_offset(edges[edge].0).as_vec3() + corner_offset(edges[edge].1).as_vec3()) * 0.5;
+
+        let mut cases = Vec::with_capacity(256);
+        for case in 0..256usize {
+            let inside = |corner: usize| case & (1 << corner) != 0;
+
+            // Directed segments on the cube faces, running around the inside region
+            let mut next_edge: [Option<usize>; 12] = [None; 12];
+            for axis in 0..3 {
+                let (u, v) = ((axis + 1) % 3, (axis + 2) % 3);
+                for side in 0..2 {
+                    let face_corners: Vec<usize> = [(0, 0), (1, 0), (1, 1), (0, 1)].iter()
+                        .map(|(a, b)| (side << axis) | (a << u) | (b << v))
+                        .collect();
+                    let mut normal = Vec3::ZERO;
+                    normal[axis] = if side == 0 { -1.0 } else { 1.0 };
+
+                    let crossed: Vec<usize> = (0..4).filter(|k| inside(face_corners[*k]) != inside(face_corners[(k + 1) % 4])).collect();
+                    let mut segments = Vec::new();
+                    if crossed.len() == 2 {
+                        let corner = *face_corners.iter().find(|corner| inside(**corner)).unwrap();
+                        let a = edge_between(face_corners[crossed[0]], face_corners[(crossed[0] + 1) % 4]);
+                        let b = edge_between(face_corners[crossed[1]], face_corners[(crossed[1] + 1) % 4]);
+                        segments.push((a, b, corner));
+                    } else if crossed.len() == 4 {
+                        // Cut off each inside corner on its own
+                        for k in 0..4 {
+                            if inside(face_corners[k]) {
+                                let a = edge_between(face_corners[(k + 3) % 4], face_corners[k]);
+                                let b = edge_between(face_corners[k], face_corners[(k + 1) % 4]);
+                                segments.push((a, b, face_corners[k]));
+                            }
+                        }
+                    }
+
+                    for (a, b, corner) in segments {
+                        // Orient so the inside corner is on the left, seen from outside the cube
+                        let (pa, pb) = (edge_midpoint(a), edge_midpoint(b));
+                        let left = (pb - pa).cross(corner_offset(corner).as_vec3() - pa).dot(normal) > 0.0;
+                        let (from, to) = if left { (a, b) } else { (b, a) };
+                        next_edge[from] = Some(to);
+                    }
+                }
+            }
+
+            // Chain the segments into loops and fan triangulate them. The loops run counter-clockwise
+            // around the inside, so the fans are reversed to face outwards.
+            let mut triangles = Vec::new();
+            let mut visited = [false; 12];
+            for start in 0..12 {
+                if visited[start] || next_edge[start].is_none() {
+                    continue;
+                }
+                let mut polygon = Vec::new();
+                let mut edge = start;
+                while !visited[edge] {
+                    visited[edge] = true;
+                    polygon.push(edge as u8);
+                    edge = next_edge[edge].unwrap();
+                }
+                // Start the fan where none of its diagonals lies in a cube face, where it could
+                // coincide with an edge of the neighbouring cube's triangles
+                let count = polygon.len();
+                let first = (0..count).find(|first| {
+                    (2..count - 1).all(|i| !share_face(&edges, polygon[*first] as usize, polygon[(first + i) % count] as usize))
+                }).unwrap_or(0);
+                for i in 1..count - 1 {
+                    triangles.push([polygon[first], polygon[(first + i + 1) % count], polygon[(first + i) % count]]);
+                }
+            }
+            cases.push(triangles);
+        }
+        CaseTable { cases }
+    }
+}
+
+/// Isosurface of `values` (a `res` grid with `spacing` mm per voxel) at `iso`. Values at or above `iso`
+/// are inside. Samples outside the grid count as outside so the surface is always closed.
+/// Vertices are in millimetres from the first voxel corner, with samples at the voxel centres.
+pub fn marching_cubes(values: &[f32], res: IVec3, spacing: Vec3, iso: f32) -> Mesh {
+    let table = CaseTable::new();
+    let edges = cube_edges();
+    let outside_value = iso - 1.0;
+
+    let sample = |coord: IVec3| -> f32 {
+        if coord.cmpge(IVec3::ZERO).all() && coord.cmplt(res).all() {
+            values[(coord.z*res.x*res.y + coord.y*res.x + coord.x) as usize]
+        } else {
+            outside_value
+        }
+    };
+
+    // Vertices are shared between cubes through the grid edge they lie on
+    let padded = res + IVec3::splat(2);
+    let edge_key = |lower: IVec3, axis: usize| -> u64 {
+        let p = lower + IVec3::ONE;
+        ((p.z as u64 * padded.y as u64 + p.y as u64) * padded.x as u64 + p.x as u64) * 3 + axis as u64
+    };
+
+    let mut mesh = Mesh::new();
+    let mut vertex_indices: HashMap<u64, u32> = HashMap::new();
+    let mut corner_values = [0.0f32; 8];
+
+    for z in -1..res.z {
+        for y in -1..res.y {
+            for x in -1..res.x {
+                let cell = IVec3::new(x, y, z);
+                let mut case = 0;
+                for corner in 0..8 {
+                    corner_values[corner] = sample(cell + corner_offset(corner));
+                    if corner_values[corner] >= iso {
+                        case |= 1 << corner;
+                    }
+                }
+                if case == 0 || case == 255 {
+                    continue;
+                }
+
+                for triangle in table.cases[case].iter() {
+                    let mut indices = [0u32; 3];
+                    for (slot, edge) in triangle.iter().enumerate() {
+                        let (lower, upper, axis) = edges[*edge as usize];
+                        let key = edge_key(cell + corner_offset(lower), axis);
+                        indices[slot] = *vertex_indices.entry(key).or_insert_with(|| {
+                            let (a, b) = (corner_values[lower], corner_values[upper]);
+                            let t = if a == b { 0.5 } else { ((iso - a) / (b - a)).max(0.0).min(1.0) };
+                            let voxel = (cell + corner_offset(lower)).as_vec3() + (corner_offset(upper) - corner_offset(lower)).as_vec3() * t;
+                            mesh.vertices.push((voxel + Vec3::splat(0.5)) * spacing);
+                            mesh.vertices.len() as u32 - 1
+                        });
+                    }
+                    mesh.triangles.push(indices);
+                }
+            }
+        }
+    }
+
+    mesh
+}
+
+/// Move a mesh from `marching_cubes` to patient coordinates, as `Volume::index_to_patient` places voxels
+fn to_patient(mut mesh: Mesh, spacing: Vec3, origin: Vec3, direction: Mat3) -> Mesh {
+    for vertex in mesh.vertices.iter_mut() {
+        *vertex = origin + direction * (*vertex - 0.5 * spacing);
+    }
+    // A direction that mirrors would turn the surface inside out
+    if direction.determinant() < 0.0 {
+        for triangle in mesh.triangles.iter_mut() {
+            triangle.swap(1, 2);
+        }
+    }
+    mesh
+}
+
+/// Surface of the volume at `threshold`, in normalized density (see `Volume::from_hu`), in patient coordinates
+pub fn volume_surface(volume: &Volume, threshold: f32) -> Mesh {
+    to_patient(marching_cubes(&volume.data, volume.res, volume.spacing, threshold), volume.spacing, volume.origin, volume.direction)
+}
+
+/// Surface around the voxels carrying `label`, in patient coordinates
+pub fn label_surface(labelmap: &LabelMap, label: u16) -> Mesh {
+    let values: Vec<f32> = labelmap.data.iter().map(|value| if *value == label { 1.0 } else { 0.0 }).collect();
+    to_patient(marching_cubes(&values, labelmap.res, labelmap.spacing, 0.5), labelmap.spacing, labelmap.origin, labelmap.direction)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Every undirected edge has to be used once in each direction, by two triangles wound the same way
+    fn assert_watertight(mesh: &Mesh) {
+        let mut directed: HashMap<(u32, u32), usize> = HashMap::new();
+        for triangle in mesh.triangles.iter() {
+            for k in 0..3 {
+                *directed.entry((triangle[k], triangle[(k + 1) % 3])).or_insert(0) += 1;
+            }
+        }
+        for ((a, b), count) in directed.iter() {
+            assert_eq!(*count, 1, "edge {} -> {} is used {} times", a, b, count);
+            assert_eq!(directed.get(&(*b, *a)), Some(&1), "edge {} -> {} has no opposite", a, b);
+        }
+    }
+
+    #[test]
+    fn every_case_is_closed() {
+        for case in 1..255usize {
+            let values: Vec<f32> = (0..8).map(|corner| if case & (1 << corner) != 0 { 1.0 } else { 0.0 }).collect();
+            let mesh = marching_cubes(&values, IVec3::splat(2), Vec3::ONE, 0.5);
+            assert_watertight(&mesh);
+            assert!(mesh.enclosed_volume() > 0.0, "case {} faces inwards", case);
+        }
+    }
+
+    #[test]
+    fn noise_gives_a_watertight_surface() {
+        let res = IVec3::new(12, 10, 9);
+        let mut state = 12345u32;
+        let values: Vec<f32> = (0..res.x * res.y * res.z).map(|_| {
+            state = state.wrapping_mul(1664525).wrapping_add(1013904223);
+            (state >> 8) as f32 / (1 << 24) as f32
+        }).collect();
+        let mesh = marching_cubes(&values, res, Vec3::new(0.5, 1.0, 2.0), 0.5);
+        assert!(mesh.triangles.len() > 1000);
+        assert_watertight(&mesh);
+        assert!(mesh.enclosed_volume() > 0.0);
+    }
+
+    #[test]
+    fn sphere_volume_is_close_to_exact() {
+        let res = IVec3::splat(32);
+        let radius = 12.0;
+        let centre = res.as_vec3() / 2.0;
+        let values: Vec<f32> = (0..res.x * res.y * res.z).map(|index| {
+            let coord = IVec3::new(index % res.x, (index / res.x) % res.y, index / (res.x * res.y));
+            radius - (coord.as_vec3() + Vec3::splat(0.5) - centre).length()
+        }).collect();
+        let mesh = marching_cubes(&values, res, Vec3::ONE, 0.0);
+        assert_watertight(&mesh);
+        let exact = 4.0 / 3.0 * std::f32::consts::PI * radius.powi(3);
+        assert!((mesh.enclosed_volume() / exact - 1.0).abs() < 0.01, "{} mm³ instead of {}", mesh.enclosed_volume(), exact);
+    }
+
+    #[test]
+    fn surfaces_are_in_patient_coordinates() {
+        // A 2 x 2 x 2 voxel block in a volume whose rows run towards -x, from a shifted origin
+        let res = IVec3::new(6, 6, 6);
+        let mut volume = Volume::new(Volume::radii_for(res, Vec3::ONE), res);
+        volume.spacing = Vec3::new(0.5, 1.0, 2.0);
+        volume.origin = Vec3::new(100.0, -50.0, 20.0);
+        volume.direction = Mat3::from_diagonal(Vec3::new(-1.0, 1.0, 1.0));
+        for z in 2..4 {
+            for y in 2..4 {
+                for x in 2..4 {
+                    volume.set(IVec3::new(x, y, z), 1.0);
+                }
+            }
+        }
+
+        let mesh = volume_surface(&volume, 0.5);
+        assert_watertight(&mesh);
+        assert!(mesh.enclosed_volume() > 0.0);
+        let low = mesh.vertices.iter().fold(Vec3::splat(f32::MAX), |a, b| a.min(*b));
+        let high = mesh.vertices.iter().fold(Vec3::splat(f32::MIN), |a, b| a.max(*b));
+        // The surface runs halfway between the inside and outside voxel centres
+        let corners = [volume.index_to_patient(Vec3::splat(1.5)), volume.index_to_patient(Vec3::splat(3.5))];
+        assert!((low - corners[0].min(corners[1])).abs().max_element() < 1e-4, "{} {:?}", low, corners);
+        assert!((high - corners[0].max(corners[1])).abs().max_element() < 1e-4, "{} {:?}", high, corners);
+    }
+}
@@ -0,0 +1,472 @@
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::cmp::Ordering;
+
+use glam::{Vec3, DVec3, DMat3};
+
+use crate::types::mesh::Mesh;
+
+pub const TAUBIN_LAMBDA: f32 = 0.5;
+pub const TAUBIN_MU: f32 = -0.53;
+// Collapses may not turn a neighbouring triangle further than this (cosine of the angle)
+const MIN_NORMAL_AGREEMENT: f64 = 0.2;
+
+/// Vertex neighbours through triangle edges
+fn vertex_neighbours(mesh: &Mesh) -> Vec<Vec<u32>> {
+    let mut neighbours: Vec<Vec<u32>> = vec![Vec::new(); mesh.vertices.len()];
+    for triangle in mesh.triangles.iter() {
+        for k in 0..3 {
+            let (a, b) = (triangle[k], triangle[(k + 1) % 3]);
+            if !neighbours[a as usize].contains(&b) {
+                neighbours[a as usize].push(b);
+            }
+            if !neighbours[b as usize].contains(&a) {
+                neighbours[b as usize].push(a);
+            }
+        }
+    }
+    neighbours
+}
+
+/// Directed edges that have no twin running the other way
+fn boundary_edges(mesh: &Mesh) -> Vec<(u32, u32)> {
+    let directed: HashSet<(u32, u32)> = mesh.triangles.iter()
+        .flat_map(|t| [(t[0], t[1]), (t[1], t[2]), (t[2], t[0])])
+        .collect();
+    directed.iter().filter(|(a, b)| !directed.contains(&(*b, *a))).cloned().collect()
+}
+
+fn boundary_vertices(mesh: &Mesh) -> Vec<bool> {
+    let mut on_boundary = vec![false; mesh.vertices.len()];
+    for (a, b) in boundary_edges(mesh) {
+        on_boundary[a as usize] = true;
+        on_boundary[b as usize] = true;
+    }
+    on_boundary
+}
+
+/// Move every vertex `factor` of the way towards the average of its neighbours. Boundary vertices stay put.
+fn laplacian_step(mesh: &mut Mesh, neighbours: &[Vec<u32>], on_boundary: &[bool], factor: f32) {
+    let moved: Vec<Vec3> = mesh.vertices.iter().enumerate().map(|(index, position)| {
+        if on_boundary[index] || neighbours[index].is_empty() {
+            return *position;
+        }
+        let average = neighbours[index].iter().map(|n| mesh.vertices[*n as usize]).sum::<Vec3>() / neighbours[index].len() as f32;
+        *position + (average - *position) * factor
+    }).collect();
+    mesh.vertices = moved;
+}
+
+/// Plain Laplacian smoothing, shrinks the mesh noticeably over many iterations
+pub fn laplacian_smooth(mesh: &mut Mesh, iterations: usize, factor: f32) {
+    let neighbours = vertex_neighbours(mesh);
+    let on_boundary = boundary_vertices(mesh);
+    for _ in 0..iterations {
+        laplacian_step(mesh, &neighbours, &on_boundary, factor);
+    }
+}
+
+/// Taubin lambda/mu smoothing, removes the marching cubes terracing without shrinking the surface
+pub fn taubin_smooth(mesh: &mut Mesh, iterations: usize) {
+    let neighbours = vertex_neighbours(mesh);
+    let on_boundary = boundary_vertices(mesh);
+    for _ in 0..iterations {
+        laplacian_step(mesh, &neighbours, &on_boundary, TAUBIN_LAMBDA);
+        laplacian_step(mesh, &neighbours, &on_boundary, TAUBIN_MU);
+    }
+}
+
+/// Drop triangles that repeat a vertex or another triangle, and vertices no triangle uses
+pub fn remove_degenerate(mesh: &mut Mesh) {
+    let mut seen = HashSet::new();
+    mesh.triangles.retain(|t| {
+        if t[0] == t[1] || t[1] == t[2] || t[2] == t[0] {
+            return false;
+        }
+        // Same triangle regardless of the starting vertex
+        let rotation = (0..3).min_by_key(|k| t[*k]).unwrap();
+        seen.insert([t[rotation], t[(rotation + 1) % 3], t[(rotation + 2) % 3]])
+    });
+    compact(mesh);
+}
+
+/// Remove unused vertices and renumber the rest
+pub fn compact(mesh: &mut Mesh) {
+    let mut remap = vec![u32::MAX; mesh.vertices.len()];
+    let mut vertices = Vec::new();
+    for triangle in mesh.triangles.iter_mut() {
+        for index in triangle.iter_mut() {
+            if remap[*index as usize] == u32::MAX {
+                remap[*index as usize] = vertices.len() as u32;
+                vertices.push(mesh.vertices[*index as usize]);
+            }
+            *index = remap[*index as usize];
+        }
+    }
+    mesh.vertices = vertices;
+}
+
+fn find_root(parents: &mut [u32], mut index: u32) -> u32 {
+    while parents[index as usize] != index {
+        parents[index as usize] = parents[parents[index as usize] as usize];
+        index = parents[index as usize];
+    }
+    index
+}
+
+/// Remove connected pieces of the mesh with less than `min_area_mm2` of surface. Returns how many were removed.
+pub fn remove_small_shells(mesh: &mut Mesh, min_area_mm2: f32) -> usize {
+    let mut parents: Vec<u32> = (0..mesh.vertices.len() as u32).collect();
+    for triangle in mesh.triangles.iter() {
+        for k in 1..3 {
+            let (a, b) = (find_root(&mut parents, triangle[0]), find_root(&mut parents, triangle[k]));
+            parents[a as usize] = b;
+        }
+    }
+
+    let mut areas: HashMap<u32, f32> = HashMap::new();
+    let roots: Vec<u32> = mesh.triangles.iter().map(|t| find_root(&mut parents, t[0])).collect();
+    for (triangle, root) in mesh.triangles.iter().zip(roots.iter()) {
+        let [a, b, c] = mesh.triangle_vertices(triangle);
+        *areas.entry(*root).or_insert(0.0) += (b - a).cross(c - a).length() * 0.5;
+    }
+
+    let removed = areas.values().filter(|area| **area < min_area_mm2).count();
+    let mut roots = roots.into_iter();
+    mesh.triangles.retain(|_| areas[&roots.next().unwrap()] >= min_area_mm2);
+    compact(mesh);
+    removed
+}
+
+/// Close boundary loops of at most `max_edges` edges (0 for any size) with a fan around their centroid.
+/// Returns the number of holes closed.
+pub fn close_holes(mesh: &mut Mesh, max_edges: usize) -> usize {
+    // The hole runs against the direction of the boundary edges around it
+    let mut next: HashMap<u32, u32> = HashMap::new();
+    let mut ambiguous = HashSet::new();
+    for (a, b) in boundary_edges(mesh) {
+        if next.insert(b, a).is_some() {
+            ambiguous.insert(b);
+        }
+    }
+
+    let mut visited = HashSet::new();
+    let mut closed = 0;
+    let starts: Vec<u32> = next.keys().cloned().collect();
+    for start in starts {
+        if visited.contains(&start) {
+            continue;
+        }
+        let mut hole = Vec::new();
+        let mut vertex = start;
+        let mut valid = true;
+        while visited.insert(vertex) {
+            hole.push(vertex);
+            // Non-manifold vertices make the loop ambiguous, leave those alone
+            valid &= !ambiguous.contains(&vertex);
+            match next.get(&vertex) {
+                Some(following) => vertex = *following,
+                None => {
+                    valid = false;
+                    break;
+                }
+            }
+        }
+        if !valid || vertex != start || hole.len() < 3 || (max_edges > 0 && hole.len() > max_edges) {
+            continue;
+        }
+
+        if hole.len() == 3 {
+            mesh.triangles.push([hole[0], hole[1], hole[2]]);
+        } else {
+            let centroid = hole.iter().map(|v| mesh.vertices[*v as usize]).sum::<Vec3>() / hole.len() as f32;
+            mesh.vertices.push(centroid);
+            let center = mesh.vertices.len() as u32 - 1;
+            for i in 0..hole.len() {
+                mesh.triangles.push([hole[i], hole[(i + 1) % hole.len()], center]);
+            }
+        }
+        closed += 1;
+    }
+    closed
+}
+
+/// Symmetric 4x4 quadric stored as its upper triangle
+#[derive(Debug, Clone, Copy, Default)]
+struct Quadric([f64; 10]);
+
+impl Quadric {
+    fn from_plane(normal: DVec3, d: f64) -> Quadric {
+        let (a, b, c) = (normal.x, normal.y, normal.z);
+        Quadric([a*a, a*b, a*c, a*d, b*b, b*c, b*d, c*c, c*d, d*d])
+    }
+
+    fn add(&self, other: &Quadric) -> Quadric {
+        let mut sum = [0.0; 10];
+        for i in 0..10 {
+            sum[i] = self.0[i] + other.0[i];
+        }
+        Quadric(sum)
+    }
+
+    fn error(&self, p: DVec3) -> f64 {
+        let q = &self.0;
+        q[0]*p.x*p.x + 2.0*q[1]*p.x*p.y + 2.0*q[2]*p.x*p.z + 2.0*q[3]*p.x
+            + q[4]*p.y*p.y + 2.0*q[5]*p.y*p.z + 2.0*q[6]*p.y
+            + q[7]*p.z*p.z + 2.0*q[8]*p.z + q[9]
+    }
+
+    /// Position minimizing the error, if the quadric is well conditioned
+    fn optimum(&self) -> Option<DVec3> {
+        let q = &self.0;
+        let matrix = DMat3::from_cols(
+            DVec3::new(q[0], q[1], q[2]),
+            DVec3::new(q[1], q[4], q[5]),
+            DVec3::new(q[2], q[5], q[7])
+        );
+        if matrix.determinant().abs() < 1e-9 {
+            return None;
+        }
+        Some(matrix.inverse() * -DVec3::new(q[3], q[6], q[8]))
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct Collapse {
+    cost: f64,
+    a: u32,
+    b: u32,
+    versions: (u32, u32),
+    position: DVec3
+}
+
+impl Eq for Collapse {}
+
+impl Ord for Collapse {
+    // Cheapest first in a max heap
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.cost.partial_cmp(&self.cost).unwrap_or(Ordering::Equal)
+    }
+}
+
+impl PartialOrd for Collapse {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Garland-Heckbert quadric error edge collapse down to about `target_triangles`.
+/// Boundary vertices are kept, and collapses that would pinch the surface or flip triangles are skipped.
+pub fn decimate(mesh: &mut Mesh, target_triangles: usize) {
+    remove_degenerate(mesh);
+    let mut positions: Vec<DVec3> = mesh.vertices.iter().map(|v| v.as_dvec3()).collect();
+    let mut triangles = mesh.triangles.clone();
+    let mut triangle_alive = vec![true; triangles.len()];
+    let mut alive_count = triangles.len();
+
+    let mut vertex_triangles: Vec<Vec<u32>> = vec![Vec::new(); positions.len()];
+    let mut quadrics = vec![Quadric::default(); positions.len()];
+    for (index, t) in triangles.iter().enumerate() {
+        let [a, b, c] = [positions[t[0] as usize], positions[t[1] as usize], positions[t[2] as usize]];
+        let normal = (b - a).cross(c - a);
+        let area = normal.length() * 0.5;
+        let normal = normal.normalize_or_zero();
+        // Area weighting keeps large flat regions from being dominated by slivers
+        let plane = Quadric::from_plane(normal, -normal.dot(a));
+        for v in t.iter() {
+            vertex_triangles[*v as usize].push(index as u32);
+            let weighted = Quadric(plane.0.map(|value| value * area));
+            quadrics[*v as usize] = quadrics[*v as usize].add(&weighted);
+        }
+    }
+    let locked = boundary_vertices(mesh);
+    let mut versions = vec![0u32; positions.len()];
+
+    let neighbours_of = |vertex: u32, triangles: &Vec<[u32; 3]>, vertex_triangles: &Vec<Vec<u32>>| -> HashSet<u32> {
+        vertex_triangles[vertex as usize].iter()
+            .flat_map(|t| triangles[*t as usize].iter().cloned())
+            .filter(|v| *v != vertex)
+            .collect()
+    };
+
+    let plan = |a: u32, b: u32, positions: &Vec<DVec3>, quadrics: &Vec<Quadric>, versions: &Vec<u32>| -> Collapse {
+        let quadric = quadrics[a as usize].add(&quadrics[b as usize]);
+        let (pa, pb) = (positions[a as usize], positions[b as usize]);
+        let mut candidates = vec![pa, pb, (pa + pb) * 0.5];
+        if let Some(optimum) = quadric.optimum() {
+            // Stay near the edge, a badly conditioned optimum can be far away
+            if optimum.distance(pa.lerp(pb, 0.5)) <= pa.distance(pb) * 2.0 {
+                candidates.push(optimum);
+            }
+        }
+        let position = candidates.into_iter().min_by(|p, q| quadric.error(*p).partial_cmp(&quadric.error(*q)).unwrap_or(Ordering::Equal)).unwrap();
+        Collapse { cost: quadric.error(position), a, b, versions: (versions[a as usize], versions[b as usize]), position }
+    };
+
+    let mut heap = BinaryHeap::new();
+    for t in triangles.iter() {
+        for k in 0..3 {
+            let (a, b) = (t[k], t[(k + 1) % 3]);
+            if a < b && !locked[a as usize] && !locked[b as usize] {
+                heap.push(plan(a, b, &positions, &quadrics, &versions));
+            }
+        }
+    }
+
+    while alive_count > target_triangles {
+        let collapse = match heap.pop() {
+            Some(collapse) => collapse,
+            None => break
+        };
+        let (a, b) = (collapse.a, collapse.b);
+        if collapse.versions != (versions[a as usize], versions[b as usize]) {
+            continue;
+        }
+
+        // Link condition: the only shared neighbours may be the tips of the triangles on the edge
+        let shared_triangles: Vec<u32> = vertex_triangles[a as usize].iter().filter(|t| triangles[**t as usize].contains(&b)).cloned().collect();
+        if shared_triangles.len() != 2 {
+            continue;
+        }
+        let neighbours_a = neighbours_of(a, &triangles, &vertex_triangles);
+        let neighbours_b = neighbours_of(b, &triangles, &vertex_triangles);
+        if neighbours_a.intersection(&neighbours_b).count() != 2 {
+            continue;
+        }
+
+        // No triangle around the edge may flip or fold over
+        let folds = [a, b].iter().any(|vertex| {
+            vertex_triangles[*vertex as usize].iter().filter(|t| !shared_triangles.contains(t)).any(|t| {
+                let triangle = triangles[*t as usize];
+                let corner = |v: u32| if v == a || v == b { collapse.position } else { positions[v as usize] };
+                let before = (positions[triangle[1] as usize] - positions[triangle[0] as usize]).cross(positions[triangle[2] as usize] - positions[triangle[0] as usize]);
+                let after = (corner(triangle[1]) - corner(triangle[0])).cross(corner(triangle[2]) - corner(triangle[0]));
+                before.normalize_or_zero().dot(after.normalize_or_zero()) < MIN_NORMAL_AGREEMENT
+            })
+        });
+        if folds {
+            continue;
+        }
+
+        // Merge b into a
+        positions[a as usize] = collapse.position;
+        quadrics[a as usize] = quadrics[a as usize].add(&quadrics[b as usize]);
+        for t in shared_triangles.iter() {
+            triangle_alive[*t as usize] = false;
+            alive_count -= 1;
+        }
+        let moved: Vec<u32> = vertex_triangles[b as usize].drain(..).collect();
+        for t in moved {
+            if !triangle_alive[t as usize] {
+                continue;
+            }
+            for v in triangles[t as usize].iter_mut() {
+                if *v == b {
+                    *v = a;
+                }
+            }
+            vertex_triangles[a as usize].push(t);
+        }
+        for vertex in neighbours_a.union(&neighbours_b) {
+            vertex_triangles[*vertex as usize].retain(|t| triangle_alive[*t as usize]);
+        }
+        vertex_triangles[a as usize].retain(|t| triangle_alive[*t as usize]);
+        versions[a as usize] += 1;
+        versions[b as usize] += 1;
+
+        for neighbour in neighbours_of(a, &triangles, &vertex_triangles) {
+            if !locked[neighbour as usize] {
+                heap.push(plan(a.min(neighbour), a.max(neighbour), &positions, &quadrics, &versions));
+            }
+        }
+    }
+
+    mesh.vertices = positions.iter().map(|p| p.as_vec3()).collect();
+    mesh.triangles = triangles.into_iter().zip(triangle_alive.into_iter()).filter(|(_, alive)| *alive).map(|(t, _)| t).collect();
+    compact(mesh);
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::IVec3;
+
+    use super::*;
+    use crate::processing::marching_cubes::marching_cubes;
+
+    /// Marching cubes sphere of `radius` mm around `centre`, on a 1 mm grid
+    fn sphere(radius: f32, centre: Vec3) -> Mesh {
+        let res = IVec3::splat((radius * 2.0) as i32 + 4);
+        let values: Vec<f32> = (0..res.x * res.y * res.z).map(|index| {
+            let coord = IVec3::new(index % res.x, (index / res.x) % res.y, index / (res.x * res.y));
+            radius - (coord.as_vec3() + Vec3::splat(0.5) - res.as_vec3() / 2.0).length()
+        }).collect();
+        let mut mesh = marching_cubes(&values, res, Vec3::ONE, 0.0);
+        let offset = centre - res.as_vec3() / 2.0;
+        mesh.vertices.iter_mut().for_each(|v| *v += offset);
+        mesh
+    }
+
+    fn sphere_volume(radius: f32) -> f32 {
+        4.0 / 3.0 * std::f32::consts::PI * radius.powi(3)
+    }
+
+    /// Every directed edge appears once and has a twin running the other way
+    fn is_closed_manifold(mesh: &Mesh) -> bool {
+        let mut directed = HashSet::new();
+        let unique = mesh.triangles.iter().all(|t| (0..3).all(|k| directed.insert((t[k], t[(k + 1) % 3]))));
+        unique && boundary_edges(mesh).is_empty()
+    }
+
+    #[test]
+    fn decimation_reaches_the_target_and_stays_manifold() {
+        let mut mesh = sphere(8.0, Vec3::ZERO);
+        assert!(mesh.triangles.len() > 2000);
+        decimate(&mut mesh, 500);
+        assert!(mesh.triangles.len() <= 500 && mesh.triangles.len() >= 490, "{} triangles", mesh.triangles.len());
+        assert!(is_closed_manifold(&mesh));
+        assert!((mesh.enclosed_volume() / sphere_volume(8.0) - 1.0).abs() < 0.05);
+    }
+
+    #[test]
+    fn holes_in_a_cut_sphere_are_closed() {
+        let mut mesh = sphere(8.0, Vec3::ZERO);
+        let triangles = mesh.triangles.len();
+        mesh.triangles.retain(|t| t.iter().any(|v| mesh.vertices[*v as usize].z < 6.0));
+        compact(&mut mesh);
+        assert!(mesh.triangles.len() < triangles && !is_closed_manifold(&mesh));
+
+        // Too large for the limit, then closed
+        let boundary = boundary_edges(&mesh).len();
+        assert_eq!(close_holes(&mut mesh, boundary - 1), 0);
+        assert_eq!(close_holes(&mut mesh, 0), 1);
+        assert!(is_closed_manifold(&mesh));
+        assert!(mesh.enclosed_volume() > 0.0);
+    }
+
+    #[test]
+    fn small_shells_are_removed() {
+        let mut mesh = sphere(8.0, Vec3::ZERO);
+        let large = mesh.clone();
+        let small = sphere(2.0, Vec3::splat(20.0));
+        let offset = mesh.vertices.len() as u32;
+        mesh.vertices.extend(small.vertices.iter());
+        mesh.triangles.extend(small.triangles.iter().map(|t| t.map(|v| v + offset)));
+
+        // Shell areas are roughly 800 and 50 mm²
+        assert_eq!(remove_small_shells(&mut mesh, 10.0), 0);
+        assert_eq!(remove_small_shells(&mut mesh, 200.0), 1);
+        assert_eq!((mesh.vertices.len(), mesh.triangles.len()), (large.vertices.len(), large.triangles.len()));
+        assert!((mesh.enclosed_volume() - large.enclosed_volume()).abs() < 1e-2);
+    }
+
+    #[test]
+    fn taubin_smoothing_keeps_the_volume() {
+        let mut mesh = sphere(8.0, Vec3::ZERO);
+        let before = mesh.enclosed_volume();
+        let mut shrunk = mesh.clone();
+        taubin_smooth(&mut mesh, 20);
+        laplacian_smooth(&mut shrunk, 40, TAUBIN_LAMBDA);
+        assert!((mesh.enclosed_volume() / before - 1.0).abs() < 0.02, "{} mm³ from {}", mesh.enclosed_volume(), before);
+        assert!(shrunk.enclosed_volume() < mesh.enclosed_volume());
+        assert!(is_closed_manifold(&mesh));
+    }
+}
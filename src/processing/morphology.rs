@@ -0,0 +1,279 @@
+use std::collections::VecDeque;
+
+use glam::{Vec3, IVec3};
+
+use crate::types::labelmap::LabelMap;
+use crate::processing::segmentation::{FACE_NEIGHBOURS, in_bounds, voxel_index, voxel_coord};
+use crate::processing::parallel::parallel_fill;
+
+/// Voxel offsets making up the neighbourhood used by erosion and dilation
+#[derive(Debug, Clone)]
+pub struct StructuringElement {
+    pub offsets: Vec<IVec3>
+}
+
+impl StructuringElement {
+    /// Ellipsoid of `radius_mm` on a grid with `spacing` mm per voxel, so that anisotropic
+    /// voxels still give a round neighbourhood in patient space
+    pub fn ball(radius_mm: f32, spacing: Vec3) -> StructuringElement {
+        let radii = Vec3::splat(radius_mm.abs()) / spacing;
+        let extent = radii.floor().as_ivec3();
+        let mut offsets = Vec::new();
+        for z in -extent.z..=extent.z {
+            for y in -extent.y..=extent.y {
+                for x in -extent.x..=extent.x {
+                    let offset = IVec3::new(x, y, z);
+                    let scaled = offset.as_vec3() / radii.max(Vec3::splat(f32::EPSILON));
+                    if offset == IVec3::ZERO || scaled.length_squared() <= 1.0 + 1e-6 {
+                        offsets.push(offset);
+                    }
+                }
+            }
+        }
+        StructuringElement { offsets }
+    }
+
+    /// Ball of `radius` voxels, ignoring spacing
+    pub fn voxel_ball(radius: i32) -> StructuringElement {
+        StructuringElement::ball(radius as f32, Vec3::ONE)
+    }
+}
+
+/// Voxels outside the grid are ignored, so shapes touching the border are not eroded from it
+pub fn erode(mask: &[bool], res: IVec3, element: &StructuringElement) -> Vec<bool> {
+    parallel_fill(mask.len(), 1, |start, part| {
+        for (i, value) in part.iter_mut().enumerate() {
+            let index = start + i;
+            if !mask[index] {
+                continue;
+            }
+            let coord = voxel_coord(res, index);
+            *value = element.offsets.iter().all(|offset| {
+                let neighbour = coord + *offset;
+                !in_bounds(res, neighbour) || mask[voxel_index(res, neighbour)]
+            });
+        }
+    })
+}
+
+pub fn dilate(mask: &[bool], res: IVec3, element: &StructuringElement) -> Vec<bool> {
+    parallel_fill(mask.len(), 1, |start, part| {
+        for (i, value) in part.iter_mut().enumerate() {
+            let index = start + i;
+            if mask[index] {
+                *value = true;
+                continue;
+            }
+            let coord = voxel_coord(res, index);
+            *value = element.offsets.iter().any(|offset| {
+                let neighbour = coord - *offset;
+                in_bounds(res, neighbour) && mask[voxel_index(res, neighbour)]
+            });
+        }
+    })
+}
+
+/// Erosion followed by dilation, removes specks and thin bridges
+pub fn open(mask: &[bool], res: IVec3, element: &StructuringElement) -> Vec<bool> {
+    dilate(&erode(mask, res, element), res, element)
+}
+
+/// Dilation followed by erosion, closes small gaps and notches
+pub fn close(mask: &[bool], res: IVec3, element: &StructuringElement) -> Vec<bool> {
+    erode(&dilate(mask, res, element), res, element)
+}
+
+/// Grow (positive `margin_mm`) or shrink (negative) a mask by a distance in millimetres
+pub fn margin(mask: &[bool], res: IVec3, spacing: Vec3, margin_mm: f32) -> Vec<bool> {
+    let element = StructuringElement::ball(margin_mm, spacing);
+    if margin_mm > 0.0 {
+        dilate(mask, res, &element)
+    } else if margin_mm < 0.0 {
+        erode(mask, res, &element)
+    } else {
+        mask.to_vec()
+    }
+}
+
+/// Fill background regions that cannot be reached from the border of the grid.
+/// Axes that are a single voxel thick have no border, so a single slice is filled in 2D.
+pub fn fill_holes(mask: &[bool], res: IVec3) -> Vec<bool> {
+    let mut outside = vec![false; mask.len()];
+    let mut queue = VecDeque::new();
+    for index in 0..mask.len() {
+        let coord = voxel_coord(res, index);
+        let on_border = (0..3).any(|axis| res[axis] > 1 && (coord[axis] == 0 || coord[axis] == res[axis] - 1));
+        if on_border && !mask[index] {
+            outside[index] = true;
+            queue.push_back(coord);
+        }
+    }
+    while let Some(coord) = queue.pop_front() {
+        for offset in FACE_NEIGHBOURS.iter() {
+            let neighbour = coord + *offset;
+            if !in_bounds(res, neighbour) {
+                continue;
+            }
+            let index = voxel_index(res, neighbour);
+            if !mask[index] && !outside[index] {
+                outside[index] = true;
+                queue.push_back(neighbour);
+            }
+        }
+    }
+    outside.iter().map(|value| !value).collect()
+}
+
+/// Fill holes separately in every axial slice, for shapes that are open at the top and bottom
+pub fn fill_holes_per_slice(mask: &[bool], res: IVec3) -> Vec<bool> {
+    let slice_len = (res.x * res.y) as usize;
+    let slice_res = IVec3::new(res.x, res.y, 1);
+    parallel_fill(mask.len(), slice_len.max(1), |start, part| {
+        for (slice_index, slice) in part.chunks_mut(slice_len).enumerate() {
+            let offset = start + slice_index * slice_len;
+            let filled = fill_holes(&mask[offset..offset + slice_len], slice_res);
+            slice.copy_from_slice(&filled);
+        }
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MorphologyOperation {
+    Erode,
+    Dilate,
+    Open,
+    Close,
+    /// Grow by a positive radius, shrink by a negative one
+    Margin,
+    FillHoles,
+    FillHolesPerSlice
+}
+
+impl MorphologyOperation {
+    pub fn from_name(name: &str) -> Option<MorphologyOperation> {
+        match name {
+            "erode" => Some(MorphologyOperation::Erode),
+            "dilate" => Some(MorphologyOperation::Dilate),
+            "open" => Some(MorphologyOperation::Open),
+            "close" => Some(MorphologyOperation::Close),
+            "margin" => Some(MorphologyOperation::Margin),
+            "fill-holes" => Some(MorphologyOperation::FillHoles),
+            "fill-holes-2d" => Some(MorphologyOperation::FillHolesPerSlice),
+            _ => None
+        }
+    }
+
+    /// Apply the operation with a ball of `radius_mm`, which hole filling ignores
+    pub fn apply(&self, mask: &[bool], res: IVec3, spacing: Vec3, radius_mm: f32) -> Vec<bool> {
+        let element = || StructuringElement::ball(radius_mm, spacing);
+        match self {
+            MorphologyOperation::Erode => erode(mask, res, &element()),
+            MorphologyOperation::Dilate => dilate(mask, res, &element()),
+            MorphologyOperation::Open => open(mask, res, &element()),
+            MorphologyOperation::Close => close(mask, res, &element()),
+            MorphologyOperation::Margin => margin(mask, res, spacing, radius_mm),
+            MorphologyOperation::FillHoles => fill_holes(mask, res),
+            MorphologyOperation::FillHolesPerSlice => fill_holes_per_slice(mask, res)
+        }
+    }
+}
+
+/// Replace the voxels of `label` with `mask`. Only background voxels are claimed, other labels are left alone.
+pub fn update_label(labelmap: &mut LabelMap, label: u16, mask: &[bool]) {
+    for (value, inside) in labelmap.data.iter_mut().zip(mask.iter()) {
+        if *inside && *value == 0 {
+            *value = label;
+        } else if !*inside && *value == label {
+            *value = 0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn count(mask: &[bool]) -> usize {
+        mask.iter().filter(|value| **value).count()
+    }
+
+    fn cube(res: IVec3, min: IVec3, max: IVec3) -> Vec<bool> {
+        (0..(res.x * res.y * res.z) as usize).map(|index| {
+            let coord = voxel_coord(res, index);
+            coord.cmpge(min).all() && coord.cmple(max).all()
+        }).collect()
+    }
+
+    #[test]
+    fn dilating_a_voxel_gives_a_ball() {
+        let res = IVec3::splat(5);
+        let mask = cube(res, IVec3::splat(2), IVec3::splat(2));
+        assert_eq!(count(&dilate(&mask, res, &StructuringElement::voxel_ball(1))), 7);
+    }
+
+    #[test]
+    fn eroding_a_cube_shrinks_it() {
+        let res = IVec3::splat(9);
+        let mask = cube(res, IVec3::splat(2), IVec3::splat(6));
+        let eroded = erode(&mask, res, &StructuringElement::voxel_ball(1));
+        assert_eq!(eroded, cube(res, IVec3::splat(3), IVec3::splat(5)));
+    }
+
+    #[test]
+    fn opening_removes_specks() {
+        let res = IVec3::splat(12);
+        let mut mask = cube(res, IVec3::splat(2), IVec3::splat(6));
+        mask[voxel_index(res, IVec3::splat(10))] = true;
+        let opened = open(&mask, res, &StructuringElement::voxel_ball(1));
+        assert!(!opened[voxel_index(res, IVec3::splat(10))]);
+        assert!(opened[voxel_index(res, IVec3::splat(4))]);
+    }
+
+    #[test]
+    fn closing_bridges_gaps() {
+        let res = IVec3::new(11, 5, 5);
+        let mut mask = cube(res, IVec3::new(1, 1, 1), IVec3::new(9, 3, 3));
+        for y in 1..=3 {
+            for z in 1..=3 {
+                mask[voxel_index(res, IVec3::new(5, y, z))] = false;
+            }
+        }
+        let closed = close(&mask, res, &StructuringElement::voxel_ball(1));
+        assert!(closed[voxel_index(res, IVec3::new(5, 2, 2))]);
+    }
+
+    #[test]
+    fn filling_a_hollow_cube() {
+        let res = IVec3::splat(7);
+        let mut mask = cube(res, IVec3::splat(1), IVec3::splat(5));
+        let inside = cube(res, IVec3::splat(2), IVec3::splat(4));
+        for (value, hole) in mask.iter_mut().zip(inside.iter()) {
+            *value = *value && !hole;
+        }
+        assert_eq!(fill_holes(&mask, res), cube(res, IVec3::splat(1), IVec3::splat(5)));
+    }
+
+    #[test]
+    fn filling_a_tube_needs_slices() {
+        let res = IVec3::new(7, 7, 4);
+        let mut mask = cube(res, IVec3::new(1, 1, 0), IVec3::new(5, 5, 3));
+        let bore = cube(res, IVec3::new(2, 2, 0), IVec3::new(4, 4, 3));
+        for (value, hole) in mask.iter_mut().zip(bore.iter()) {
+            *value = *value && !hole;
+        }
+        assert_eq!(fill_holes(&mask, res), mask);
+        assert_eq!(fill_holes_per_slice(&mask, res), cube(res, IVec3::new(1, 1, 0), IVec3::new(5, 5, 3)));
+    }
+
+    #[test]
+    fn margins_respect_anisotropic_spacing() {
+        let res = IVec3::new(9, 9, 9);
+        let mask = cube(res, IVec3::splat(4), IVec3::splat(4));
+        let grown = margin(&mask, res, Vec3::new(1.0, 1.0, 2.0), 2.0);
+        assert!(grown[voxel_index(res, IVec3::new(6, 4, 4))]);
+        assert!(!grown[voxel_index(res, IVec3::new(7, 4, 4))]);
+        assert!(grown[voxel_index(res, IVec3::new(4, 4, 5))]);
+        assert!(!grown[voxel_index(res, IVec3::new(4, 4, 6))]);
+        assert_eq!(margin(&grown, res, Vec3::new(1.0, 1.0, 2.0), -2.0), mask);
+    }
+}
@@ -0,0 +1,18 @@
+/// Fill a vector of `count` values on all available cores. `f` receives the index of the first value
+/// of its chunk and the chunk to fill. `chunk_multiple` keeps chunk boundaries aligned, e.g. to whole slices.
+pub fn parallel_fill<T: Clone + Default + Send>(count: usize, chunk_multiple: usize, f: impl Fn(usize, &mut [T]) + Sync) -> Vec<T> {
+    let mut output = vec![T::default(); count];
+    if count == 0 {
+        return output;
+    }
+    let threads = std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+    let units = count.div_ceil(chunk_multiple);
+    let chunk = units.div_ceil(threads).max(1) * chunk_multiple;
+    std::thread::scope(|scope| {
+        for (chunk_index, part) in output.chunks_mut(chunk).enumerate() {
+            let f = &f;
+            scope.spawn(move || f(chunk_index * chunk, part));
+        }
+    });
+    output
+}
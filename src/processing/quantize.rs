@@ -0,0 +1,311 @@
+use crate::types::volume::Volume;
+use crate::types::labelmap::LabelMap;
+
+// Same stopping criteria as ct3d3-python/k_means_quantize.py
+const K_MEANS_EPS: f32 = 0.05;
+const K_MEANS_MAX_ITER: usize = 50;
+const K_MEANS_HISTOGRAM_BINS: usize = 4096;
+const OTSU_HISTOGRAM_BINS: usize = 256;
+/// Most classes the quantizers are asked for, one per Otsu histogram bin
+pub const MAX_CLASSES: usize = OTSU_HISTOGRAM_BINS;
+
+/// Intensity classes of a volume. `centers` are sorted ascending, `thresholds[i]` separates class i from
+/// class i + 1, and `labels` holds the class of every voxel.
+pub struct Quantization {
+    pub centers: Vec<f32>,
+    pub thresholds: Vec<f32>,
+    pub labels: Vec<u16>
+}
+
+/// Counts of `values` in `bins` equal bins over their range, with the range
+struct Histogram {
+    counts: Vec<f64>,
+    min: f32,
+    max: f32
+}
+
+impl Histogram {
+    fn new(values: &[f32], bins: usize) -> Histogram {
+        let min = values.iter().cloned().fold(f32::INFINITY, f32::min);
+        let max = values.iter().cloned().fold(f32::NEG_INFINITY, f32::max);
+        let mut counts = vec![0.0; bins];
+        if values.is_empty() {
+            return Histogram { counts, min: 0.0, max: 0.0 };
+        }
+        let width = (max - min).max(f32::EPSILON);
+        for value in values.iter() {
+            let bin = (((value - min) / width) * bins as f32) as usize;
+            counts[bin.min(bins - 1)] += 1.0;
+        }
+        Histogram { counts, min, max }
+    }
+
+    fn bin_width(&self) -> f32 {
+        (self.max - self.min) / self.counts.len() as f32
+    }
+
+    fn bin_center(&self, bin: usize) -> f32 {
+        self.min + (bin as f32 + 0.5) * self.bin_width()
+    }
+
+    /// Upper edge of `bin`
+    fn bin_edge(&self, bin: usize) -> f32 {
+        self.min + (bin + 1) as f32 * self.bin_width()
+    }
+}
+
+impl Quantization {
+    /// Classes split at sorted `thresholds`, with the class means as centres. Classes without voxels are
+    /// dropped with the threshold below them, so there can be fewer classes than thresholds suggest.
+    pub fn from_thresholds(values: &[f32], thresholds: Vec<f32>) -> Quantization {
+        let mut labels: Vec<u16> = values.iter().map(|value| thresholds.iter().filter(|threshold| *value > **threshold).count() as u16).collect();
+        let mut sums = vec![0.0f64; thresholds.len() + 1];
+        let mut counts = vec![0usize; thresholds.len() + 1];
+        for (value, label) in values.iter().zip(labels.iter()) {
+            sums[*label as usize] += *value as f64;
+            counts[*label as usize] += 1;
+        }
+
+        let kept: Vec<usize> = (0..counts.len()).filter(|class| counts[*class] > 0).collect();
+        let mut renumber = vec![0u16; counts.len()];
+        for (new_class, class) in kept.iter().enumerate() {
+            renumber[*class] = new_class as u16;
+        }
+        labels.iter_mut().for_each(|label| *label = renumber[*label as usize]);
+        let centers = kept.iter().map(|class| (sums[*class] / counts[*class] as f64) as f32).collect();
+        // Nothing lies between two kept classes, so the threshold just below the upper one separates them
+        let thresholds = kept.iter().skip(1).map(|class| thresholds[class - 1]).collect();
+        Quantization { centers, thresholds, labels }
+    }
+
+    /// Voxel values replaced by their class centres, like the `qimg` of the Python version
+    pub fn quantized(&self) -> Vec<f32> {
+        self.labels.iter().map(|label| self.centers[*label as usize]).collect()
+    }
+
+    /// Labelmap with one label per class. Class 0 (the darkest, usually air) is background.
+    pub fn to_labelmap(&self, volume: &Volume) -> LabelMap {
+        let mut labelmap = LabelMap::for_volume(volume);
+        labelmap.data = self.labels.clone();
+        for (class, center) in self.centers.iter().enumerate().skip(1) {
+            let label = labelmap.table.get_or_insert(class as u16);
+            label.name = match volume.to_hu(*center) {
+                Some(hu) => format!("Class {} ({:.0} HU)", class, hu),
+                None => format!("Class {} ({:.3})", class, center)
+            };
+        }
+        labelmap
+    }
+}
+
+/// Lloyd iterations over histogram bins from the given starting centres. Returns the centres and the
+/// compactness (sum of squared distances to the nearest centre), like cv2.kmeans.
+fn lloyd(histogram: &Histogram, mut centers: Vec<f32>) -> (Vec<f32>, f64) {
+    let k = centers.len();
+    let nearest = |centers: &[f32], value: f32| (0..k).min_by(|a, b| (centers[*a] - value).abs().partial_cmp(&(centers[*b] - value).abs()).unwrap()).unwrap();
+
+    // The Python version stops once no centre moves more than EPS, relative to the value range here
+    let tolerance = K_MEANS_EPS * 1e-2 * (histogram.max - histogram.min).max(f32::EPSILON);
+    for _ in 0..K_MEANS_MAX_ITER {
+        let mut sums = vec![0.0f64; k];
+        let mut counts = vec![0.0f64; k];
+        for (bin, count) in histogram.counts.iter().enumerate() {
+            if *count > 0.0 {
+                let value = histogram.bin_center(bin);
+                let class = nearest(&centers, value);
+                sums[class] += value as f64 * count;
+                counts[class] += count;
+            }
+        }
+        let mut moved = 0.0f32;
+        for class in 0..k {
+            if counts[class] > 0.0 {
+                let updated = (sums[class] / counts[class]) as f32;
+                moved = moved.max((updated - centers[class]).abs());
+                centers[class] = updated;
+            }
+        }
+        if moved <= tolerance {
+            break;
+        }
+    }
+
+    let compactness = histogram.counts.iter().enumerate().map(|(bin, count)| {
+        let value = histogram.bin_center(bin);
+        let distance = (value - centers[nearest(&centers, value)]) as f64;
+        count * distance * distance
+    }).sum();
+    (centers, compactness)
+}
+
+/// 1D k-means over the voxel values, port of ct3d3-python/k_means_quantize.py. Instead of random restarts
+/// it tries two deterministic starts (evenly spaced quantiles and greedy k-means++) and keeps the more
+/// compact result.
+pub fn k_means(values: &[f32], k: usize) -> Quantization {
+    let k = k.max(1);
+    // Iterating over a fine histogram gives the same centres as iterating over every voxel, much faster
+    let histogram = Histogram::new(values, K_MEANS_HISTOGRAM_BINS);
+    let total: f64 = histogram.counts.iter().sum();
+
+    let mut quantile_centers = Vec::with_capacity(k);
+    let mut cumulative = 0.0;
+    let mut bin = 0;
+    for class in 0..k {
+        let quantile = (class as f64 + 0.5) / k as f64 * total;
+        while bin < histogram.counts.len() - 1 && cumulative + histogram.counts[bin] < quantile {
+            cumulative += histogram.counts[bin];
+            bin += 1;
+        }
+        quantile_centers.push(histogram.bin_center(bin));
+    }
+
+    // Each further centre goes to the bin with the most weight far away from the centres so far
+    let mut spread_centers = vec![quantile_centers[k / 2]];
+    while spread_centers.len() < k {
+        let farthest = (0..histogram.counts.len()).max_by(|a, b| {
+            let score = |bin: usize| {
+                let value = histogram.bin_center(bin);
+                let distance = spread_centers.iter().map(|center| (center - value).abs()).fold(f32::INFINITY, f32::min) as f64;
+                histogram.counts[bin] * distance * distance
+            };
+            score(*a).partial_cmp(&score(*b)).unwrap()
+        }).unwrap();
+        spread_centers.push(histogram.bin_center(farthest));
+    }
+
+    let (quantile_result, spread_result) = (lloyd(&histogram, quantile_centers), lloyd(&histogram, spread_centers));
+    let mut centers = if spread_result.1 < quantile_result.1 { spread_result.0 } else { quantile_result.0 };
+
+    centers.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    let thresholds = centers.windows(2).map(|pair| (pair[0] + pair[1]) * 0.5).collect();
+    Quantization::from_thresholds(values, thresholds)
+}
+
+/// Multi-level Otsu: the `classes - 1` thresholds maximizing the between-class variance over a
+/// 256 bin histogram, found by dynamic programming over the bins (at most one class per bin)
+pub fn multi_otsu(values: &[f32], classes: usize) -> Quantization {
+    let histogram = Histogram::new(values, OTSU_HISTOGRAM_BINS);
+    let bins = histogram.counts.len();
+    let classes = classes.max(2).min(bins);
+
+    // Prefix sums of the weight and first moment, so each class's contribution is O(1)
+    let mut weight = vec![0.0f64; bins + 1];
+    let mut moment = vec![0.0f64; bins + 1];
+    for bin in 0..bins {
+        weight[bin + 1] = weight[bin] + histogram.counts[bin];
+        moment[bin + 1] = moment[bin] + histogram.counts[bin] * bin as f64;
+    }
+    // Between-class variance term of bins [start, end)
+    let term = |start: usize, end: usize| {
+        let w = weight[end] - weight[start];
+        if w <= 0.0 {
+            0.0
+        } else {
+            let m = moment[end] - moment[start];
+            m * m / w
+        }
+    };
+
+    // best[c][end]: highest total of c classes covering bins [0, end), with the start of the last class
+    let mut best = vec![vec![(f64::NEG_INFINITY, 0usize); bins + 1]; classes + 1];
+    for end in 1..=bins {
+        best[1][end] = (term(0, end), 0);
+    }
+    for class in 2..=classes {
+        for end in class..=bins {
+            for start in (class - 1)..end {
+                let total = best[class - 1][start].0 + term(start, end);
+                if total > best[class][end].0 {
+                    best[class][end] = (total, start);
+                }
+            }
+        }
+    }
+
+    let mut splits = Vec::with_capacity(classes - 1);
+    let mut end = bins;
+    for class in (2..=classes).rev() {
+        end = best[class][end].1;
+        splits.push(end);
+    }
+    splits.reverse();
+
+    let thresholds = splits.iter().map(|split| histogram.bin_edge(split - 1)).collect();
+    Quantization::from_thresholds(values, thresholds)
+}
+
+/// Classic two class Otsu threshold
+pub fn otsu(values: &[f32]) -> Quantization {
+    multi_otsu(values, 2)
+}
+
+/// Cutoffs worth trying on a freshly loaded scan: the multi-Otsu thresholds between
+/// air, soft tissue and bone, darkest first
+pub fn suggest_cutoffs(volume: &Volume) -> Vec<f32> {
+    multi_otsu(&volume.data, 3).thresholds
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `per_class` noisy values around each of `levels`, interleaved
+    fn clustered(levels: &[f32], per_class: usize) -> Vec<f32> {
+        (0..per_class * levels.len()).map(|i| {
+            let jitter = ((i * 7919) % 101) as f32 / 100.0 * 0.04 - 0.02;
+            levels[i % levels.len()] + jitter
+        }).collect()
+    }
+
+    /// Every voxel lands in the class of the level it was drawn around, and the centres are close to the levels
+    fn assert_separates(quantization: &Quantization, levels: &[f32]) {
+        assert_eq!(quantization.centers.len(), levels.len());
+        assert_eq!(quantization.thresholds.len(), levels.len() - 1);
+        for (class, level) in levels.iter().enumerate() {
+            assert!((quantization.centers[class] - level).abs() < 0.01, "centre {} for {}", quantization.centers[class], level);
+        }
+        assert!(quantization.labels.iter().enumerate().all(|(i, label)| *label as usize == i % levels.len()));
+    }
+
+    #[test]
+    fn otsu_splits_two_clusters() {
+        let levels = [0.2, 0.7];
+        let quantization = otsu(&clustered(&levels, 500));
+        assert_separates(&quantization, &levels);
+        assert!(quantization.thresholds[0] > 0.22 && quantization.thresholds[0] < 0.68);
+    }
+
+    #[test]
+    fn multi_otsu_splits_three_and_five_clusters() {
+        let levels = [0.1, 0.5, 0.9];
+        assert_separates(&multi_otsu(&clustered(&levels, 400), 3), &levels);
+        let levels = [0.0, 0.2, 0.4, 0.6, 0.8];
+        assert_separates(&multi_otsu(&clustered(&levels, 200), 5), &levels);
+    }
+
+    #[test]
+    fn k_means_finds_the_cluster_centres() {
+        let levels = [0.1, 0.5, 0.9];
+        let values = clustered(&levels, 400);
+        let quantization = k_means(&values, 3);
+        assert_separates(&quantization, &levels);
+        assert_eq!(quantization.quantized()[1], quantization.centers[1]);
+        assert_separates(&k_means(&clustered(&[0.3, 0.6], 500), 2), &[0.3, 0.6]);
+    }
+
+    #[test]
+    fn empty_classes_are_dropped() {
+        let constant = vec![0.4; 100];
+        for quantization in [k_means(&constant, 3), multi_otsu(&constant, 3)] {
+            assert_eq!(quantization.centers, vec![0.4]);
+            assert!(quantization.thresholds.is_empty());
+            assert!(quantization.labels.iter().all(|label| *label == 0));
+        }
+
+        // Two values asked to form four classes
+        let quantization = k_means(&[0.2, 0.2, 0.8, 0.8, 0.8], 4);
+        assert_eq!(quantization.centers, vec![0.2, 0.8]);
+        assert_eq!(quantization.labels, vec![0, 0, 1, 1, 1]);
+        assert!(quantization.thresholds[0] >= 0.2 && quantization.thresholds[0] < 0.8);
+    }
+}
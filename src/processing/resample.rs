@@ -0,0 +1,451 @@
+use glam::{Vec3, IVec3, Mat3};
+
+use crate::types::volume::Volume;
+use crate::types::labelmap::LabelMap;
+use crate::types::ct3d_error::CT3DError;
+use crate::processing::parallel::parallel_fill;
+use crate::loaders::samples::voxel_count;
+
+const LANCZOS_RADIUS: i32 = 3;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Interpolation {
+    Nearest,
+    Trilinear,
+    Lanczos
+}
+
+impl Interpolation {
+    pub fn from_name(name: &str) -> Option<Interpolation> {
+        match name {
+            "nearest" => Some(Interpolation::Nearest),
+            "trilinear" | "linear" => Some(Interpolation::Trilinear),
+            "lanczos" => Some(Interpolation::Lanczos),
+            _ => None
+        }
+    }
+}
+
+/// Direction each voxel axis points in, as patient axis (0 = x, 1 = y, 2 = z) and sign.
+/// Written as axis codes like nibabel: "RAS" means x increases towards the patient's right,
+/// y towards anterior and z towards superior. Patient space itself is LPS.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Orientation {
+    pub axes: [(usize, bool); 3]
+}
+
+const AXIS_CODES: [(char, usize, bool); 6] = [('L', 0, true), ('R', 0, false), ('P', 1, true), ('A', 1, false), ('S', 2, true), ('I', 2, false)];
+
+impl Orientation {
+    pub fn from_code(code: &str) -> Option<Orientation> {
+        let letters: Vec<char> = code.to_ascii_uppercase().chars().collect();
+        if letters.len() != 3 {
+            return None;
+        }
+        let mut axes = [(0, true); 3];
+        for (axis, letter) in letters.iter().enumerate() {
+            let (_, patient_axis, positive) = AXIS_CODES.iter().find(|(code, _, _)| code == letter)?;
+            axes[axis] = (*patient_axis, *positive);
+        }
+        if axes[0].0 == axes[1].0 || axes[1].0 == axes[2].0 || axes[0].0 == axes[2].0 {
+            return None;
+        }
+        Some(Orientation { axes })
+    }
+
+    pub fn code(&self) -> String {
+        self.axes.iter().map(|(patient_axis, positive)| AXIS_CODES.iter().find(|(_, a, p)| a == patient_axis && p == positive).unwrap().0).collect()
+    }
+
+    pub fn of_volume(volume: &Volume) -> Orientation {
+        Orientation::of_direction(volume.direction)
+    }
+
+    /// Closest axis aligned orientation of oblique axes. The strongest direction cosine is
+    /// assigned first, so every voxel axis gets a different patient axis.
+    pub fn of_direction(direction: Mat3) -> Orientation {
+        let mut axes = [(0, true); 3];
+        let mut voxel_axes_left = vec![0, 1, 2];
+        let mut patient_axes_left = vec![0, 1, 2];
+        while !voxel_axes_left.is_empty() {
+            let mut best = (0, 0, -1.0f32);
+            for voxel_axis in voxel_axes_left.iter() {
+                for patient_axis in patient_axes_left.iter() {
+                    let cosine = direction.col(*voxel_axis)[*patient_axis];
+                    if cosine.abs() > best.2 {
+                        best = (*voxel_axis, *patient_axis, cosine.abs());
+                    }
+                }
+            }
+            let (voxel_axis, patient_axis, _) = best;
+            axes[voxel_axis] = (patient_axis, direction.col(voxel_axis)[patient_axis] >= 0.0);
+            voxel_axes_left.retain(|axis| *axis != voxel_axis);
+            patient_axes_left.retain(|axis| *axis != patient_axis);
+        }
+        Orientation { axes }
+    }
+}
+
+fn clamped(volume: &Volume, coord: IVec3) -> f32 {
+    volume.get(coord.clamp(IVec3::ZERO, volume.res - IVec3::ONE))
+}
+
+fn lanczos_weight(x: f32) -> f32 {
+    let a = LANCZOS_RADIUS as f32;
+    if x.abs() < 1e-6 {
+        1.0
+    } else if x.abs() >= a {
+        0.0
+    } else {
+        let pi_x = std::f32::consts::PI * x;
+        a * pi_x.sin() * (pi_x / a).sin() / (pi_x * pi_x)
+    }
+}
+
+/// Value at a continuous voxel index (integer indices are voxel centres), edges extended outwards
+pub fn sample(volume: &Volume, index: Vec3, interpolation: Interpolation) -> f32 {
+    match interpolation {
+        Interpolation::Nearest => clamped(volume, index.round().as_ivec3()),
+        Interpolation::Trilinear => {
+            let base = index.floor();
+            let t = index - base;
+            let base = base.as_ivec3();
+            let mut sum = 0.0;
+            for corner in 0..8 {
+                let offset = IVec3::new(corner & 1, (corner >> 1) & 1, (corner >> 2) & 1);
+                let weight = Vec3::select(offset.cmpeq(IVec3::ONE), t, Vec3::ONE - t);
+                sum += weight.x * weight.y * weight.z * clamped(volume, base + offset);
+            }
+            sum
+        },
+        Interpolation::Lanczos => {
+            let base = index.floor().as_ivec3();
+            let taps = (2 * LANCZOS_RADIUS) as usize;
+            // Separable weights per axis, normalized so flat regions stay flat
+            let mut weights = [[0.0f32; (2 * LANCZOS_RADIUS) as usize]; 3];
+            for axis in 0..3 {
+                for tap in 0..taps {
+                    weights[axis][tap] = lanczos_weight(index[axis] - (base[axis] + tap as i32 - LANCZOS_RADIUS + 1) as f32);
+                }
+                let total: f32 = weights[axis].iter().sum();
+                weights[axis].iter_mut().for_each(|weight| *weight /= total);
+            }
+            let mut sum = 0.0;
+            for z in 0..taps {
+                for y in 0..taps {
+                    for x in 0..taps {
+                        let weight = weights[0][x] * weights[1][y] * weights[2][z];
+                        if weight != 0.0 {
+                            let offset = IVec3::new(x as i32, y as i32, z as i32) - IVec3::splat(LANCZOS_RADIUS - 1);
+                            sum += weight * clamped(volume, base + offset);
+                        }
+                    }
+                }
+            }
+            sum
+        }
+    }
+}
+
+/// Resample to a new voxel spacing covering the same physical extent (rounded to whole voxels,
+/// centred on the original extent)
+pub fn resample(volume: &Volume, spacing: Vec3, interpolation: Interpolation) -> Result<Volume, CT3DError> {
+    if !spacing.is_finite() || spacing.min_element() <= 0.0 {
+        return Err(CT3DError::Usage(format!("Spacing must be positive, found {}", spacing)));
+    }
+    let extent = volume.res.as_vec3() * volume.spacing;
+    let res = (extent / spacing).round().as_ivec3().max(IVec3::ONE);
+    voxel_count(res)?;
+    let shift_mm = (extent - res.as_vec3() * spacing) * 0.5;
+    // Old voxel index of the first new voxel, and of one step along each new axis
+    let first = (shift_mm + 0.5 * spacing) / volume.spacing - Vec3::splat(0.5);
+    let step = spacing / volume.spacing;
+
+    let slice = (res.x * res.y) as usize;
+    let data = parallel_fill((res.x * res.y * res.z) as usize, slice, |start, chunk: &mut [f32]| {
+        for (offset, value) in chunk.iter_mut().enumerate() {
+            let index = start + offset;
+            let coord = IVec3::new((index % res.x as usize) as i32, ((index / res.x as usize) % res.y as usize) as i32, (index / slice) as i32);
+            *value = sample(volume, first + coord.as_vec3() * step, interpolation);
+        }
+    });
+
+    let mut result = volume.with_data(data);
+    result.res = res;
+    result.spacing = spacing;
+    result.radii = volume.radii * (res.as_vec3() * spacing) / extent;
+    result.origin = volume.index_to_patient(first);
+    Ok(result)
+}
+
+/// Resample to cubic voxels, `spacing_mm` on every axis
+pub fn resample_isotropic(volume: &Volume, spacing_mm: f32, interpolation: Interpolation) -> Result<Volume, CT3DError> {
+    resample(volume, Vec3::splat(spacing_mm), interpolation)
+}
+
+/// Voxels from `min` (inclusive) to `max` (exclusive), clamped to the volume
+pub fn crop(volume: &Volume, min: IVec3, max: IVec3) -> Volume {
+    let min = min.clamp(IVec3::ZERO, volume.res - IVec3::ONE);
+    let max = max.clamp(min + IVec3::ONE, volume.res);
+    let res = max - min;
+
+    let mut data = Vec::with_capacity((res.x * res.y * res.z) as usize);
+    for z in min.z..max.z {
+        for y in min.y..max.y {
+            for x in min.x..max.x {
+                data.push(volume.get(IVec3::new(x, y, z)));
+            }
+        }
+    }
+
+    let mut result = volume.with_data(data);
+    result.res = res;
+    result.radii = volume.radii * res.as_vec3() / volume.res.as_vec3();
+    result.origin = volume.index_to_patient(min.as_vec3());
+    result
+}
+
+/// Output axis `i` reads input axis `axes[i]`, backwards if `flipped[i]`
+fn remap_axes(volume: &Volume, axes: [usize; 3], flipped: [bool; 3]) -> Volume {
+    let res = IVec3::new(volume.res[axes[0]], volume.res[axes[1]], volume.res[axes[2]]);
+    let source = |coord: IVec3| {
+        let mut source = IVec3::ZERO;
+        for axis in 0..3 {
+            source[axes[axis]] = if flipped[axis] { res[axis] - 1 - coord[axis] } else { coord[axis] };
+        }
+        source
+    };
+
+    let mut data = Vec::with_capacity(volume.data.len());
+    for z in 0..res.z {
+        for y in 0..res.y {
+            for x in 0..res.x {
+                data.push(volume.get(source(IVec3::new(x, y, z))));
+            }
+        }
+    }
+
+    let column = |axis: usize| volume.direction.col(axes[axis]) * if flipped[axis] { -1.0 } else { 1.0 };
+    let mut result = volume.with_data(data);
+    result.res = res;
+    result.spacing = Vec3::new(volume.spacing[axes[0]], volume.spacing[axes[1]], volume.spacing[axes[2]]);
+    result.radii = Vec3::new(volume.radii[axes[0]], volume.radii[axes[1]], volume.radii[axes[2]]);
+    result.direction = Mat3::from_cols(column(0), column(1), column(2));
+    result.origin = volume.index_to_patient(source(IVec3::ZERO).as_vec3());
+    result
+}
+
+pub fn flip(volume: &Volume, axis: usize) -> Volume {
+    let mut flipped = [false; 3];
+    flipped[axis] = true;
+    remap_axes(volume, [0, 1, 2], flipped)
+}
+
+/// Reorder the axes, output axis `i` is input axis `axes[i]`
+pub fn permute(volume: &Volume, axes: [usize; 3]) -> Result<Volume, CT3DError> {
+    let mut sorted = axes;
+    sorted.sort();
+    if sorted != [0, 1, 2] {
+        return Err(CT3DError::Usage(format!("{:?} is not a permutation of the axes 0, 1 and 2", axes)));
+    }
+    Ok(remap_axes(volume, axes, [false; 3]))
+}
+
+/// Permute and flip so the voxel axes point the way `orientation` describes. Oblique volumes
+/// keep their exact direction, only the closest axis order and signs change.
+pub fn reorient(volume: &Volume, orientation: Orientation) -> Volume {
+    let current = Orientation::of_volume(volume);
+    let mut axes = [0; 3];
+    let mut flipped = [false; 3];
+    for axis in 0..3 {
+        let (patient_axis, positive) = orientation.axes[axis];
+        let source = (0..3).find(|source| current.axes[*source].0 == patient_axis).unwrap();
+        axes[axis] = source;
+        flipped[axis] = current.axes[source].1 != positive;
+    }
+    remap_axes(volume, axes, flipped)
+}
+
+/// `reorient` for labelmaps, keeping the label table
+pub fn reorient_labelmap(labelmap: &LabelMap, orientation: Orientation) -> LabelMap {
+    if Orientation::of_direction(labelmap.direction) == orientation {
+        return LabelMap { data: labelmap.data.clone(), table: labelmap.table.clone(), ..*labelmap };
+    }
+    let volume = reorient(&labelmap.to_volume(), orientation);
+    let mut reoriented = LabelMap::for_volume(&volume);
+    reoriented.data = volume.data.iter().map(|value| *value as u16).collect();
+    reoriented.table = labelmap.table.clone();
+    reoriented
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn oblique_volume() -> Volume {
+        let res = IVec3::new(6, 5, 4);
+        let mut volume = Volume::new(Volume::radii_for(res, Vec3::new(0.7, 1.3, 2.1)), res);
+        volume.spacing = Vec3::new(0.7, 1.3, 2.1);
+        volume.origin = Vec3::new(10.0, -20.0, 30.0);
+        // Roughly x towards anterior, y towards inferior, z towards left
+        volume.direction = Mat3::from_rotation_z(0.2) * Mat3::from_cols(Vec3::new(0.0, -1.0, 0.0), Vec3::new(0.0, 0.0, -1.0), Vec3::new(1.0, 0.0, 0.0));
+        volume.data = (0..volume.data.len()).map(|index| index as f32).collect();
+        volume
+    }
+
+    /// Every voxel of `transformed` sits at the patient position of an `original` voxel with the same value
+    fn assert_same_patient_grid(original: &Volume, transformed: &Volume) {
+        for z in 0..transformed.res.z {
+            for y in 0..transformed.res.y {
+                for x in 0..transformed.res.x {
+                    let coord = IVec3::new(x, y, z);
+                    let index = original.patient_to_index(transformed.index_to_patient(coord.as_vec3()));
+                    assert!((index - index.round()).abs().max_element() < 1e-3, "{:?} maps between voxels at {:?}", coord, index);
+                    assert_eq!(transformed.get(coord), original.get(index.round().as_ivec3()));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn crop_preserves_patient_coordinates() {
+        let volume = oblique_volume();
+        let cropped = crop(&volume, IVec3::new(1, 2, 1), IVec3::new(5, 4, 3));
+        assert_eq!(cropped.res, IVec3::new(4, 2, 2));
+        assert_same_patient_grid(&volume, &cropped);
+    }
+
+    #[test]
+    fn flip_and_permute_preserve_patient_coordinates() {
+        let volume = oblique_volume();
+        for axis in 0..3 {
+            assert_same_patient_grid(&volume, &flip(&volume, axis));
+        }
+        let permuted = permute(&volume, [2, 0, 1]).unwrap();
+        assert_eq!(permuted.res, IVec3::new(4, 6, 5));
+        assert_eq!(permuted.spacing, Vec3::new(2.1, 0.7, 1.3));
+        assert_same_patient_grid(&volume, &permuted);
+        assert!(permute(&volume, [0, 0, 1]).is_err());
+    }
+
+    #[test]
+    fn reorient_preserves_patient_coordinates() {
+        let volume = oblique_volume();
+        assert_eq!(Orientation::of_volume(&volume).code(), "AIL");
+        for code in ["RAS", "LPS", "LIP", "SRA"] {
+            let orientation = Orientation::from_code(code).unwrap();
+            let reoriented = reorient(&volume, orientation);
+            assert_eq!(Orientation::of_volume(&reoriented), orientation);
+            assert_same_patient_grid(&volume, &reoriented);
+        }
+        assert!(Orientation::from_code("LRS").is_none());
+    }
+
+    #[test]
+    fn resample_keeps_patient_positions_of_values() {
+        // A field linear in patient position is reproduced exactly by trilinear interpolation
+        let mut volume = oblique_volume();
+        let field = |patient: Vec3| patient.dot(Vec3::new(0.5, -0.25, 1.0));
+        let values: Vec<f32> = (0..volume.data.len()).map(|index| {
+            let res = volume.res;
+            let coord = IVec3::new(index as i32 % res.x, (index as i32 / res.x) % res.y, index as i32 / (res.x * res.y));
+            field(volume.index_to_patient(coord.as_vec3()))
+        }).collect();
+        volume.data = values;
+
+        let resampled = resample_isotropic(&volume, 0.5, Interpolation::Trilinear).unwrap();
+        assert_eq!(resampled.res, IVec3::new(8, 13, 17));
+        assert_eq!(resampled.direction, volume.direction);
+        let mut checked = 0;
+        for z in 0..resampled.res.z {
+            for y in 0..resampled.res.y {
+                for x in 0..resampled.res.x {
+                    let patient = resampled.index_to_patient(IVec3::new(x, y, z).as_vec3());
+                    let index = volume.patient_to_index(patient);
+                    // Outside the outermost voxel centres the edge values are extended instead
+                    if index.cmpge(Vec3::ZERO).all() && index.cmple((volume.res - IVec3::ONE).as_vec3()).all() {
+                        assert!((resampled.get(IVec3::new(x, y, z)) - field(patient)).abs() < 1e-3);
+                        checked += 1;
+                    }
+                }
+            }
+        }
+        assert!(checked > 0);
+    }
+
+    #[test]
+    fn resample_at_same_spacing_is_identity() {
+        let volume = oblique_volume();
+        for interpolation in [Interpolation::Nearest, Interpolation::Trilinear, Interpolation::Lanczos] {
+            let resampled = resample(&volume, volume.spacing, interpolation).unwrap();
+            assert_eq!(resampled.res, volume.res);
+            assert!((resampled.origin - volume.origin).abs().max_element() < 1e-4);
+            for (a, b) in resampled.data.iter().zip(volume.data.iter()) {
+                assert!((a - b).abs() < 1e-3);
+            }
+        }
+    }
+
+    #[test]
+    fn invalid_spacings_are_rejected() {
+        let volume = oblique_volume();
+        for spacing in [Vec3::new(1.0, 0.0, 1.0), Vec3::new(1.0, 1.0, -0.5), Vec3::new(f32::NAN, 1.0, 1.0), Vec3::splat(f32::INFINITY), Vec3::splat(1e-9)] {
+            assert!(resample(&volume, spacing, Interpolation::Nearest).is_err(), "{}", spacing);
+        }
+        assert!(resample_isotropic(&volume, 0.0, Interpolation::Trilinear).is_err());
+    }
+
+    #[test]
+    fn serialization_keeps_patient_geometry() {
+        let volume = oblique_volume();
+        let path = std::env::temp_dir().join("ct3d3_resample_test_volume.txt").to_string_lossy().into_owned();
+        volume.serialize_to_file(path.clone()).unwrap();
+        let loaded = Volume::deserialize_from_file(path.clone()).unwrap();
+        std::fs::remove_file(path).ok();
+        assert_eq!(loaded.origin, volume.origin);
+        assert!((loaded.direction.to_cols_array()[0] - volume.direction.to_cols_array()[0]).abs() < 1e-6);
+        assert_same_patient_grid(&volume, &loaded);
+    }
+
+    #[test]
+    fn legacy_files_have_no_direction() {
+        let path = std::env::temp_dir().join("ct3d3_resample_test_legacy.txt").to_string_lossy().into_owned();
+        let mut bytes = b"1 1 1\n2 1 1\nspacing 1 1 1\n".to_vec();
+        bytes.extend([0.25f32, 0.5].iter().flat_map(|value| value.to_ne_bytes()));
+        std::fs::write(&path, bytes).unwrap();
+        let legacy = Volume::file_has_direction(path.clone()).unwrap();
+        let loaded = Volume::deserialize_from_file(path.clone()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(!legacy);
+        assert_eq!((loaded.direction, loaded.data), (Mat3::IDENTITY, vec![0.25, 0.5]));
+
+        oblique_volume().serialize_to_file(path.clone()).unwrap();
+        let current = Volume::file_has_direction(path.clone()).unwrap();
+        std::fs::remove_file(path).unwrap();
+        assert!(current);
+    }
+
+    #[test]
+    fn labelmaps_follow_their_volume() {
+        let mut volume = oblique_volume();
+        volume.data = (0..volume.data.len()).map(|index| (index % 5) as f32).collect();
+        let mut labelmap = LabelMap::for_volume(&volume);
+        labelmap.data = (0..labelmap.data.len()).map(|index| (index % 5) as u16).collect();
+        labelmap.table.get_or_insert(3).name = "liver".to_owned();
+
+        // Saved with its geometry, then loaded next to a volume that was reoriented for display
+        let path = std::env::temp_dir().join("ct3d3_resample_test_labels.nii.gz").to_string_lossy().into_owned();
+        labelmap.save(path.clone()).unwrap();
+        let loaded = LabelMap::load(path.clone()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(path.replace(".nii.gz", ".ctbl")).unwrap();
+        assert!(loaded.matches(&volume));
+
+        let display = reorient(&volume, Orientation::from_code("LIP").unwrap());
+        assert!(!loaded.matches(&display));
+        let reoriented = reorient_labelmap(&loaded, Orientation::of_volume(&display));
+        assert!(reoriented.matches(&display));
+        assert_eq!(reoriented.data, display.data.iter().map(|value| *value as u16).collect::<Vec<u16>>());
+        assert_eq!(reoriented.table.get(3).unwrap().name, "liver");
+    }
+}
@@ -0,0 +1,194 @@
+use serde::Serialize;
+
+use crate::types::volume::Volume;
+use crate::types::roi::{Roi, NamedRoi};
+
+pub const DEFAULT_HISTOGRAM_BINS: usize = 32;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Histogram {
+    pub min: f32,
+    pub max: f32,
+    pub counts: Vec<usize>
+}
+
+/// Statistics of the voxels inside a region. Values are in HU when the volume
+/// has a HU range, otherwise in normalized density.
+#[derive(Debug, Clone, Serialize)]
+pub struct RoiStatistics {
+    pub name: String,
+    pub units: String,
+    pub voxel_count: usize,
+    pub volume_ml: f32,
+    pub mean: f32,
+    pub median: f32,
+    pub std_dev: f32,
+    pub min: f32,
+    pub max: f32,
+    pub histogram: Histogram
+}
+
+impl Histogram {
+    pub fn new(values: &[f32], min: f32, max: f32, bins: usize) -> Histogram {
+        let bins = bins.max(1);
+        let mut counts = vec![0usize; bins];
+        let range = max - min;
+        for value in values.iter() {
+            let bin = if range > 0.0 { ((value - min) / range * bins as f32) as usize } else { 0 };
+            counts[bin.min(bins - 1)] += 1;
+        }
+        Histogram { min, max, counts }
+    }
+}
+
+impl RoiStatistics {
+    pub fn compute(volume: &Volume, name: String, roi: &Roi, bins: usize) -> RoiStatistics {
+        RoiStatistics::from_indices(volume, name, roi.voxel_indices(volume), bins)
+    }
+
+    /// Statistics of the voxels at `indices`, e.g. everything carrying one label of a labelmap.
+    /// Voxels without a value (NaN) are left out, of the counts as well.
+    pub fn from_indices(volume: &Volume, name: String, indices: Vec<usize>, bins: usize) -> RoiStatistics {
+        let mut values: Vec<f32> = indices.into_iter()
+            .map(|index| volume.data[index])
+            .filter(|value| !value.is_nan())
+            .map(|value| volume.to_hu(value).unwrap_or(value))
+            .collect();
+
+        let units = if volume.hu_range.is_some() { "HU" } else { "normalized" }.to_owned();
+        let voxel_count = values.len();
+        let voxel_volume_mm3 = volume.spacing.x * volume.spacing.y * volume.spacing.z;
+        let volume_ml = voxel_count as f32 * voxel_volume_mm3 / 1000.0;
+
+        if values.is_empty() {
+            return RoiStatistics {
+                name, units, voxel_count, volume_ml,
+                mean: f32::NAN, median: f32::NAN, std_dev: f32::NAN, min: f32::NAN, max: f32::NAN,
+                histogram: Histogram { min: f32::NAN, max: f32::NAN, counts: vec![0; bins.max(1)] }
+            };
+        }
+
+        values.sort_by(|a, b| a.total_cmp(b));
+
+        let n = values.len() as f64;
+        let mean = values.iter().map(|v| *v as f64).sum::<f64>() / n;
+        let variance = values.iter().map(|v| (*v as f64 - mean).powi(2)).sum::<f64>() / n;
+        let median = if values.len() % 2 == 0 {
+            0.5 * (values[values.len()/2 - 1] + values[values.len()/2])
+        } else {
+            values[values.len()/2]
+        };
+        let min = values[0];
+        let max = values[values.len() - 1];
+
+        RoiStatistics {
+            name,
+            units,
+            voxel_count,
+            volume_ml,
+            mean: mean as f32,
+            median,
+            std_dev: variance.sqrt() as f32,
+            min,
+            max,
+            histogram: Histogram::new(&values, min, max, bins)
+        }
+    }
+
+    pub fn compute_all(volume: &Volume, rois: &[NamedRoi], bins: usize) -> Vec<RoiStatistics> {
+        rois.iter().map(|named| RoiStatistics::compute(volume, named.name.clone(), &named.roi, bins)).collect()
+    }
+
+    pub fn describe(&self) -> String {
+        format!("{}: {} voxels, {:.2} mL, mean {:.1} +/- {:.1}, median {:.1}, min {:.1}, max {:.1} ({})",
+            self.name, self.voxel_count, self.volume_ml, self.mean, self.std_dev, self.median, self.min, self.max, self.units)
+    }
+}
+
+pub const CSV_HEADER: &str = "name,units,voxel_count,volume_ml,mean,median,std_dev,min,max,histogram_min,histogram_max,histogram_counts";
+
+pub fn to_csv(statistics: &[RoiStatistics]) -> String {
+    let mut csv = String::from(CSV_HEADER);
+    csv.push('\n');
+    for s in statistics.iter() {
+        let counts: Vec<String> = s.histogram.counts.iter().map(|count| count.to_string()).collect();
+        csv.push_str(&format!("\"{}\",{},{},{},{},{},{},{},{},{},{},{}\n",
+            s.name.replace('"', "\"\""), s.units, s.voxel_count, s.volume_ml, s.mean, s.median, s.std_dev, s.min, s.max,
+            s.histogram.min, s.histogram.max, counts.join(" ")));
+    }
+    csv
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::{IVec3, Vec2, Vec3};
+
+    use super::*;
+    use crate::types::roi::SliceContour;
+
+    /// 10 voxels along each axis, 1 mm apart, each holding its x index
+    fn ramp_volume() -> Volume {
+        let mut volume = Volume::new(Vec3::ONE, IVec3::splat(10));
+        for z in 0..10 {
+            for y in 0..10 {
+                for x in 0..10 {
+                    volume.set(IVec3::new(x, y, z), x as f32);
+                }
+            }
+        }
+        volume
+    }
+
+    #[test]
+    fn regions_select_the_voxels_whose_centres_they_contain() {
+        let volume = ramp_volume();
+
+        // Centres at 0.5 to 3.5 mm along each axis
+        let cube = RoiStatistics::compute(&volume, "box".to_owned(), &Roi::Box { min: Vec3::ZERO, max: Vec3::splat(4.0) }, 4);
+        assert_eq!(cube.voxel_count, 64);
+        assert!((cube.volume_ml - 0.064).abs() < 1e-6);
+        assert_eq!((cube.mean, cube.median, cube.min, cube.max), (1.5, 1.5, 0.0, 3.0));
+        assert_eq!(cube.histogram.counts, vec![16, 16, 16, 16]);
+
+        // The 2 x 2 x 2 voxels around a corner between voxel centres
+        let sphere = RoiStatistics::compute(&volume, "sphere".to_owned(), &Roi::Sphere { center: Vec3::splat(5.0), radius: 1.0 }, 4);
+        assert_eq!(sphere.voxel_count, 8);
+        assert_eq!(sphere.median, 4.5);
+
+        let square = vec![Vec2::new(1.0, 1.0), Vec2::new(1.0, 3.0), Vec2::new(3.0, 3.0), Vec2::new(3.0, 1.0)];
+        let freehand = Roi::Freehand { contours: vec![SliceContour { slice: 2, points: square }] };
+        let outline = RoiStatistics::compute(&volume, "freehand".to_owned(), &freehand, 4);
+        assert_eq!(outline.voxel_count, 4);
+        assert_eq!(outline.mean, 1.5);
+    }
+
+    #[test]
+    fn voxels_without_a_value_are_left_out() {
+        let mut volume = ramp_volume();
+        volume.set(IVec3::new(3, 0, 0), f32::NAN);
+        let statistics = RoiStatistics::compute(&volume, "box".to_owned(), &Roi::Box { min: Vec3::ZERO, max: Vec3::splat(4.0) }, 4);
+        assert_eq!(statistics.voxel_count, 63);
+        assert!(statistics.mean.is_finite() && statistics.max == 3.0);
+    }
+
+    #[test]
+    fn reports_list_every_region() {
+        let volume = ramp_volume();
+        let rois = vec![
+            NamedRoi { name: "say \"a\"".to_owned(), roi: Roi::Box { min: Vec3::ZERO, max: Vec3::splat(4.0) } },
+            NamedRoi { name: "empty".to_owned(), roi: Roi::Sphere { center: Vec3::splat(-50.0), radius: 1.0 } }
+        ];
+        let statistics = RoiStatistics::compute_all(&volume, &rois, 2);
+
+        let csv = to_csv(&statistics);
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines[0], CSV_HEADER);
+        assert_eq!(lines[1], "\"say \"\"a\"\"\",normalized,64,0.064,1.5,1.5,1.118034,0,3,0,3,32 32");
+        assert!(lines[2].starts_with("\"empty\",normalized,0,0,NaN"));
+
+        let json: serde_json::Value = serde_json::from_str(&serde_json::to_string(&statistics).unwrap()).unwrap();
+        assert_eq!(json[0]["voxel_count"], 64);
+        assert_eq!(json[0]["histogram"]["counts"], serde_json::json!([32, 32]));
+        assert!(json[1]["mean"].is_null());
+    }
+}
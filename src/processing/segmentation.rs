@@ -0,0 +1,237 @@
+use std::collections::VecDeque;
+
+use glam::IVec3;
+
+use crate::types::volume::Volume;
+use crate::types::labelmap::LabelMap;
+
+const CONFIDENCE_INITIAL_RADIUS: i32 = 2;
+const CONFIDENCE_ITERATIONS: usize = 4;
+
+/// How a region decides whether a neighbouring voxel belongs to it.
+/// Values are normalized densities, use `Volume::from_hu` to convert.
+#[derive(Debug, Clone, Copy)]
+pub enum GrowCriterion {
+    /// Voxels with `low <= value <= high`
+    Range { low: f32, high: f32 },
+    /// Voxels within `multiplier` standard deviations of the region's mean, re-estimated
+    /// from the grown region a few times (confidence connected, as in ITK)
+    Confidence { multiplier: f32 }
+}
+
+/// How a mask is written into a labelmap
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LabelOperation {
+    /// Overwrite whatever label is there
+    Replace,
+    /// Reset voxels that currently carry the label to background
+    Erase
+}
+
+/// 6-connected neighbours
+pub const FACE_NEIGHBOURS: [IVec3; 6] = [
+    IVec3::new(-1, 0, 0),
+    IVec3::new(1, 0, 0),
+    IVec3::new(0, -1, 0),
+    IVec3::new(0, 1, 0),
+    IVec3::new(0, 0, -1),
+    IVec3::new(0, 0, 1)
+];
+
+pub fn in_bounds(res: IVec3, coord: IVec3) -> bool {
+    coord.cmpge(IVec3::ZERO).all() && coord.cmplt(res).all()
+}
+
+pub fn voxel_index(res: IVec3, coord: IVec3) -> usize {
+    (coord.z*res.x*res.y + coord.y*res.x + coord.x) as usize
+}
+
+pub fn voxel_coord(res: IVec3, index: usize) -> IVec3 {
+    let index = index as i32;
+    IVec3::new(index % res.x, (index / res.x) % res.y, index / (res.x * res.y))
+}
+
+/// Global threshold, `low <= value <= high`
+pub fn threshold(volume: &Volume, low: f32, high: f32) -> Vec<bool> {
+    volume.data.iter().map(|value| *value >= low && *value <= high).collect()
+}
+
+fn flood_fill(volume: &Volume, seed: IVec3, low: f32, high: f32) -> Vec<bool> {
+    let mut mask = vec![false; volume.data.len()];
+    if !in_bounds(volume.res, seed) {
+        return mask;
+    }
+    let seed_value = volume.get(seed);
+    if seed_value < low || seed_value > high {
+        return mask;
+    }
+    let mut queue = VecDeque::new();
+    mask[voxel_index(volume.res, seed)] = true;
+    queue.push_back(seed);
+    while let Some(coord) = queue.pop_front() {
+        for offset in FACE_NEIGHBOURS.iter() {
+            let neighbour = coord + *offset;
+            if !in_bounds(volume.res, neighbour) {
+                continue;
+            }
+            let index = voxel_index(volume.res, neighbour);
+            if !mask[index] {
+                let value = volume.data[index];
+                if value >= low && value <= high {
+                    mask[index] = true;
+                    queue.push_back(neighbour);
+                }
+            }
+        }
+    }
+    mask
+}
+
+fn mean_and_std_dev(values: impl Iterator<Item = f32>) -> (f32, f32) {
+    let mut count = 0.0f64;
+    let mut sum = 0.0f64;
+    let mut sum_squares = 0.0f64;
+    for value in values {
+        count += 1.0;
+        sum += value as f64;
+        sum_squares += (value as f64) * (value as f64);
+    }
+    if count == 0.0 {
+        return (0.0, 0.0);
+    }
+    let mean = sum / count;
+    let variance = (sum_squares / count - mean * mean).max(0.0);
+    (mean as f32, variance.sqrt() as f32)
+}
+
+/// Seeded 3D region growing over 6-connected voxels
+pub fn region_grow(volume: &Volume, seed: IVec3, criterion: GrowCriterion) -> Vec<bool> {
+    match criterion {
+        GrowCriterion::Range { low, high } => flood_fill(volume, seed, low, high),
+        GrowCriterion::Confidence { multiplier } => {
+            if !in_bounds(volume.res, seed) {
+                return vec![false; volume.data.len()];
+            }
+
+            // Initial estimate from a small cube around the seed
+            let lower = (seed - IVec3::splat(CONFIDENCE_INITIAL_RADIUS)).max(IVec3::ZERO);
+            let upper = (seed + IVec3::splat(CONFIDENCE_INITIAL_RADIUS)).min(volume.res - IVec3::ONE);
+            let mut neighbourhood = Vec::new();
+            for z in lower.z..=upper.z {
+                for y in lower.y..=upper.y {
+                    for x in lower.x..=upper.x {
+                        neighbourhood.push(volume.get(IVec3::new(x, y, z)));
+                    }
+                }
+            }
+            let (mut mean, mut std_dev) = mean_and_std_dev(neighbourhood.into_iter());
+
+            let mut mask = Vec::new();
+            for _ in 0..CONFIDENCE_ITERATIONS {
+                mask = flood_fill(volume, seed, mean - multiplier * std_dev, mean + multiplier * std_dev);
+                let region = mask.iter().zip(volume.data.iter()).filter(|(inside, _)| **inside).map(|(_, value)| *value);
+                let (new_mean, new_std_dev) = mean_and_std_dev(region);
+                if new_mean == mean && new_std_dev == std_dev {
+                    break;
+                }
+                mean = new_mean;
+                std_dev = new_std_dev;
+            }
+            mask
+        }
+    }
+}
+
+/// Write `mask` into `labelmap` and make sure the label has a table entry
+pub fn apply_mask(labelmap: &mut LabelMap, mask: &[bool], label: u16, operation: LabelOperation) {
+    for (value, inside) in labelmap.data.iter_mut().zip(mask.iter()) {
+        if !*inside {
+            continue;
+        }
+        match operation {
+            LabelOperation::Replace => *value = label,
+            LabelOperation::Erase => if *value == label { *value = 0 }
+        }
+    }
+    if operation != LabelOperation::Erase && label != 0 {
+        labelmap.table.get_or_insert(label);
+    }
+}
+
+/// Smallest label value that is not in use yet
+pub fn next_free_label(labelmap: &LabelMap) -> u16 {
+    let mut value = 1;
+    while labelmap.table.get(value).is_some() {
+        value += 1;
+    }
+    value
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::Vec3;
+
+    use super::*;
+
+    fn count(mask: &[bool]) -> usize {
+        mask.iter().filter(|value| **value).count()
+    }
+
+    /// 12 voxels along each axis, `value(coord)` in each
+    fn volume_from(value: impl Fn(IVec3) -> f32) -> Volume {
+        let res = IVec3::splat(12);
+        let mut volume = Volume::new(Vec3::ONE, res);
+        volume.data = (0..volume.data.len()).map(|index| value(voxel_coord(res, index))).collect();
+        volume
+    }
+
+    #[test]
+    fn thresholds_include_both_ends() {
+        let volume = volume_from(|coord| coord.x as f32);
+        let mask = threshold(&volume, 2.0, 4.0);
+        assert_eq!(count(&mask), 3 * 12 * 12);
+        assert!(mask.iter().enumerate().all(|(index, inside)| *inside == (2..=4).contains(&voxel_coord(volume.res, index).x)));
+    }
+
+    #[test]
+    fn range_growing_stays_in_the_seeded_region() {
+        // Two slabs of 1.0 separated by a wall of 0.0 at x = 4
+        let volume = volume_from(|coord| if coord.x == 4 { 0.0 } else { 1.0 });
+        let range = GrowCriterion::Range { low: 0.5, high: 2.0 };
+
+        let mask = region_grow(&volume, IVec3::new(1, 5, 5), range);
+        assert_eq!(count(&mask), 4 * 12 * 12);
+        assert!(mask.iter().enumerate().all(|(index, inside)| *inside == (voxel_coord(volume.res, index).x < 4)));
+        assert_eq!(count(&region_grow(&volume, IVec3::new(9, 0, 11), range)), 7 * 12 * 12);
+
+        // Seeds outside the range or the volume grow nothing
+        assert_eq!(count(&region_grow(&volume, IVec3::new(4, 5, 5), range)), 0);
+        assert_eq!(count(&region_grow(&volume, IVec3::new(12, 5, 5), range)), 0);
+    }
+
+    #[test]
+    fn confidence_growing_finds_the_seeded_region() {
+        // Two noisy regions, 0.3 below x = 6 and 0.7 from there on
+        let noise = |coord: IVec3| ((coord.x * 7 + coord.y * 13 + coord.z * 31) % 11) as f32 / 10.0 * 0.04 - 0.02;
+        let volume = volume_from(|coord| if coord.x < 6 { 0.3 } else { 0.7 } + noise(coord));
+
+        let mask = region_grow(&volume, IVec3::new(2, 6, 6), GrowCriterion::Confidence { multiplier: 2.5 });
+        assert!(mask.iter().enumerate().all(|(index, inside)| *inside == (voxel_coord(volume.res, index).x < 6)));
+        let mask = region_grow(&volume, IVec3::new(10, 0, 0), GrowCriterion::Confidence { multiplier: 2.5 });
+        assert_eq!(count(&mask), 6 * 12 * 12);
+        assert!(!mask[voxel_index(volume.res, IVec3::new(5, 0, 0))]);
+    }
+
+    #[test]
+    fn masks_replace_and_erase_labels() {
+        let res = IVec3::new(4, 1, 1);
+        let mut labelmap = LabelMap::new(res, Vec3::ONE);
+        apply_mask(&mut labelmap, &[true, true, false, false], 2, LabelOperation::Replace);
+        apply_mask(&mut labelmap, &[false, true, true, false], 3, LabelOperation::Replace);
+        assert_eq!(labelmap.data, vec![2, 3, 3, 0]);
+        assert_eq!(next_free_label(&labelmap), 1);
+
+        apply_mask(&mut labelmap, &[true, true, true, true], 3, LabelOperation::Erase);
+        assert_eq!(labelmap.data, vec![2, 0, 0, 0]);
+    }
+}
@@ -0,0 +1,64 @@
+use std::io::Write;
+use std::path::Path;
+use std::time::Duration;
+
+use subprocess::{Exec, Redirection};
+
+use crate::types::ct3d_error::CT3DError;
+use crate::types::application_state::ApplicationState;
+use crate::types::camera_state::{CameraState, KeyframeAnimation};
+
+const VIDEO_EXTENSIONS: [&str; 5] = ["mp4", "mkv", "webm", "avi", "mov"];
+
+pub fn is_video_path(path: &str) -> bool {
+    match Path::new(path).extension().and_then(|extension| extension.to_str()) {
+        Some(extension) => VIDEO_EXTENSIONS.contains(&extension.to_ascii_lowercase().as_str()),
+        None => false
+    }
+}
+
+/// Render a single camera state into `application_state.screen_buffer`
+pub fn render_camera_state(application_state: &mut ApplicationState, camera_state: &CameraState) -> Result<(), CT3DError> {
+    camera_state.apply(application_state);
+    crate::application::update_transfer_function(application_state)?;
+    crate::application::main(application_state, Duration::ZERO)
+}
+
+/// Render every frame of `animation` at a fixed frame rate.
+/// `output` is either a directory (one PPM per frame) or a video file encoded by ffmpeg.
+pub fn render_animation(application_state: &mut ApplicationState, animation: &KeyframeAnimation, fps: f32, output: String) -> Result<usize, CT3DError> {
+
+    let frame_count = animation.frame_count(fps);
+
+    if is_video_path(&output) {
+        let size = format!("{}x{}", application_state.width, application_state.height);
+        let rate = format!("{}", fps);
+        let mut process = Exec::cmd("ffmpeg")
+            .args(&["-y", "-loglevel", "error", "-f", "rawvideo", "-pix_fmt", "rgb24", "-s", size.as_str(), "-r", rate.as_str(), "-i", "-", "-pix_fmt", "yuv420p", output.as_str()])
+            .stdin(Redirection::Pipe)
+            .popen()?;
+
+        for frame in 0..frame_count {
+            let camera_state = animation.sample(frame as f32 / fps).unwrap();
+            render_camera_state(application_state, &camera_state)?;
+            process.stdin.as_mut().unwrap().write_all(&application_state.screen_buffer.to_rgb_bytes())?;
+        }
+
+        // Closing stdin tells ffmpeg the stream is finished
+        process.stdin.take();
+        let exit_status = process.wait()?;
+        if !exit_status.success() {
+            return Err(std::io::Error::other("ffmpeg exited with non-zero code").into());
+        }
+    } else {
+        std::fs::create_dir_all(&output)?;
+        for frame in 0..frame_count {
+            let camera_state = animation.sample(frame as f32 / fps).unwrap();
+            render_camera_state(application_state, &camera_state)?;
+            let frame_path = Path::new(&output).join(format!("frame_{:05}.ppm", frame));
+            application_state.screen_buffer.serialize_to_ppm(frame_path.to_str().unwrap().to_owned())?;
+        }
+    }
+
+    Ok(frame_count)
+}
@@ -0,0 +1,49 @@
+use std::time::Instant;
+
+use crate::types::ct3d_error::CT3DError;
+use crate::types::application_state::{ApplicationState, FilterPreview};
+use crate::processing::filters::PREVIEW_FILTERS;
+
+/// Show the next filter of `PREVIEW_FILTERS` on the device without touching the volume.
+/// After the last one the original is shown again.
+pub fn cycle_preview(application_state: &mut ApplicationState) -> Result<(), CT3DError> {
+    let next = match application_state.filter_preview.as_ref() {
+        Some(preview) => preview.index + 1,
+        None => 0
+    };
+    if next >= PREVIEW_FILTERS.len() {
+        return discard_preview(application_state);
+    }
+    let volume = match application_state.volume.as_ref() {
+        Some(volume) => volume,
+        None => return Ok(())
+    };
+
+    let filter = PREVIEW_FILTERS[next];
+    let start = Instant::now();
+    let filtered = filter.apply_with_opencl(&application_state.opencl_state, volume)?;
+    println!("Previewing {} ({:.2} s), Shift+V applies it", filter.describe(), start.elapsed().as_secs_f32());
+
+    crate::application::upload_volume(&mut application_state.opencl_state, &filtered)?;
+    application_state.filter_preview = Some(FilterPreview { index: next, filter, volume: Box::new(filtered) });
+    Ok(())
+}
+
+/// Go back to showing the unfiltered volume
+pub fn discard_preview(application_state: &mut ApplicationState) -> Result<(), CT3DError> {
+    if application_state.filter_preview.take().is_some() {
+        println!("Filter preview off");
+        if let Some(volume) = application_state.volume.as_ref() {
+            crate::application::upload_volume(&mut application_state.opencl_state, volume)?;
+        }
+    }
+    Ok(())
+}
+
+/// Replace the volume with the previewed one. Measurements, ROIs and labels stay valid as the grid is unchanged.
+pub fn apply_preview(application_state: &mut ApplicationState) {
+    if let Some(preview) = application_state.filter_preview.take() {
+        println!("Applied {}", preview.filter.describe());
+        application_state.volume = Some(preview.volume);
+    }
+}
@@ -0,0 +1,184 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use ocl::Event;
+use ocl::enums::{ProfilingInfo, ProfilingInfoResult};
+
+use crate::types::application_state::ApplicationState;
+use crate::tools::bitmap_font::GLYPH_HEIGHT;
+
+const STATS_WINDOW: usize = 60; // Frames and kernel runs averaged by the statistics overlay
+pub const MIN_FPS: f32 = 1.0; // Lowest frame rate a limit can be set to
+const OVERLAY_MARGIN: i32 = 8;
+const OVERLAY_TEXT_SCALE: usize = 2;
+const OVERLAY_COLOR: (u8, u8, u8) = (255, 255, 160);
+
+/// Frame and kernel times of the last few frames, for the statistics overlay
+pub struct FrameStats {
+    frame_seconds: VecDeque<f32>,
+    kernel_seconds: VecDeque<f32>,
+    pending_kernels: Vec<Event> // Kernels whose profiling times are not available yet
+}
+
+fn push_bounded(values: &mut VecDeque<f32>, value: f32) {
+    if values.len() == STATS_WINDOW {
+        values.pop_front();
+    }
+    values.push_back(value);
+}
+
+fn mean(values: &VecDeque<f32>) -> Option<f32> {
+    if values.is_empty() { None } else { Some(values.iter().sum::<f32>() / values.len() as f32) }
+}
+
+fn profiled_nanoseconds(event: &Event, info: ProfilingInfo) -> Option<u64> {
+    event.profiling_info(info).ok().and_then(|result: ProfilingInfoResult| result.time().ok())
+}
+
+impl Default for FrameStats {
+    fn default() -> Self {
+        FrameStats::new()
+    }
+}
+
+impl FrameStats {
+    pub fn new() -> FrameStats {
+        FrameStats { frame_seconds: VecDeque::new(), kernel_seconds: VecDeque::new(), pending_kernels: Vec::new() }
+    }
+
+    pub fn record_frame(&mut self, delta_time: Duration) {
+        push_bounded(&mut self.frame_seconds, delta_time.as_secs_f32());
+    }
+
+    pub fn record_kernel_seconds(&mut self, seconds: f32) {
+        push_bounded(&mut self.kernel_seconds, seconds);
+    }
+
+    /// Remember a kernel run, enqueued on a queue with profiling enabled, to time once it has finished
+    pub fn track_kernel(&mut self, event: Event) {
+        self.pending_kernels.push(event);
+    }
+
+    /// Record the kernels that have finished, without waiting for the others
+    pub fn collect_kernel_times(&mut self) {
+        let mut still_running = Vec::new();
+        for event in self.pending_kernels.drain(..) {
+            if !event.is_complete().unwrap_or(true) {
+                still_running.push(event);
+                continue;
+            }
+            // Events without profiling information, e.g. from a queue without profiling, are dropped
+            if let (Some(start), Some(end)) = (profiled_nanoseconds(&event, ProfilingInfo::Start), profiled_nanoseconds(&event, ProfilingInfo::End)) {
+                push_bounded(&mut self.kernel_seconds, end.saturating_sub(start) as f32 * 1e-9);
+            }
+        }
+        self.pending_kernels = still_running;
+    }
+
+    pub fn frames_per_second(&self) -> Option<f32> {
+        mean(&self.frame_seconds).filter(|seconds| *seconds > 0.0).map(|seconds| 1.0 / seconds)
+    }
+
+    pub fn mean_frame_milliseconds(&self) -> Option<f32> {
+        mean(&self.frame_seconds).map(|seconds| seconds * 1000.0)
+    }
+
+    /// Average time of one run of the render kernel
+    pub fn mean_kernel_milliseconds(&self) -> Option<f32> {
+        mean(&self.kernel_seconds).map(|seconds| seconds * 1000.0)
+    }
+
+    /// E.g. "60.0 FPS  16.7 ms  kernel 4.20 ms"
+    pub fn summary(&self) -> String {
+        let mut summary = match (self.frames_per_second(), self.mean_frame_milliseconds()) {
+            (Some(fps), Some(milliseconds)) => format!("{:.1} FPS  {:.1} ms", fps, milliseconds),
+            _ => "-- FPS".to_owned()
+        };
+        if let Some(milliseconds) = self.mean_kernel_milliseconds() {
+            summary += &format!("  kernel {:.2} ms", milliseconds);
+        }
+        summary
+    }
+}
+
+/// Sleeps away what is left of each frame so the loop runs at most `max_fps` times a second
+pub struct FrameLimiter {
+    frame_duration: Option<Duration>,
+    next_frame: Instant
+}
+
+impl FrameLimiter {
+    /// No limit for `None` or a rate of 0. Rates below `MIN_FPS` are raised to it.
+    pub fn new(max_fps: Option<f32>) -> FrameLimiter {
+        let frame_duration = max_fps.filter(|fps| *fps > 0.0).map(|fps| Duration::from_secs_f32(1.0 / fps.max(MIN_FPS)));
+        FrameLimiter { frame_duration, next_frame: Instant::now() }
+    }
+
+    pub fn wait(&mut self) {
+        if let Some(frame_duration) = self.frame_duration {
+            let now = Instant::now();
+            if self.next_frame > now {
+                std::thread::sleep(self.next_frame - now);
+            }
+            // A frame that ran late moves the schedule instead of making the next ones hurry to catch up
+            self.next_frame = self.next_frame.max(now) + frame_duration;
+        }
+    }
+}
+
+pub fn draw_overlays(application_state: &mut ApplicationState) {
+    if !application_state.show_frame_stats {
+        return;
+    }
+    let summary = application_state.frame_stats.summary();
+    // Top right, out of the way of the HUD
+    let width = crate::tools::bitmap_font::text_width(&summary, OVERLAY_TEXT_SCALE) as i32;
+    let (x, y) = (application_state.width as i32 - OVERLAY_MARGIN - width, OVERLAY_MARGIN);
+    let height = (GLYPH_HEIGHT * OVERLAY_TEXT_SCALE) as i32;
+    application_state.screen_buffer.blend_rect(x - 4, y - 4, width + 8, height + 8, (0, 0, 0), 0.6);
+    application_state.screen_buffer.draw_text(x, y, &summary, OVERLAY_TEXT_SCALE, OVERLAY_COLOR);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stats_average_the_last_frames() {
+        let mut stats = FrameStats::new();
+        assert_eq!(stats.summary(), "-- FPS");
+        for _ in 0..STATS_WINDOW {
+            stats.record_frame(Duration::from_millis(100));
+        }
+        for _ in 0..STATS_WINDOW {
+            stats.record_frame(Duration::from_millis(20));
+        }
+        stats.record_kernel_seconds(0.004);
+        stats.record_kernel_seconds(0.002);
+        assert!((stats.frames_per_second().unwrap() - 50.0).abs() < 0.01);
+        assert_eq!(stats.summary(), "50.0 FPS  20.0 ms  kernel 3.00 ms");
+    }
+
+    #[test]
+    fn limiter_keeps_frames_apart() {
+        let mut limiter = FrameLimiter::new(Some(200.0));
+        let start = Instant::now();
+        for _ in 0..11 {
+            limiter.wait();
+        }
+        // The first frame goes out at once, the other ten at 5 ms intervals
+        assert!(start.elapsed() >= Duration::from_millis(50));
+
+        let mut unlimited = FrameLimiter::new(None);
+        let start = Instant::now();
+        for _ in 0..1000 {
+            unlimited.wait();
+        }
+        assert!(start.elapsed() < Duration::from_millis(50));
+
+        // Rates too slow for a frame duration are held at the minimum
+        assert_eq!(FrameLimiter::new(Some(1e-40)).frame_duration, Some(Duration::from_secs_f32(1.0 / MIN_FPS)));
+        assert_eq!(FrameLimiter::new(Some(f32::NAN)).frame_duration, None);
+        assert_eq!(FrameLimiter::new(Some(f32::INFINITY)).frame_duration, Some(Duration::ZERO));
+    }
+}
@@ -0,0 +1,151 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use ocl::{Context, Program};
+
+use crate::types::ct3d_error::CT3DError;
+use crate::tools::resources::load_resource;
+
+pub const KERNEL_HELPERS_DIRECTORY: &str = "kernel_helpers";
+const DEFINES_FILE_NAME: &str = "<defines>";
+
+/// Kernel source with its `#include`s expanded and `#define`s injected, remembering where every line
+/// came from so compiler messages can point at the original files
+pub struct KernelSource {
+    pub text: String,
+    pub defines: Vec<(String, String)>,
+    file_names: Vec<String>,
+    lines: Vec<(usize, usize)>, // index into file_names, line number in that file (1 based)
+    watched: Vec<(PathBuf, Option<SystemTime>)>
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+/// The file name of an `#include "name"` or `#include <name>` line
+fn include_target(line: &str) -> Option<&str> {
+    let rest = line.trim_start().strip_prefix('#')?.trim_start().strip_prefix("include")?.trim();
+    let name = rest.strip_prefix('"').and_then(|rest| rest.strip_suffix('"'))
+        .or_else(|| rest.strip_prefix('<').and_then(|rest| rest.strip_suffix('>')))?;
+    Some(name)
+}
+
+impl KernelSource {
+    /// Load the resource `relative_path` (e.g. "kernels/render.cl"), preceded by
+    /// `defines`. Included files are resolved relative to `kernel_helpers/` and each is pasted only once,
+    /// so helpers can include what they depend on.
+    pub fn load(relative_path: &str, defines: Vec<(String, String)>) -> Result<KernelSource, CT3DError> {
+        let mut source = KernelSource {
+            text: String::new(),
+            defines: Vec::new(),
+            file_names: vec![DEFINES_FILE_NAME.to_owned()],
+            lines: Vec::new(),
+            watched: Vec::new()
+        };
+        for (line_number, (name, value)) in defines.iter().enumerate() {
+            source.push_line(&format!("#define {} {}", name, value), 0, line_number + 1);
+        }
+        source.defines = defines;
+
+        source.append_file(relative_path.to_owned(), None)?;
+        Ok(source)
+    }
+
+    fn push_line(&mut self, line: &str, file: usize, line_number: usize) {
+        self.text.push_str(line);
+        self.text.push('\n');
+        self.lines.push((file, line_number));
+    }
+
+    fn append_file(&mut self, relative_path: String, included_from: Option<(usize, usize)>) -> Result<(), CT3DError> {
+        if self.file_names.contains(&relative_path) {
+            return Ok(());
+        }
+        let resource = load_resource(&relative_path).map_err(|e| match included_from {
+            Some((file, line_number)) => e.context(format!("{}:{}: cannot include {}", self.file_names[file], line_number, relative_path)),
+            None => e
+        })?;
+
+        let file = self.file_names.len();
+        self.file_names.push(relative_path);
+        if let Some(path) = resource.path {
+            self.watched.push((path.clone(), modified_time(&path)));
+        }
+
+        for (index, line) in resource.text.lines().enumerate() {
+            match include_target(line) {
+                Some(name) => self.append_file(format!("{}/{}", KERNEL_HELPERS_DIRECTORY, name), Some((file, index + 1)))?,
+                None => self.push_line(line, file, index + 1)
+            }
+        }
+        Ok(())
+    }
+
+    /// File and line of a line of `text` (1 based)
+    pub fn locate(&self, line: usize) -> Option<(&str, usize)> {
+        let (file, line_number) = *self.lines.get(line.checked_sub(1)?)?;
+        Some((self.file_names[file].as_str(), line_number))
+    }
+
+    /// Rewrite the `<source>:line:column:` locations compilers put in build logs to the original files
+    pub fn map_build_log(&self, log: &str) -> String {
+        log.lines().map(|line| self.map_build_log_line(line)).collect::<Vec<String>>().join("\n")
+    }
+
+    fn map_build_log_line(&self, line: &str) -> String {
+        if let Some(colon) = line.find(':') {
+            let (prefix, rest) = (&line[..colon], &line[colon + 1..]);
+            let digits = rest.chars().take_while(|c| c.is_ascii_digit()).count();
+            if digits > 0 && !prefix.contains(' ') && rest[digits..].starts_with(':') {
+                if let Some((file, line_number)) = rest[..digits].parse().ok().and_then(|line| self.locate(line)) {
+                    return format!("{}:{}{}", file, line_number, &rest[digits..]);
+                }
+            }
+        }
+        line.to_owned()
+    }
+
+    pub fn build(&self, context: &Context) -> Result<Program, CT3DError> {
+        Program::builder()
+            .src(self.text.as_str())
+            .build(context)
+            .map_err(|e| CT3DError::OpenCL(format!("Kernel build failed:\n{}", self.map_build_log(&e.to_string())).into()))
+    }
+
+    /// Whether any of the files this was assembled from was modified since. Never true for embedded kernels.
+    pub fn changed(&self) -> bool {
+        self.watched.iter().any(|(path, modified)| modified_time(path) != *modified)
+    }
+
+    /// Accept the files as they are now, so a broken edit is only reported once
+    pub fn mark_seen(&mut self) {
+        for (path, modified) in self.watched.iter_mut() {
+            *modified = modified_time(path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_kernel_expands_includes_and_defines() {
+        let source = KernelSource::load("kernels/render.cl", vec![("DOWNSAMPLING".to_owned(), "2".to_owned())]).unwrap();
+        assert!(source.text.starts_with("#define DOWNSAMPLING 2\n"));
+        assert!(!source.text.contains("#include"));
+        // math.cl is included by both render.cl and raycasting.cl
+        assert_eq!(source.text.matches("float3 float3_scaled_by(").count(), 1);
+
+        let kernel_line = source.text.lines().position(|line| line.starts_with("__kernel void render(")).unwrap() + 1;
+        let (file, line_number) = source.locate(kernel_line).unwrap();
+        assert_eq!(file, "kernels/render.cl");
+        assert_eq!(load_resource(file).unwrap().text.lines().nth(line_number - 1), Some("__kernel void render("));
+        assert_eq!(
+            source.map_build_log(&format!("<kernel>:{}:5: error: expected ';'\n1 error generated.", kernel_line)),
+            format!("kernels/render.cl:{}:5: error: expected ';'\n1 error generated.", line_number)
+        );
+    }
+}
@@ -0,0 +1,120 @@
+use crate::types::application_state::ApplicationState;
+use crate::types::measurement::{Measurement, MeasurementKind};
+use crate::tools::picking::{pick_surface, project_to_screen};
+
+const MEASUREMENT_COLOR: (u8, u8, u8) = (255, 220, 0);
+const ACTIVE_MEASUREMENT_COLOR: (u8, u8, u8) = (0, 220, 255);
+const MARKER_RADIUS: i32 = 2;
+
+pub fn cycle_tool(application_state: &mut ApplicationState) {
+    finish_active(application_state);
+    application_state.measurement_tool = MeasurementKind::cycle(application_state.measurement_tool);
+    match application_state.measurement_tool {
+        Some(kind) => println!("Measurement tool: {}", kind.name()),
+        None => println!("Measurement tool: off")
+    }
+}
+
+/// Place a point of the active measurement on the surface under pixel (x, y)
+pub fn place_point(application_state: &mut ApplicationState, x: i32, y: i32) {
+    let kind = match application_state.measurement_tool {
+        Some(kind) => kind,
+        None => return
+    };
+
+    // Prefer what the renderer actually hit, fall back to marching on the CPU
+    let point = match (application_state.probe(x, y), application_state.volume.as_ref()) {
+        (Some(probe), _) => Some(probe.local),
+        (None, Some(volume)) if !application_state.write_hit_buffer => pick_surface(application_state, volume, x as f32 + 0.5, y as f32 + 0.5),
+        _ => None
+    };
+
+    let point = match point {
+        Some(point) => point,
+        None => return
+    };
+
+    let measurement = application_state.active_measurement.get_or_insert_with(|| Measurement::new(kind));
+    measurement.points.push(point);
+
+    if measurement.is_complete() {
+        finish_active(application_state);
+    }
+}
+
+/// Move the active measurement into the list. Measurements without enough points are dropped.
+pub fn finish_active(application_state: &mut ApplicationState) {
+    if let Some(measurement) = application_state.active_measurement.take() {
+        if measurement.is_measurable() {
+            application_state.measurements.push(measurement);
+            print_measurements(application_state);
+        }
+    }
+}
+
+/// Remove the last placed point, or the last finished measurement if nothing is in progress
+pub fn undo(application_state: &mut ApplicationState) {
+    match application_state.active_measurement.as_mut() {
+        Some(measurement) => {
+            measurement.points.pop();
+            if measurement.points.is_empty() {
+                application_state.active_measurement = None;
+            }
+        },
+        None => {
+            if application_state.measurements.pop().is_some() {
+                print_measurements(application_state);
+            }
+        }
+    }
+}
+
+/// Delete the finished measurement at `index`, as picked in the measurement list
+pub fn delete(application_state: &mut ApplicationState, index: usize) {
+    if index < application_state.measurements.len() {
+        application_state.measurements.remove(index);
+        println!("Deleted measurement {}", index + 1);
+        print_measurements(application_state);
+    }
+}
+
+pub fn print_measurements(application_state: &ApplicationState) {
+    let volume = match application_state.volume.as_ref() {
+        Some(volume) => volume,
+        None => return
+    };
+    println!("Measurements:");
+    for (index, measurement) in application_state.measurements.iter().enumerate() {
+        println!("  {}. {}", index + 1, measurement.describe(volume));
+    }
+}
+
+fn draw_measurement(application_state: &mut ApplicationState, measurement: &Measurement, color: (u8, u8, u8)) {
+    let screen_points: Vec<Option<(i32, i32)>> = measurement.points.iter()
+        .map(|point| project_to_screen(application_state, *point).map(|p| (p.x as i32, p.y as i32)))
+        .collect();
+
+    for pair in screen_points.windows(2) {
+        if let (Some(a), Some(b)) = (pair[0], pair[1]) {
+            application_state.screen_buffer.draw_line(a.0, a.1, b.0, b.1, color);
+        }
+    }
+
+    for point in screen_points.iter().flatten() {
+        application_state.screen_buffer.draw_marker(point.0, point.1, MARKER_RADIUS, color);
+    }
+}
+
+/// Draw all measurements over the rendered image
+pub fn draw_overlays(application_state: &mut ApplicationState) {
+    let measurements = std::mem::take(&mut application_state.measurements);
+    for measurement in measurements.iter() {
+        draw_measurement(application_state, measurement, MEASUREMENT_COLOR);
+    }
+    application_state.measurements = measurements;
+
+    if let Some(measurement) = application_state.active_measurement.take() {
+        draw_measurement(application_state, &measurement, ACTIVE_MEASUREMENT_COLOR);
+        application_state.active_measurement = Some(measurement);
+    }
+}
@@ -0,0 +1,125 @@
+use glam::{Vec2, Vec3};
+
+use crate::types::application_state::ApplicationState;
+use crate::types::volume::Volume;
+
+// Must match F in kernels/render.cl
+const FOCAL_LENGTH: f32 = 1.0;
+
+/// Ray through the centre of screen pixel (x, y), expressed in local volume coordinates.
+/// Mirrors the camera setup at the top of the `render` kernel.
+pub fn camera_ray(application_state: &ApplicationState, x: f32, y: f32) -> (Vec3, Vec3) {
+    let w = application_state.width as f32;
+    let h = application_state.height as f32;
+
+    // The shorter side of the screen spans -0.5..0.5
+    let short_side = w.min(h);
+    let u = (x / w - 0.5) * w / short_side;
+    let v = (0.5 - y / h) * h / short_side;
+
+    let ro = Vec3::new(0.0, 0.0, application_state.camera_z);
+    let rd = Vec3::new(u, v, FOCAL_LENGTH).normalize();
+
+    (world_to_local(application_state, ro), world_to_local(application_state, rd))
+}
+
+pub fn world_to_local(application_state: &ApplicationState, world: Vec3) -> Vec3 {
+    Vec3::new(
+        application_state.RIGHT.dot(world),
+        application_state.UP.dot(world),
+        application_state.FORWARD.dot(world)
+    )
+}
+
+pub fn local_to_world(application_state: &ApplicationState, local: Vec3) -> Vec3 {
+    application_state.RIGHT * local.x + application_state.UP * local.y + application_state.FORWARD * local.z
+}
+
+/// Screen position of a point in local volume coordinates, or `None` if it is behind the camera
+pub fn project_to_screen(application_state: &ApplicationState, local: Vec3) -> Option<Vec2> {
+    let w = application_state.width as f32;
+    let h = application_state.height as f32;
+
+    let relative = local_to_world(application_state, local) - Vec3::new(0.0, 0.0, application_state.camera_z);
+    if relative.z <= 1.0e-6 {
+        return None;
+    }
+
+    let u = relative.x / relative.z * FOCAL_LENGTH;
+    let v = relative.y / relative.z * FOCAL_LENGTH;
+
+    let short_side = w.min(h);
+    Some(Vec2::new((u * short_side / w + 0.5) * w, (0.5 - v * short_side / h) * h))
+}
+
+/// Parameters at which the ray enters and leaves the box [-radii, radii]
+fn ray_box_interval(ro: Vec3, rd: Vec3, radii: Vec3) -> Option<(f32, f32)> {
+    let inverse = rd.recip();
+    let t0 = (-radii - ro) * inverse;
+    let t1 = (radii - ro) * inverse;
+    let t_near = t0.min(t1).max_element().max(0.0);
+    let t_far = t0.max(t1).min_element();
+    if t_near > t_far {
+        return None;
+    }
+    Some((t_near, t_far))
+}
+
+/// First point along the ray through pixel (x, y) whose density reaches `low_cutoff`,
+/// found with the same fixed step march as the renderer
+pub fn pick_surface(application_state: &ApplicationState, volume: &Volume, x: f32, y: f32) -> Option<Vec3> {
+    let (ro, rd) = camera_ray(application_state, x, y);
+    let (t_near, t_far) = ray_box_interval(ro, rd, volume.radii)?;
+
+    let cell_size = 2.0 * volume.radii / volume.res.as_vec3();
+    // DOWNSAMPLING in the render kernel is defined from the kernel config
+    let step = cell_size.min_element() * application_state.opencl_state.kernel_config.downsampling as f32;
+
+    let mut t = t_near;
+    while t <= t_far {
+        let point = ro + rd * t;
+        if !application_state.clip_box.contains_local(point, volume.radii) {
+            t += step;
+            continue;
+        }
+        if let Some(value) = volume.query_local(point) {
+            if value >= application_state.low_cutoff {
+                return Some(point);
+            }
+        }
+        t += step;
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::Quat;
+
+    use super::*;
+    use crate::types::camera_state::CameraState;
+
+    #[test]
+    fn rays_pass_through_projected_points() {
+        for (width, height) in [(1920, 1080), (600, 1000)] {
+            let mut application_state = ApplicationState::new(width, height);
+            let view = CameraState { orientation: Quat::from_euler(glam::EulerRot::YXZ, 0.7, -0.4, 0.1), camera_z: -3.0, low_cutoff: 0.0, transfer_function: None, clip_box: None };
+            view.apply(&mut application_state);
+
+            for local in [Vec3::ZERO, Vec3::new(0.4, -0.3, 0.2), Vec3::new(-0.9, 0.8, -0.5)] {
+                let pixel = project_to_screen(&application_state, local).unwrap();
+                assert!(pixel.x >= 0.0 && pixel.x <= width as f32 && pixel.y >= 0.0 && pixel.y <= height as f32, "{} at {}", local, pixel);
+                let (ro, rd) = camera_ray(&application_state, pixel.x, pixel.y);
+                let along = (local - ro).dot(rd);
+                assert!(along > 0.0);
+                assert!((ro + rd * along).distance(local) < 1e-4, "{}x{}: ray misses {} by {}", width, height, local, (ro + rd * along).distance(local));
+            }
+
+            // The centre of the screen looks straight ahead, points behind the camera do not project
+            let (_, rd) = camera_ray(&application_state, width as f32 / 2.0, height as f32 / 2.0);
+            assert!(local_to_world(&application_state, rd).abs_diff_eq(Vec3::Z, 1e-6));
+            assert!(project_to_screen(&application_state, world_to_local(&application_state, Vec3::new(0.0, 0.0, -4.0))).is_none());
+        }
+    }
+}
@@ -1,22 +1,47 @@
 use std::env;
-use std::path::Path;
-use std::fs::File;
-use std::io::Read;
+use std::fs;
+use std::path::PathBuf;
 
-use crate::types::ct3d_error::CT3DError;
+use crate::types::ct3d_error::{CT3DError, ErrorContext};
 
-pub fn get_resource_path(relative_path: String) -> Result<String, CT3DError> {
-    let mut exe_dir = env::current_exe()?;
-    exe_dir.pop();
-    let exe_dir_path = exe_dir.to_str().unwrap().to_owned();
-    let resource_path = Path::new(&exe_dir_path).join(relative_path.clone());
-    Ok(resource_path.to_str().unwrap().to_owned())
+/// Directory to read resources from instead of the copies compiled into the binary, for editing
+/// kernels while the viewer runs: `CT3D_RESOURCE_DIR=src cargo run`
+pub const RESOURCE_DIRECTORY_VARIABLE: &str = "CT3D_RESOURCE_DIR";
+
+/// Resources compiled into the binary, by path relative to src/
+const EMBEDDED_RESOURCES: [(&str, &str); 4] = [
+    ("kernels/render.cl", include_str!("../kernels/render.cl")),
+    ("kernels/filters.cl", include_str!("../kernels/filters.cl")),
+    ("kernel_helpers/math.cl", include_str!("../kernel_helpers/math.cl")),
+    ("kernel_helpers/raycasting.cl", include_str!("../kernel_helpers/raycasting.cl"))
+];
+
+/// A resource's contents and, when it was read from the override directory, the file to watch
+pub struct Resource {
+    pub text: String,
+    pub path: Option<PathBuf>
+}
+
+/// The override directory, if `RESOURCE_DIRECTORY_VARIABLE` is set
+pub fn resource_directory() -> Option<PathBuf> {
+    env::var_os(RESOURCE_DIRECTORY_VARIABLE).filter(|value| !value.is_empty()).map(PathBuf::from)
+}
+
+/// Look up a resource such as "kernels/render.cl", from the override directory when one is set
+/// and the embedded copy otherwise. Does not depend on where the executable or working directory is.
+pub fn load_resource(relative_path: &str) -> Result<Resource, CT3DError> {
+    if let Some(directory) = resource_directory() {
+        let path = directory.join(relative_path);
+        let text = fs::read_to_string(&path)
+            .with_context(|| format!("Cannot read {} (from {})", path.display(), RESOURCE_DIRECTORY_VARIABLE))?;
+        return Ok(Resource { text, path: Some(path) });
+    }
+    EMBEDDED_RESOURCES.iter()
+        .find(|(name, _)| *name == relative_path)
+        .map(|(_, text)| Resource { text: (*text).to_owned(), path: None })
+        .ok_or_else(|| CT3DError::not_found(format!("No resource named {}", relative_path)))
 }
 
 pub fn read_resource_file_as_text(relative_path: String) -> Result<String, CT3DError> {
-    let resource_path = get_resource_path(relative_path)?;
-    let mut file = File::open(&resource_path)?;
-    let mut file_contents = String::new();
-    file.read_to_string(&mut file_contents)?;
-    Ok(file_contents)
-}
\ No newline at end of file
+    Ok(load_resource(&relative_path)?.text)
+}
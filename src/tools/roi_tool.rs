@@ -0,0 +1,207 @@
+use glam::{Vec2, Vec3};
+
+use crate::types::application_state::ApplicationState;
+use crate::types::roi::{Roi, NamedRoi, SliceContour};
+use crate::processing::roi_statistics::{RoiStatistics, DEFAULT_HISTOGRAM_BINS};
+use crate::tools::picking::{camera_ray, pick_surface, project_to_screen};
+
+const ROI_SIZE_MM: f32 = 10.0; // sphere radius, half the box edge
+const ROI_COLOR: (u8, u8, u8) = (255, 0, 200);
+const SPHERE_OUTLINE_SEGMENTS: usize = 48;
+
+/// Place a sphere or box ROI centred on the surface under pixel (x, y) and print its statistics
+pub fn place_roi(application_state: &mut ApplicationState, x: i32, y: i32, sphere: bool) {
+    let probe = match application_state.probe(x, y) {
+        Some(probe) => probe,
+        None => return
+    };
+    let roi = if sphere {
+        Roi::Sphere { center: probe.patient_mm, radius: ROI_SIZE_MM }
+    } else {
+        Roi::Box { min: probe.patient_mm - Vec3::splat(ROI_SIZE_MM), max: probe.patient_mm + Vec3::splat(ROI_SIZE_MM) }
+    };
+    add_roi(application_state, roi);
+}
+
+fn add_roi(application_state: &mut ApplicationState, roi: Roi) {
+    let name = format!("ROI {}", application_state.rois.len() + 1);
+    let statistics = RoiStatistics::compute(application_state.volume.as_ref().unwrap(), name.clone(), &roi, DEFAULT_HISTOGRAM_BINS);
+    println!("{}", statistics.describe());
+    application_state.rois.push(NamedRoi { name, roi });
+}
+
+/// Start a freehand ROI on the axial slice through the surface under pixel (x, y)
+pub fn start_freehand(application_state: &mut ApplicationState, x: i32, y: i32) {
+    // Prefer what the renderer actually hit, fall back to marching on the CPU
+    let point = match (application_state.probe(x, y), application_state.volume.as_ref()) {
+        (Some(probe), _) => Some(probe.local),
+        (None, Some(volume)) if !application_state.write_hit_buffer => pick_surface(application_state, volume, x as f32 + 0.5, y as f32 + 0.5),
+        _ => None
+    };
+    let (point, volume) = match (point, application_state.volume.as_ref()) {
+        (Some(point), Some(volume)) => (point, volume),
+        _ => return
+    };
+    let slice = (volume.local_to_voxel(point).z.floor() as i32).clamp(0, volume.res.z - 1);
+    application_state.freehand_contour = Some(SliceContour { slice, points: Vec::new() });
+    extend_freehand(application_state, x, y);
+}
+
+/// Add the point where the ray through pixel (x, y) crosses the slice of the freehand ROI being drawn
+pub fn extend_freehand(application_state: &mut ApplicationState, x: i32, y: i32) {
+    let (volume, contour) = match (application_state.volume.as_ref(), application_state.freehand_contour.as_ref()) {
+        (Some(volume), Some(contour)) => (volume, contour),
+        _ => return
+    };
+    let plane_z = volume.mm_to_local(Vec3::new(0.0, 0.0, (contour.slice as f32 + 0.5) * volume.spacing.z)).z;
+    let (ro, rd) = camera_ray(application_state, x as f32 + 0.5, y as f32 + 0.5);
+    // Seen edge-on the slice gives no usable point
+    if rd.z.abs() < 1.0e-6 {
+        return;
+    }
+    let t = (plane_z - ro.z) / rd.z;
+    if t <= 0.0 {
+        return;
+    }
+    let mm = volume.local_to_mm(ro + rd * t);
+    let point = Vec2::new(mm.x, mm.y);
+    // Skip points closer than half a voxel to the last one, a slow drag would add one per pixel
+    let min_step = 0.5 * volume.spacing.x.min(volume.spacing.y);
+    let contour = application_state.freehand_contour.as_mut().unwrap();
+    if !contour.points.last().is_some_and(|last| last.distance(point) < min_step) {
+        contour.points.push(point);
+    }
+}
+
+/// Close the freehand ROI being drawn and print its statistics, or drop it if it has fewer than three points
+pub fn finish_freehand(application_state: &mut ApplicationState) {
+    match application_state.freehand_contour.take() {
+        Some(contour) if contour.points.len() >= 3 => add_roi(application_state, Roi::Freehand { contours: vec![contour] }),
+        _ => {}
+    }
+}
+
+fn draw_polyline_mm(application_state: &mut ApplicationState, points_mm: &[Vec3]) {
+    let volume = application_state.volume.as_ref().unwrap();
+    let screen_points: Vec<Option<(i32, i32)>> = points_mm.iter()
+        .map(|point| project_to_screen(application_state, volume.mm_to_local(*point)).map(|p| (p.x as i32, p.y as i32)))
+        .collect();
+    for pair in screen_points.windows(2) {
+        if let (Some(a), Some(b)) = (pair[0], pair[1]) {
+            application_state.screen_buffer.draw_line(a.0, a.1, b.0, b.1, ROI_COLOR);
+        }
+    }
+}
+
+pub fn draw_overlays(application_state: &mut ApplicationState) {
+    if application_state.volume.is_none() {
+        return;
+    }
+    let rois: Vec<Roi> = application_state.rois.iter().map(|named| named.roi.clone()).collect();
+    for roi in rois.iter() {
+        match roi {
+            Roi::Sphere { center, radius } => {
+                // Three orthogonal great circles
+                for axis in 0..3 {
+                    let outline: Vec<Vec3> = (0..=SPHERE_OUTLINE_SEGMENTS).map(|i| {
+                        let angle = i as f32 / SPHERE_OUTLINE_SEGMENTS as f32 * 2.0 * std::f32::consts::PI;
+                        let (s, c) = angle.sin_cos();
+                        let offset = match axis {
+                            0 => Vec3::new(0.0, c, s),
+                            1 => Vec3::new(c, 0.0, s),
+                            _ => Vec3::new(c, s, 0.0)
+                        };
+                        *center + offset * *radius
+                    }).collect();
+                    draw_polyline_mm(application_state, &outline);
+                }
+            },
+            Roi::Box { min, max } => {
+                let corner = |i: usize| Vec3::new(
+                    if i & 1 == 0 { min.x } else { max.x },
+                    if i & 2 == 0 { min.y } else { max.y },
+                    if i & 4 == 0 { min.z } else { max.z }
+                );
+                for i in 0..8 {
+                    for bit in [1, 2, 4] {
+                        if i & bit == 0 {
+                            draw_polyline_mm(application_state, &[corner(i), corner(i | bit)]);
+                        }
+                    }
+                }
+            },
+            Roi::Freehand { contours } => {
+                let volume = application_state.volume.as_ref().unwrap();
+                let spacing_z = volume.spacing.z;
+                for contour in contours.iter() {
+                    let z = (contour.slice as f32 + 0.5) * spacing_z;
+                    let mut outline: Vec<Vec3> = contour.points.iter().map(|p| Vec3::new(p.x, p.y, z)).collect();
+                    if let Some(first) = outline.first().cloned() {
+                        outline.push(first);
+                    }
+                    draw_polyline_mm(application_state, &outline);
+                }
+            }
+        }
+    }
+    if let Some(contour) = application_state.freehand_contour.clone() {
+        let z = (contour.slice as f32 + 0.5) * application_state.volume.as_ref().unwrap().spacing.z;
+        let outline: Vec<Vec3> = contour.points.iter().map(|p| Vec3::new(p.x, p.y, z)).collect();
+        draw_polyline_mm(application_state, &outline);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::IVec3;
+
+    use super::*;
+    use crate::application::perform;
+    use crate::input::action::Action;
+    use crate::types::volume::Volume;
+
+    fn drag(application_state: &mut ApplicationState, points: &[(i32, i32)]) {
+        perform(&Action::PointerMove { x: points[0].0, y: points[0].1 }, application_state).unwrap();
+        perform(&Action::DrawFreehandRoi, application_state).unwrap();
+        for &(x, y) in points[1..].iter() {
+            perform(&Action::PointerMove { x, y }, application_state).unwrap();
+        }
+        perform(&Action::DrawFreehandRoiEnd, application_state).unwrap();
+    }
+
+    #[test]
+    fn freehand_rois_are_drawn_on_the_slice_under_the_pointer() {
+        // 20 x 20 x 10 voxels of 1 x 1 x 2 mm, dense from slice 4 on
+        let res = IVec3::new(20, 20, 10);
+        let spacing = Vec3::new(1.0, 1.0, 2.0);
+        let mut volume = Volume::new(Volume::radii_for(res, spacing), res);
+        volume.spacing = spacing;
+        for z in 4..res.z {
+            for y in 0..res.y {
+                for x in 0..res.x {
+                    volume.set(IVec3::new(x, y, z), 1.0);
+                }
+            }
+        }
+        let mut application_state = ApplicationState::new(200, 200);
+        application_state.volume = Some(Box::new(volume));
+        application_state.low_cutoff = 0.5;
+
+        // Too few points to enclose anything
+        drag(&mut application_state, &[(100, 100), (120, 100)]);
+        assert!(application_state.rois.is_empty());
+        assert!(application_state.freehand_contour.is_none());
+
+        drag(&mut application_state, &[(80, 80), (120, 80), (120, 120), (80, 120)]);
+        assert_eq!(application_state.rois.len(), 1);
+        let volume = application_state.volume.as_ref().unwrap();
+        let roi = &application_state.rois[0].roi;
+        match roi {
+            Roi::Freehand { contours } => assert_eq!((contours.len(), contours[0].slice, contours[0].points.len()), (1, 4, 4)),
+            _ => panic!("expected a freehand ROI, found {:?}", roi)
+        }
+        assert!(roi.contains_voxel(volume, IVec3::new(10, 10, 4)));
+        assert!(!roi.contains_voxel(volume, IVec3::new(10, 10, 5)));
+        assert!(!roi.contains_voxel(volume, IVec3::new(1, 10, 4)));
+    }
+}
@@ -0,0 +1,157 @@
+use crate::types::ct3d_error::CT3DError;
+use crate::types::application_state::ApplicationState;
+use crate::types::labelmap::LabelMap;
+use crate::processing::segmentation::{threshold, region_grow, apply_mask, next_free_label, GrowCriterion, LabelOperation};
+use crate::processing::roi_statistics::{RoiStatistics, DEFAULT_HISTOGRAM_BINS};
+use crate::processing::connected_components::{Components, Connectivity};
+use crate::processing::morphology::{MorphologyOperation, update_label};
+
+pub const LABELMAP_EXPORT_PATH: &str = "temp/labelmap.nii.gz";
+const CONFIDENCE_MULTIPLIER: f32 = 2.5;
+pub const MIN_ISLAND_ML: f32 = 0.5;
+const CONNECTIVITY: Connectivity = Connectivity::Vertex;
+pub const MORPHOLOGY_RADIUS_MM: f32 = 1.0;
+
+pub fn print_label_statistics(application_state: &ApplicationState, label: u16) {
+    let (volume, labelmap) = match (application_state.volume.as_ref(), application_state.labelmap.as_ref()) {
+        (Some(volume), Some(labelmap)) => (volume, labelmap),
+        _ => return
+    };
+    let name = labelmap.table.get(label).map(|entry| entry.name.clone()).unwrap_or(format!("Label {}", label));
+    let statistics = RoiStatistics::from_indices(volume, name, labelmap.label_indices(label), DEFAULT_HISTOGRAM_BINS);
+    println!("{}", statistics.describe());
+}
+
+/// Write `mask` into a new label, creating the labelmap if there is none yet
+fn add_label(application_state: &mut ApplicationState, mask: Vec<bool>) -> Result<(), CT3DError> {
+    if !mask.iter().any(|inside| *inside) {
+        println!("Nothing to segment.");
+        return Ok(());
+    }
+    if application_state.labelmap.is_none() {
+        let labelmap = LabelMap::for_volume(application_state.volume.as_ref().unwrap());
+        application_state.labelmap = Some(Box::new(labelmap));
+    }
+    let labelmap = application_state.labelmap.as_mut().unwrap();
+    let label = next_free_label(labelmap);
+    apply_mask(labelmap, &mask, label, LabelOperation::Replace);
+    crate::application::upload_labelmap(application_state)?;
+    print_label_statistics(application_state, label);
+    Ok(())
+}
+
+/// Everything at or above the current cutoff becomes a new label
+pub fn threshold_at_cutoff(application_state: &mut ApplicationState) -> Result<(), CT3DError> {
+    let mask = match application_state.volume.as_ref() {
+        Some(volume) => threshold(volume, application_state.low_cutoff, f32::MAX),
+        None => return Ok(())
+    };
+    add_label(application_state, mask)
+}
+
+/// Grow a new label from the voxel under pixel (x, y), either over everything above the
+/// cutoff or by confidence connected statistics around the seed
+pub fn grow_from_pixel(application_state: &mut ApplicationState, x: i32, y: i32, confidence: bool) -> Result<(), CT3DError> {
+    let probe = match application_state.probe(x, y) {
+        Some(probe) => probe,
+        None => return Ok(())
+    };
+    let criterion = if confidence {
+        GrowCriterion::Confidence { multiplier: CONFIDENCE_MULTIPLIER }
+    } else {
+        GrowCriterion::Range { low: application_state.low_cutoff, high: f32::MAX }
+    };
+    let mask = region_grow(application_state.volume.as_ref().unwrap(), probe.voxel, criterion);
+    add_label(application_state, mask)
+}
+
+/// Remove the label under pixel (x, y) from the labelmap
+pub fn erase_label_at_pixel(application_state: &mut ApplicationState, x: i32, y: i32) -> Result<(), CT3DError> {
+    let probe = match application_state.probe(x, y) {
+        Some(probe) => probe,
+        None => return Ok(())
+    };
+    if let Some(labelmap) = application_state.labelmap.as_mut() {
+        let label = labelmap.get(probe.voxel);
+        if label == 0 {
+            return Ok(());
+        }
+        labelmap.data.iter_mut().filter(|value| **value == label).for_each(|value| *value = 0);
+        labelmap.table.labels.retain(|entry| entry.value != label);
+        println!("Erased label {}.", label);
+        crate::application::upload_labelmap(application_state)?;
+    }
+    Ok(())
+}
+
+/// What to keep of the label under the cursor
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IslandCleanup {
+    /// Only the component under the cursor
+    Picked,
+    /// Only the largest component
+    Largest,
+    /// Every component of at least `MIN_ISLAND_ML`
+    RemoveSmall
+}
+
+/// Split the label under pixel (x, y) into connected components and drop the unwanted ones
+pub fn clean_islands_at_pixel(application_state: &mut ApplicationState, x: i32, y: i32, cleanup: IslandCleanup) -> Result<(), CT3DError> {
+    let probe = match application_state.probe(x, y) {
+        Some(probe) => probe,
+        None => return Ok(())
+    };
+    let labelmap = match application_state.labelmap.as_mut() {
+        Some(labelmap) => labelmap,
+        None => return Ok(())
+    };
+    let label = labelmap.get(probe.voxel);
+    if label == 0 {
+        return Ok(());
+    }
+
+    let components = Components::label(&labelmap.label_mask(label), labelmap.res, labelmap.spacing, CONNECTIVITY);
+    println!("Label {} has {} components:", label, components.components.len());
+    for component in components.components.iter() {
+        println!("    {}", component.describe());
+    }
+    let mask = match cleanup {
+        IslandCleanup::Picked => components.pick(probe.voxel),
+        IslandCleanup::Largest => components.keep_largest(1),
+        IslandCleanup::RemoveSmall => components.remove_smaller_than(MIN_ISLAND_ML)
+    };
+    labelmap.restrict_label(label, &mask);
+    crate::application::upload_labelmap(application_state)?;
+    print_label_statistics(application_state, label);
+    Ok(())
+}
+
+/// Apply a morphological operation to the label under pixel (x, y)
+pub fn morph_label_at_pixel(application_state: &mut ApplicationState, x: i32, y: i32, operation: MorphologyOperation, radius_mm: f32) -> Result<(), CT3DError> {
+    let probe = match application_state.probe(x, y) {
+        Some(probe) => probe,
+        None => return Ok(())
+    };
+    let labelmap = match application_state.labelmap.as_mut() {
+        Some(labelmap) => labelmap,
+        None => return Ok(())
+    };
+    let label = labelmap.get(probe.voxel);
+    if label == 0 {
+        return Ok(());
+    }
+    let mask = operation.apply(&labelmap.label_mask(label), labelmap.res, labelmap.spacing, radius_mm);
+    update_label(labelmap, label, &mask);
+    println!("{:?} label {}", operation, label);
+    crate::application::upload_labelmap(application_state)?;
+    print_label_statistics(application_state, label);
+    Ok(())
+}
+
+pub fn export(application_state: &ApplicationState) -> Result<(), CT3DError> {
+    if let Some(labelmap) = application_state.labelmap.as_ref() {
+        labelmap.save(LABELMAP_EXPORT_PATH.to_owned())?;
+        println!("Saved labelmap to {}", LABELMAP_EXPORT_PATH);
+    }
+    Ok(())
+}
@@ -0,0 +1,94 @@
+use crate::types::ct3d_error::{CT3DError, ErrorContext};
+use crate::types::application_state::ApplicationState;
+use crate::types::session::{Session, SESSION_VERSION};
+use crate::types::volume::Volume;
+use crate::types::labelmap::LabelMap;
+
+/// The labelmap saved with a session, next to it: temp/session.json keeps its labels in temp/session_labels.nii.gz
+pub fn labels_path(session_path: &str) -> String {
+    let stem = session_path.rsplit_once('.').filter(|(_, extension)| !extension.contains('/')).map(|(stem, _)| stem).unwrap_or(session_path);
+    format!("{}_labels.nii.gz", stem)
+}
+
+/// Write the session to `application_state.session_path`. The labelmap is saved beside it, edits
+/// included, and becomes the labelmap source.
+pub fn save(application_state: &mut ApplicationState) -> Result<(), CT3DError> {
+    let path = application_state.session_path.clone();
+    if let Some(labelmap) = application_state.labelmap.as_ref() {
+        let labels = labels_path(&path);
+        labelmap.save(labels.clone()).with_context(|| format!("Could not save the labels of session {}", path))?;
+        application_state.labelmap_source = Some(labels);
+    }
+    Session::capture(application_state).serialize_to_file(path.clone()).with_context(|| format!("Could not save session {}", path))?;
+    println!("Saved session to {}", path);
+    crate::ui::overlay::toast(application_state, format!("Saved session {}", path));
+    Ok(())
+}
+
+fn load_volume(application_state: &mut ApplicationState, source: Option<String>) -> Result<(), CT3DError> {
+    match source {
+        // Volume files, as rendered by `CT3D3 animate --volume`
+        Some(path) if path.ends_with(".txt") => {
+            let volume = Volume::deserialize_from_file(path.clone()).with_context(|| format!("Could not load volume {}", path))?;
+            crate::application::change_volume(application_state, Box::new(volume))?;
+            application_state.volume_source = Some(path);
+        },
+        // Anything dropped on the viewer, converted again
+        Some(path) => crate::application::drop_file(path, application_state)?,
+        None => {
+            let volume = crate::content::generate_initial_volume::generate_initial_volume()?;
+            crate::application::change_volume(application_state, Box::new(volume))?;
+            application_state.volume_source = None;
+        }
+    }
+    Ok(())
+}
+
+/// Restore a session saved by `save`, loading its files unless they are already loaded
+pub fn load(application_state: &mut ApplicationState, path: String) -> Result<(), CT3DError> {
+    let session = Session::deserialize_from_file(path.clone()).with_context(|| format!("Could not load session {}", path))?;
+    if session.is_newer() {
+        println!("Session {} is from a newer version (format {}, this build reads {}), settings it adds are ignored.", path, session.version, SESSION_VERSION);
+    }
+
+    if session.volume_source != application_state.volume_source {
+        load_volume(application_state, session.volume_source.clone())?;
+    }
+    match session.labelmap_source.clone() {
+        Some(labels) => {
+            let labelmap = LabelMap::load(labels.clone()).with_context(|| format!("Could not load labelmap {}", labels))?;
+            crate::application::change_labelmap(application_state, Box::new(labelmap))?;
+            application_state.labelmap_source = Some(labels);
+        },
+        None => {
+            application_state.labelmap = None;
+            application_state.labelmap_source = None;
+        }
+    }
+    application_state.show_labels = session.show_labels;
+
+    application_state.playback.playing = false;
+    session.view.apply(application_state);
+    crate::application::update_transfer_function(application_state)?;
+
+    application_state.active_measurement = None;
+    application_state.measurements = session.measurements;
+    application_state.rois = session.rois;
+
+    application_state.session_path = path.clone();
+    println!("Loaded session {}", path);
+    crate::ui::overlay::toast(application_state, format!("Loaded session {}", path));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn labels_are_saved_next_to_the_session() {
+        assert_eq!(labels_path("temp/session.json"), "temp/session_labels.nii.gz");
+        assert_eq!(labels_path("projects/knee"), "projects/knee_labels.nii.gz");
+        assert_eq!(labels_path("../scans.v2/knee"), "../scans.v2/knee_labels.nii.gz");
+    }
+}
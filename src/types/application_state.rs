@@ -1,9 +1,21 @@
-use ocl::{flags, Platform, Device, Context, Queue, Buffer, Program, Kernel};
-use glam::{Vec3};
-use defaultdict::DefaultHashMap;
+use std::time::Instant;
 
-use crate::types::rgb_image::RGBImage;
+use ocl::{Device, Context, Queue, Buffer, Program, Kernel};
+use glam::{Vec3, IVec3};
+
+use crate::types::rgb_image::{RGBImage, FrameReadback};
 use crate::types::volume::Volume;
+use crate::types::camera_state::CameraPresets;
+use crate::types::measurement::{Measurement, MeasurementKind};
+use crate::types::roi::{NamedRoi, SliceContour};
+use crate::types::labelmap::LabelMap;
+use crate::processing::filters::Filter;
+use crate::tools::kernel_loader::KernelSource;
+use crate::tools::frame_timing::FrameStats;
+use crate::types::transfer_function::{TransferFunction, TRANSFER_FUNCTION_ENTRIES};
+use crate::types::clip_box::ClipBox;
+use crate::ui::overlay::OverlayState;
+use crate::types::session::SESSION_PATH;
 
 pub struct DragState {
     pub dragging: bool,
@@ -13,25 +25,108 @@ pub struct DragState {
     pub init_y: i32,
     pub init_RIGHT: Vec3,
     pub init_UP: Vec3,
-    pub init_FORWARD: Vec3
+    pub init_FORWARD: Vec3,
+    pub last_zoom: Option<Instant>
+}
+
+// How long after the last wheel event the coarse level of detail is kept
+const ZOOM_SETTLE_SECONDS: f32 = 0.25;
+// Per pixel layout of hit_buffer: present, local x, local y, local z, depth, sampled value
+pub const HIT_BUFFER_STRIDE: usize = 6;
+
+/// What the renderer hit under a given pixel
+#[derive(Debug, Clone, Copy)]
+pub struct Probe {
+    pub local: Vec3,
+    pub voxel: IVec3,
+    pub patient_mm: Vec3, // mm from the first voxel corner, the frame ROIs and measurements use
+    pub patient_lps: Vec3, // scanner patient coordinates, see `Volume::index_to_patient`
+    pub depth: f32,
+    pub value: f32,
+    pub hu: Option<f32>
+}
+
+pub struct PlaybackState {
+    pub playing: bool,
+    pub time: f32
+}
+
+/// A filtered copy of the volume shown in place of the original until it is applied or discarded
+pub struct FilterPreview {
+    pub index: usize,
+    pub filter: Filter,
+    pub volume: Box<Volume>
+}
+
+/// How far the image has been refined since the render inputs (`signature`) last changed
+pub struct RenderProgress {
+    pub signature: Vec<f32>,
+    pub pass: usize
+}
+
+/// A level of the volume's mip chain in input_data_buffer. Level 0 is the volume itself.
+pub struct VolumeLevel {
+    pub offset: usize,
+    pub res: IVec3
+}
+
+/// Compile time constants of the render kernel, injected as #defines. Changing them rebuilds the kernel.
+#[derive(Debug, Clone, PartialEq)]
+pub struct KernelConfig {
+    pub downsampling: u32, // multiple of the voxel size the ray marches by
+    pub normal_search_radius: u32, // voxels around a hit that the surface normal is estimated from
+    pub dropoff_rate: f32 // how fast shading darkens with distance from the camera
+}
+
+impl KernelConfig {
+    pub fn defines(&self) -> Vec<(String, String)> {
+        vec![
+            ("DOWNSAMPLING".to_owned(), self.downsampling.to_string()),
+            ("NORMAL_SEARCH_RADIUS".to_owned(), self.normal_search_radius.to_string()),
+            ("DROPOFF_RATE".to_owned(), format!("{:?}", self.dropoff_rate)),
+            ("HIT_BUFFER_STRIDE".to_owned(), HIT_BUFFER_STRIDE.to_string()),
+            ("TRANSFER_FUNCTION_ENTRIES".to_owned(), TRANSFER_FUNCTION_ENTRIES.to_string())
+        ]
+    }
+}
+
+impl Default for KernelConfig {
+    fn default() -> Self {
+        KernelConfig { downsampling: 1, normal_search_radius: 1, dropoff_rate: 0.70 }
+    }
 }
+
 pub struct OpenCLState {
     pub device: Option<Device>,
     pub context: Option<Context>,
     pub queue: Option<Queue>,
     pub screen_dimensions_buffer: Option<Buffer<i32>>,
-    pub output_buffer: Option<Buffer<f32>>,
+    pub output_buffer: Option<Buffer<u32>>,
+    pub accumulation_buffer: Option<Buffer<f32>>,
     pub input_data_buffer: Option<Buffer<f32>>,
     pub general_parameters_buffer: Option<Buffer<f32>>,
     pub axes_buffer: Option<Buffer<f32>>,
+    pub hit_buffer: Option<Buffer<f32>>,
+    pub labels_buffer: Option<Buffer<u16>>,
+    pub label_colors_buffer: Option<Buffer<f32>>,
+    pub render_parameters_buffer: Option<Buffer<i32>>,
+    pub transfer_function_buffer: Option<Buffer<f32>>,
+    pub contents_version: u64, // bumped whenever a buffer the render kernel reads from changes
+    pub volume_levels: Vec<VolumeLevel>,
     pub program: Option<Program>,
-    pub kernel: Option<Kernel>
+    pub filters_program: Option<Program>,
+    pub kernel: Option<Kernel>,
+    pub kernel_config: KernelConfig,
+    pub render_source: Option<KernelSource>,
+    pub filters_source: Option<KernelSource>,
+    pub last_kernel_check: Option<Instant>
 }
 
 pub struct ApplicationState {
     pub width: u32,
     pub height: u32,
     pub screen_buffer: RGBImage,
+    pub frame_readback: FrameReadback,
     pub opencl_state: OpenCLState,
     pub RIGHT: Vec3,
     pub UP: Vec3,
@@ -39,8 +134,33 @@ pub struct ApplicationState {
     pub drag_state: DragState,
     pub camera_z: f32,
     pub low_cutoff: f32,
+    pub transfer_function: TransferFunction,
+    pub clip_box: ClipBox,
     pub volume: Option<Box<Volume>>,
-    pub keymap: DefaultHashMap<sdl2::keyboard::Scancode,bool>
+    pub volume_source: Option<String>, // the file the volume was loaded or converted from
+    pub labelmap: Option<Box<LabelMap>>,
+    pub labelmap_source: Option<String>,
+    pub show_labels: bool,
+    pub camera_presets: CameraPresets,
+    pub playback: PlaybackState,
+    pub measurement_tool: Option<MeasurementKind>,
+    pub active_measurement: Option<Measurement>,
+    pub measurements: Vec<Measurement>,
+    pub rois: Vec<NamedRoi>,
+    pub freehand_contour: Option<SliceContour>, // the freehand ROI being drawn
+    pub filter_preview: Option<FilterPreview>,
+    pub suggested_cutoffs: Vec<f32>,
+    pub write_hit_buffer: bool,
+    pub progressive_rendering: bool,
+    pub supersamples: u32,
+    pub render_progress: RenderProgress,
+    pub frame_stats: FrameStats,
+    pub show_frame_stats: bool,
+    pub overlay: OverlayState,
+    pub session_path: String, // where sessions are saved to and loaded from
+    pub hit_data: Vec<f32>,
+    pub mouse_x: i32,
+    pub mouse_y: i32
 }
 
 impl DragState {
@@ -51,7 +171,27 @@ impl DragState {
             init_y: 0,
             init_RIGHT: Vec3::new(1.0, 0.0, 0.0),
             init_UP: Vec3::new(0.0, 1.0, 0.0),
-            init_FORWARD: Vec3::new(0.0, 0.0, 1.0)
+            init_FORWARD: Vec3::new(0.0, 0.0, 1.0),
+            last_zoom: None
+        }
+    }
+    /// Whether the camera is being moved, so a coarse level of detail is good enough
+    pub fn interacting(&self) -> bool {
+        self.dragging || self.last_zoom.map(|time| time.elapsed().as_secs_f32() < ZOOM_SETTLE_SECONDS).unwrap_or(false)
+    }
+}
+
+impl Default for PlaybackState {
+    fn default() -> Self {
+        PlaybackState::new()
+    }
+}
+
+impl PlaybackState {
+    pub fn new() -> PlaybackState {
+        PlaybackState {
+            playing: false,
+            time: 0.0
         }
     }
 }
@@ -64,11 +204,24 @@ impl OpenCLState {
             queue: None,
             screen_dimensions_buffer: None,
             output_buffer: None,
+            accumulation_buffer: None,
             input_data_buffer: None,
             axes_buffer: None,
+            hit_buffer: None,
+            labels_buffer: None,
+            label_colors_buffer: None,
+            render_parameters_buffer: None,
+            transfer_function_buffer: None,
+            contents_version: 0,
+            volume_levels: Vec::new(),
             general_parameters_buffer: None,
             program: None,
-            kernel: None
+            filters_program: None,
+            kernel: None,
+            kernel_config: KernelConfig::default(),
+            render_source: None,
+            filters_source: None,
+            last_kernel_check: None
         }
     }
 }
@@ -79,6 +232,7 @@ impl ApplicationState {
             width: width,
             height: height,
             screen_buffer: RGBImage::new(width as usize, height as usize),
+            frame_readback: FrameReadback::new((width*height) as usize),
             opencl_state: OpenCLState::new(),
             RIGHT: Vec3::new(1.0, 0.0, 0.0),
             UP: Vec3::new(0.0, 1.0, 0.0),
@@ -86,8 +240,60 @@ impl ApplicationState {
             drag_state: DragState::new(),
             camera_z: -5.0,
             low_cutoff: 0.0,
+            transfer_function: TransferFunction::new(),
+            clip_box: ClipBox::new(),
             volume: None,
-            keymap:  DefaultHashMap::<sdl2::keyboard::Scancode,bool>::new()
+            volume_source: None,
+            labelmap: None,
+            labelmap_source: None,
+            show_labels: true,
+            camera_presets: CameraPresets::new(),
+            playback: PlaybackState::new(),
+            measurement_tool: None,
+            active_measurement: None,
+            measurements: Vec::new(),
+            rois: Vec::new(),
+            freehand_contour: None,
+            filter_preview: None,
+            suggested_cutoffs: Vec::new(),
+            write_hit_buffer: false,
+            progressive_rendering: false,
+            supersamples: 0,
+            render_progress: RenderProgress { signature: Vec::new(), pass: 0 },
+            frame_stats: FrameStats::new(),
+            show_frame_stats: false,
+            overlay: OverlayState::new(),
+            session_path: SESSION_PATH.to_owned(),
+            hit_data: vec![0.0; (width*height) as usize * HIT_BUFFER_STRIDE],
+            mouse_x: 0,
+            mouse_y: 0
         }
     }
-}
\ No newline at end of file
+}
+
+impl ApplicationState {
+    /// Voxel and patient coordinate under pixel (x, y) from the last rendered frame.
+    /// Only available when `write_hit_buffer` is enabled.
+    pub fn probe(&self, x: i32, y: i32) -> Option<Probe> {
+        if !self.write_hit_buffer || x < 0 || y < 0 || x as u32 >= self.width || y as u32 >= self.height {
+            return None;
+        }
+        let volume = self.volume.as_ref()?;
+        let offset = (y as usize * self.width as usize + x as usize) * HIT_BUFFER_STRIDE;
+        let hit = &self.hit_data[offset..offset + HIT_BUFFER_STRIDE];
+        if hit[0] == 0.0 {
+            return None;
+        }
+        let local = Vec3::new(hit[1], hit[2], hit[3]);
+        let value = hit[5];
+        Some(Probe {
+            local,
+            voxel: volume.local_to_voxel(local).as_ivec3().min(volume.res - IVec3::ONE),
+            patient_mm: volume.local_to_mm(local),
+            patient_lps: volume.local_to_patient(local),
+            depth: hit[4],
+            value,
+            hu: volume.to_hu(value)
+        })
+    }
+}
@@ -0,0 +1,232 @@
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+
+use glam::{Vec3, Quat, Mat3};
+use serde::{Serialize, Deserialize};
+
+use crate::types::ct3d_error::CT3DError;
+use crate::types::application_state::ApplicationState;
+use crate::types::transfer_function::TransferFunction;
+use crate::types::clip_box::ClipBox;
+
+/// Everything needed to reproduce a view of the volume. Camera files written before the transfer
+/// function and clipping were part of it leave those as they are.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CameraState {
+    pub orientation: Quat,
+    pub camera_z: f32,
+    pub low_cutoff: f32,
+    #[serde(default)]
+    pub transfer_function: Option<TransferFunction>,
+    #[serde(default)]
+    pub clip_box: Option<ClipBox>
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CameraBookmark {
+    pub name: String,
+    pub state: CameraState
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Keyframe {
+    pub time: f32, // seconds from the start of the animation
+    pub state: CameraState
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KeyframeAnimation {
+    pub keyframes: Vec<Keyframe>
+}
+
+/// The contents of a camera file: named bookmarks plus a single fly-through
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CameraPresets {
+    pub bookmarks: Vec<CameraBookmark>,
+    pub animation: KeyframeAnimation
+}
+
+impl CameraState {
+    pub fn capture(application_state: &ApplicationState) -> CameraState {
+        // RIGHT, UP and FORWARD are the images of the world axes under the camera rotation
+        let rotation = Mat3::from_cols(application_state.RIGHT, application_state.UP, application_state.FORWARD);
+        CameraState {
+            orientation: Quat::from_mat3(&rotation).normalize(),
+            camera_z: application_state.camera_z,
+            low_cutoff: application_state.low_cutoff,
+            transfer_function: Some(application_state.transfer_function.clone()),
+            clip_box: Some(application_state.clip_box)
+        }
+    }
+
+    /// Call `application::update_transfer_function` afterwards to upload the transfer function
+    pub fn apply(&self, application_state: &mut ApplicationState) {
+        application_state.RIGHT = self.orientation * Vec3::new(1.0, 0.0, 0.0);
+        application_state.UP = self.orientation * Vec3::new(0.0, 1.0, 0.0);
+        application_state.FORWARD = self.orientation * Vec3::new(0.0, 0.0, 1.0);
+        application_state.camera_z = self.camera_z;
+        application_state.low_cutoff = self.low_cutoff;
+        if let Some(transfer_function) = self.transfer_function.as_ref() {
+            application_state.transfer_function = transfer_function.clone();
+        }
+        if let Some(clip_box) = self.clip_box {
+            application_state.clip_box = clip_box;
+        }
+        application_state.drag_state.dragging = false;
+    }
+
+    pub fn interpolate(&self, other: &CameraState, t: f32) -> CameraState {
+        let t = t.max(0.0).min(1.0);
+        CameraState {
+            orientation: self.orientation.slerp(other.orientation, t).normalize(),
+            camera_z: self.camera_z + (other.camera_z - self.camera_z) * t,
+            low_cutoff: self.low_cutoff + (other.low_cutoff - self.low_cutoff) * t,
+            transfer_function: match (self.transfer_function.as_ref(), other.transfer_function.as_ref()) {
+                (Some(from), Some(to)) => Some(from.interpolate(to, t)),
+                (from, to) => from.or(to).cloned()
+            },
+            clip_box: match (self.clip_box, other.clip_box) {
+                (Some(from), Some(to)) => Some(from.interpolate(&to, t)),
+                (from, to) => from.or(to)
+            }
+        }
+    }
+}
+
+impl Default for KeyframeAnimation {
+    fn default() -> Self {
+        KeyframeAnimation::new()
+    }
+}
+
+impl KeyframeAnimation {
+    pub fn new() -> KeyframeAnimation {
+        KeyframeAnimation { keyframes: Vec::new() }
+    }
+
+    pub fn duration(&self) -> f32 {
+        self.keyframes.last().map(|keyframe| keyframe.time).unwrap_or(0.0)
+    }
+
+    /// Insert a keyframe, keeping the list ordered by time
+    pub fn insert(&mut self, keyframe: Keyframe) {
+        let index = self.keyframes.iter().position(|k| k.time > keyframe.time).unwrap_or(self.keyframes.len());
+        self.keyframes.insert(index, keyframe);
+    }
+
+    /// Camera state at `time` seconds. Times outside the animation clamp to the first/last keyframe.
+    pub fn sample(&self, time: f32) -> Option<CameraState> {
+        let first = self.keyframes.first()?;
+        if time <= first.time {
+            return Some(first.state.clone());
+        }
+        for pair in self.keyframes.windows(2) {
+            let (a, b) = (&pair[0], &pair[1]);
+            if time <= b.time {
+                let span = b.time - a.time;
+                let t = if span > 0.0 { (time - a.time) / span } else { 1.0 };
+                return Some(a.state.interpolate(&b.state, t));
+            }
+        }
+        Some(self.keyframes.last().unwrap().state.clone())
+    }
+
+    /// Number of frames needed to play the whole animation at `fps`, including the final keyframe
+    pub fn frame_count(&self, fps: f32) -> usize {
+        if self.keyframes.is_empty() {
+            return 0;
+        }
+        (self.duration() * fps).floor() as usize + 1
+    }
+}
+
+impl Default for CameraPresets {
+    fn default() -> Self {
+        CameraPresets::new()
+    }
+}
+
+impl CameraPresets {
+    pub fn new() -> CameraPresets {
+        CameraPresets {
+            bookmarks: Vec::new(),
+            animation: KeyframeAnimation::new()
+        }
+    }
+
+    pub fn get_bookmark(&self, name: &str) -> Option<&CameraBookmark> {
+        self.bookmarks.iter().find(|bookmark| bookmark.name == name)
+    }
+
+    /// Add a bookmark, replacing any existing bookmark with the same name
+    pub fn set_bookmark(&mut self, name: String, state: CameraState) {
+        match self.bookmarks.iter_mut().find(|bookmark| bookmark.name == name) {
+            Some(bookmark) => bookmark.state = state,
+            None => self.bookmarks.push(CameraBookmark { name, state })
+        }
+    }
+
+    pub fn serialize_to_file(&self, path: String) -> Result<(), CT3DError> {
+        let file = File::create(path)?;
+        serde_json::to_writer_pretty(BufWriter::new(file), self)?;
+        Ok(())
+    }
+
+    pub fn deserialize_from_file(path: String) -> Result<CameraPresets, CT3DError> {
+        let file = File::open(path)?;
+        let presets = serde_json::from_reader(BufReader::new(file))?;
+        Ok(presets)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state(angle: f32, camera_z: f32) -> CameraState {
+        CameraState { orientation: Quat::from_rotation_y(angle), camera_z, low_cutoff: 0.0, transfer_function: None, clip_box: None }
+    }
+
+    #[test]
+    fn samples_follow_the_keyframes() {
+        let mut animation = KeyframeAnimation::new();
+        assert!(animation.sample(0.0).is_none());
+        assert_eq!(animation.frame_count(30.0), 0);
+
+        animation.insert(Keyframe { time: 2.0, state: state(std::f32::consts::FRAC_PI_2, -3.0) });
+        animation.insert(Keyframe { time: 0.0, state: state(0.0, -5.0) });
+        assert_eq!(animation.duration(), 2.0);
+        assert_eq!(animation.frame_count(30.0), 61);
+
+        // Clamped before and after, exact at the keyframes
+        assert_eq!(animation.sample(-1.0).unwrap(), state(0.0, -5.0));
+        assert_eq!(animation.sample(0.0).unwrap().camera_z, -5.0);
+        assert_eq!(animation.sample(5.0).unwrap().camera_z, -3.0);
+        assert!(animation.sample(2.0).unwrap().orientation.angle_between(Quat::from_rotation_y(std::f32::consts::FRAC_PI_2)) < 1e-4);
+
+        // Halfway in time is halfway along the rotation
+        let halfway = animation.sample(1.0).unwrap();
+        assert!((halfway.camera_z + 4.0).abs() < 1e-6);
+        assert!(halfway.orientation.angle_between(Quat::from_rotation_y(std::f32::consts::FRAC_PI_4)) < 1e-4);
+    }
+
+    #[test]
+    fn transfer_function_and_clipping_are_captured_and_blended() {
+        let mut application_state = ApplicationState::new(8, 8);
+        let before = CameraState::capture(&application_state);
+        application_state.transfer_function.set_level(0.2);
+        application_state.clip_box.set_max(2, 0.5);
+        let after = CameraState::capture(&application_state);
+
+        let halfway = before.interpolate(&after, 0.5);
+        assert!((halfway.transfer_function.unwrap().level - 0.35).abs() < 1e-6);
+        assert!((halfway.clip_box.unwrap().max.z - 0.75).abs() < 1e-6);
+
+        // Bookmarks saved before these existed keep the current ones
+        let old: CameraState = serde_json::from_str(r#"{"orientation": [0.0, 0.0, 0.0, 1.0], "camera_z": -4.0, "low_cutoff": 0.1}"#).unwrap();
+        old.apply(&mut application_state);
+        assert_eq!(application_state.camera_z, -4.0);
+        assert_eq!(application_state.transfer_function.level, 0.2);
+        assert_eq!(application_state.clip_box.max.z, 0.5);
+    }
+}
@@ -0,0 +1,88 @@
+use glam::Vec3;
+use serde::{Serialize, Deserialize};
+
+const MIN_EXTENT: f32 = 0.01; // Smallest share of an axis the box can be narrowed to
+
+/// The part of the volume that is rendered, as fractions 0 to 1 along its x, y and z axes.
+/// Everything outside is cut away, so the surfaces behind the cut show through.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ClipBox {
+    pub min: Vec3,
+    pub max: Vec3
+}
+
+impl Default for ClipBox {
+    fn default() -> Self {
+        ClipBox::new()
+    }
+}
+
+impl ClipBox {
+    /// The whole volume
+    pub fn new() -> ClipBox {
+        ClipBox { min: Vec3::ZERO, max: Vec3::ONE }
+    }
+
+    pub fn is_clipped(&self) -> bool {
+        *self != ClipBox::new()
+    }
+
+    /// Move the lower face along `axis`, staying below the upper one
+    pub fn set_min(&mut self, axis: usize, fraction: f32) {
+        self.min[axis] = fraction.max(0.0).min(self.max[axis] - MIN_EXTENT);
+    }
+
+    /// Move the upper face along `axis`, staying above the lower one
+    pub fn set_max(&mut self, axis: usize, fraction: f32) {
+        self.max[axis] = fraction.min(1.0).max(self.min[axis] + MIN_EXTENT);
+    }
+
+    pub fn reset_axis(&mut self, axis: usize) {
+        self.min[axis] = 0.0;
+        self.max[axis] = 1.0;
+    }
+
+    /// Corners in the renderer's local coordinates, for a volume spanning [-radii, radii]
+    pub fn local_bounds(&self, radii: Vec3) -> (Vec3, Vec3) {
+        (-radii + self.min * 2.0 * radii, -radii + self.max * 2.0 * radii)
+    }
+
+    pub fn contains_local(&self, local: Vec3, radii: Vec3) -> bool {
+        let (low, high) = self.local_bounds(radii);
+        local.cmpge(low).all() && local.cmple(high).all()
+    }
+
+    pub fn interpolate(&self, other: &ClipBox, t: f32) -> ClipBox {
+        let t = t.max(0.0).min(1.0);
+        ClipBox { min: self.min.lerp(other.min, t), max: self.max.lerp(other.max, t) }
+    }
+
+    /// Parameters for the render kernel: the lower then the upper corner
+    pub fn to_parameters(&self) -> [f32; 6] {
+        [self.min.x, self.min.y, self.min.z, self.max.x, self.max.y, self.max.z]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn faces_stay_in_order_and_map_to_local_coordinates() {
+        let mut clip_box = ClipBox::new();
+        assert!(!clip_box.is_clipped());
+        clip_box.set_min(0, 0.25);
+        clip_box.set_max(0, 0.1);
+        assert!((clip_box.max.x - (0.25 + MIN_EXTENT)).abs() < 1e-6);
+        assert!(clip_box.is_clipped());
+
+        let radii = Vec3::new(1.0, 0.5, 0.5);
+        assert!(clip_box.contains_local(Vec3::new(-0.49, 0.0, 0.0), radii));
+        assert!(!clip_box.contains_local(Vec3::new(-0.51, 0.0, 0.0), radii));
+        assert!(!clip_box.contains_local(Vec3::new(0.0, 0.0, 0.0), radii));
+
+        clip_box.reset_axis(0);
+        assert!(!clip_box.is_clipped());
+        assert_eq!(ClipBox::new().interpolate(&ClipBox { min: Vec3::splat(0.5), max: Vec3::ONE }, 0.5).min, Vec3::splat(0.25));
+    }
+}
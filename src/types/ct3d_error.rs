@@ -2,37 +2,139 @@ use std::fmt;
 use std::error::Error;
 
 #[derive(Debug)]
-pub struct CT3DError {
-    source: Option<Box<dyn Error + 'static>>
+pub enum CT3DError {
+    /// Reading or writing a file, or running another program, failed
+    Io(std::io::Error),
+    /// Text that should hold a number or a JSON document does not
+    Parse(Box<dyn Error + 'static>),
+    /// A file does not follow the format it is in
+    Format(String),
+    /// The OpenCL runtime failed, or no usable device was found
+    OpenCL(Box<dyn Error + 'static>),
+    /// SDL failed to create or update the window
+    Sdl(String),
+    /// A DICOM series could not be converted
+    Dicom(String),
+    /// Input that is well formed but that CT3D does not handle
+    Unsupported(String),
+    /// Invalid command line or arguments
+    Usage(String),
+    /// An error with a description of what was being done when it happened
+    Context { context: String, source: Box<CT3DError> }
 }
 
 impl fmt::Display for CT3DError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f,"{}",format!("{:?}",self))
+        match self {
+            CT3DError::Io(e) => write!(f, "{}", e),
+            CT3DError::Parse(e) => write!(f, "Parse error: {}", e),
+            CT3DError::Format(message) => write!(f, "{}", message),
+            CT3DError::OpenCL(e) => write!(f, "OpenCL error: {}", e),
+            CT3DError::Sdl(message) => write!(f, "SDL error: {}", message),
+            CT3DError::Dicom(message) => write!(f, "DICOM error: {}", message),
+            CT3DError::Unsupported(message) => write!(f, "{}", message),
+            CT3DError::Usage(message) => write!(f, "{}", message),
+            CT3DError::Context { context, source } => write!(f, "{}: {}", context, source)
+        }
     }
 }
 
-// impl Error for CT3DError {
-//     fn source(&self) -> Option<&(dyn Error + 'static)> {
-//         match &self.source {
-//             Some(s)=>{
-//                 Some(s.as_ref())
-//             },
-//             None => None
-//         }
-//     }
-// }
+impl Error for CT3DError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            CT3DError::Io(e) => Some(e),
+            CT3DError::Parse(e) => Some(e.as_ref()),
+            CT3DError::OpenCL(e) => Some(e.as_ref()),
+            CT3DError::Context { source, .. } => Some(source.as_ref()),
+            _ => None
+        }
+    }
+}
 
 impl CT3DError {
-    pub fn new(source:Option<Box<dyn Error + 'static>>) -> CT3DError {
-        CT3DError {
-            source: source
+    /// Wrap in a description of what was being done, e.g. the file being loaded
+    pub fn context(self, context: impl Into<String>) -> CT3DError {
+        CT3DError::Context { context: context.into(), source: Box::new(self) }
+    }
+
+    /// Error for a missing file or device, as `Io` so callers can tell by `std::io::ErrorKind`
+    pub fn not_found(message: String) -> CT3DError {
+        CT3DError::Io(std::io::Error::new(std::io::ErrorKind::NotFound, message))
+    }
+}
+
+/// `context` for results, converting the error on the way
+pub trait ErrorContext<T> {
+    fn context(self, context: impl Into<String>) -> Result<T, CT3DError>;
+    fn with_context<C: Into<String>>(self, context: impl FnOnce() -> C) -> Result<T, CT3DError>;
+}
+
+impl<T, E: Into<CT3DError>> ErrorContext<T> for Result<T, E> {
+    fn context(self, context: impl Into<String>) -> Result<T, CT3DError> {
+        self.map_err(|e| e.into().context(context))
+    }
+
+    fn with_context<C: Into<String>>(self, context: impl FnOnce() -> C) -> Result<T, CT3DError> {
+        self.map_err(|e| e.into().context(context()))
+    }
+}
+
+impl From<std::io::Error> for CT3DError {
+    fn from(e: std::io::Error) -> Self {
+        CT3DError::Io(e)
+    }
+}
+
+impl From<std::num::ParseIntError> for CT3DError {
+    fn from(e: std::num::ParseIntError) -> Self {
+        CT3DError::Parse(Box::new(e))
+    }
+}
+
+impl From<std::num::ParseFloatError> for CT3DError {
+    fn from(e: std::num::ParseFloatError) -> Self {
+        CT3DError::Parse(Box::new(e))
+    }
+}
+
+impl From<serde_json::Error> for CT3DError {
+    fn from(e: serde_json::Error) -> Self {
+        CT3DError::Parse(Box::new(e))
+    }
+}
+
+impl From<ocl::Error> for CT3DError {
+    fn from(e: ocl::Error) -> Self {
+        CT3DError::OpenCL(Box::new(e))
+    }
+}
+
+impl From<subprocess::PopenError> for CT3DError {
+    fn from(e: subprocess::PopenError) -> Self {
+        match e {
+            subprocess::PopenError::IoError(e) => CT3DError::Io(e),
+            e => CT3DError::Io(std::io::Error::other(e))
         }
     }
 }
 
-impl<E: Error + 'static> From<E> for CT3DError {
-    fn from(e: E) -> Self {
-        Self::new(Some(Box::new(e)))
+#[cfg(feature = "sdl")]
+impl From<sdl2::video::WindowBuildError> for CT3DError {
+    fn from(e: sdl2::video::WindowBuildError) -> Self {
+        CT3DError::Sdl(e.to_string())
+    }
+}
+
+#[cfg(feature = "sdl")]
+impl From<sdl2::IntegerOrSdlError> for CT3DError {
+    fn from(e: sdl2::IntegerOrSdlError) -> Self {
+        CT3DError::Sdl(e.to_string())
+    }
+}
+
+#[cfg(feature = "sdl")]
+impl From<sdl2::render::TextureValueError> for CT3DError {
+    fn from(e: sdl2::render::TextureValueError) -> Self {
+        CT3DError::Sdl(e.to_string())
     }
 }
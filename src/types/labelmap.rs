@@ -0,0 +1,335 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write, BufWriter};
+
+use glam::{Vec3, IVec3, Mat3};
+use ocl::Buffer;
+use serde::{Serialize, Deserialize};
+
+use crate::types::ct3d_error::CT3DError;
+use crate::types::volume::Volume;
+
+pub const MAX_LABELS: usize = 65536;
+// An image with more different values than this holds intensities rather than labels
+const MAX_LABEL_VALUES: usize = 256;
+
+// Distinct colours handed out to labels without an explicit colour
+const PALETTE: [(u8, u8, u8); 10] = [
+    (230, 25, 75),
+    (60, 180, 75),
+    (0, 130, 200),
+    (245, 130, 48),
+    (145, 30, 180),
+    (70, 240, 240),
+    (240, 50, 230),
+    (210, 245, 60),
+    (250, 190, 190),
+    (0, 128, 128)
+];
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Label {
+    pub value: u16,
+    pub name: String,
+    pub color: (u8, u8, u8),
+    pub opacity: f32,
+    pub visible: bool
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LabelTable {
+    pub labels: Vec<Label>
+}
+
+// Largest differences in spacing and origin (mm) and direction cosines for a labelmap to still fit a volume
+const GEOMETRY_TOLERANCE_MM: f32 = 1e-2;
+const DIRECTION_TOLERANCE: f32 = 1e-3;
+
+/// One label value per voxel, 0 is background. Voxel layout and patient geometry match `Volume`.
+pub struct LabelMap {
+    pub res: IVec3,
+    pub spacing: Vec3,
+    pub origin: Vec3,
+    pub direction: Mat3,
+    pub data: Vec<u16>,
+    pub table: LabelTable
+}
+
+impl Label {
+    pub fn new(value: u16) -> Label {
+        Label {
+            value,
+            name: format!("Label {}", value),
+            color: PALETTE[(value as usize + PALETTE.len() - 1) % PALETTE.len()],
+            opacity: 0.5,
+            visible: true
+        }
+    }
+}
+
+impl Default for LabelTable {
+    fn default() -> Self {
+        LabelTable::new()
+    }
+}
+
+impl LabelTable {
+    pub fn new() -> LabelTable {
+        LabelTable { labels: Vec::new() }
+    }
+
+    pub fn get(&self, value: u16) -> Option<&Label> {
+        self.labels.iter().find(|label| label.value == value)
+    }
+
+    pub fn get_mut(&mut self, value: u16) -> Option<&mut Label> {
+        self.labels.iter_mut().find(|label| label.value == value)
+    }
+
+    /// Get a label, adding a default entry for it if it is missing
+    pub fn get_or_insert(&mut self, value: u16) -> &mut Label {
+        match self.labels.iter().position(|label| label.value == value) {
+            Some(index) => &mut self.labels[index],
+            None => {
+                self.labels.push(Label::new(value));
+                self.labels.sort_by_key(|label| label.value);
+                self.get_mut(value).unwrap()
+            }
+        }
+    }
+
+    /// Read a colour table in the 3D Slicer format, one "value name r g b a" entry per line
+    pub fn deserialize_from_file(path: String) -> Result<LabelTable, CT3DError> {
+        let reader = BufReader::new(File::open(path)?);
+        let mut table = LabelTable::new();
+        for line in reader.lines() {
+            let line = line?;
+            let parts: Vec<&str> = line.split_whitespace().collect();
+            if parts.is_empty() || parts[0].starts_with('#') {
+                continue;
+            }
+            if parts.len() < 5 {
+                return Err(CT3DError::Format(format!("Invalid colour table entry: {}", line)));
+            }
+            let channel = |text: &str| text.parse::<u8>().map_err(|_| CT3DError::Format(format!("Colour values must be 0 to 255, found {} in: {}", text, line)));
+            let value = parts[0].parse::<u16>()?;
+            let color = (channel(parts[2])?, channel(parts[3])?, channel(parts[4])?);
+            let opacity = if parts.len() > 5 { Some(channel(parts[5])? as f32 / 255.0) } else { None };
+            let label = table.get_or_insert(value);
+            label.name = parts[1].to_owned();
+            label.color = color;
+            if let Some(opacity) = opacity {
+                label.opacity = opacity;
+            }
+        }
+        Ok(table)
+    }
+
+    /// Write the table in the format read by `deserialize_from_file`
+    pub fn serialize_to_file(&self, path: String) -> Result<(), CT3DError> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writeln!(writer, "# value name r g b a")?;
+        for label in self.labels.iter() {
+            // Names are whitespace separated in this format
+            let name = label.name.split_whitespace().collect::<Vec<&str>>().join("_");
+            writeln!(writer, "{} {} {} {} {} {}", label.value, name, label.color.0, label.color.1, label.color.2, (label.opacity * 255.0).round() as u8)?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// RGBA per label value as uploaded to the device. Hidden labels get zero alpha.
+    pub fn to_color_data(&self) -> Vec<f32> {
+        let mut data = vec![0.0f32; MAX_LABELS * 4];
+        for label in self.labels.iter() {
+            let offset = label.value as usize * 4;
+            data[offset] = label.color.0 as f32 / 255.0;
+            data[offset + 1] = label.color.1 as f32 / 255.0;
+            data[offset + 2] = label.color.2 as f32 / 255.0;
+            data[offset + 3] = if label.visible && label.value != 0 { label.opacity.max(0.0).min(1.0) } else { 0.0 };
+        }
+        data
+    }
+}
+
+impl LabelMap {
+    pub fn new(res: IVec3, spacing: Vec3) -> LabelMap {
+        LabelMap {
+            res,
+            spacing,
+            origin: Vec3::ZERO,
+            direction: Mat3::IDENTITY,
+            data: vec![0; (res.x*res.y*res.z) as usize],
+            table: LabelTable::new()
+        }
+    }
+
+    /// Empty labelmap on the same grid as `volume`
+    pub fn for_volume(volume: &Volume) -> LabelMap {
+        let mut labelmap = LabelMap::new(volume.res, volume.spacing);
+        labelmap.origin = volume.origin;
+        labelmap.direction = volume.direction;
+        labelmap
+    }
+
+    /// Labelmap from the (integer) values of a loaded image, creating a table entry for every label present
+    pub fn from_volume(volume: &Volume) -> Result<LabelMap, CT3DError> {
+        let mut labelmap = LabelMap::for_volume(volume);
+        let mut present = vec![false; MAX_LABELS];
+        for (label, value) in labelmap.data.iter_mut().zip(volume.data.iter()) {
+            if *value < 0.0 || *value >= MAX_LABELS as f32 || value.fract() != 0.0 {
+                return Err(CT3DError::Format(format!("{} is not a valid label value", value)));
+            }
+            *label = *value as u16;
+            present[*label as usize] = true;
+        }
+        for value in 1..MAX_LABELS {
+            if present[value] {
+                labelmap.table.get_or_insert(value as u16);
+            }
+        }
+        Ok(labelmap)
+    }
+
+    /// Whether an image read with `Volume::read_image` is a segmentation: whole values from 0 and only a
+    /// few different ones, where a scan has negative HU or a spread of intensities
+    pub fn holds_labels(image: &Volume) -> bool {
+        let mut present = vec![false; MAX_LABELS];
+        let mut count = 0;
+        for value in image.data.iter() {
+            if *value < 0.0 || *value >= MAX_LABELS as f32 || value.fract() != 0.0 {
+                return false;
+            }
+            if !present[*value as usize] {
+                present[*value as usize] = true;
+                count += 1;
+                if count > MAX_LABEL_VALUES {
+                    return false;
+                }
+            }
+        }
+        true
+    }
+
+    /// Load a labelmap from a NIfTI or NRRD file. A 3D Slicer colour table next to it
+    /// (same name with a .ctbl extension) provides label names and colours.
+    pub fn load(path: String) -> Result<LabelMap, CT3DError> {
+        LabelMap::from_image(&Volume::read_image(path.clone())?, &path)
+    }
+
+    /// The labels of an image read from `path`, named by the colour table next to it
+    pub fn from_image(image: &Volume, path: &str) -> Result<LabelMap, CT3DError> {
+        let mut labelmap = LabelMap::from_volume(image)?;
+
+        let table_path = LabelMap::table_path(path);
+        if std::path::Path::new(&table_path).exists() {
+            for entry in LabelTable::deserialize_from_file(table_path)?.labels.into_iter() {
+                let value = entry.value;
+                *labelmap.table.get_or_insert(value) = entry;
+            }
+        }
+
+        Ok(labelmap)
+    }
+
+    /// Label values as a volume with the same geometry, for the processing written for volumes
+    pub fn to_volume(&self) -> Volume {
+        let mut volume = Volume::new(Volume::radii_for(self.res, self.spacing), self.res);
+        volume.spacing = self.spacing;
+        volume.origin = self.origin;
+        volume.direction = self.direction;
+        volume.data = self.data.iter().map(|value| *value as f32).collect();
+        volume
+    }
+
+    /// Write the labelmap as NIfTI or NRRD depending on the extension of `path`, with its colour table beside it
+    pub fn save(&self, path: String) -> Result<(), CT3DError> {
+        if crate::loaders::nifti::is_nifti_path(&path) {
+            crate::loaders::nifti::write_nifti_u16(path.clone(), self.res, self.spacing, self.origin, self.direction, &self.data)?;
+        } else if crate::loaders::nrrd::is_nrrd_path(&path) {
+            crate::loaders::nrrd::write_nrrd_u16(path.clone(), self.res, self.spacing, self.origin, self.direction, &self.data)?;
+        } else {
+            return Err(CT3DError::Unsupported(format!("Unsupported labelmap format: {}", path)));
+        }
+        self.table.serialize_to_file(LabelMap::table_path(&path))
+    }
+
+    fn table_path(path: &str) -> String {
+        let stem = path.trim_end_matches(".gz").rsplit_once('.').map(|(stem, _)| stem.to_owned()).unwrap_or(path.to_owned());
+        format!("{}.ctbl", stem)
+    }
+
+    /// Indices into `data` of every voxel carrying `label`
+    pub fn label_indices(&self, label: u16) -> Vec<usize> {
+        self.data.iter().enumerate().filter(|(_, value)| **value == label).map(|(index, _)| index).collect()
+    }
+
+    /// Mask of the voxels carrying `label`
+    pub fn label_mask(&self, label: u16) -> Vec<bool> {
+        self.data.iter().map(|value| *value == label).collect()
+    }
+
+    /// Reset the voxels of `label` that are outside `mask` to background
+    pub fn restrict_label(&mut self, label: u16, mask: &[bool]) {
+        for (value, inside) in self.data.iter_mut().zip(mask.iter()) {
+            if *value == label && !*inside {
+                *value = 0;
+            }
+        }
+    }
+
+    pub fn index(&self, coord: IVec3) -> usize {
+        (coord.z*self.res.x*self.res.y + coord.y*self.res.x + coord.x) as usize
+    }
+
+    pub fn get(&self, coord: IVec3) -> u16 {
+        self.data[self.index(coord)]
+    }
+
+    pub fn set(&mut self, coord: IVec3, label: u16) {
+        let index = self.index(coord);
+        self.data[index] = label;
+    }
+
+    /// Whether the labelmap covers the same voxels of the patient as `volume`
+    pub fn matches(&self, volume: &Volume) -> bool {
+        self.res == volume.res
+            && self.spacing.abs_diff_eq(volume.spacing, GEOMETRY_TOLERANCE_MM)
+            && self.origin.abs_diff_eq(volume.origin, GEOMETRY_TOLERANCE_MM)
+            && self.direction.abs_diff_eq(volume.direction, DIRECTION_TOLERANCE)
+    }
+
+    pub fn voxel_count(&self, label: u16) -> usize {
+        self.data.iter().filter(|value| **value == label).count()
+    }
+
+    pub fn to_ocl_buffer(&self, buffer: &mut Buffer<u16>) -> Result<(), CT3DError> {
+        buffer.write(&self.data).enq()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_table(name: &str, text: &str) -> Result<LabelTable, CT3DError> {
+        let path = std::env::temp_dir().join(name).to_string_lossy().into_owned();
+        std::fs::write(&path, text).unwrap();
+        let table = LabelTable::deserialize_from_file(path.clone());
+        std::fs::remove_file(path).unwrap();
+        table
+    }
+
+    #[test]
+    fn colour_tables_read_back_and_reject_out_of_range_colours() {
+        let table = read_table("ct3d3_colour_table_test.ctbl", "# value name r g b a\n3 liver 255 0 128 255\n1 bone 10 20 30\n").unwrap();
+        assert_eq!(table.labels.iter().map(|label| label.value).collect::<Vec<u16>>(), vec![1, 3]);
+        assert_eq!(table.get(3).unwrap().color, (255, 0, 128));
+        assert_eq!(table.get(3).unwrap().opacity, 1.0);
+        assert_eq!(table.get(1).unwrap().opacity, Label::new(1).opacity);
+
+        for line in ["1 bone 256 0 0", "1 bone 0 -1 0", "1 bone 0 0 0 300"] {
+            assert!(matches!(read_table("ct3d3_colour_table_invalid_test.ctbl", line), Err(CT3DError::Format(_))), "{}", line);
+        }
+    }
+}
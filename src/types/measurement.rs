@@ -0,0 +1,165 @@
+use glam::Vec3;
+use serde::{Serialize, Deserialize};
+
+use crate::types::volume::Volume;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MeasurementKind {
+    Distance,
+    Angle,
+    Polyline
+}
+
+/// A measurement whose points live in the renderer's local volume coordinates
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Measurement {
+    pub kind: MeasurementKind,
+    pub points: Vec<Vec3>
+}
+
+impl MeasurementKind {
+    /// Number of points after which the measurement is finished automatically.
+    /// Polylines are open ended and finished by the user.
+    pub fn required_points(&self) -> Option<usize> {
+        match self {
+            MeasurementKind::Distance => Some(2),
+            MeasurementKind::Angle => Some(3),
+            MeasurementKind::Polyline => None
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            MeasurementKind::Distance => "Distance",
+            MeasurementKind::Angle => "Angle",
+            MeasurementKind::Polyline => "Polyline"
+        }
+    }
+
+    /// Next tool when cycling with the keyboard, `None` means no tool is active
+    pub fn cycle(tool: Option<MeasurementKind>) -> Option<MeasurementKind> {
+        match tool {
+            None => Some(MeasurementKind::Distance),
+            Some(MeasurementKind::Distance) => Some(MeasurementKind::Angle),
+            Some(MeasurementKind::Angle) => Some(MeasurementKind::Polyline),
+            Some(MeasurementKind::Polyline) => None
+        }
+    }
+}
+
+impl Measurement {
+    pub fn new(kind: MeasurementKind) -> Measurement {
+        Measurement { kind, points: Vec::new() }
+    }
+
+    pub fn is_complete(&self) -> bool {
+        match self.kind.required_points() {
+            Some(required) => self.points.len() >= required,
+            None => false
+        }
+    }
+
+    /// Whether there are enough points to report a value
+    pub fn is_measurable(&self) -> bool {
+        match self.kind.required_points() {
+            Some(required) => self.points.len() >= required,
+            None => self.points.len() >= 2
+        }
+    }
+
+    pub fn points_mm(&self, volume: &Volume) -> Vec<Vec3> {
+        self.points.iter().map(|point| volume.local_to_mm(*point)).collect()
+    }
+
+    /// Length in millimetres for distances and polylines, angle in degrees for angles
+    pub fn value(&self, volume: &Volume) -> Option<f32> {
+        if !self.is_measurable() {
+            return None;
+        }
+        let points = self.points_mm(volume);
+        match self.kind {
+            MeasurementKind::Distance | MeasurementKind::Polyline => {
+                Some(points.windows(2).map(|pair| pair[0].distance(pair[1])).sum())
+            },
+            MeasurementKind::Angle => {
+                // Angle at the middle point, which has none when an arm has no length
+                let a = points[0] - points[1];
+                let b = points[2] - points[1];
+                if a.length_squared() == 0.0 || b.length_squared() == 0.0 {
+                    return None;
+                }
+                Some(a.angle_between(b).to_degrees())
+            }
+        }
+    }
+
+    pub fn describe(&self, volume: &Volume) -> String {
+        match (self.kind, self.value(volume)) {
+            (MeasurementKind::Angle, Some(degrees)) => format!("{}: {:.1} deg", self.kind.name(), degrees),
+            (_, Some(millimetres)) => format!("{}: {:.2} mm", self.kind.name(), millimetres),
+            (_, None) if self.is_measurable() => format!("{}: undefined, points coincide", self.kind.name()),
+            (_, None) => format!("{}: incomplete", self.kind.name())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::IVec3;
+
+    use super::*;
+
+    /// 10 x 20 x 5 voxels of 0.5 x 0.5 x 3 mm
+    fn anisotropic_volume() -> Volume {
+        let res = IVec3::new(10, 20, 5);
+        let spacing = Vec3::new(0.5, 0.5, 3.0);
+        let mut volume = Volume::new(Volume::radii_for(res, spacing), res);
+        volume.spacing = spacing;
+        volume
+    }
+
+    fn measurement(volume: &Volume, kind: MeasurementKind, voxels: &[Vec3]) -> Measurement {
+        Measurement { kind, points: voxels.iter().map(|voxel| volume.voxel_to_local(*voxel)).collect() }
+    }
+
+    #[test]
+    fn distances_use_the_voxel_spacing() {
+        let volume = anisotropic_volume();
+        // Two voxels apart along z and four along x are 6 and 2 mm
+        let along_z = measurement(&volume, MeasurementKind::Distance, &[Vec3::new(1.0, 1.0, 1.0), Vec3::new(1.0, 1.0, 3.0)]);
+        assert!((along_z.value(&volume).unwrap() - 6.0).abs() < 1e-4);
+        let along_x = measurement(&volume, MeasurementKind::Distance, &[Vec3::new(1.0, 1.0, 1.0), Vec3::new(5.0, 1.0, 1.0)]);
+        assert!((along_x.value(&volume).unwrap() - 2.0).abs() < 1e-4);
+        assert_eq!(along_z.describe(&volume), "Distance: 6.00 mm");
+
+        let unfinished = measurement(&volume, MeasurementKind::Distance, &[Vec3::ONE]);
+        assert!(unfinished.value(&volume).is_none());
+        assert_eq!(unfinished.describe(&volume), "Distance: incomplete");
+    }
+
+    #[test]
+    fn polylines_sum_their_segments() {
+        let volume = anisotropic_volume();
+        let polyline = measurement(&volume, MeasurementKind::Polyline, &[Vec3::ZERO, Vec3::new(4.0, 0.0, 0.0), Vec3::new(4.0, 0.0, 1.0), Vec3::new(4.0, 6.0, 1.0)]);
+        assert!((polyline.value(&volume).unwrap() - (2.0 + 3.0 + 3.0)).abs() < 1e-4);
+        assert!(!polyline.is_complete());
+        assert_eq!(polyline.describe(&volume), "Polyline: 8.00 mm");
+    }
+
+    #[test]
+    fn angles_are_at_the_middle_point() {
+        let volume = anisotropic_volume();
+        // 3 mm along x and 3 mm along z, which is 6 voxels against 1
+        let angle = measurement(&volume, MeasurementKind::Angle, &[Vec3::new(6.0, 0.0, 0.0), Vec3::ZERO, Vec3::new(6.0, 0.0, 1.0)]);
+        assert!((angle.value(&volume).unwrap() - 45.0).abs() < 1e-3);
+        let right = measurement(&volume, MeasurementKind::Angle, &[Vec3::new(4.0, 0.0, 0.0), Vec3::ZERO, Vec3::new(0.0, 0.0, 1.0)]);
+        assert!((right.value(&volume).unwrap() - 90.0).abs() < 1e-3);
+        assert_eq!(right.describe(&volume), "Angle: 90.0 deg");
+
+        // No angle when the middle point coincides with an end
+        let degenerate = measurement(&volume, MeasurementKind::Angle, &[Vec3::ZERO, Vec3::ZERO, Vec3::new(0.0, 0.0, 1.0)]);
+        assert!(degenerate.is_complete());
+        assert!(degenerate.value(&volume).is_none());
+        assert_eq!(degenerate.describe(&volume), "Angle: undefined, points coincide");
+    }
+}
@@ -0,0 +1,249 @@
+use std::fs::File;
+use std::io::{Write, BufWriter};
+
+use byteorder::{LittleEndian, WriteBytesExt};
+use glam::Vec3;
+
+use super::ct3d_error::CT3DError;
+
+/// Indexed triangle mesh in millimetres, triangles wound counter-clockwise seen from outside. Surfaces of
+/// volumes and labelmaps are in patient coordinates, so exported files line up with the scan in other tools.
+#[derive(Debug, Clone, Default)]
+pub struct Mesh {
+    pub vertices: Vec<Vec3>,
+    pub triangles: Vec<[u32; 3]>
+}
+
+impl Mesh {
+    pub fn new() -> Mesh {
+        Mesh { vertices: Vec::new(), triangles: Vec::new() }
+    }
+
+    pub fn triangle_vertices(&self, triangle: &[u32; 3]) -> [Vec3; 3] {
+        [self.vertices[triangle[0] as usize], self.vertices[triangle[1] as usize], self.vertices[triangle[2] as usize]]
+    }
+
+    pub fn triangle_normal(&self, triangle: &[u32; 3]) -> Vec3 {
+        let [a, b, c] = self.triangle_vertices(triangle);
+        (b - a).cross(c - a).normalize_or_zero()
+    }
+
+    /// Enclosed volume in mm³, only meaningful for closed meshes
+    pub fn enclosed_volume(&self) -> f32 {
+        self.triangles.iter().map(|triangle| {
+            let [a, b, c] = self.triangle_vertices(triangle);
+            a.dot(b.cross(c)) as f64 / 6.0
+        }).sum::<f64>() as f32
+    }
+
+    pub fn describe(&self) -> String {
+        format!("{} vertices, {} triangles, {:.2} mL enclosed", self.vertices.len(), self.triangles.len(), self.enclosed_volume() / 1000.0)
+    }
+
+    /// Write STL, OBJ or PLY depending on the extension of `path`. STL is binary unless `ascii` is set.
+    pub fn serialize_to_file(&self, path: String, ascii: bool) -> Result<(), CT3DError> {
+        let lowercase = path.to_ascii_lowercase();
+        if lowercase.ends_with(".stl") {
+            if ascii {
+                self.serialize_to_ascii_stl(path)
+            } else {
+                self.serialize_to_binary_stl(path)
+            }
+        } else if lowercase.ends_with(".obj") {
+            self.serialize_to_obj(path)
+        } else if lowercase.ends_with(".ply") {
+            self.serialize_to_ply(path)
+        } else {
+            Err(CT3DError::Unsupported(format!("Unsupported mesh format: {}", path)))
+        }
+    }
+
+    pub fn serialize_to_binary_stl(&self, path: String) -> Result<(), CT3DError> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        let mut header = [0u8; 80];
+        let name = b"CT3D mesh";
+        header[..name.len()].copy_from_slice(name);
+        writer.write_all(&header)?;
+        writer.write_u32::<LittleEndian>(self.triangles.len() as u32)?;
+        for triangle in self.triangles.iter() {
+            let normal = self.triangle_normal(triangle);
+            for v in std::iter::once(normal).chain(self.triangle_vertices(triangle).into_iter()) {
+                writer.write_f32::<LittleEndian>(v.x)?;
+                writer.write_f32::<LittleEndian>(v.y)?;
+                writer.write_f32::<LittleEndian>(v.z)?;
+            }
+            writer.write_u16::<LittleEndian>(0)?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    pub fn serialize_to_ascii_stl(&self, path: String) -> Result<(), CT3DError> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writeln!(writer, "solid ct3d")?;
+        for triangle in self.triangles.iter() {
+            let n = self.triangle_normal(triangle);
+            writeln!(writer, "  facet normal {:e} {:e} {:e}", n.x, n.y, n.z)?;
+            writeln!(writer, "    outer loop")?;
+            for v in self.triangle_vertices(triangle).iter() {
+                writeln!(writer, "      vertex {:e} {:e} {:e}", v.x, v.y, v.z)?;
+            }
+            writeln!(writer, "    endloop")?;
+            writeln!(writer, "  endfacet")?;
+        }
+        writeln!(writer, "endsolid ct3d")?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    pub fn serialize_to_obj(&self, path: String) -> Result<(), CT3DError> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writeln!(writer, "# CT3D mesh, millimetres")?;
+        for v in self.vertices.iter() {
+            writeln!(writer, "v {} {} {}", v.x, v.y, v.z)?;
+        }
+        for triangle in self.triangles.iter() {
+            // OBJ indices start at 1
+            writeln!(writer, "f {} {} {}", triangle[0] + 1, triangle[1] + 1, triangle[2] + 1)?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Binary little endian PLY
+    pub fn serialize_to_ply(&self, path: String) -> Result<(), CT3DError> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        write!(writer, "ply\nformat binary_little_endian 1.0\ncomment CT3D mesh, millimetres\n")?;
+        write!(writer, "element vertex {}\nproperty float x\nproperty float y\nproperty float z\n", self.vertices.len())?;
+        write!(writer, "element face {}\nproperty list uchar int vertex_indices\nend_header\n", self.triangles.len())?;
+        for v in self.vertices.iter() {
+            writer.write_f32::<LittleEndian>(v.x)?;
+            writer.write_f32::<LittleEndian>(v.y)?;
+            writer.write_f32::<LittleEndian>(v.z)?;
+        }
+        for triangle in self.triangles.iter() {
+            writer.write_u8(3)?;
+            for index in triangle.iter() {
+                writer.write_i32::<LittleEndian>(*index as i32)?;
+            }
+        }
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Read};
+
+    use byteorder::ReadBytesExt;
+
+    use super::*;
+
+    /// Unit tetrahedron, wound to face outwards
+    fn tetrahedron() -> Mesh {
+        Mesh {
+            vertices: vec![Vec3::ZERO, Vec3::X, Vec3::Y, Vec3::new(0.0, 0.0, 1.5)],
+            triangles: vec![[0, 2, 1], [0, 1, 3], [0, 3, 2], [1, 2, 3]]
+        }
+    }
+
+    fn write(mesh: &Mesh, name: &str, ascii: bool) -> Vec<u8> {
+        let path = std::env::temp_dir().join(name).to_string_lossy().into_owned();
+        mesh.serialize_to_file(path.clone(), ascii).unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        bytes
+    }
+
+    /// Vertex positions of every triangle in file order
+    fn corners(mesh: &Mesh) -> Vec<Vec3> {
+        mesh.triangles.iter().flat_map(|triangle| mesh.triangle_vertices(triangle)).collect()
+    }
+
+    fn read_vec3(cursor: &mut Cursor<Vec<u8>>) -> Vec3 {
+        let mut v = Vec3::ZERO;
+        for axis in 0..3 {
+            v[axis] = cursor.read_f32::<LittleEndian>().unwrap();
+        }
+        v
+    }
+
+    #[test]
+    fn enclosed_volume_is_positive_for_outward_triangles() {
+        assert!((tetrahedron().enclosed_volume() - 0.25).abs() < 1e-6);
+    }
+
+    #[test]
+    fn binary_stl_reads_back() {
+        let mesh = tetrahedron();
+        let mut cursor = Cursor::new(write(&mesh, "ct3d3_mesh_test.stl", false));
+        cursor.set_position(80);
+        assert_eq!(cursor.read_u32::<LittleEndian>().unwrap(), 4);
+        let mut read = Vec::new();
+        for triangle in mesh.triangles.iter() {
+            assert_eq!(read_vec3(&mut cursor), mesh.triangle_normal(triangle));
+            for _ in 0..3 {
+                read.push(read_vec3(&mut cursor));
+            }
+            assert_eq!(cursor.read_u16::<LittleEndian>().unwrap(), 0);
+        }
+        assert_eq!(read, corners(&mesh));
+        assert_eq!(cursor.position() as usize, cursor.get_ref().len());
+    }
+
+    #[test]
+    fn ascii_stl_reads_back() {
+        let mesh = tetrahedron();
+        let text = String::from_utf8(write(&mesh, "ct3d3_mesh_ascii_test.stl", true)).unwrap();
+        let numbers = |line: &str| -> Vec3 {
+            let values: Vec<f32> = line.split_whitespace().skip_while(|word| word.parse::<f32>().is_err()).map(|word| word.parse().unwrap()).collect();
+            Vec3::new(values[0], values[1], values[2])
+        };
+        let read: Vec<Vec3> = text.lines().filter(|line| line.trim_start().starts_with("vertex")).map(numbers).collect();
+        let normals: Vec<Vec3> = text.lines().filter(|line| line.trim_start().starts_with("facet normal")).map(numbers).collect();
+        assert_eq!(read, corners(&mesh));
+        assert_eq!(normals, mesh.triangles.iter().map(|triangle| mesh.triangle_normal(triangle)).collect::<Vec<Vec3>>());
+        assert!(text.starts_with("solid ") && text.trim_end().ends_with("endsolid ct3d"));
+    }
+
+    #[test]
+    fn obj_reads_back() {
+        let mesh = tetrahedron();
+        let text = String::from_utf8(write(&mesh, "ct3d3_mesh_test.obj", false)).unwrap();
+        let mut read = Mesh::new();
+        for line in text.lines() {
+            let words: Vec<&str> = line.split_whitespace().collect();
+            match words.first() {
+                Some(&"v") => read.vertices.push(Vec3::new(words[1].parse().unwrap(), words[2].parse().unwrap(), words[3].parse().unwrap())),
+                Some(&"f") => read.triangles.push([1, 2, 3].map(|k| words[k].parse::<u32>().unwrap() - 1)),
+                _ => {}
+            }
+        }
+        assert_eq!((read.vertices, read.triangles), (mesh.vertices, mesh.triangles));
+    }
+
+    #[test]
+    fn ply_reads_back() {
+        let mesh = tetrahedron();
+        let bytes = write(&mesh, "ct3d3_mesh_test.ply", false);
+        let header_end = bytes.windows(11).position(|window| window == b"end_header\n").unwrap() + 11;
+        let header = String::from_utf8(bytes[..header_end].to_vec()).unwrap();
+        assert!(header.contains("element vertex 4\n") && header.contains("element face 4\n"));
+
+        let mut cursor = Cursor::new(bytes[header_end..].to_vec());
+        let vertices: Vec<Vec3> = (0..4).map(|_| read_vec3(&mut cursor)).collect();
+        let triangles: Vec<[u32; 3]> = (0..4).map(|_| {
+            assert_eq!(cursor.read_u8().unwrap(), 3);
+            [0; 3].map(|_: u32| cursor.read_i32::<LittleEndian>().unwrap() as u32)
+        }).collect();
+        assert_eq!((vertices, triangles), (mesh.vertices, mesh.triangles));
+        assert_eq!(cursor.read(&mut [0u8; 1]).unwrap(), 0);
+    }
+
+    #[test]
+    fn unknown_extensions_are_refused() {
+        let path = std::env::temp_dir().join("ct3d3_mesh_test.vtk").to_string_lossy().into_owned();
+        assert!(matches!(tetrahedron().serialize_to_file(path, false), Err(CT3DError::Unsupported(_))));
+    }
+}
@@ -1,65 +1,336 @@
+use std::fs::File;
+use std::io::{Write, BufWriter};
+
+#[cfg(feature = "sdl")]
 use sdl2::render::Texture;
 
-use ocl::{Buffer, Queue};
+use ocl::{Buffer, RwVec, FutureWriteGuard};
 
 use super::ct3d_error::CT3DError;
+use crate::tools::bitmap_font::{glyph, GLYPH_WIDTH};
 
 pub struct RGBImage {
     width: usize,
     height: usize,
-    pixel_data: Vec<u8>,
+    pixel_data: Vec<u32>, // packed 0xAARRGGBB, the layout of SDL's ARGB8888 and of the render kernel's output
+    rendered: Vec<u32> // the last rendered frame, before overlays
+}
+
+fn pack(value: (u8, u8, u8)) -> u32 {
+    0xff000000 | (value.0 as u32) << 16 | (value.1 as u32) << 8 | value.2 as u32
 }
 
 impl RGBImage {
     pub fn new(width: usize, height: usize) -> RGBImage {
         let num_pixels = width * height;
-        let mut pixel_data = vec![0u8; num_pixels * 3];
+        let pixel_data = vec![pack((0, 0, 0)); num_pixels];
+
+        RGBImage { width, height, rendered: pixel_data.clone(), pixel_data }
+    }
 
-        RGBImage { width, height, pixel_data }
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// Tightly packed RGB24 rows, top row first
+    pub fn to_rgb_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.pixel_data.len() * 3);
+        for pixel in self.pixel_data.iter() {
+            bytes.extend_from_slice(&[(pixel >> 16) as u8, (pixel >> 8) as u8, *pixel as u8]);
+        }
+        bytes
     }
 
     pub fn get_pixel(&self, x: usize, y: usize) -> (u8, u8, u8) {
-        let index = (y * self.width + x) * 3;
-        (self.pixel_data[index], self.pixel_data[index + 1], self.pixel_data[index + 2])
+        let pixel = self.pixel_data[y * self.width + x];
+        ((pixel >> 16) as u8, (pixel >> 8) as u8, pixel as u8)
     }
 
     pub fn set_pixel(&mut self, x: usize, y: usize, value: (u8, u8, u8)) {
-        let index = (y * self.width + x) * 3;
-        self.pixel_data[index] = value.0;
-        self.pixel_data[index + 1] = value.1;
-        self.pixel_data[index + 2] = value.2;
+        self.pixel_data[y * self.width + x] = pack(value);
     }
 
+    /// Like `set_pixel` but silently ignores coordinates outside the image
+    pub fn set_pixel_clipped(&mut self, x: i32, y: i32, value: (u8, u8, u8)) {
+        if x >= 0 && y >= 0 && (x as usize) < self.width && (y as usize) < self.height {
+            self.set_pixel(x as usize, y as usize, value);
+        }
+    }
 
-    // What the hell is going on here? Is it sld2 or my code that is causing this function to need to be so wonky?
-    pub fn copy_to_texture(&self, texture: &mut Texture){
-        texture.with_lock(sdl2::rect::Rect::new(0,0,self.width as u32, self.height as u32), |buffer: &mut [u8], pitch: usize| {
-            for x in 0..self.width {
-                for y in 0..self.height {
-                    let pixel = self.get_pixel(x, y);
-                    let tid = (y*self.width + x);
-                    buffer[(tid*4)+2] =  pixel.0;
-                    buffer[(tid*4)+1] =  pixel.1;
-                    buffer[(tid*4)+0] =  pixel.2;
-                    buffer[(tid*4)+3] =  255;
- 
-                }
+    pub fn draw_line(&mut self, x0: i32, y0: i32, x1: i32, y1: i32, value: (u8, u8, u8)) {
+        // Bresenham
+        let dx = (x1 - x0).abs();
+        let dy = -(y1 - y0).abs();
+        let sx = if x0 < x1 { 1 } else { -1 };
+        let sy = if y0 < y1 { 1 } else { -1 };
+        let mut error = dx + dy;
+        let (mut x, mut y) = (x0, y0);
+        loop {
+            self.set_pixel_clipped(x, y, value);
+            if x == x1 && y == y1 {
+                break;
+            }
+            let e2 = 2 * error;
+            if e2 >= dy {
+                error += dy;
+                x += sx;
+            }
+            if e2 <= dx {
+                error += dx;
+                y += sy;
+            }
+        }
+    }
+
+    /// Filled square centred on (x, y)
+    pub fn draw_marker(&mut self, x: i32, y: i32, radius: i32, value: (u8, u8, u8)) {
+        for py in (y - radius)..=(y + radius) {
+            for px in (x - radius)..=(x + radius) {
+                self.set_pixel_clipped(px, py, value);
             }
-        }).unwrap();
+        }
+    }
+
+    /// Mix `value` into a rectangle with weight `alpha` (0 keeps the image, 1 paints over it)
+    pub fn blend_rect(&mut self, x: i32, y: i32, width: i32, height: i32, value: (u8, u8, u8), alpha: f32) {
+        let mix = |from: u8, to: u8| (from as f32 + (to as f32 - from as f32) * alpha).round() as u8;
+        for py in y.max(0)..(y + height).min(self.height as i32) {
+            for px in x.max(0)..(x + width).min(self.width as i32) {
+                let (r, g, b) = self.get_pixel(px as usize, py as usize);
+                self.set_pixel(px as usize, py as usize, (mix(r, value.0), mix(g, value.1), mix(b, value.2)));
+            }
+        }
     }
 
-    pub fn extract_from_buffer(&mut self, buffer: &Buffer<f32>) -> Result<(), CT3DError>{
-        let mut data = vec![0.0f32; self.width*self.height*3];
-        buffer.read(&mut data).enq().unwrap();
-        for x in 0..self.width {
-            for y in 0..self.height {
-                for channel in 0..3 {
-                    self.pixel_data[((y*self.width+x)*3)+channel] = (255.0 * data[((y*self.width+x)*3)+channel].max(0.0).min(1.0)) as u8;
+    /// One line of text in the bitmap font with its top left corner at (x, y), each font pixel `scale` pixels wide
+    pub fn draw_text(&mut self, x: i32, y: i32, text: &str, scale: usize, value: (u8, u8, u8)) {
+        let scale = scale.max(1) as i32;
+        for (index, character) in text.chars().enumerate() {
+            let left = x + index as i32 * (GLYPH_WIDTH as i32 + 1) * scale;
+            for (row, bits) in glyph(character).iter().enumerate() {
+                for column in 0..GLYPH_WIDTH {
+                    if bits & (1 << (GLYPH_WIDTH - 1 - column)) != 0 {
+                        let (px, py) = (left + column as i32 * scale, y + row as i32 * scale);
+                        self.blend_rect(px, py, scale, scale, value, 1.0);
+                    }
                 }
             }
         }
+    }
+
+    /// Copy into memory laid out like a locked ARGB8888 texture, `pitch` bytes per row. ARGB8888 is a
+    /// packed format, each pixel a 32 bit 0xAARRGGBB in the machine's byte order (BGRA in memory on
+    /// little endian machines, ARGB on big endian ones).
+    pub fn copy_to_locked(&self, buffer: &mut [u8], pitch: usize) {
+        let row_bytes = self.width * 4;
+        for (y, row) in self.pixel_data.chunks_exact(self.width).enumerate() {
+            let line = &mut buffer[y * pitch..y * pitch + row_bytes];
+            for (bytes, pixel) in line.chunks_exact_mut(4).zip(row.iter()) {
+                bytes.copy_from_slice(&pixel.to_ne_bytes());
+            }
+        }
+    }
+
+    #[cfg(feature = "sdl")]
+    pub fn copy_to_texture(&self, texture: &mut Texture) -> Result<(), CT3DError> {
+        texture.with_lock(sdl2::rect::Rect::new(0,0,self.width as u32, self.height as u32), |buffer: &mut [u8], pitch: usize| {
+            self.copy_to_locked(buffer, pitch);
+        }).map_err(CT3DError::Sdl)
+    }
+
+    /// Keep a rendered frame of packed pixels, shown by the next `resolve`
+    pub fn set_rendered(&mut self, frame: &[u32]) {
+        let pixels = self.rendered.len();
+        self.rendered.copy_from_slice(&frame[..pixels]);
+    }
+
+    /// Pixels from the last rendered frame, replacing anything drawn since
+    pub fn resolve(&mut self) {
+        self.pixel_data.copy_from_slice(&self.rendered);
+    }
+
+    pub fn serialize_to_ppm(&self, path: String) -> Result<(), CT3DError> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writer.write_all(format!("P6\n{} {}\n255\n", self.width, self.height).as_bytes())?;
+        writer.write_all(&self.to_rgb_bytes())?;
+        writer.flush()?;
+        Ok(())
+    }
+
+}
+
+/// Double buffered, non-blocking reads of the frames the render kernel writes. A read is queued
+/// right after the kernel and collected on the next frame, so the transfer overlaps with
+/// presenting the previous frame instead of stalling on it.
+pub struct FrameReadback {
+    slots: [RwVec<u32>; 2],
+    pending: [Option<FutureWriteGuard<Vec<u32>>>; 2],
+    next: usize
+}
+
+impl FrameReadback {
+    pub fn new(pixels: usize) -> FrameReadback {
+        FrameReadback {
+            slots: [RwVec::from(vec![0u32; pixels]), RwVec::from(vec![0u32; pixels])],
+            pending: [None, None],
+            next: 0
+        }
+    }
+
+    /// Queue a read of `buffer` without waiting for it
+    pub fn request(&mut self, buffer: &Buffer<u32>) -> Result<(), CT3DError> {
+        let slot = self.next;
+        // The slot's previous frame is dropped if nobody collected it
+        self.pending[slot] = Some(buffer.read(&self.slots[slot]).enq_async()?);
+        self.next = 1 - slot;
+        Ok(())
+    }
 
+    /// Wait for the older outstanding read and hand its frame to `image`. With `wait_for_newest` the
+    /// latest request is waited for as well, for frames that queued nothing new or cannot lag behind.
+    pub fn collect(&mut self, image: &mut RGBImage, wait_for_newest: bool) -> Result<(), CT3DError> {
+        let slots = if wait_for_newest { vec![self.next, 1 - self.next] } else { vec![self.next] };
+        for slot in slots {
+            if let Some(future) = self.pending[slot].take() {
+                let frame = future.wait()?;
+                image.set_rendered(&frame);
+            }
+        }
         Ok(())
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::*;
+    use crate::types::application_state::ApplicationState;
+
+    const FRAMES: u32 = 20;
+
+    /// The path frames took before packed output: a float RGB frame read back from the device,
+    /// averaged in, converted to bytes and copied into the texture one pixel at a time
+    fn legacy_frame(frame: &[f32], accumulation: &mut [f32], bytes: &mut [u8], texture: &mut [u8], width: usize, height: usize, pitch: usize) {
+        for (accumulated, value) in accumulation.iter_mut().zip(frame.iter()) {
+            *accumulated += (value - *accumulated) * 0.5;
+        }
+        for (byte, value) in bytes.iter_mut().zip(accumulation.iter()) {
+            *byte = (255.0 * value.max(0.0).min(1.0)) as u8;
+        }
+        for x in 0..width {
+            for y in 0..height {
+                let pixel = (y * width + x) * 3;
+                let offset = y * pitch + x * 4;
+                texture[offset + 2] = bytes[pixel + 0];
+                texture[offset + 1] = bytes[pixel + 1];
+                texture[offset + 0] = bytes[pixel + 2];
+                texture[offset + 3] = 255;
+            }
+        }
+    }
+
+    fn time_frames(mut frame: impl FnMut()) -> Duration {
+        frame();
+        let start = Instant::now();
+        for _ in 0..FRAMES {
+            frame();
+        }
+        start.elapsed() / FRAMES
+    }
+
+    /// Host side cost per frame of getting a rendered frame into the texture.
+    /// `cargo test --release frame_time -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn frame_time_packed_against_legacy() {
+        for (width, height) in [(1920, 1080), (3840, 2160)] {
+            let pixels = width * height;
+            let pitch = width * 4;
+            let mut texture = vec![0u8; height * pitch];
+
+            let float_frame: Vec<f32> = (0..pixels * 3).map(|i| (i % 255) as f32 / 255.0).collect();
+            let mut accumulation = vec![0.0f32; pixels * 3];
+            let mut bytes = vec![0u8; pixels * 3];
+            let legacy = time_frames(|| legacy_frame(&float_frame, &mut accumulation, &mut bytes, &mut texture, width, height, pitch));
+
+            let packed_frame: Vec<u32> = (0..pixels as u32).map(|i| 0xff000000 | i).collect();
+            let mut image = RGBImage::new(width, height);
+            let packed = time_frames(|| {
+                image.set_rendered(&packed_frame);
+                image.resolve();
+                image.copy_to_locked(&mut texture, pitch);
+            });
+
+            println!(
+                "{}x{}: legacy {:.2} ms ({} MB read back), packed {:.2} ms ({} MB read back)",
+                width, height,
+                legacy.as_secs_f64() * 1000.0, pixels * 12 / 1_000_000,
+                packed.as_secs_f64() * 1000.0, pixels * 4 / 1_000_000
+            );
+            assert!(packed < legacy);
+        }
+    }
+
+    /// The whole trip of a frame from the device into the texture: the legacy float RGB frame read back
+    /// with a blocking read and converted on the host, against the packed frame read back through
+    /// `FrameReadback` and copied in one go. The render kernel itself is left out, it is the same for both.
+    /// Needs an OpenCL GPU: `cargo test --release frame_time_with_readback -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn frame_time_with_readback_packed_against_legacy() {
+        let mut application_state = ApplicationState::new(16, 16);
+        if let Err(e) = crate::application::init(&mut application_state) {
+            println!("Skipped, no OpenCL device: {}", e);
+            return;
+        }
+        let queue = application_state.opencl_state.queue.clone().unwrap();
+        for (width, height) in [(1920, 1080), (3840, 2160)] {
+            let pixels = width * height;
+            let pitch = width * 4;
+            let mut texture = vec![0u8; height * pitch];
+
+            let float_buffer = Buffer::<f32>::builder().queue(queue.clone()).len(pixels * 3).fill_val(0.5).build().unwrap();
+            let mut float_frame = vec![0.0f32; pixels * 3];
+            let mut accumulation = vec![0.0f32; pixels * 3];
+            let mut bytes = vec![0u8; pixels * 3];
+            let legacy = time_frames(|| {
+                float_buffer.read(&mut float_frame).enq().unwrap();
+                legacy_frame(&float_frame, &mut accumulation, &mut bytes, &mut texture, width, height, pitch);
+            });
+
+            let packed_buffer = Buffer::<u32>::builder().queue(queue.clone()).len(pixels).fill_val(0xff808080).build().unwrap();
+            let mut readback = FrameReadback::new(pixels);
+            let mut image = RGBImage::new(width, height);
+            let packed = time_frames(|| {
+                readback.request(&packed_buffer).unwrap();
+                readback.collect(&mut image, false).unwrap();
+                image.resolve();
+                image.copy_to_locked(&mut texture, pitch);
+            });
+            readback.collect(&mut image, true).unwrap();
+
+            println!(
+                "{}x{} with readback: legacy {:.2} ms, packed {:.2} ms",
+                width, height, legacy.as_secs_f64() * 1000.0, packed.as_secs_f64() * 1000.0
+            );
+            assert!(packed < legacy);
+        }
+    }
 
-}
\ No newline at end of file
+    #[test]
+    fn copy_to_locked_honours_pitch() {
+        let mut image = RGBImage::new(3, 2);
+        image.set_pixel(2, 1, (1, 2, 3));
+        let pitch = 3 * 4 + 8;
+        let mut texture = vec![0u8; 2 * pitch];
+        image.copy_to_locked(&mut texture, pitch);
+        let expected = if cfg!(target_endian = "little") { [3, 2, 1, 255] } else { [255, 1, 2, 3] };
+        assert_eq!(&texture[pitch + 8..pitch + 12], &expected);
+        assert_eq!(&texture[12..pitch], &[0; 8]);
+    }
+}
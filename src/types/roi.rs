@@ -0,0 +1,94 @@
+use glam::{Vec2, Vec3, IVec3};
+use serde::{Serialize, Deserialize};
+
+use crate::types::volume::Volume;
+
+/// Polygon drawn on one axial slice, points are millimetres in the slice plane
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SliceContour {
+    pub slice: i32,
+    pub points: Vec<Vec2>
+}
+
+/// Region of interest in millimetre coordinates (see `Volume::local_to_mm`)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "shape", rename_all = "lowercase")]
+pub enum Roi {
+    Sphere { center: Vec3, radius: f32 },
+    Box { min: Vec3, max: Vec3 },
+    Freehand { contours: Vec<SliceContour> }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NamedRoi {
+    pub name: String,
+    pub roi: Roi
+}
+
+fn polygon_contains(points: &[Vec2], p: Vec2) -> bool {
+    // Even-odd rule
+    let mut inside = false;
+    let mut j = points.len().wrapping_sub(1);
+    for i in 0..points.len() {
+        let (a, b) = (points[i], points[j]);
+        if (a.y > p.y) != (b.y > p.y) && p.x < (b.x - a.x) * (p.y - a.y) / (b.y - a.y) + a.x {
+            inside = !inside;
+        }
+        j = i;
+    }
+    inside
+}
+
+impl Roi {
+    /// Whether the centre of `voxel` lies inside the region
+    pub fn contains_voxel(&self, volume: &Volume, voxel: IVec3) -> bool {
+        let center = (voxel.as_vec3() + 0.5) * volume.spacing;
+        match self {
+            Roi::Sphere { center: sphere_center, radius } => center.distance_squared(*sphere_center) <= radius * radius,
+            Roi::Box { min, max } => center.cmpge(*min).all() && center.cmple(*max).all(),
+            Roi::Freehand { contours } => contours.iter().any(|contour| {
+                contour.slice == voxel.z && polygon_contains(&contour.points, Vec2::new(center.x, center.y))
+            })
+        }
+    }
+
+    /// Inclusive voxel range that can contain the region, clamped to the volume
+    pub fn voxel_bounds(&self, volume: &Volume) -> (IVec3, IVec3) {
+        let (min_mm, max_mm) = match self {
+            Roi::Sphere { center, radius } => (*center - Vec3::splat(*radius), *center + Vec3::splat(*radius)),
+            Roi::Box { min, max } => (*min, *max),
+            Roi::Freehand { contours } => {
+                let mut min = Vec3::splat(f32::MAX);
+                let mut max = Vec3::splat(f32::MIN);
+                for contour in contours.iter() {
+                    let z = (contour.slice as f32 + 0.5) * volume.spacing.z;
+                    for point in contour.points.iter() {
+                        min = min.min(Vec3::new(point.x, point.y, z));
+                        max = max.max(Vec3::new(point.x, point.y, z));
+                    }
+                }
+                (min, max)
+            }
+        };
+        let lower = (min_mm / volume.spacing).floor().as_ivec3().max(IVec3::ZERO);
+        let upper = (max_mm / volume.spacing).floor().as_ivec3().min(volume.res - IVec3::ONE);
+        (lower, upper)
+    }
+
+    /// Indices into `Volume::data` of every voxel inside the region
+    pub fn voxel_indices(&self, volume: &Volume) -> Vec<usize> {
+        let (lower, upper) = self.voxel_bounds(volume);
+        let mut indices = Vec::new();
+        for z in lower.z..=upper.z {
+            for y in lower.y..=upper.y {
+                for x in lower.x..=upper.x {
+                    let voxel = IVec3::new(x, y, z);
+                    if self.contains_voxel(volume, voxel) {
+                        indices.push((z*volume.res.x*volume.res.y + y*volume.res.x + x) as usize);
+                    }
+                }
+            }
+        }
+        indices
+    }
+}
@@ -0,0 +1,124 @@
+use std::fs::File;
+use std::io::BufWriter;
+
+use serde::{Serialize, Deserialize};
+
+use crate::types::ct3d_error::CT3DError;
+use crate::types::application_state::ApplicationState;
+use crate::types::camera_state::CameraState;
+use crate::types::measurement::Measurement;
+use crate::types::roi::NamedRoi;
+
+/// Version of the session format written by this build. Bump it when the meaning of a field
+/// changes; fields that are only added need `#[serde(default)]` so older files still load.
+pub const SESSION_VERSION: u32 = 1;
+pub const SESSION_PATH: &str = "temp/session.json";
+
+fn default_show_labels() -> bool {
+    true
+}
+
+/// What the viewer was showing: the files it loaded, the view (with the transfer function and clip
+/// box) and the annotations. Segmentations are referenced by `labelmap_source`, the voxels stay in their own file.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Session {
+    pub version: u32,
+    #[serde(default)]
+    pub volume_source: Option<String>, // None for the generated initial volume
+    #[serde(default)]
+    pub labelmap_source: Option<String>,
+    pub view: CameraState,
+    #[serde(default = "default_show_labels")]
+    pub show_labels: bool,
+    #[serde(default)]
+    pub measurements: Vec<Measurement>,
+    #[serde(default)]
+    pub rois: Vec<NamedRoi>
+}
+
+impl Session {
+    pub fn capture(application_state: &ApplicationState) -> Session {
+        Session {
+            version: SESSION_VERSION,
+            volume_source: application_state.volume_source.clone(),
+            labelmap_source: application_state.labelmap_source.clone(),
+            view: CameraState::capture(application_state),
+            show_labels: application_state.show_labels,
+            measurements: application_state.measurements.clone(),
+            rois: application_state.rois.clone()
+        }
+    }
+
+    /// Whether the file was written by a newer build, whose additions were skipped when reading it
+    pub fn is_newer(&self) -> bool {
+        self.version > SESSION_VERSION
+    }
+
+    pub fn to_json(&self) -> Result<String, CT3DError> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    /// Fields this build does not know are ignored, so sessions from newer builds load as far as they can
+    pub fn from_json(text: &str) -> Result<Session, CT3DError> {
+        let value: serde_json::Value = serde_json::from_str(text)?;
+        if !value.get("version").map(|version| version.is_u64()).unwrap_or(false) {
+            return Err(CT3DError::Format("Not a session file, it has no version".to_owned()));
+        }
+        Ok(serde_json::from_value(value)?)
+    }
+
+    pub fn serialize_to_file(&self, path: String) -> Result<(), CT3DError> {
+        let file = File::create(path)?;
+        serde_json::to_writer_pretty(BufWriter::new(file), self)?;
+        Ok(())
+    }
+
+    pub fn deserialize_from_file(path: String) -> Result<Session, CT3DError> {
+        Session::from_json(&std::fs::read_to_string(path)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::Vec3;
+
+    use super::*;
+    use crate::types::measurement::MeasurementKind;
+
+    #[test]
+    fn sessions_round_trip() {
+        let mut application_state = ApplicationState::new(8, 8);
+        application_state.volume_source = Some("scans/IM-0001.dcm".to_owned());
+        application_state.camera_z = -3.0;
+        application_state.low_cutoff = 0.42;
+        application_state.transfer_function.enabled = true;
+        application_state.transfer_function.set_window(0.3);
+        application_state.clip_box.set_min(1, 0.4);
+        application_state.measurements.push(Measurement { kind: MeasurementKind::Distance, points: vec![Vec3::ZERO, Vec3::ONE] });
+
+        let session = Session::from_json(&Session::capture(&application_state).to_json().unwrap()).unwrap();
+        assert_eq!(session.version, SESSION_VERSION);
+        assert_eq!(session.volume_source.as_deref(), Some("scans/IM-0001.dcm"));
+        assert_eq!(session.view, CameraState::capture(&application_state));
+        assert_eq!(session.view.transfer_function.as_ref(), Some(&application_state.transfer_function));
+        assert_eq!(session.view.clip_box, Some(application_state.clip_box));
+        assert_eq!(session.measurements[0].points, vec![Vec3::ZERO, Vec3::ONE]);
+        assert!(session.labelmap_source.is_none() && session.rois.is_empty());
+    }
+
+    #[test]
+    fn missing_and_unknown_fields_are_tolerated() {
+        let view = r#"{"orientation": [0.0, 0.0, 0.0, 1.0], "camera_z": -5.0, "low_cutoff": 0.25}"#;
+
+        let minimal = Session::from_json(&format!(r#"{{"version": 1, "view": {}}}"#, view)).unwrap();
+        assert!(minimal.show_labels);
+        assert!(minimal.view.transfer_function.is_none() && minimal.view.clip_box.is_none());
+        assert!(!minimal.is_newer());
+
+        let newer = Session::from_json(&format!(r#"{{"version": 7, "view": {}, "lighting": {{"ambient": 0.2}}}}"#, view)).unwrap();
+        assert!(newer.is_newer());
+        assert_eq!(newer.view.low_cutoff, 0.25);
+
+        assert!(Session::from_json(&format!(r#"{{"view": {}}}"#, view)).is_err());
+    }
+}
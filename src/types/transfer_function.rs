@@ -0,0 +1,214 @@
+use serde::{Serialize, Deserialize};
+
+/// Entries of the colour table the render kernel looks surface colours up in, for densities 0 to 1
+pub const TRANSFER_FUNCTION_ENTRIES: usize = 256;
+const MIN_WINDOW: f32 = 0.01;
+
+/// A colour on the ramp of a transfer function, at `position` 0 (the bottom of the window) to 1 (the top)
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ColorPoint {
+    pub position: f32,
+    pub color: [f32; 3]
+}
+
+/// Surface colour by density. The ramp of colour points is stretched over the densities from
+/// `level - window / 2` to `level + window / 2`; densities outside take the colour of the nearer end.
+/// When it is not enabled surfaces are coloured by position, as they always were.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TransferFunction {
+    pub enabled: bool,
+    pub level: f32,
+    pub window: f32,
+    pub points: Vec<ColorPoint> // sorted by position
+}
+
+fn mix(a: [f32; 3], b: [f32; 3], t: f32) -> [f32; 3] {
+    [a[0] + (b[0] - a[0]) * t, a[1] + (b[1] - a[1]) * t, a[2] + (b[2] - a[2]) * t]
+}
+
+impl Default for TransferFunction {
+    fn default() -> Self {
+        TransferFunction::new()
+    }
+}
+
+impl TransferFunction {
+    /// Soft tissue red to bone white over the whole density range
+    pub fn new() -> TransferFunction {
+        TransferFunction {
+            enabled: false,
+            level: 0.5,
+            window: 1.0,
+            points: vec![
+                ColorPoint { position: 0.0, color: [0.6, 0.2, 0.15] },
+                ColorPoint { position: 0.4, color: [0.85, 0.55, 0.4] },
+                ColorPoint { position: 1.0, color: [1.0, 1.0, 0.95] }
+            ]
+        }
+    }
+
+    pub fn set_window(&mut self, window: f32) {
+        self.window = window.max(MIN_WINDOW).min(1.0);
+    }
+
+    pub fn set_level(&mut self, level: f32) {
+        self.level = level.max(0.0).min(1.0);
+    }
+
+    /// Position on the ramp of a density, clamped to the ends
+    pub fn position_of(&self, density: f32) -> f32 {
+        let low = self.level - self.window / 2.0;
+        ((density - low) / self.window.max(MIN_WINDOW)).max(0.0).min(1.0)
+    }
+
+    /// Density at a position on the ramp
+    pub fn density_at(&self, position: f32) -> f32 {
+        self.level - self.window / 2.0 + position * self.window
+    }
+
+    pub fn color_at_position(&self, position: f32) -> [f32; 3] {
+        let first = match self.points.first() {
+            Some(point) => point,
+            None => return [1.0, 1.0, 1.0]
+        };
+        if position <= first.position {
+            return first.color;
+        }
+        for pair in self.points.windows(2) {
+            if position <= pair[1].position {
+                let span = (pair[1].position - pair[0].position).max(f32::EPSILON);
+                return mix(pair[0].color, pair[1].color, (position - pair[0].position) / span);
+            }
+        }
+        self.points.last().unwrap().color
+    }
+
+    pub fn color_of(&self, density: f32) -> [f32; 3] {
+        self.color_at_position(self.position_of(density))
+    }
+
+    /// `TRANSFER_FUNCTION_ENTRIES` RGBA colours (alpha 1) for evenly spaced densities from 0 to 1
+    pub fn to_table(&self) -> Vec<f32> {
+        (0..TRANSFER_FUNCTION_ENTRIES).flat_map(|entry| {
+            let color = self.color_of(entry as f32 / (TRANSFER_FUNCTION_ENTRIES - 1) as f32);
+            vec![color[0], color[1], color[2], 1.0]
+        }).collect()
+    }
+
+    fn sort(&mut self) {
+        self.points.sort_by(|a, b| a.position.partial_cmp(&b.position).unwrap());
+    }
+
+    /// Add a point with the colour the ramp has there, returning its index
+    pub fn insert_point(&mut self, position: f32) -> usize {
+        let position = position.max(0.0).min(1.0);
+        let color = self.color_at_position(position);
+        self.points.push(ColorPoint { position, color });
+        self.sort();
+        self.points.iter().position(|point| point.position == position).unwrap()
+    }
+
+    /// Move a point along the ramp, returning its index after the points are put back in order
+    pub fn move_point(&mut self, index: usize, position: f32) -> usize {
+        let position = position.max(0.0).min(1.0);
+        self.points[index].position = position;
+        let moved = self.points[index];
+        self.sort();
+        self.points.iter().position(|point| *point == moved).unwrap()
+    }
+
+    /// Blend towards `other`, for camera animations. The ramp gets a point wherever either ramp has one,
+    /// so it matches each of them exactly at its end. Switching on or off happens halfway.
+    pub fn interpolate(&self, other: &TransferFunction, t: f32) -> TransferFunction {
+        let t = t.max(0.0).min(1.0);
+        let mut positions: Vec<f32> = self.points.iter().chain(other.points.iter()).map(|point| point.position).collect();
+        positions.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        positions.dedup();
+        TransferFunction {
+            enabled: if t < 0.5 { self.enabled } else { other.enabled },
+            level: self.level + (other.level - self.level) * t,
+            window: self.window + (other.window - self.window) * t,
+            points: positions.iter().map(|position| ColorPoint {
+                position: *position,
+                color: mix(self.color_at_position(*position), other.color_at_position(*position), t)
+            }).collect()
+        }
+    }
+
+    /// Remove a point, keeping at least two so there is still a ramp
+    pub fn remove_point(&mut self, index: usize) {
+        if self.points.len() > 2 && index < self.points.len() {
+            self.points.remove(index);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_color(actual: [f32; 3], expected: [f32; 3]) {
+        assert!(actual.iter().zip(expected.iter()).all(|(a, e)| (a - e).abs() < 1e-5), "{:?} != {:?}", actual, expected);
+    }
+
+    #[test]
+    fn colours_interpolate_within_the_window_and_clamp_outside() {
+        let mut transfer_function = TransferFunction {
+            enabled: true,
+            level: 0.5,
+            window: 0.4,
+            points: vec![
+                ColorPoint { position: 0.0, color: [0.0, 0.0, 0.0] },
+                ColorPoint { position: 1.0, color: [1.0, 0.5, 0.0] }
+            ]
+        };
+        assert_color(transfer_function.color_of(0.1), [0.0, 0.0, 0.0]);
+        assert_color(transfer_function.color_of(0.5), [0.5, 0.25, 0.0]);
+        assert_color(transfer_function.color_of(0.9), [1.0, 0.5, 0.0]);
+        assert!((transfer_function.density_at(0.25) - 0.4).abs() < 1e-6);
+
+        transfer_function.set_window(0.0);
+        assert_eq!(transfer_function.window, MIN_WINDOW);
+
+        let table = transfer_function.to_table();
+        assert_eq!(table.len(), TRANSFER_FUNCTION_ENTRIES * 4);
+        assert_color([table[0], table[1], table[2]], [0.0, 0.0, 0.0]);
+        assert_eq!(table[table.len() - 1], 1.0);
+    }
+
+    #[test]
+    fn edited_points_stay_in_order() {
+        let mut transfer_function = TransferFunction::new();
+        let inserted = transfer_function.insert_point(0.7);
+        assert_eq!(inserted, 2);
+        assert_color(transfer_function.points[inserted].color, TransferFunction::new().color_at_position(0.7));
+
+        let moved = transfer_function.move_point(inserted, 0.1);
+        assert_eq!(moved, 1);
+        let positions: Vec<f32> = transfer_function.points.iter().map(|point| point.position).collect();
+        assert_eq!(positions, vec![0.0, 0.1, 0.4, 1.0]);
+
+        transfer_function.remove_point(1);
+        transfer_function.remove_point(1);
+        transfer_function.remove_point(0);
+        assert_eq!(transfer_function.points.len(), 2);
+    }
+
+    #[test]
+    fn interpolation_matches_both_ends() {
+        let start = TransferFunction::new();
+        let mut end = TransferFunction::new();
+        end.enabled = true;
+        end.set_level(0.3);
+        end.insert_point(0.8);
+        end.points[0].color = [0.0, 0.0, 1.0];
+
+        for density in [0.0, 0.2, 0.45, 0.7, 1.0] {
+            assert_color(start.interpolate(&end, 0.0).color_of(density), start.color_of(density));
+            assert_color(start.interpolate(&end, 1.0).color_of(density), end.color_of(density));
+        }
+        let halfway = start.interpolate(&end, 0.5);
+        assert!((halfway.level - 0.4).abs() < 1e-6);
+        assert!(halfway.enabled && !start.interpolate(&end, 0.4).enabled);
+    }
+}
@@ -1,48 +1,90 @@
 use std::fs::File;
 use std::io::{Write, BufRead, BufReader, Read};
 
-use glam::{Vec3, IVec3};
+use glam::{Vec2, Vec3, IVec3, Mat3};
 use ocl::Buffer;
 
 
-use crate::types::ct3d_error::CT3DError;
+use crate::types::ct3d_error::{CT3DError, ErrorContext};
+use crate::processing::parallel::parallel_fill;
+
+// Levels of detail stop once the longest axis is this short
+const MIP_MIN_RES: i32 = 32;
 
 pub struct Volume {
     pub radii: Vec3, 
     pub res: IVec3,
+    pub spacing: Vec3, // millimetres per voxel along each axis
+    pub hu_range: Option<Vec2>, // Hounsfield units that the normalized values 0.0 and 1.0 correspond to
+    pub origin: Vec3, // patient (LPS, as in DICOM) millimetre position of the centre of the first voxel
+    pub direction: Mat3, // columns are the patient space directions of the x, y and z voxel axes
     pub data: Vec<f32>
 }
 
+const SPACING_KEY: &str = "spacing";
+const HU_RANGE_KEY: &str = "hu_range";
+const ORIGIN_KEY: &str = "origin";
+const DIRECTION_KEY: &str = "direction";
+const METADATA_KEYS: [&str; 4] = [SPACING_KEY, HU_RANGE_KEY, ORIGIN_KEY, DIRECTION_KEY];
+
 pub fn text_to_Vec3(text: String) -> Result<Vec3, CT3DError>{
     
     let mut data_vec: Vec<f32> = Vec::<f32>::new();
 
     for value in text.split_whitespace().into_iter() {
-        data_vec.push(value.parse::<f32>().map_err(
-            |e| CT3DError::new(Some(Box::new(e)))
-        )?);
+        data_vec.push(value.parse::<f32>()?);
     }
 
     if data_vec.len() != 3 {
-        return Err(CT3DError::new(None));
+        return Err(CT3DError::Format(format!("Expected 3 numbers, found: {}", text.trim())));
     }
 
     Ok(Vec3::new(data_vec[0], data_vec[1],data_vec[2]))
 
 }
 
+pub fn text_to_Vec2(text: String) -> Result<Vec2, CT3DError>{
+    
+    let mut data_vec: Vec<f32> = Vec::<f32>::new();
+
+    for value in text.split_whitespace().into_iter() {
+        data_vec.push(value.parse::<f32>()?);
+    }
+
+    if data_vec.len() != 2 {
+        return Err(CT3DError::Format(format!("Expected 2 numbers, found: {}", text.trim())));
+    }
+
+    Ok(Vec2::new(data_vec[0], data_vec[1]))
+
+}
+
+pub fn text_to_Mat3(text: String) -> Result<Mat3, CT3DError>{
+    
+    let mut data_vec: Vec<f32> = Vec::<f32>::new();
+
+    for value in text.split_whitespace().into_iter() {
+        data_vec.push(value.parse::<f32>()?);
+    }
+
+    if data_vec.len() != 9 {
+        return Err(CT3DError::Format(format!("Expected 9 numbers, found: {}", text.trim())));
+    }
+
+    Ok(Mat3::from_cols_slice(&data_vec))
+
+}
+
 pub fn text_to_IVec3(text: String) -> Result<IVec3, CT3DError>{
     
     let mut data_vec: Vec<i32> = Vec::<i32>::new();
 
     for value in text.split_whitespace().into_iter() {
-        data_vec.push(value.parse::<i32>().map_err(
-            |e| CT3DError::new(Some(Box::new(e)))
-        )?);
+        data_vec.push(value.parse::<i32>()?);
     }
 
     if data_vec.len() != 3 {
-        return Err(CT3DError::new(None));
+        return Err(CT3DError::Format(format!("Expected 3 numbers, found: {}", text.trim())));
     }
 
     Ok(IVec3::new(data_vec[0], data_vec[1],data_vec[2]))
@@ -54,9 +96,30 @@ impl Volume {
         Self {
             radii:radii,
             res:res,
+            spacing: Vec3::new(1.0, 1.0, 1.0),
+            hu_range: None,
+            origin: Vec3::ZERO,
+            direction: Mat3::IDENTITY,
             data: vec![0.0;(res.x*res.y*res.z).try_into().unwrap()]
         }
     }
+    /// Same grid and metadata with different voxel values
+    pub fn with_data(&self, data: Vec<f32>) -> Volume {
+        Volume {
+            radii: self.radii,
+            res: self.res,
+            spacing: self.spacing,
+            hu_range: self.hu_range,
+            origin: self.origin,
+            direction: self.direction,
+            data
+        }
+    }
+    /// Radii proportional to the physical extent, scaled so the shortest axis has radius 1.0 (as in dicom_to_volume.py)
+    pub fn radii_for(res: IVec3, spacing: Vec3) -> Vec3 {
+        let half_extent = res.as_vec3() * spacing / 2.0;
+        half_extent / half_extent.min_element()
+    }
     pub fn set(&mut self, coord: IVec3, value:f32){
         let idx = coord.z*self.res.x*self.res.y + coord.y*self.res.x + coord.x;
         self.data[idx as usize] = value;
@@ -65,7 +128,89 @@ impl Volume {
         let idx = coord.z*self.res.x*self.res.y + coord.y*self.res.x + coord.x;
         return self.data[idx as usize];
     }
-    pub fn to_ocl_buffer(&self, buffer: &mut Buffer<f32>){
+
+    /// Continuous voxel coordinates (0..res along each axis) of a point in the renderer's local space (-radii..radii)
+    pub fn local_to_voxel(&self, local: Vec3) -> Vec3 {
+        (local + self.radii) / (2.0 * self.radii) * self.res.as_vec3()
+    }
+    pub fn voxel_to_local(&self, voxel: Vec3) -> Vec3 {
+        voxel / self.res.as_vec3() * (2.0 * self.radii) - self.radii
+    }
+    /// Millimetre coordinates measured from the corner of the first voxel
+    pub fn local_to_mm(&self, local: Vec3) -> Vec3 {
+        self.local_to_voxel(local) * self.spacing
+    }
+    pub fn mm_to_local(&self, mm: Vec3) -> Vec3 {
+        self.voxel_to_local(mm / self.spacing)
+    }
+    /// Patient position of a voxel index, where integer indices are voxel centres
+    pub fn index_to_patient(&self, index: Vec3) -> Vec3 {
+        self.origin + self.direction * (index * self.spacing)
+    }
+    pub fn patient_to_index(&self, patient: Vec3) -> Vec3 {
+        self.direction.inverse() * (patient - self.origin) / self.spacing
+    }
+    pub fn local_to_patient(&self, local: Vec3) -> Vec3 {
+        self.index_to_patient(self.local_to_voxel(local) - Vec3::splat(0.5))
+    }
+    pub fn contains_local(&self, local: Vec3) -> bool {
+        local.cmpge(-self.radii).all() && local.cmplt(self.radii).all()
+    }
+    /// Hounsfield units of a normalized value, if the volume came from calibrated CT data
+    pub fn to_hu(&self, value: f32) -> Option<f32> {
+        self.hu_range.map(|hu_range| hu_range.x + value * (hu_range.y - hu_range.x))
+    }
+    /// Inverse of `to_hu`
+    pub fn from_hu(&self, hu: f32) -> Option<f32> {
+        self.hu_range.map(|hu_range| (hu - hu_range.x) / (hu_range.y - hu_range.x))
+    }
+    /// Same lookup as `vd_query` in render.cl
+    pub fn query_local(&self, local: Vec3) -> Option<f32> {
+        if !self.contains_local(local) {
+            return None;
+        }
+        let voxel = self.local_to_voxel(local).as_ivec3().min(self.res - IVec3::ONE);
+        Some(self.get(voxel))
+    }
+    /// Half resolution copy covering the same extent, each voxel the mean of a 2x2x2 block.
+    /// Odd sizes round up, repeating the last voxel.
+    pub fn downsampled(&self) -> Volume {
+        let res = (self.res + IVec3::ONE) / 2;
+        let slice = (res.x * res.y) as usize;
+        let data = parallel_fill((res.x * res.y * res.z) as usize, slice, |start, chunk: &mut [f32]| {
+            for (offset, value) in chunk.iter_mut().enumerate() {
+                let index = start + offset;
+                let coord = IVec3::new((index % res.x as usize) as i32, ((index / res.x as usize) % res.y as usize) as i32, (index / slice) as i32);
+                let mut sum = 0.0;
+                for corner in 0..8 {
+                    let source = coord * 2 + IVec3::new(corner & 1, (corner >> 1) & 1, (corner >> 2) & 1);
+                    sum += self.get(source.min(self.res - IVec3::ONE));
+                }
+                *value = sum / 8.0;
+            }
+        });
+        let spacing = self.spacing * self.res.as_vec3() / res.as_vec3();
+        let mut result = self.with_data(data);
+        result.res = res;
+        result.spacing = spacing;
+        result.origin = self.index_to_patient(0.5 * spacing / self.spacing - Vec3::splat(0.5));
+        result
+    }
+    /// Successively halved copies for level of detail rendering, finest first, not including the volume itself
+    pub fn mip_levels(&self) -> Vec<Volume> {
+        let mut levels: Vec<Volume> = Vec::new();
+        while levels.last().map(|level| level.res).unwrap_or(self.res).max_element() > MIP_MIN_RES {
+            let next = levels.last().unwrap_or(self).downsampled();
+            levels.push(next);
+        }
+        levels
+    }
+    /// Number of floats `to_ocl_buffer` writes, header included
+    pub fn ocl_buffer_len(&self) -> usize {
+        7 + self.data.len()
+    }
+    /// Write header and data starting `offset` floats into the buffer, see `vd_build` in render.cl
+    pub fn to_ocl_buffer(&self, buffer: &Buffer<f32>, offset: usize) -> Result<(), CT3DError> {
         let mut data = Vec::<f32>::new();
         data.push(1.0f32);
         data.push(self.radii.x);
@@ -75,7 +220,8 @@ impl Volume {
         data.push(self.res.y as f32);
         data.push(self.res.z as f32);
         data.extend(self.data.clone());
-        buffer.write(&data).enq().unwrap();
+        buffer.write(&data).offset(offset).enq()?;
+        Ok(())
     }
     pub fn normalize(&mut self){
         let min = *self.data.iter().min_by(|x, y| x.partial_cmp(y).unwrap()).unwrap();
@@ -84,45 +230,116 @@ impl Volume {
             *x = (*x-min)/(max-min);
         });
     }
+    /// Normalize values in the file's own units, which are HU for CT, and keep their range as the HU range
+    pub fn normalize_to_hu_range(&mut self) {
+        let min = self.data.iter().fold(f32::MAX, |a, b| a.min(*b));
+        let max = self.data.iter().fold(f32::MIN, |a, b| a.max(*b));
+        if max > min {
+            self.hu_range = Some(Vec2::new(min, max));
+            self.normalize();
+        } else {
+            self.data.iter_mut().for_each(|x| *x = 0.0);
+        }
+    }
+    /// A NIfTI or NRRD image, holding the values stored in the file
+    pub fn read_image(path: String) -> Result<Volume, CT3DError> {
+        if crate::loaders::nifti::is_nifti_path(&path) {
+            crate::loaders::nifti::read_nifti(path)
+        } else if crate::loaders::nrrd::is_nrrd_path(&path) {
+            crate::loaders::nrrd::read_nrrd(path)
+        } else {
+            Err(CT3DError::Unsupported(format!("Unsupported image format: {}", path)))
+        }
+    }
 
     pub fn serialize_to_file(&self, path: String) -> Result<(), CT3DError> {
         
-        let mut file = File::create(path).map_err(|e| CT3DError::new(Some(Box::new(e))))?;
+        let mut file = File::create(path)?;
         
         let line1: String = format!("{} {} {}\n", self.radii.x, self.radii.y, self.radii.z);
         let line2: String = format!("{} {} {}\n", self.res.x, self.res.y, self.res.z);
 
-        file.write_all(line1.as_bytes()).map_err(|e| CT3DError::new(Some(Box::new(e))))?;
+        file.write_all(line1.as_bytes())?;
 
-        file.write_all(line2.as_bytes()).map_err(|e| CT3DError::new(Some(Box::new(e))))?;
+        file.write_all(line2.as_bytes())?;
+
+        let spacing_line: String = format!("{} {} {} {}\n", SPACING_KEY, self.spacing.x, self.spacing.y, self.spacing.z);
+        file.write_all(spacing_line.as_bytes())?;
+
+        if let Some(hu_range) = self.hu_range {
+            let hu_range_line: String = format!("{} {} {}\n", HU_RANGE_KEY, hu_range.x, hu_range.y);
+            file.write_all(hu_range_line.as_bytes())?;
+        }
+
+        let origin_line: String = format!("{} {} {} {}\n", ORIGIN_KEY, self.origin.x, self.origin.y, self.origin.z);
+        file.write_all(origin_line.as_bytes())?;
+
+        let direction_values: Vec<String> = self.direction.to_cols_array().iter().map(|value| value.to_string()).collect();
+        let direction_line: String = format!("{} {}\n", DIRECTION_KEY, direction_values.join(" "));
+        file.write_all(direction_line.as_bytes())?;
 
         for value in self.data.iter() {
             let bytes = (*value).to_ne_bytes();
-            file.write_all(&bytes).map_err(|e| CT3DError::new(Some(Box::new(e))))?;
+            file.write_all(&bytes)?;
         }
 
         Ok(())
 
     }
+    /// Whether the file at `path` records the direction of its axes. Files written before patient
+    /// geometry was stored go straight from the metadata to the voxels without one.
+    pub fn file_has_direction(path: String) -> Result<bool, CT3DError> {
+        let file = File::open(&path).with_context(|| format!("Cannot open {}", path))?;
+        let mut reader = BufReader::new(file);
+        let mut line: String = String::new();
+        reader.read_line(&mut line)?;
+        reader.read_line(&mut line)?;
+        while let Some(key) = METADATA_KEYS.iter().find(|key| reader.fill_buf().map(|buf| buf.starts_with(format!("{} ", key).as_bytes())).unwrap_or(false)) {
+            if *key == DIRECTION_KEY {
+                return Ok(true);
+            }
+            reader.read_line(&mut line)?;
+        }
+        Ok(false)
+    }
+
     pub fn deserialize_from_file(path: String) -> Result<Volume,CT3DError> {
-        let file = File::open(path).map_err(|e| CT3DError::new(Some(Box::new(e))))?;
+        let file = File::open(&path).with_context(|| format!("Cannot open {}", path))?;
         let mut reader = BufReader::new(file);
 
         let mut line1: String = String::new();
 
-        reader.read_line(&mut line1).map_err(|e| CT3DError::new(Some(Box::new(e))))?;
+        reader.read_line(&mut line1)?;
 
         let mut line2: String = String::new(); 
-        reader.read_line(&mut line2).map_err(|e| CT3DError::new(Some(Box::new(e))))?;
+        reader.read_line(&mut line2)?;
 
         let radii = text_to_Vec3(line1)?;
         let res = text_to_IVec3(line2)?;
 
+        // Optional metadata lines, older files go straight to the voxel data
+        let mut spacing = Vec3::new(1.0, 1.0, 1.0);
+        let mut hu_range = None;
+        let mut origin = Vec3::ZERO;
+        let mut direction = Mat3::IDENTITY;
+        while let Some(key) = METADATA_KEYS.iter().find(|key| reader.fill_buf().map(|buf| buf.starts_with(format!("{} ", key).as_bytes())).unwrap_or(false)) {
+            let mut line: String = String::new();
+            reader.read_line(&mut line)?;
+            let value = line[key.len()..].to_owned();
+            match *key {
+                SPACING_KEY => spacing = text_to_Vec3(value)?,
+                HU_RANGE_KEY => hu_range = Some(text_to_Vec2(value)?),
+                ORIGIN_KEY => origin = text_to_Vec3(value)?,
+                DIRECTION_KEY => direction = text_to_Mat3(value)?,
+                _ => {}
+            }
+        }
+
         let mut data_bytes: Vec<u8> = Vec::<u8>::new();
         reader.read_to_end(&mut data_bytes)?;
         
         if data_bytes.len() % 4 != 0 {
-            return Err(CT3DError::new(None));
+            return Err(CT3DError::Format(format!("Voxel data of {} bytes is not a whole number of floats", data_bytes.len())));
         }
 
         let mut data = Vec::<f32>::new();
@@ -137,9 +354,67 @@ impl Volume {
 
         let mut result = Volume::new(radii, res);
 
+        result.spacing = spacing;
+        result.hu_range = hu_range;
+        result.origin = origin;
+        result.direction = direction;
         result.data = data;
 
         Ok(result)
     }
 
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn noise_volume(res: IVec3) -> Volume {
+        let mut volume = Volume::new(Vec3::ONE, res);
+        volume.spacing = Vec3::new(0.7, 0.9, 2.5);
+        volume.origin = Vec3::new(-120.0, 35.0, 80.0);
+        volume.direction = Mat3::from_rotation_z(0.3) * Mat3::from_cols(Vec3::X, Vec3::new(0.0, 0.0, -1.0), Vec3::Y);
+        volume.data = (0..volume.data.len()).map(|index| ((index * 7919) % 1000) as f32 / 1000.0).collect();
+        volume
+    }
+
+    fn mean(volume: &Volume) -> f64 {
+        volume.data.iter().map(|value| *value as f64).sum::<f64>() / volume.data.len() as f64
+    }
+
+    #[test]
+    fn downsampling_preserves_the_mean() {
+        let volume = noise_volume(IVec3::new(8, 6, 4));
+        let half = volume.downsampled();
+        assert_eq!(half.res, IVec3::new(4, 3, 2));
+        assert!((mean(&half) - mean(&volume)).abs() < 1e-6);
+    }
+
+    #[test]
+    fn odd_sizes_round_up() {
+        let mut volume = noise_volume(IVec3::new(5, 4, 3));
+        let half = volume.downsampled();
+        assert_eq!(half.res, IVec3::new(3, 2, 2));
+        // The last voxel along x averages the last input voxel with itself
+        let expected = [IVec3::new(4, 0, 0), IVec3::new(4, 1, 0), IVec3::new(4, 0, 1), IVec3::new(4, 1, 1)].iter().map(|coord| volume.get(*coord)).sum::<f32>() / 4.0;
+        assert!((half.get(IVec3::new(2, 0, 0)) - expected).abs() < 1e-6);
+
+        volume.data.iter_mut().for_each(|value| *value = 0.25);
+        assert!(volume.downsampled().data.iter().all(|value| *value == 0.25));
+    }
+
+    #[test]
+    fn mip_levels_cover_the_same_extent() {
+        let volume = noise_volume(IVec3::new(100, 70, 40));
+        let levels = volume.mip_levels();
+        assert_eq!(levels.iter().map(|level| level.res).collect::<Vec<IVec3>>(), vec![IVec3::new(50, 35, 20), IVec3::new(25, 18, 10)]);
+        // Outer corners of the first and last voxels
+        let corners = |volume: &Volume| (volume.index_to_patient(Vec3::splat(-0.5)), volume.index_to_patient(volume.res.as_vec3() - Vec3::splat(0.5)));
+        let (low, high) = corners(&volume);
+        for level in levels.iter() {
+            let (level_low, level_high) = corners(level);
+            assert!(level_low.abs_diff_eq(low, 1e-3) && level_high.abs_diff_eq(high, 1e-3), "{} to {} instead of {} to {}", level_low, level_high, low, high);
+            assert_eq!(level.direction, volume.direction);
+        }
+    }
+}
@@ -0,0 +1,586 @@
+use std::path::Path;
+use std::time::Instant;
+
+use crate::types::application_state::ApplicationState;
+use crate::types::ct3d_error::CT3DError;
+use crate::input::keybindings::{InputEvent, MOUSE_LEFT, MOUSE_RIGHT};
+use crate::tools::bitmap_font::text_width;
+use crate::ui::widgets::{Rect, Slider, TransferFunctionEditor, draw_label, line_height, TEXT_COLOR, TEXT_SCALE};
+
+const MARGIN: i32 = 8;
+const TOAST_SECONDS: f32 = 4.0;
+const MAX_TOASTS: usize = 4;
+const ERROR_COLOR: (u8, u8, u8) = (255, 130, 110);
+const PANEL_MAX_WIDTH: i32 = 560;
+const SLIDER_TEXT_WIDTH: i32 = 200;
+const EDITOR_HEIGHT: i32 = 80;
+const HISTOGRAM_BINS: usize = 128;
+const HUD_MEASUREMENTS: usize = 3; // Most recent measurements listed in the HUD
+const MEASUREMENT_LIST_WIDTH: i32 = 340;
+const MEASUREMENT_LIST_ROWS: usize = 12; // Measurements listed at once, the wheel scrolls through the rest
+const DELETE_COLOR: (u8, u8, u8) = (190, 60, 50);
+// Colours a transfer function point steps through with the mouse wheel
+const POINT_COLORS: [[f32; 3]; 8] = [
+    [1.0, 1.0, 0.95], [0.85, 0.55, 0.4], [0.6, 0.2, 0.15], [0.9, 0.8, 0.3],
+    [0.3, 0.7, 0.3], [0.3, 0.5, 0.9], [0.6, 0.3, 0.8], [0.15, 0.15, 0.15]
+];
+
+pub struct Toast {
+    pub text: String,
+    pub error: bool,
+    pub shown: Instant
+}
+
+/// What a drag in the control panel changes
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Control {
+    Cutoff,
+    Level,
+    Window,
+    ColorPoint(usize),
+    Clip { axis: usize, upper: bool } // a face of the clip box
+}
+
+const SLIDERS: [Control; 6] = [
+    Control::Cutoff, Control::Level, Control::Window,
+    Control::Clip { axis: 0, upper: false }, Control::Clip { axis: 1, upper: false }, Control::Clip { axis: 2, upper: false }
+];
+const AXIS_NAMES: [&str; 3] = ["X", "Y", "Z"];
+
+/// Text and controls drawn over the rendered image: a HUD with the state of the viewer, short-lived
+/// messages, a panel with the cutoff, window, level and clipping sliders and the transfer function
+/// editor, and the list of measurements
+pub struct OverlayState {
+    pub show_hud: bool,
+    pub show_controls: bool,
+    pub show_measurements: bool,
+    pub toasts: Vec<Toast>,
+    dragging: Option<Control>,
+    histogram: Option<Vec<f32>>, // of the current volume, relative bin heights
+    measurement_scroll: usize // first measurement in the list
+}
+
+impl Default for OverlayState {
+    fn default() -> Self {
+        OverlayState::new()
+    }
+}
+
+impl OverlayState {
+    pub fn new() -> OverlayState {
+        OverlayState {
+            show_hud: false,
+            show_controls: false,
+            show_measurements: false,
+            toasts: Vec::new(),
+            dragging: None,
+            histogram: None,
+            measurement_scroll: 0
+        }
+    }
+
+    /// Forget what was worked out from the previous volume
+    pub fn volume_changed(&mut self) {
+        self.histogram = None;
+    }
+}
+
+fn push_toast(application_state: &mut ApplicationState, text: String, error: bool) {
+    let toasts = &mut application_state.overlay.toasts;
+    toasts.push(Toast { text, error, shown: Instant::now() });
+    if toasts.len() > MAX_TOASTS {
+        toasts.remove(0);
+    }
+}
+
+/// Show a message for a few seconds
+pub fn toast(application_state: &mut ApplicationState, text: String) {
+    push_toast(application_state, text, false);
+}
+
+pub fn toast_error(application_state: &mut ApplicationState, text: String) {
+    push_toast(application_state, text, true);
+}
+
+/// The control panel's place on a `width` x `height` screen
+struct Layout {
+    panel: Rect,
+    sliders: Vec<(Control, Slider)>,
+    editor: TransferFunctionEditor
+}
+
+fn layout(width: u32, height: u32) -> Layout {
+    let row = line_height();
+    let panel_width = (width as i32 - 2 * MARGIN).min(PANEL_MAX_WIDTH);
+    let panel_height = MARGIN + SLIDERS.len() as i32 * row + MARGIN + EDITOR_HEIGHT + MARGIN;
+    let panel = Rect { x: MARGIN, y: height as i32 - MARGIN - panel_height, width: panel_width, height: panel_height };
+    let inner_x = panel.x + MARGIN;
+    let inner_width = panel.width - 2 * MARGIN;
+
+    let sliders = SLIDERS.iter().enumerate().map(|(index, control)| {
+        let bounds = Rect { x: inner_x, y: panel.y + MARGIN + index as i32 * row, width: inner_width, height: row };
+        (*control, Slider::new(bounds, SLIDER_TEXT_WIDTH))
+    }).collect();
+    let editor = TransferFunctionEditor {
+        area: Rect { x: inner_x, y: panel.y + MARGIN + SLIDERS.len() as i32 * row + MARGIN, width: inner_width, height: EDITOR_HEIGHT }
+    };
+    Layout { panel, sliders, editor }
+}
+
+impl Layout {
+    /// The slider `control` is dragged on. Both faces of the clip box along an axis share one.
+    fn slider(&self, control: Control) -> Option<&Slider> {
+        let row = match control {
+            Control::Clip { axis, .. } => Control::Clip { axis, upper: false },
+            control => control
+        };
+        self.sliders.iter().find(|(slider_control, _)| *slider_control == row).map(|(_, slider)| slider)
+    }
+}
+
+/// The measurement list's place in the top right corner: a title, then a row per listed measurement
+/// with a button deleting it at the right end
+struct MeasurementList {
+    panel: Rect,
+    first: usize, // index of the measurement in the first row
+    rows: Vec<Rect>
+}
+
+fn measurement_list(application_state: &ApplicationState) -> MeasurementList {
+    let row = line_height();
+    let (width, height) = (application_state.width as i32, application_state.height as i32);
+    let bottom = if application_state.overlay.show_controls { layout(application_state.width, application_state.height).panel.y } else { height };
+    let count = application_state.measurements.len();
+    let fitting = ((bottom - 3 * MARGIN) / row - 1).max(0) as usize;
+    let listed = count.min(MEASUREMENT_LIST_ROWS).min(fitting);
+    let first = application_state.overlay.measurement_scroll.min(count - listed);
+
+    let panel_width = (width - 2 * MARGIN).min(MEASUREMENT_LIST_WIDTH);
+    let panel = Rect { x: width - MARGIN - panel_width, y: MARGIN, width: panel_width, height: MARGIN + (listed as i32 + 1) * row + MARGIN };
+    let rows = (0..listed).map(|index| Rect { x: panel.x + MARGIN, y: panel.y + MARGIN + (index as i32 + 1) * row, width: panel.width - 2 * MARGIN, height: row }).collect();
+    MeasurementList { panel, first, rows }
+}
+
+impl MeasurementList {
+    fn delete_button(row: &Rect) -> Rect {
+        Rect { x: row.x + row.width - row.height, y: row.y, width: row.height, height: row.height }
+    }
+
+    /// The measurement whose delete button is at (x, y)
+    fn delete_at(&self, x: i32, y: i32) -> Option<usize> {
+        self.rows.iter().position(|row| MeasurementList::delete_button(row).contains(x, y)).map(|index| self.first + index)
+    }
+}
+
+/// A normalized density as HU when the volume has a HU range
+fn density_text(application_state: &ApplicationState, density: f32) -> String {
+    match application_state.volume.as_ref().and_then(|volume| volume.to_hu(density)) {
+        Some(hu) => format!("{:.0} HU", hu),
+        None => format!("{:.3}", density)
+    }
+}
+
+fn control_text(application_state: &ApplicationState, control: Control) -> String {
+    let transfer_function = &application_state.transfer_function;
+    match control {
+        Control::Cutoff => format!("Cutoff {}", density_text(application_state, application_state.low_cutoff)),
+        Control::Level => format!("Level {}", density_text(application_state, transfer_function.level)),
+        Control::Window => match application_state.volume.as_ref().and_then(|volume| volume.hu_range) {
+            Some(hu_range) => format!("Window {:.0} HU", transfer_function.window * (hu_range.y - hu_range.x)),
+            None => format!("Window {:.3}", transfer_function.window)
+        },
+        Control::ColorPoint(_) => String::new(),
+        Control::Clip { axis, .. } => format!(
+            "Clip {} {:.0}-{:.0}%", AXIS_NAMES[axis], application_state.clip_box.min[axis] * 100.0, application_state.clip_box.max[axis] * 100.0
+        )
+    }
+}
+
+fn control_value(application_state: &ApplicationState, control: Control) -> f32 {
+    match control {
+        Control::Cutoff => application_state.low_cutoff,
+        Control::Level => application_state.transfer_function.level,
+        Control::Window => application_state.transfer_function.window,
+        Control::ColorPoint(index) => application_state.transfer_function.points[index].position,
+        Control::Clip { axis, upper } => if upper { application_state.clip_box.max[axis] } else { application_state.clip_box.min[axis] }
+    }
+}
+
+/// Apply a drag of `control` to pixel column `x`. Editing the transfer function switches it on.
+fn drag_control(application_state: &mut ApplicationState, control: Control, x: i32) -> Result<(), CT3DError> {
+    let layout = layout(application_state.width, application_state.height);
+    let slider_value = layout.slider(control).map(|slider| slider.value_at(x));
+    let transfer_function = &mut application_state.transfer_function;
+    match control {
+        Control::Cutoff => {
+            application_state.low_cutoff = slider_value.unwrap();
+            return Ok(());
+        },
+        Control::Clip { axis, upper } => {
+            if upper {
+                application_state.clip_box.set_max(axis, slider_value.unwrap());
+            } else {
+                application_state.clip_box.set_min(axis, slider_value.unwrap());
+            }
+            return Ok(());
+        },
+        Control::Level => transfer_function.set_level(slider_value.unwrap()),
+        Control::Window => transfer_function.set_window(slider_value.unwrap()),
+        Control::ColorPoint(index) => {
+            let position = layout.editor.position_at(transfer_function, x);
+            let index = transfer_function.move_point(index, position);
+            application_state.overlay.dragging = Some(Control::ColorPoint(index));
+        }
+    }
+    application_state.transfer_function.enabled = true;
+    crate::application::update_transfer_function(application_state)
+}
+
+/// Give the overlay the first look at window input. Returns true when the control panel used it, in
+/// which case it should not go on to the keybindings. Pointer positions are taken from the last `PointerMoved`.
+pub fn handle_input(event: &InputEvent, application_state: &mut ApplicationState) -> Result<bool, CT3DError> {
+    match event {
+        InputEvent::PointerMoved { x, y } => match application_state.overlay.dragging {
+            Some(control) => {
+                application_state.mouse_x = *x;
+                application_state.mouse_y = *y;
+                drag_control(application_state, control, *x)?;
+                Ok(true)
+            },
+            None => Ok(false)
+        },
+        InputEvent::Pressed(button) if button == MOUSE_LEFT || button == MOUSE_RIGHT => {
+            let (x, y) = (application_state.mouse_x, application_state.mouse_y);
+            if application_state.overlay.show_measurements {
+                let list = measurement_list(application_state);
+                if list.panel.contains(x, y) {
+                    if let Some(index) = list.delete_at(x, y).filter(|_| button == MOUSE_LEFT) {
+                        crate::tools::measurements::delete(application_state, index);
+                    }
+                    return Ok(true);
+                }
+            }
+            let layout = layout(application_state.width, application_state.height);
+            if !application_state.overlay.show_controls || !layout.panel.contains(x, y) {
+                return Ok(false);
+            }
+            let point = layout.editor.point_at(&application_state.transfer_function, x, y);
+            let slider = layout.sliders.iter().find(|(_, slider)| slider.contains(x, y));
+            if button == MOUSE_RIGHT {
+                if let Some(index) = point {
+                    application_state.transfer_function.remove_point(index);
+                    crate::application::update_transfer_function(application_state)?;
+                } else if let Some((Control::Clip { axis, .. }, _)) = slider {
+                    application_state.clip_box.reset_axis(*axis);
+                }
+            } else if let Some((control, slider)) = slider {
+                // Clip sliders move whichever face is nearer
+                let control = match *control {
+                    Control::Clip { axis, .. } => {
+                        let value = slider.value_at(x);
+                        let clip_box = &application_state.clip_box;
+                        Control::Clip { axis, upper: (value - clip_box.max[axis]).abs() < (value - clip_box.min[axis]).abs() }
+                    },
+                    control => control
+                };
+                application_state.overlay.dragging = Some(control);
+                drag_control(application_state, control, x)?;
+            } else if layout.editor.contains(x, y) {
+                let index = match point {
+                    Some(index) => index,
+                    None => {
+                        let position = layout.editor.position_at(&application_state.transfer_function, x);
+                        application_state.transfer_function.insert_point(position)
+                    }
+                };
+                application_state.overlay.dragging = Some(Control::ColorPoint(index));
+                drag_control(application_state, Control::ColorPoint(index), x)?;
+            }
+            // Clicks on the panel never reach the volume behind it
+            Ok(true)
+        },
+        InputEvent::Released(button) if button == MOUSE_LEFT => Ok(application_state.overlay.dragging.take().is_some()),
+        InputEvent::Wheel(steps) => {
+            let (x, y) = (application_state.mouse_x, application_state.mouse_y);
+            if application_state.overlay.show_measurements {
+                let list = measurement_list(application_state);
+                if list.panel.contains(x, y) {
+                    // Wheel up goes back towards the first measurement
+                    let last_first = application_state.measurements.len() - list.rows.len();
+                    application_state.overlay.measurement_scroll = (list.first as i32 - steps).max(0).min(last_first as i32) as usize;
+                    return Ok(true);
+                }
+            }
+            let layout = layout(application_state.width, application_state.height);
+            if !application_state.overlay.show_controls || !layout.panel.contains(x, y) {
+                return Ok(false);
+            }
+            if let Some(index) = layout.editor.point_at(&application_state.transfer_function, x, y) {
+                let point = &mut application_state.transfer_function.points[index];
+                let current = POINT_COLORS.iter().position(|color| *color == point.color).unwrap_or(0) as i32;
+                point.color = POINT_COLORS[(current + steps.signum()).rem_euclid(POINT_COLORS.len() as i32) as usize];
+                application_state.transfer_function.enabled = true;
+                crate::application::update_transfer_function(application_state)?;
+            }
+            Ok(true)
+        },
+        _ => Ok(false)
+    }
+}
+
+fn file_name(path: &str) -> String {
+    Path::new(path).file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_else(|| path.to_owned())
+}
+
+/// What the HUD shows: the files loaded, cutoff, active tool or mode, and recent measurements
+pub fn hud_lines(application_state: &ApplicationState) -> Vec<String> {
+    let mut lines = Vec::new();
+    if let Some(source) = application_state.volume_source.as_ref() {
+        lines.push(file_name(source));
+    }
+    if let Some(source) = application_state.labelmap_source.as_ref() {
+        lines.push(format!("Labels {}{}", file_name(source), if application_state.show_labels { "" } else { " (hidden)" }));
+    }
+    lines.push(control_text(application_state, Control::Cutoff));
+    if application_state.transfer_function.enabled {
+        lines.push(format!("Transfer function, {}", control_text(application_state, Control::Window)));
+    }
+    if application_state.clip_box.is_clipped() {
+        lines.push("Clipped".to_owned());
+    }
+    if let Some(tool) = application_state.measurement_tool {
+        lines.push(format!("Tool {}", tool.name()));
+    }
+    if let Some(preview) = application_state.filter_preview.as_ref() {
+        lines.push(format!("Preview {}", preview.filter.describe()));
+    }
+    if application_state.playback.playing {
+        lines.push(format!("Playing {:.1} s", application_state.playback.time));
+    }
+    if let Some(volume) = application_state.volume.as_ref() {
+        let count = application_state.measurements.len();
+        for (index, measurement) in application_state.measurements.iter().enumerate().skip(count.saturating_sub(HUD_MEASUREMENTS)) {
+            lines.push(format!("{}. {}", index + 1, measurement.describe(volume)));
+        }
+    }
+    lines
+}
+
+/// Relative counts of densities 0 to 1, on a log scale so small peaks stay visible
+fn histogram(application_state: &ApplicationState) -> Vec<f32> {
+    let volume = match application_state.volume.as_ref() {
+        Some(volume) => volume,
+        None => return Vec::new()
+    };
+    let mut counts = vec![0.0f32; HISTOGRAM_BINS];
+    for value in volume.data.iter() {
+        let bin = (value.max(0.0).min(1.0) * (HISTOGRAM_BINS - 1) as f32).round() as usize;
+        counts[bin] += 1.0;
+    }
+    let max = counts.iter().map(|count| count.ln_1p()).fold(0.0, f32::max).max(f32::EPSILON);
+    counts.iter().map(|count| count.ln_1p() / max).collect()
+}
+
+fn draw_controls(application_state: &mut ApplicationState) {
+    if application_state.overlay.histogram.is_none() {
+        application_state.overlay.histogram = Some(histogram(application_state));
+    }
+    let layout = layout(application_state.width, application_state.height);
+    application_state.screen_buffer.blend_rect(layout.panel.x, layout.panel.y, layout.panel.width, layout.panel.height, (0, 0, 0), 0.5);
+    for (control, slider) in layout.sliders.iter() {
+        let text = control_text(application_state, *control);
+        match *control {
+            Control::Clip { axis, .. } => {
+                let clip_box = application_state.clip_box;
+                slider.draw_range(&mut application_state.screen_buffer, clip_box.min[axis], clip_box.max[axis], &text);
+            },
+            control => {
+                let value = control_value(application_state, control);
+                slider.draw(&mut application_state.screen_buffer, value, &text);
+            }
+        }
+    }
+    let histogram = application_state.overlay.histogram.take().unwrap();
+    layout.editor.draw(&mut application_state.screen_buffer, &application_state.transfer_function, &histogram, application_state.low_cutoff);
+    application_state.overlay.histogram = Some(histogram);
+}
+
+fn draw_measurement_list(application_state: &mut ApplicationState) {
+    let list = measurement_list(application_state);
+    let count = application_state.measurements.len();
+    let title = if count == 0 {
+        "No measurements".to_owned()
+    } else if list.rows.len() < count {
+        format!("Measurements {}-{} of {}", list.first + 1, list.first + list.rows.len(), count)
+    } else {
+        format!("Measurements ({})", count)
+    };
+    let lines: Vec<String> = application_state.measurements.iter().enumerate().skip(list.first).take(list.rows.len())
+        .map(|(index, measurement)| match application_state.volume.as_ref() {
+            Some(volume) => format!("{}. {}", index + 1, measurement.describe(volume)),
+            None => format!("{}. {}", index + 1, measurement.kind.name())
+        })
+        .collect();
+
+    let image = &mut application_state.screen_buffer;
+    image.blend_rect(list.panel.x, list.panel.y, list.panel.width, list.panel.height, (0, 0, 0), 0.5);
+    image.draw_text(list.panel.x + MARGIN + 3, list.panel.y + MARGIN + 3, &title, TEXT_SCALE, TEXT_COLOR);
+    for (row, line) in list.rows.iter().zip(lines.iter()) {
+        image.draw_text(row.x + 3, row.y + 3, line, TEXT_SCALE, TEXT_COLOR);
+        let button = MeasurementList::delete_button(row);
+        image.blend_rect(button.x + 1, button.y + 1, button.width - 2, button.height - 2, DELETE_COLOR, 1.0);
+        image.draw_text(button.x + (button.width - text_width("X", TEXT_SCALE) as i32) / 2, button.y + 3, "X", TEXT_SCALE, TEXT_COLOR);
+    }
+}
+
+/// Draw the HUD, the control panel, the measurement list and the toasts over the rendered image
+pub fn draw_overlays(application_state: &mut ApplicationState) {
+    let row = line_height();
+    if application_state.overlay.show_hud {
+        for (index, line) in hud_lines(application_state).iter().enumerate() {
+            draw_label(&mut application_state.screen_buffer, MARGIN, MARGIN + index as i32 * row, line, TEXT_COLOR);
+        }
+    }
+
+    if application_state.overlay.show_measurements {
+        draw_measurement_list(application_state);
+    }
+
+    let mut bottom = application_state.height as i32 - MARGIN;
+    if application_state.overlay.show_controls {
+        draw_controls(application_state);
+        bottom = layout(application_state.width, application_state.height).panel.y - MARGIN;
+    }
+
+    application_state.overlay.toasts.retain(|toast| toast.shown.elapsed().as_secs_f32() < TOAST_SECONDS);
+    let toasts: Vec<(String, bool)> = application_state.overlay.toasts.iter().map(|toast| (toast.text.clone(), toast.error)).collect();
+    for (index, (text, error)) in toasts.iter().rev().enumerate() {
+        let y = bottom - (index as i32 + 1) * row;
+        draw_label(&mut application_state.screen_buffer, MARGIN, y, text, if *error { ERROR_COLOR } else { TEXT_COLOR });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn press_at(application_state: &mut ApplicationState, x: i32, y: i32) -> bool {
+        application_state.mouse_x = x;
+        application_state.mouse_y = y;
+        handle_input(&InputEvent::Pressed(MOUSE_LEFT.to_owned()), application_state).unwrap()
+    }
+
+    #[test]
+    fn sliders_capture_drags_only_when_the_panel_is_shown() {
+        let mut application_state = ApplicationState::new(640, 480);
+        let layout = layout(640, 480);
+        let cutoff_track = layout.sliders[0].1.track;
+        let (x, y) = (cutoff_track.x_at(0.25), cutoff_track.y + 1);
+
+        assert!(!press_at(&mut application_state, x, y));
+        assert_eq!(application_state.low_cutoff, 0.0);
+
+        application_state.overlay.show_controls = true;
+        assert!(press_at(&mut application_state, x, y));
+        assert!((application_state.low_cutoff - 0.25).abs() < 0.01);
+        assert!(handle_input(&InputEvent::PointerMoved { x: cutoff_track.x_at(0.75), y: 0 }, &mut application_state).unwrap());
+        assert!((application_state.low_cutoff - 0.75).abs() < 0.01);
+        assert!(handle_input(&InputEvent::Released(MOUSE_LEFT.to_owned()), &mut application_state).unwrap());
+        assert!(!handle_input(&InputEvent::PointerMoved { x: 5, y: 5 }, &mut application_state).unwrap());
+
+        // Above the panel clicks go to the volume
+        assert!(!press_at(&mut application_state, x, layout.panel.y - 1));
+    }
+
+    #[test]
+    fn clip_sliders_move_the_nearer_face() {
+        let mut application_state = ApplicationState::new(640, 480);
+        application_state.overlay.show_controls = true;
+        let layout = layout(640, 480);
+        let track = layout.slider(Control::Clip { axis: 2, upper: true }).unwrap().track;
+
+        assert!(press_at(&mut application_state, track.x_at(0.8), track.y + 1));
+        handle_input(&InputEvent::Released(MOUSE_LEFT.to_owned()), &mut application_state).unwrap();
+        assert!((application_state.clip_box.max.z - 0.8).abs() < 0.01);
+        assert!(press_at(&mut application_state, track.x_at(0.3), track.y + 1));
+        handle_input(&InputEvent::Released(MOUSE_LEFT.to_owned()), &mut application_state).unwrap();
+        assert!((application_state.clip_box.min.z - 0.3).abs() < 0.01);
+        assert!((application_state.clip_box.max.z - 0.8).abs() < 0.01);
+        assert_eq!(hud_lines(&application_state).last().unwrap(), "Clipped");
+
+        application_state.mouse_x = track.x_at(0.5);
+        handle_input(&InputEvent::Pressed(MOUSE_RIGHT.to_owned()), &mut application_state).unwrap();
+        assert!(!application_state.clip_box.is_clipped());
+    }
+
+    #[test]
+    fn clicking_the_editor_adds_a_point_and_switches_the_transfer_function_on() {
+        let mut application_state = ApplicationState::new(640, 480);
+        application_state.overlay.show_controls = true;
+        let editor = layout(640, 480).editor.area;
+        let points = application_state.transfer_function.points.len();
+
+        assert!(press_at(&mut application_state, editor.x_at(0.7), editor.y + 5));
+        handle_input(&InputEvent::Released(MOUSE_LEFT.to_owned()), &mut application_state).unwrap();
+        assert_eq!(application_state.transfer_function.points.len(), points + 1);
+        assert!(application_state.transfer_function.enabled);
+
+        application_state.mouse_x = editor.x_at(0.7);
+        handle_input(&InputEvent::Pressed(MOUSE_RIGHT.to_owned()), &mut application_state).unwrap();
+        assert_eq!(application_state.transfer_function.points.len(), points);
+    }
+
+    #[test]
+    fn the_measurement_list_scrolls_and_deletes() {
+        use crate::types::measurement::{Measurement, MeasurementKind};
+
+        let mut application_state = ApplicationState::new(640, 480);
+        for length in 0..20 {
+            let points = vec![glam::Vec3::ZERO, glam::Vec3::new(length as f32, 0.0, 0.0)];
+            application_state.measurements.push(Measurement { kind: MeasurementKind::Distance, points });
+        }
+        let list = measurement_list(&application_state);
+        assert_eq!((list.first, list.rows.len()), (0, MEASUREMENT_LIST_ROWS));
+        let (x, y) = (list.panel.x + 5, list.panel.y + 5);
+        assert!(!press_at(&mut application_state, x, y));
+
+        application_state.overlay.show_measurements = true;
+        application_state.mouse_x = x;
+        application_state.mouse_y = y;
+        assert!(handle_input(&InputEvent::Wheel(-100), &mut application_state).unwrap());
+        let list = measurement_list(&application_state);
+        assert_eq!(list.first, 20 - MEASUREMENT_LIST_ROWS);
+
+        // The button on the last row deletes the last measurement, clicks elsewhere on the list do nothing
+        let button = MeasurementList::delete_button(list.rows.last().unwrap());
+        assert!(press_at(&mut application_state, button.x + 2, button.y + 2));
+        assert_eq!(application_state.measurements.len(), 19);
+        assert_eq!(application_state.measurements.last().unwrap().points[1].x, 18.0);
+        assert!(press_at(&mut application_state, x, y));
+        assert_eq!(application_state.measurements.len(), 19);
+        assert_eq!(measurement_list(&application_state).first, 19 - MEASUREMENT_LIST_ROWS);
+
+        // The first row deletes the measurement scrolled to the top
+        let list = measurement_list(&application_state);
+        let button = MeasurementList::delete_button(&list.rows[0]);
+        assert!(press_at(&mut application_state, button.x + 2, button.y + 2));
+        assert_eq!(application_state.measurements[19 - MEASUREMENT_LIST_ROWS].points[1].x, (20 - MEASUREMENT_LIST_ROWS) as f32);
+        draw_overlays(&mut application_state);
+    }
+
+    #[test]
+    fn hud_and_toasts_draw_over_the_image() {
+        let mut application_state = ApplicationState::new(320, 240);
+        application_state.volume_source = Some("scans/chest/IM-0001.dcm".to_owned());
+        application_state.low_cutoff = 0.5;
+        assert_eq!(hud_lines(&application_state), vec!["IM-0001.dcm".to_owned(), "Cutoff 0.500".to_owned()]);
+
+        application_state.overlay.show_hud = true;
+        toast_error(&mut application_state, "Could not load".to_owned());
+        draw_overlays(&mut application_state);
+        let image = &application_state.screen_buffer;
+        let lit = |x0: usize, y0: usize, y1: usize| (y0..y1).any(|y| (x0..x0 + 100).any(|x| image.get_pixel(x, y) != (0, 0, 0)));
+        assert!(lit(MARGIN as usize, MARGIN as usize, MARGIN as usize + 20));
+        assert!(lit(MARGIN as usize, 240 - MARGIN as usize - 20, 240 - MARGIN as usize));
+    }
+}
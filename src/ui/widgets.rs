@@ -0,0 +1,164 @@
+use crate::types::rgb_image::RGBImage;
+use crate::types::transfer_function::TransferFunction;
+use crate::tools::bitmap_font::{text_width, GLYPH_HEIGHT};
+
+pub const TEXT_SCALE: usize = 2;
+pub const TEXT_COLOR: (u8, u8, u8) = (255, 255, 255);
+const BACKGROUND_COLOR: (u8, u8, u8) = (0, 0, 0);
+const BACKGROUND_ALPHA: f32 = 0.6;
+const TRACK_COLOR: (u8, u8, u8) = (90, 90, 90);
+const FILL_COLOR: (u8, u8, u8) = (70, 140, 220);
+const HANDLE_COLOR: (u8, u8, u8) = (255, 255, 255);
+const HISTOGRAM_COLOR: (u8, u8, u8) = (120, 120, 120);
+const WINDOW_COLOR: (u8, u8, u8) = (255, 255, 255);
+const CUTOFF_COLOR: (u8, u8, u8) = (255, 210, 60);
+const POINT_RADIUS: i32 = 4;
+const COLOR_STRIP_HEIGHT: i32 = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Rect {
+    pub x: i32,
+    pub y: i32,
+    pub width: i32,
+    pub height: i32
+}
+
+impl Rect {
+    pub fn contains(&self, x: i32, y: i32) -> bool {
+        x >= self.x && y >= self.y && x < self.x + self.width && y < self.y + self.height
+    }
+
+    /// Where `x` falls between the left (0) and right (1) edges, clamped
+    pub fn fraction_at(&self, x: i32) -> f32 {
+        ((x - self.x) as f32 / (self.width - 1).max(1) as f32).max(0.0).min(1.0)
+    }
+
+    pub fn x_at(&self, fraction: f32) -> i32 {
+        self.x + (fraction.max(0.0).min(1.0) * (self.width - 1) as f32).round() as i32
+    }
+}
+
+/// Height of a line of text with the padding `draw_label` puts around it
+pub fn line_height() -> i32 {
+    (GLYPH_HEIGHT * TEXT_SCALE) as i32 + 6
+}
+
+/// Text on a translucent dark box, so it stays readable over any rendering
+pub fn draw_label(image: &mut RGBImage, x: i32, y: i32, text: &str, color: (u8, u8, u8)) {
+    let width = text_width(text, TEXT_SCALE) as i32;
+    image.blend_rect(x, y, width + 6, line_height(), BACKGROUND_COLOR, BACKGROUND_ALPHA);
+    image.draw_text(x + 3, y + 3, text, TEXT_SCALE, color);
+}
+
+/// A horizontal slider over 0 to 1, with its text to the left of the track
+pub struct Slider {
+    pub text_width: i32,
+    pub track: Rect
+}
+
+impl Slider {
+    /// A slider filling `bounds`, with room for text of `text_width` pixels
+    pub fn new(bounds: Rect, text_width: i32) -> Slider {
+        let track = Rect { x: bounds.x + text_width, y: bounds.y + 2, width: (bounds.width - text_width).max(1), height: bounds.height - 4 };
+        Slider { text_width, track }
+    }
+
+    pub fn contains(&self, x: i32, y: i32) -> bool {
+        self.track.contains(x, y)
+    }
+
+    pub fn value_at(&self, x: i32) -> f32 {
+        self.track.fraction_at(x)
+    }
+
+    pub fn draw(&self, image: &mut RGBImage, value: f32, text: &str) {
+        image.draw_text(self.track.x - self.text_width, self.track.y + (self.track.height - (GLYPH_HEIGHT * TEXT_SCALE) as i32) / 2, text, TEXT_SCALE, TEXT_COLOR);
+        image.blend_rect(self.track.x, self.track.y, self.track.width, self.track.height, TRACK_COLOR, 1.0);
+        let handle_x = self.track.x_at(value);
+        image.blend_rect(self.track.x, self.track.y, handle_x - self.track.x, self.track.height, FILL_COLOR, 1.0);
+        image.blend_rect(handle_x - 2, self.track.y - 2, 5, self.track.height + 4, HANDLE_COLOR, 1.0);
+    }
+
+    /// A range from `low` to `high` with a handle at each end
+    pub fn draw_range(&self, image: &mut RGBImage, low: f32, high: f32, text: &str) {
+        image.draw_text(self.track.x - self.text_width, self.track.y + (self.track.height - (GLYPH_HEIGHT * TEXT_SCALE) as i32) / 2, text, TEXT_SCALE, TEXT_COLOR);
+        image.blend_rect(self.track.x, self.track.y, self.track.width, self.track.height, TRACK_COLOR, 1.0);
+        let (low_x, high_x) = (self.track.x_at(low), self.track.x_at(high));
+        image.blend_rect(low_x, self.track.y, high_x - low_x, self.track.height, FILL_COLOR, 1.0);
+        for handle_x in [low_x, high_x] {
+            image.blend_rect(handle_x - 2, self.track.y - 2, 5, self.track.height + 4, HANDLE_COLOR, 1.0);
+        }
+    }
+}
+
+/// Densities 0 to 1 from left to right over the volume's histogram, with the window shaded, the
+/// ramp of colours along the bottom and its colour points as handles
+pub struct TransferFunctionEditor {
+    pub area: Rect
+}
+
+impl TransferFunctionEditor {
+    pub fn contains(&self, x: i32, y: i32) -> bool {
+        self.area.contains(x, y)
+    }
+
+    fn point_x(&self, transfer_function: &TransferFunction, index: usize) -> i32 {
+        self.area.x_at(transfer_function.density_at(transfer_function.points[index].position))
+    }
+
+    fn point_y(&self) -> i32 {
+        self.area.y + self.area.height - COLOR_STRIP_HEIGHT / 2
+    }
+
+    /// Position on the transfer function's ramp of the density at `x`
+    pub fn position_at(&self, transfer_function: &TransferFunction, x: i32) -> f32 {
+        transfer_function.position_of(self.area.fraction_at(x))
+    }
+
+    /// The point whose handle is at (x, y), if any. Points share a row, so any height within the editor will do.
+    pub fn point_at(&self, transfer_function: &TransferFunction, x: i32, y: i32) -> Option<usize> {
+        if !self.contains(x, y) {
+            return None;
+        }
+        (0..transfer_function.points.len())
+            .map(|index| (index, (self.point_x(transfer_function, index) - x).abs()))
+            .filter(|(_, distance)| *distance <= POINT_RADIUS + 1)
+            .min_by_key(|(_, distance)| *distance)
+            .map(|(index, _)| index)
+    }
+
+    /// `histogram` holds relative bin heights (0 to 1) over densities 0 to 1
+    pub fn draw(&self, image: &mut RGBImage, transfer_function: &TransferFunction, histogram: &[f32], cutoff: f32) {
+        let area = self.area;
+        image.blend_rect(area.x, area.y, area.width, area.height, BACKGROUND_COLOR, BACKGROUND_ALPHA);
+
+        let bars_height = area.height - COLOR_STRIP_HEIGHT;
+        if !histogram.is_empty() {
+            for column in 0..area.width {
+                let bin = (area.fraction_at(area.x + column) * (histogram.len() - 1) as f32).round() as usize;
+                let height = (histogram[bin] * bars_height as f32) as i32;
+                image.blend_rect(area.x + column, area.y + bars_height - height, 1, height, HISTOGRAM_COLOR, 1.0);
+            }
+        }
+
+        let window_left = area.x_at(transfer_function.density_at(0.0));
+        let window_right = area.x_at(transfer_function.density_at(1.0));
+        image.blend_rect(window_left, area.y, window_right - window_left + 1, bars_height, WINDOW_COLOR, 0.15);
+
+        for column in 0..area.width {
+            let color = transfer_function.color_of(area.fraction_at(area.x + column));
+            let color = ((color[0] * 255.0) as u8, (color[1] * 255.0) as u8, (color[2] * 255.0) as u8);
+            image.blend_rect(area.x + column, area.y + bars_height, 1, COLOR_STRIP_HEIGHT, color, 1.0);
+        }
+
+        let cutoff_x = area.x_at(cutoff);
+        image.draw_line(cutoff_x, area.y, cutoff_x, area.y + bars_height - 1, CUTOFF_COLOR);
+
+        for (index, point) in transfer_function.points.iter().enumerate() {
+            let (x, y) = (self.point_x(transfer_function, index), self.point_y());
+            let color = ((point.color[0] * 255.0) as u8, (point.color[1] * 255.0) as u8, (point.color[2] * 255.0) as u8);
+            image.draw_marker(x, y, POINT_RADIUS + 1, HANDLE_COLOR);
+            image.draw_marker(x, y, POINT_RADIUS - 1, color);
+        }
+    }
+}
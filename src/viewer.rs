@@ -0,0 +1,206 @@
+use std::time::Instant;
+
+use sdl2::mouse::MouseButton;
+use sdl2::pixels::{Color, PixelFormatEnum};
+use sdl2::event::{Event, WindowEvent};
+use sdl2::keyboard::Keycode;
+
+use ct3d3::types::application_state::ApplicationState;
+use ct3d3::types::ct3d_error::CT3DError;
+use ct3d3::tools::frame_timing::{FrameLimiter, MIN_FPS};
+use ct3d3::cli::CommandLine;
+use ct3d3::input::keybindings::{InputEvent, InputMapper, Keybindings, KEYBINDINGS_PATH, MOUSE_LEFT, MOUSE_MIDDLE, MOUSE_RIGHT};
+
+const SCREEN_WIDTH: u32 = 640;
+const SCREEN_HEIGHT: u32 = 640;
+
+/// The interactive viewer. `args` are its options: `--vsync off`, `--max-fps <n>` and `--session <path>`.
+pub fn run_viewer(args: Vec<String>) -> Result<(), CT3DError> {
+    let command_line = CommandLine::parse(args)?;
+    let vsync = command_line.switch_option("vsync", true)?;
+    let max_fps = command_line.parsed_option("max-fps", 0.0f32)?;
+    if max_fps.is_nan() || (max_fps != 0.0 && max_fps < MIN_FPS) {
+        return Err(ct3d3::cli::usage_error(format!("--max-fps must be 0 (no limit) or at least {}, found {}", MIN_FPS, max_fps)));
+    }
+
+    // Initialize SDL2
+    let sdl_context = sdl2::init().map_err(CT3DError::Sdl)?;
+    let video_subsystem = sdl_context.video().map_err(CT3DError::Sdl)?;
+
+    // Create the window
+    let window = video_subsystem.window("CT3D", SCREEN_WIDTH, SCREEN_HEIGHT)
+        .position_centered()
+        .resizable()
+        .allow_highdpi()
+        .build()?;
+
+    // Create a canvas for the screen
+    let mut canvas_builder = window.into_canvas();
+    if vsync {
+        canvas_builder = canvas_builder.present_vsync();
+    }
+    let mut canvas = canvas_builder.build()?;
+    let mut frame_limiter = FrameLimiter::new(Some(max_fps));
+
+    // Set the background color to white
+    canvas.set_draw_color(Color::RGB(255, 255, 255));
+    canvas.clear();
+    canvas.present();
+
+    // Create the event pump
+    let mut event_pump = sdl_context.event_pump().map_err(CT3DError::Sdl)?;
+    
+    let texture_creator = canvas.texture_creator();
+
+    // Render at the drawable size, which is larger than the window size on HiDPI displays.
+    // Mouse positions come in window coordinates and are scaled by `pixel_scale`.
+    let (drawable_width, drawable_height) = canvas.output_size().map_err(CT3DError::Sdl)?;
+    let mut pixel_scale = drawable_width as f32 / canvas.window().size().0 as f32;
+
+    let mut screen_texture = texture_creator.create_texture_streaming(PixelFormatEnum::ARGB8888,drawable_width,drawable_height)?;
+
+    // Not exactly just the app state. Also is the owner of any variables that need to passed around by reference
+    let mut application_state = ApplicationState::new(drawable_width, drawable_height);
+    application_state.write_hit_buffer = true;
+    application_state.progressive_rendering = true;
+    application_state.overlay.show_hud = true;
+
+    ct3d3::application::init(&mut application_state)?;
+
+    // A session that does not exist yet is where Ctrl+S will save
+    if let Some(session_path) = command_line.option("session") {
+        application_state.session_path = session_path.clone();
+        if std::path::Path::new(session_path).exists() {
+            ct3d3::tools::session_tool::load(&mut application_state, session_path.clone())?;
+        }
+    }
+
+    let mut input_mapper = InputMapper::new(Keybindings::load_or_default(KEYBINDINGS_PATH)?);
+
+    let mut window_title = String::new();
+    let mut render_error: Option<String> = None;
+
+    // Initialize the previous frame time to the current time
+    let mut prev_frame_time = Instant::now();
+
+    // Run the event loop
+    'running: loop {
+        for event in event_pump.poll_iter() {
+            let result = match event {
+                Event::Quit {..} | Event::KeyDown { keycode: Some(Keycode::Escape), .. } => {
+                    break 'running
+                },
+                Event::Window { win_event: WindowEvent::Resized(..) | WindowEvent::SizeChanged(..), .. } => {
+                    match canvas.output_size().map_err(CT3DError::Sdl) {
+                        Ok((drawable_width, drawable_height)) => {
+                            pixel_scale = drawable_width as f32 / canvas.window().size().0.max(1) as f32;
+                            if drawable_width > 0 && drawable_height > 0 && (drawable_width, drawable_height) != (application_state.width, application_state.height) {
+                                ct3d3::application::resize(drawable_width, drawable_height, &mut application_state).and_then(|_| {
+                                    screen_texture = texture_creator.create_texture_streaming(PixelFormatEnum::ARGB8888,drawable_width,drawable_height)?;
+                                    Ok(())
+                                })
+                            } else {
+                                Ok(())
+                            }
+                        },
+                        Err(e) => Err(e)
+                    }
+                },
+                event => {
+                    let mut result = Ok(());
+                    for input_event in input_events(event, pixel_scale) {
+                        match ct3d3::ui::overlay::handle_input(&input_event, &mut application_state) {
+                            Ok(true) => continue,
+                            Ok(false) => {},
+                            Err(e) => {
+                                result = Err(e);
+                                continue;
+                            }
+                        }
+                        for action in input_mapper.handle(input_event) {
+                            result = result.and(ct3d3::application::perform(&action, &mut application_state));
+                        }
+                    }
+                    result
+                }
+            };
+            // A failed action (a file that does not load, say) is reported and the viewer keeps running
+            if let Err(e) = result {
+                report_error(&mut application_state, e);
+            }
+        }
+
+        // Measure the delta time by subtracting the previous frame time from the current time
+        let delta_time = Instant::now() - prev_frame_time;
+        prev_frame_time = Instant::now();
+
+        for action in input_mapper.held_actions(delta_time.as_secs_f32()) {
+            if let Err(e) = ct3d3::application::perform(&action, &mut application_state) {
+                report_error(&mut application_state, e);
+            }
+        }
+        // A render error (a kernel that does not build after a hot reload, say) leaves the last frame up.
+        // It is reported once rather than every frame until it goes away.
+        match ct3d3::application::main(&mut application_state, delta_time) {
+            Ok(()) => render_error = None,
+            Err(e) => if render_error.as_ref() != Some(&e.to_string()) {
+                render_error = Some(e.to_string());
+                report_error(&mut application_state, e);
+            }
+        }
+
+        application_state.screen_buffer.copy_to_texture(&mut screen_texture)?;
+
+        canvas.copy(&screen_texture, sdl2::rect::Rect::new(0,0,application_state.width, application_state.height), sdl2::rect::Rect::new(0,0,application_state.width, application_state.height)).map_err(CT3DError::Sdl)?;
+
+        canvas.present();
+        frame_limiter.wait();
+
+        let probe_readout = ct3d3::application::probe_readout(&application_state);
+        let new_window_title = if probe_readout.is_empty() { "CT3D".to_owned() } else { format!("CT3D - {}", probe_readout) };
+        if new_window_title != window_title {
+            canvas.window_mut().set_title(&new_window_title).map_err(|e| CT3DError::Sdl(e.to_string()))?;
+            window_title = new_window_title;
+        }
+    }
+
+    ct3d3::application::quit()
+
+}
+
+/// Print the error and show it in the overlay, the viewer keeps running
+fn report_error(application_state: &mut ApplicationState, e: CT3DError) {
+    eprintln!("{}", e);
+    ct3d3::ui::overlay::toast_error(application_state, e.to_string());
+}
+
+fn mouse_button_name(button: MouseButton) -> Option<&'static str> {
+    match button {
+        MouseButton::Left => Some(MOUSE_LEFT),
+        MouseButton::Middle => Some(MOUSE_MIDDLE),
+        MouseButton::Right => Some(MOUSE_RIGHT),
+        _ => None
+    }
+}
+
+/// The window events the keybindings apply to, with mouse positions in drawable pixels
+fn input_events(event: Event, pixel_scale: f32) -> Vec<InputEvent> {
+    let scaled = |x: i32, y: i32| InputEvent::PointerMoved { x: (x as f32 * pixel_scale) as i32, y: (y as f32 * pixel_scale) as i32 };
+    match event {
+        Event::KeyDown { scancode: Some(scancode), repeat: false, .. } => vec![InputEvent::Pressed(scancode.name().to_owned())],
+        Event::KeyUp { scancode: Some(scancode), .. } => vec![InputEvent::Released(scancode.name().to_owned())],
+        // Buttons act where they are pressed, which may not be where the last motion event left the pointer
+        Event::MouseButtonDown { mouse_btn, x, y, .. } => match mouse_button_name(mouse_btn) {
+            Some(name) => vec![scaled(x, y), InputEvent::Pressed(name.to_owned())],
+            None => Vec::new()
+        },
+        Event::MouseButtonUp { mouse_btn, x, y, .. } => match mouse_button_name(mouse_btn) {
+            Some(name) => vec![scaled(x, y), InputEvent::Released(name.to_owned())],
+            None => Vec::new()
+        },
+        Event::MouseMotion { x, y, .. } => vec![scaled(x, y)],
+        Event::MouseWheel { y, .. } => vec![InputEvent::Wheel(y)],
+        Event::DropFile { filename, .. } => vec![InputEvent::FileDropped(filename)],
+        _ => Vec::new()
+    }
+}